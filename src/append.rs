@@ -0,0 +1,185 @@
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+use crate::output::{self, LineEnding};
+use crate::terminal::ProcessedScreen;
+
+/// Format seconds since the Unix epoch as `YYYY-MM-DD HH:MM:SS UTC`.
+fn format_utc(secs: u64) -> String {
+    let days = secs / 86_400;
+    let rem = secs % 86_400;
+    // Civil-from-days (Howard Hinnant), valid for any date after 1970
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Append the capture's plain text to `path` under a separator header.
+/// The whole record is written in one call while holding an exclusive lock,
+/// so concurrent captures never interleave.
+pub(crate) fn append_capture(
+    path: &Path,
+    screen: &ProcessedScreen,
+    source: &str,
+    now: SystemTime,
+    ending: LineEnding,
+) -> Result<()> {
+    let secs = now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let mut record =
+        format!("==== kakoune-scrollback {source} {} ====", format_utc(secs)).into_bytes();
+    record.extend_from_slice(ending.as_bytes());
+    output::write_text_to(&mut record, screen, ending)?;
+
+    let mut f = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    f.lock()
+        .with_context(|| format!("failed to lock {}", path.display()))?;
+    f.write_all(&record)
+        .with_context(|| format!("failed to append to {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kitty::PipeData;
+    use crate::palette;
+    use crate::terminal::{self, DEFAULT_MAX_SCROLLBACK_LINES};
+    use std::time::Duration;
+
+    fn screen_for(input: &[u8]) -> ProcessedScreen {
+        let pd = PipeData {
+            cursor_x: 0,
+            cursor_y: 0,
+            lines: 24,
+            columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        terminal::process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        )
+    }
+
+    #[test]
+    fn format_utc_known_dates() {
+        assert_eq!(format_utc(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_utc(951_782_400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(format_utc(1_700_000_000), "2023-11-14 22:13:20 UTC");
+    }
+
+    #[test]
+    fn append_two_captures_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("captures.log");
+        let t0 = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        append_capture(
+            &log,
+            &screen_for(b"\x1b[31mfirst\x1b[0m"),
+            "kitty:1",
+            t0,
+            LineEnding::Lf,
+        )
+        .unwrap();
+        append_capture(
+            &log,
+            &screen_for(b"second\r\nthird"),
+            "tmux:%5",
+            t0 + Duration::from_secs(60),
+            LineEnding::Lf,
+        )
+        .unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&log).unwrap(),
+            "==== kakoune-scrollback kitty:1 2023-11-14 22:13:20 UTC ====\n\
+             first\n\
+             ==== kakoune-scrollback tmux:%5 2023-11-14 22:14:20 UTC ====\n\
+             second\n\
+             third\n"
+        );
+    }
+
+    #[test]
+    fn append_crlf() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("captures.log");
+        let t0 = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        append_capture(
+            &log,
+            &screen_for(b"a\r\nb"),
+            "kitty:1",
+            t0,
+            LineEnding::Crlf,
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read(&log).unwrap(),
+            b"==== kakoune-scrollback kitty:1 2023-11-14 22:13:20 UTC ====\r\na\r\nb\r\n"
+        );
+    }
+
+    #[test]
+    fn concurrent_appends_do_not_interleave() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("captures.log");
+        let body = "x".repeat(4000);
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let log = log.clone();
+                let input = format!("{i}{body}");
+                std::thread::spawn(move || {
+                    let pd = PipeData {
+                        cursor_x: 0,
+                        cursor_y: 0,
+                        lines: 24,
+                        columns: 5000,
+                        scrolled_by: 0,
+                        cursor_shape: None,
+                    };
+                    let screen = terminal::process_bytes(
+                        &pd,
+                        input.as_bytes(),
+                        &palette::DEFAULT_PALETTE,
+                        DEFAULT_MAX_SCROLLBACK_LINES,
+                    );
+                    append_capture(&log, &screen, "kitty:1", SystemTime::now(), LineEnding::Lf)
+                        .unwrap();
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        let content = std::fs::read_to_string(&log).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 16);
+        for pair in lines.chunks(2) {
+            assert!(pair[0].starts_with("==== kakoune-scrollback"));
+            assert_eq!(pair[1].len(), 4001);
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use std::fmt;
+use std::fmt::Write as FmtWrite;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use crate::face_rules::FaceRules;
+use crate::kitty::{self, PipeData};
+use crate::output::{self, LineEnding};
+use crate::palette;
+use crate::terminal;
+use crate::TargetId;
+
+/// Screen the synthetic capture is replayed on.
+const ROWS: u16 = 24;
+const COLUMNS: u16 = 120;
+
+/// Generate `lines` lines of colored input mixing the 16-color, 256-color
+/// and truecolor SGR forms with bold/italic/underline, plus a wide
+/// character, so every face path of the processor is exercised. The same
+/// size always yields the same bytes.
+pub(crate) fn synthetic_input(lines: usize) -> Vec<u8> {
+    let mut s = String::new();
+    for i in 0..lines {
+        if i > 0 {
+            s.push_str("\r\n");
+        }
+        write!(
+            s,
+            "{i:06} \x1b[3{}mlevel\x1b[0m \x1b[38;5;{}mmodule::path\x1b[0m \
+             \x1b[38;2;{};{};200m\x1b[1mtruecolor\x1b[22m\x1b[0m \
+             \x1b[3;4mstyled\x1b[0m plain text 日本 \x1b[4{}m bg \x1b[0m",
+            i % 8,
+            16 + i % 216,
+            i % 256,
+            (i * 7) % 256,
+            i % 8,
+        )
+        .unwrap();
+    }
+    s.into_bytes()
+}
+
+/// Timings and sizes of one benchmark run.
+pub(crate) struct BenchReport {
+    pub input_bytes: usize,
+    pub lines: usize,
+    pub spans: usize,
+    pub max_line_spans: usize,
+    pub process: Duration,
+    pub write: Duration,
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "input:   {} bytes", self.input_bytes)?;
+        writeln!(
+            f,
+            "output:  {} lines, {} spans (at most {} on one line)",
+            self.lines, self.spans, self.max_line_spans
+        )?;
+        writeln!(f, "process: {:.3}s", self.process.as_secs_f64())?;
+        writeln!(f, "write:   {:.3}s", self.write.as_secs_f64())?;
+        write!(
+            f,
+            "total:   {:.3}s",
+            (self.process + self.write).as_secs_f64()
+        )
+    }
+}
+
+/// Process `lines` lines of synthetic input and render the buffer text,
+/// ranges and init script into memory, timing each half.
+pub(crate) fn run(lines: usize) -> Result<BenchReport> {
+    let input = synthetic_input(lines);
+    let pipe_data = PipeData {
+        cursor_x: 0,
+        cursor_y: usize::from(ROWS) - 1,
+        lines: ROWS,
+        columns: COLUMNS,
+        scrolled_by: 0,
+        cursor_shape: None,
+    };
+
+    let start = Instant::now();
+    let screen = terminal::process_bytes_traced(
+        &pipe_data,
+        &input,
+        &palette::DEFAULT_PALETTE,
+        lines + usize::from(ROWS),
+        &terminal::ProcessOptions::default(),
+        None,
+    );
+    let process = start.elapsed();
+
+    let start = Instant::now();
+    let mut text = Vec::new();
+    output::write_text_to(&mut text, &screen, LineEnding::Lf)?;
+    let mut ranges = Vec::new();
+    output::write_ranges_to(
+        &mut ranges,
+        &screen,
+        output::RangesWrapMode::Direct,
+        None,
+        &FaceRules::default(),
+        false,
+    )?;
+    let tmp_dir = Path::new("/tmp/ksb-benchmark");
+    output::render_init_kak(
+        &screen,
+        &TargetId::Kitty(kitty::parse_window_id("1")?),
+        tmp_dir,
+        &tmp_dir.join("ranges.kak"),
+        &output::InitOptions::default(),
+    )?;
+    let write = start.elapsed();
+
+    Ok(BenchReport {
+        input_bytes: input.len(),
+        lines: screen.lines.len(),
+        spans: screen.lines.iter().map(|l| l.spans.len()).sum(),
+        max_line_spans: screen
+            .lines
+            .iter()
+            .map(|l| l.spans.len())
+            .max()
+            .unwrap_or(0),
+        process,
+        write,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn synthetic_input_is_deterministic() {
+        assert_eq!(synthetic_input(50), synthetic_input(50));
+        assert!(synthetic_input(0).is_empty());
+        let input = String::from_utf8(synthetic_input(3)).unwrap();
+        assert_eq!(input.split("\r\n").count(), 3);
+    }
+
+    #[test]
+    fn run_small_benchmark() {
+        let report = run(100).unwrap();
+        assert_eq!(report.lines, 100);
+        assert!(report.spans > 0);
+        assert!(report.max_line_spans > 0);
+        assert!(report.to_string().contains("100 lines"));
+    }
+}
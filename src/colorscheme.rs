@@ -0,0 +1,119 @@
+use std::fmt::Write as FmtWrite;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::output;
+
+/// Kakoune faces and the ANSI color (0-15) each takes its foreground from.
+/// `comment` uses bright black, like most terminal themes dim it.
+const FACE_COLORS: &[(&str, usize)] = &[
+    ("value", 1),
+    ("type", 3),
+    ("variable", 2),
+    ("module", 2),
+    ("function", 4),
+    ("string", 2),
+    ("keyword", 5),
+    ("operator", 3),
+    ("attribute", 6),
+    ("comment", 8),
+    ("documentation", 8),
+    ("meta", 5),
+    ("builtin", 4),
+    ("title", 4),
+    ("header", 6),
+    ("link", 6),
+    ("bullet", 3),
+    ("list", 7),
+    ("LineNumbers", 8),
+    ("Error", 9),
+];
+
+fn rgb(palette: &[u8; 48], idx: usize) -> [u8; 3] {
+    [palette[idx * 3], palette[idx * 3 + 1], palette[idx * 3 + 2]]
+}
+
+fn kak([r, g, b]: [u8; 3]) -> String {
+    format!("rgb:{r:02X}{g:02X}{b:02X}")
+}
+
+/// Default foreground/background when the terminal does not report them:
+/// white on black from the palette.
+pub(crate) fn palette_defaults(palette: &[u8; 48]) -> ([u8; 3], [u8; 3]) {
+    (rgb(palette, 7), rgb(palette, 0))
+}
+
+/// Render a Kakoune colorscheme from a resolved palette and the terminal's
+/// default colors.
+pub(crate) fn render(palette: &[u8; 48], fg: [u8; 3], bg: [u8; 3]) -> String {
+    let mut s = String::from("# Generated by kakoune-scrollback --export-colorscheme\n\n");
+    writeln!(s, "set-face global Default {},{}", kak(fg), kak(bg)).unwrap();
+    for (face, idx) in FACE_COLORS {
+        writeln!(s, "set-face global {face} {}", kak(rgb(palette, *idx))).unwrap();
+    }
+    writeln!(s, "set-face global StatusLine {},{}", kak(bg), kak(fg)).unwrap();
+    writeln!(
+        s,
+        "set-face global PrimarySelection {},{}",
+        kak(bg),
+        kak(rgb(palette, 4))
+    )
+    .unwrap();
+    writeln!(
+        s,
+        "set-face global SecondarySelection {},{}",
+        kak(bg),
+        kak(rgb(palette, 12))
+    )
+    .unwrap();
+    writeln!(
+        s,
+        "set-face global MenuBackground {},{}",
+        kak(fg),
+        kak(rgb(palette, 8))
+    )
+    .unwrap();
+    writeln!(
+        s,
+        "set-face global MenuForeground {},{}",
+        kak(bg),
+        kak(rgb(palette, 6))
+    )
+    .unwrap();
+    s
+}
+
+pub(crate) fn write(path: &Path, palette: &[u8; 48], fg: [u8; 3], bg: [u8; 3]) -> Result<()> {
+    output::atomic_write(path, render(palette, fg, bg).as_bytes())
+        .with_context(|| format!("failed to write colorscheme to {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::palette;
+
+    #[test]
+    fn default_face_uses_captured_fg_bg() {
+        let out = render(
+            &palette::DEFAULT_PALETTE,
+            [0xC0, 0xCA, 0xF5],
+            [0x1A, 0x1B, 0x26],
+        );
+        assert!(out
+            .lines()
+            .any(|l| l == "set-face global Default rgb:C0CAF5,rgb:1A1B26"));
+        // comment = bright black (color 8)
+        assert!(out
+            .lines()
+            .any(|l| l == "set-face global comment rgb:666666"));
+    }
+
+    #[test]
+    fn palette_defaults_white_on_black() {
+        let (fg, bg) = palette_defaults(&palette::DEFAULT_PALETTE);
+        assert_eq!(fg, rgb(&palette::DEFAULT_PALETTE, 7));
+        assert_eq!(bg, [0, 0, 0]);
+    }
+}
@@ -0,0 +1,91 @@
+use encoding_rs::Encoding;
+
+/// Encodings accepted by `--input-encoding`. All are ASCII-compatible, so
+/// escape sequences survive transcoding unchanged.
+pub(crate) const SUPPORTED: &[&str] = &[
+    "utf-8",
+    "latin1",
+    "windows-1252",
+    "shift_jis",
+    "euc-jp",
+    "euc-kr",
+    "gbk",
+    "big5",
+];
+
+/// Resolve an `--input-encoding` name. `latin1` / `iso-8859-1` map to
+/// windows-1252, which agrees with Latin-1 on every printable byte.
+pub(crate) fn parse_input_encoding(name: &str) -> Result<&'static Encoding, String> {
+    let lower = name.to_ascii_lowercase();
+    let label = match lower.as_str() {
+        "latin1" | "latin-1" | "iso-8859-1" => "windows-1252",
+        "sjis" | "shift-jis" => "shift_jis",
+        other => other,
+    };
+    match Encoding::for_label(label.as_bytes()) {
+        Some(enc) if SUPPORTED.contains(&enc.name().to_ascii_lowercase().as_str()) => Ok(enc),
+        _ => Err(format!(
+            "unsupported --input-encoding '{name}' (supported: {})",
+            SUPPORTED.join(", ")
+        )),
+    }
+}
+
+/// Transcode raw terminal bytes to UTF-8. UTF-8 input is returned as-is so
+/// that the default path never touches the data.
+pub(crate) fn transcode(data: Vec<u8>, encoding: &'static Encoding) -> Vec<u8> {
+    if encoding == encoding_rs::UTF_8 {
+        return data;
+    }
+    let (decoded, _, _) = encoding.decode(&data);
+    decoded.into_owned().into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_input_encoding_aliases() {
+        assert_eq!(
+            parse_input_encoding("latin1").unwrap(),
+            encoding_rs::WINDOWS_1252
+        );
+        assert_eq!(
+            parse_input_encoding("ISO-8859-1").unwrap(),
+            encoding_rs::WINDOWS_1252
+        );
+        assert_eq!(
+            parse_input_encoding("sjis").unwrap(),
+            encoding_rs::SHIFT_JIS
+        );
+        assert_eq!(parse_input_encoding("utf-8").unwrap(), encoding_rs::UTF_8);
+    }
+
+    #[test]
+    fn parse_input_encoding_rejects_unsupported() {
+        assert!(parse_input_encoding("utf-16le").is_err());
+        assert!(parse_input_encoding("nope").is_err());
+    }
+
+    #[test]
+    fn transcode_latin1() {
+        // "café" in Latin-1 with a color escape around it
+        let data = b"\x1b[31mcaf\xe9\x1b[0m".to_vec();
+        let out = transcode(data, encoding_rs::WINDOWS_1252);
+        assert_eq!(out, "\x1b[31mcafé\x1b[0m".as_bytes());
+    }
+
+    #[test]
+    fn transcode_shift_jis() {
+        // "日本" in Shift_JIS
+        let out = transcode(b"\x93\xfa\x96\x7b".to_vec(), encoding_rs::SHIFT_JIS);
+        assert_eq!(out, "日本".as_bytes());
+    }
+
+    #[test]
+    fn transcode_utf8_passthrough() {
+        let data = b"caf\xc3\xa9 \xff".to_vec();
+        assert_eq!(transcode(data.clone(), encoding_rs::UTF_8), data);
+    }
+}
@@ -0,0 +1,176 @@
+use std::borrow::Cow;
+
+use crate::terminal::canonicalize_face;
+
+/// `--face-rules`: `from => to` rewrites applied to span faces as the
+/// ranges are written. A `from` that is a single color (`rgb:0000CC`,
+/// `blue`) replaces that color wherever it is the foreground, background
+/// or underline color, and only by another color; a `from` with `,`, `+`
+/// or `@` must match the whole face, in any flag order.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct FaceRules {
+    rules: Vec<(String, String)>,
+}
+
+fn is_whole_face(pattern: &str) -> bool {
+    pattern.contains([',', '+', '@'])
+}
+
+/// Whether `s` is a Kakoune color: `default`, a named color (optionally
+/// `bright-`), `rgb:RRGGBB` or `rgba:RRGGBBAA`.
+fn is_color(s: &str) -> bool {
+    const NAMED: &[&str] = &[
+        "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+    ];
+    let hex = |digits: &str, len: usize| {
+        digits.len() == len && digits.bytes().all(|b| b.is_ascii_hexdigit())
+    };
+    if let Some(digits) = s.strip_prefix("rgb:") {
+        return hex(digits, 6);
+    }
+    if let Some(digits) = s.strip_prefix("rgba:") {
+        return hex(digits, 8);
+    }
+    s == "default" || NAMED.contains(&s.strip_prefix("bright-").unwrap_or(s))
+}
+
+impl FaceRules {
+    /// Parse a rules file: one `from => to` per line, `#` comments and
+    /// blank lines ignored.
+    pub fn parse(text: &str) -> Result<FaceRules, String> {
+        let mut rules = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (from, to) = line
+                .split_once("=>")
+                .map(|(from, to)| (from.trim(), to.trim()))
+                .filter(|(from, to)| !from.is_empty() && !to.is_empty())
+                .ok_or_else(|| format!("line {}: expected 'FROM => TO', got '{line}'", n + 1))?;
+            if !is_whole_face(from) && !is_color(to) {
+                return Err(format!(
+                    "line {}: color '{from}' can only be replaced by a color, not '{to}'",
+                    n + 1
+                ));
+            }
+            let from = if is_whole_face(from) {
+                canonicalize_face(from)
+            } else {
+                from.to_string()
+            };
+            rules.push((from, to.to_string()));
+        }
+        Ok(FaceRules { rules })
+    }
+
+    /// `face` rewritten by the first whole-face rule that matches it, or
+    /// else with every color rule applied to its colors. Colors compare
+    /// case-insensitively, so `rgb:0000cc` matches `rgb:0000CC`, and flags
+    /// in any order, so `+ib` matches `+bi`.
+    pub fn apply<'a>(&self, face: &'a str) -> Cow<'a, str> {
+        if self.rules.is_empty() {
+            return Cow::Borrowed(face);
+        }
+        let canonical = canonicalize_face(face);
+        if let Some((_, to)) = self
+            .rules
+            .iter()
+            .find(|(from, _)| is_whole_face(from) && from.eq_ignore_ascii_case(&canonical))
+        {
+            return Cow::Owned(to.clone());
+        }
+
+        let split = face.find(['+', '@']).unwrap_or(face.len());
+        let (colors, rest) = face.split_at(split);
+        let mut changed = false;
+        let colors: Vec<&str> = colors
+            .split(',')
+            .map(|color| {
+                match self
+                    .rules
+                    .iter()
+                    .find(|(from, _)| !is_whole_face(from) && from.eq_ignore_ascii_case(color))
+                {
+                    Some((_, to)) => {
+                        changed = true;
+                        to.as_str()
+                    }
+                    None => color,
+                }
+            })
+            .collect();
+        if changed {
+            Cow::Owned(format!("{}{rest}", colors.join(",")))
+        } else {
+            Cow::Borrowed(face)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rules_file() {
+        let rules = FaceRules::parse(
+            "# darker blues\n\
+             rgb:0000CC => rgb:5555FF\n\
+             \n\
+             rgb:FF0000,default+b => Error\n",
+        )
+        .unwrap();
+        assert_eq!(
+            rules.rules,
+            [
+                ("rgb:0000CC".to_string(), "rgb:5555FF".to_string()),
+                ("rgb:FF0000,default+b".to_string(), "Error".to_string()),
+            ]
+        );
+        assert_eq!(FaceRules::parse(""), Ok(FaceRules::default()));
+    }
+
+    #[test]
+    fn parse_rejects_malformed_lines() {
+        assert_eq!(
+            FaceRules::parse("rgb:0000CC => rgb:5555FF\nrgb:0000CC\n"),
+            Err("line 2: expected 'FROM => TO', got 'rgb:0000CC'".to_string())
+        );
+        assert!(FaceRules::parse("=> rgb:5555FF").is_err());
+        assert!(FaceRules::parse("blue => white,blue").is_err());
+        assert_eq!(
+            FaceRules::parse("blue => Error"),
+            Err("line 1: color 'blue' can only be replaced by a color, not 'Error'".to_string())
+        );
+        assert!(FaceRules::parse("blue => bright-cyan\nrgb:0000CC => rgba:5555FF80").is_ok());
+    }
+
+    #[test]
+    fn apply_color_rule_to_fg_and_bg() {
+        let rules = FaceRules::parse("rgb:0000CC => rgb:5555FF").unwrap();
+        assert_eq!(rules.apply("rgb:0000CC,default"), "rgb:5555FF,default");
+        assert_eq!(rules.apply("default,rgb:0000cc+b"), "default,rgb:5555FF+b");
+        assert_eq!(rules.apply("rgb:0000CC+u"), "rgb:5555FF+u");
+        assert!(matches!(
+            rules.apply("rgb:0000CD,default"),
+            Cow::Borrowed("rgb:0000CD,default")
+        ));
+    }
+
+    #[test]
+    fn apply_whole_face_rule_first() {
+        let rules =
+            FaceRules::parse("rgb:CC0000 => rgb:FF5555\nrgb:CC0000,default+b => Error").unwrap();
+        assert_eq!(rules.apply("rgb:CC0000,default+b"), "Error");
+        assert_eq!(rules.apply("rgb:CC0000,default+i"), "rgb:FF5555,default+i");
+    }
+
+    #[test]
+    fn whole_face_rule_matches_any_flag_order() {
+        let rules = FaceRules::parse("red,default+ib => Error").unwrap();
+        assert_eq!(rules.apply("red,default+bi"), "Error");
+        assert_eq!(rules.apply("red,default+ib"), "Error");
+    }
+}
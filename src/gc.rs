@@ -0,0 +1,123 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+
+/// Default age after which a leftover `ksb-*` directory is considered stale.
+pub(crate) const DEFAULT_MAX_AGE_HOURS: u64 = 24;
+
+/// Temp directories `materialize` may have used: `$TMPDIR` (via
+/// `env::temp_dir`) and `/tmp`, deduplicated.
+fn candidate_dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![std::env::temp_dir()];
+    let tmp = PathBuf::from("/tmp");
+    if !dirs.contains(&tmp) {
+        dirs.push(tmp);
+    }
+    dirs
+}
+
+/// Remove `ksb-*` directories in `dir` whose mtime is older than `max_age`
+/// relative to `now`. Returns the number of directories removed.
+/// Symlinks and plain files are never touched.
+pub(crate) fn remove_stale_in(dir: &Path, max_age: Duration, now: SystemTime) -> Result<usize> {
+    let entries =
+        std::fs::read_dir(dir).with_context(|| format!("failed to read {}", dir.display()))?;
+    let mut removed = 0;
+    for entry in entries.flatten() {
+        if !entry.file_name().to_string_lossy().starts_with("ksb-") {
+            continue;
+        }
+        let Ok(meta) = std::fs::symlink_metadata(entry.path()) else {
+            continue;
+        };
+        if !meta.is_dir() {
+            continue;
+        }
+        let Ok(modified) = meta.modified() else {
+            continue;
+        };
+        // Future mtimes (clock skew) count as fresh
+        let age = now.duration_since(modified).unwrap_or_default();
+        if age < max_age {
+            continue;
+        }
+        match std::fs::remove_dir_all(entry.path()) {
+            Ok(()) => removed += 1,
+            Err(e) => eprintln!("warning: failed to remove {}: {e}", entry.path().display()),
+        }
+    }
+    Ok(removed)
+}
+
+/// Scan all candidate temp directories and report how many stale dirs were reclaimed.
+pub(crate) fn run(max_age_hours: u64) -> Result<()> {
+    let max_age = Duration::from_secs(max_age_hours.saturating_mul(3600));
+    let now = SystemTime::now();
+    let mut removed = 0;
+    for dir in candidate_dirs() {
+        if !dir.is_dir() {
+            continue;
+        }
+        removed += remove_stale_in(&dir, max_age, now)?;
+    }
+    println!("kakoune-scrollback: reclaimed {removed} stale temp dir(s)");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_dir_with_age(parent: &Path, name: &str, age: Duration) -> PathBuf {
+        let path = parent.join(name);
+        std::fs::create_dir(&path).unwrap();
+        std::fs::write(path.join("text.txt"), "x").unwrap();
+        let dir = std::fs::File::open(&path).unwrap();
+        dir.set_modified(SystemTime::now() - age).unwrap();
+        path
+    }
+
+    #[test]
+    fn removes_only_old_ksb_dirs() {
+        let parent = tempfile::tempdir().unwrap();
+        let hour = Duration::from_secs(3600);
+        let old = make_dir_with_age(parent.path(), "ksb-old", 48 * hour);
+        let new = make_dir_with_age(parent.path(), "ksb-new", Duration::ZERO);
+        let other = make_dir_with_age(parent.path(), "not-ksb", 48 * hour);
+
+        let removed = remove_stale_in(parent.path(), 24 * hour, SystemTime::now()).unwrap();
+
+        assert_eq!(removed, 1);
+        assert!(!old.exists(), "old ksb- dir should be removed");
+        assert!(new.exists(), "fresh ksb- dir should be kept");
+        assert!(other.exists(), "non-ksb dir should never be touched");
+    }
+
+    #[test]
+    fn ignores_ksb_files() {
+        let parent = tempfile::tempdir().unwrap();
+        let file = parent.path().join("ksb-file");
+        std::fs::write(&file, "x").unwrap();
+        let later = SystemTime::now() + Duration::from_secs(100 * 3600);
+
+        let removed = remove_stale_in(parent.path(), Duration::from_secs(3600), later).unwrap();
+
+        assert_eq!(removed, 0);
+        assert!(file.exists());
+    }
+
+    #[test]
+    fn missing_dir_is_error() {
+        let parent = tempfile::tempdir().unwrap();
+        let missing = parent.path().join("nope");
+        assert!(remove_stale_in(&missing, Duration::ZERO, SystemTime::now()).is_err());
+    }
+
+    #[test]
+    fn candidate_dirs_includes_temp_dir() {
+        let dirs = candidate_dirs();
+        assert!(dirs.contains(&std::env::temp_dir()));
+        assert!(dirs.contains(&PathBuf::from("/tmp")));
+    }
+}
@@ -0,0 +1,123 @@
+use std::ffi::{CString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Signals that would otherwise kill the process before kak takes over.
+const SIGNALS: [libc::c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+/// A NUL-terminated `argv` for `execv`, built before any handler is
+/// installed so the handler itself never allocates.
+struct Argv {
+    _strings: Vec<CString>,
+    ptrs: Vec<*const libc::c_char>,
+}
+
+// The pointers only reference `_strings`, which is never mutated
+unsafe impl Send for Argv {}
+unsafe impl Sync for Argv {}
+
+static CLEANUP: OnceLock<Argv> = OnceLock::new();
+
+/// Find `name` in the directories of `path` (a `PATH` value).
+fn find_in_path(name: &str, path: &OsStr) -> Option<PathBuf> {
+    std::env::split_paths(path)
+        .map(|dir| dir.join(name))
+        .find(|candidate| candidate.is_file())
+}
+
+/// `rm -rf -- <dir>` with `rm` resolved to an absolute path, since the
+/// PATH-searching exec variants are not async-signal-safe.
+fn cleanup_argv(dir: &Path, path: &OsStr) -> Option<Vec<CString>> {
+    let rm = find_in_path("rm", path)?;
+    [
+        rm.as_os_str(),
+        OsStr::new("-rf"),
+        OsStr::new("--"),
+        dir.as_os_str(),
+    ]
+    .iter()
+    .map(|arg| CString::new(arg.as_bytes()).ok())
+    .collect()
+}
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    // execv is async-signal-safe: the process becomes `rm`, which removes
+    // the directory and exits in our place
+    if let Some(argv) = CLEANUP.get() {
+        unsafe { libc::execv(argv.ptrs[0], argv.ptrs.as_ptr()) };
+    }
+    unsafe { libc::_exit(128 + signal) };
+}
+
+/// Remove `dir` if SIGINT, SIGTERM or SIGHUP arrives before the process
+/// execs kak. exec resets the handlers, so kak is unaffected; signals that
+/// are already ignored stay ignored.
+pub(crate) fn remove_on_signal(dir: &Path) {
+    let Some(path) = std::env::var_os("PATH") else {
+        return;
+    };
+    let Some(strings) = cleanup_argv(dir, &path) else {
+        return;
+    };
+    let mut ptrs: Vec<*const libc::c_char> = strings.iter().map(|s| s.as_ptr()).collect();
+    ptrs.push(std::ptr::null());
+    if CLEANUP
+        .set(Argv {
+            _strings: strings,
+            ptrs,
+        })
+        .is_err()
+    {
+        return;
+    }
+
+    for signal in SIGNALS {
+        unsafe {
+            let mut old: libc::sigaction = std::mem::zeroed();
+            if libc::sigaction(signal, std::ptr::null(), &mut old) != 0
+                || old.sa_sigaction == libc::SIG_IGN
+            {
+                continue;
+            }
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, std::ptr::null_mut());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cleanup_argv_removes_dir() {
+        let parent = tempfile::tempdir().unwrap();
+        let dir = parent.path().join("ksb-it's here");
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("text.txt"), "x").unwrap();
+        std::fs::create_dir(dir.join("sub")).unwrap();
+
+        let path = std::env::var_os("PATH").unwrap();
+        let argv = cleanup_argv(&dir, &path).unwrap();
+        assert!(Path::new(OsStr::from_bytes(argv[0].as_bytes())).is_absolute());
+        assert_eq!(argv[1..3], [c"-rf".to_owned(), c"--".to_owned()]);
+
+        // What the handler execs, run as a child instead
+        let status = std::process::Command::new(OsStr::from_bytes(argv[0].as_bytes()))
+            .args(argv[1..].iter().map(|a| OsStr::from_bytes(a.as_bytes())))
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert!(!dir.exists());
+        assert!(parent.path().exists());
+    }
+
+    #[test]
+    fn cleanup_argv_needs_rm_on_path() {
+        let empty = tempfile::tempdir().unwrap();
+        assert!(cleanup_argv(Path::new("/tmp/x"), empty.path().as_os_str()).is_none());
+    }
+}
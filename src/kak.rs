@@ -0,0 +1,121 @@
+use std::time::{Duration, Instant};
+
+/// How long the headless kak probe may run before it is killed.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Parse a Kakoune color of the form `rgb:RRGGBB`. Named colors and
+/// `default` have no fixed RGB value and yield `None`.
+fn parse_rgb(color: &str) -> Option<[u8; 3]> {
+    let hex = color.trim().strip_prefix("rgb:")?;
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+/// Extract the `Default` face's `(fg, bg)` from `debug faces` output
+/// (lines like ` * Default: rgb:EBDBB2,rgb:282828`).
+/// Returns `None` unless both colors are RGB.
+pub(crate) fn parse_debug_faces(output: &str) -> Option<([u8; 3], [u8; 3])> {
+    let face = output
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("* Default:"))?;
+    let colors = face.split('+').next()?;
+    let (fg, bg) = colors.split_once(',')?;
+    // Kakoune may append an underline color after a second comma
+    let bg = bg.split(',').next()?;
+    Some((parse_rgb(fg)?, parse_rgb(bg)?))
+}
+
+/// Ask a short-lived headless kak (with the user's kakrc) for its `Default`
+/// face colors. Returns `None` if kak is missing, slow, or the face is not RGB.
+pub(crate) fn probe_kak_defaults() -> Option<([u8; 3], [u8; 3])> {
+    let out = tempfile::NamedTempFile::new().ok()?;
+    let out_path = crate::output::escape_kak_single_quote(&out.path().display().to_string());
+    let mut child = std::process::Command::new("kak")
+        .args(["-ui", "dummy", "-e"])
+        .arg(format!(
+            "debug faces; evaluate-commands -buffer *debug* %{{ write -force '{out_path}' }}; quit!"
+        ))
+        .env("KAKOUNE_SCROLLBACK", "1")
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .ok()?;
+
+    let deadline = Instant::now() + PROBE_TIMEOUT;
+    loop {
+        match child.try_wait() {
+            Ok(Some(_)) => break,
+            Ok(None) if Instant::now() < deadline => {
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            _ => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return None;
+            }
+        }
+    }
+
+    let output = std::fs::read_to_string(out.path()).ok()?;
+    parse_debug_faces(&output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_debug_faces_rgb() {
+        let output = "\
+Faces:
+ * Default: rgb:EBDBB2,rgb:282828
+ * PrimarySelection: white,blue+fg
+";
+        assert_eq!(
+            parse_debug_faces(output),
+            Some(([0xEB, 0xDB, 0xB2], [0x28, 0x28, 0x28]))
+        );
+    }
+
+    #[test]
+    fn parse_debug_faces_with_attributes_and_underline_color() {
+        let output = " * Default: rgb:ffffff,rgb:000000,rgb:ff0000+b\n";
+        assert_eq!(
+            parse_debug_faces(output),
+            Some(([0xFF, 0xFF, 0xFF], [0x00, 0x00, 0x00]))
+        );
+    }
+
+    #[test]
+    fn parse_debug_faces_named_colors_unsupported() {
+        assert_eq!(parse_debug_faces(" * Default: default,default\n"), None);
+        assert_eq!(parse_debug_faces(" * Default: white,black\n"), None);
+    }
+
+    #[test]
+    fn parse_debug_faces_missing_default() {
+        assert_eq!(parse_debug_faces("Faces:\n * Error: red,default\n"), None);
+        assert_eq!(parse_debug_faces(""), None);
+    }
+
+    #[test]
+    fn probe_kak_defaults_does_not_hang() {
+        let kak_available = std::process::Command::new("kak")
+            .arg("-version")
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .map(|s| s.success())
+            .unwrap_or(false);
+        if !kak_available {
+            return;
+        }
+        let start = Instant::now();
+        let _ = probe_kak_defaults();
+        assert!(start.elapsed() < PROBE_TIMEOUT + Duration::from_secs(1));
+    }
+}
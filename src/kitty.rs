@@ -20,6 +20,99 @@ pub struct PipeData {
     pub cursor_y: usize,
     pub lines: u16,
     pub columns: u16,
+    pub scrolled_by: usize, // lines the Kitty window was scrolled up (0 for tmux)
+    pub cursor_shape: Option<CursorShape>, // only if the pipe data carries it
+}
+
+/// Terminal cursor shape, using Kitty's names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorShape {
+    Block,
+    Beam,
+    Underline,
+}
+
+impl CursorShape {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "block" => Some(CursorShape::Block),
+            "beam" => Some(CursorShape::Beam),
+            "underline" => Some(CursorShape::Underline),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CursorShape::Block => "block",
+            CursorShape::Beam => "beam",
+            CursorShape::Underline => "underline",
+        }
+    }
+}
+
+/// How the scrollback window was launched (`launch --type=...`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OpenMode {
+    #[default]
+    Overlay,
+    OsWindow,
+}
+
+impl OpenMode {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "overlay" => Some(OpenMode::Overlay),
+            "os-window" => Some(OpenMode::OsWindow),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OpenMode::Overlay => "overlay",
+            OpenMode::OsWindow => "os-window",
+        }
+    }
+}
+
+impl PipeData {
+    /// Use `columns` (the width the scrollback was recorded at) for the
+    /// vt100 grid instead of the live width. The cursor row is still counted
+    /// from the bottom of the screen; its column is clamped into the new width.
+    pub fn with_capture_columns(&self, columns: u16) -> PipeData {
+        let columns = columns.max(1);
+        PipeData {
+            cursor_x: self.cursor_x.min(usize::from(columns) - 1),
+            cursor_y: self.cursor_y,
+            lines: self.lines,
+            columns,
+            scrolled_by: self.scrolled_by,
+            cursor_shape: self.cursor_shape,
+        }
+    }
+
+    /// Shrink `lines`/`columns` to at most `max`, so malformed pipe data
+    /// cannot make vt100 allocate a giant grid. The cursor keeps its distance
+    /// from the bottom row and is clamped into the new width. Returns a
+    /// warning describing the change, or `None` if the geometry was in range.
+    pub fn clamp_geometry(&mut self, max: u16) -> Option<String> {
+        let max = max.max(1);
+        if self.lines <= max && self.columns <= max {
+            return None;
+        }
+        let warning = format!(
+            "screen geometry {}x{} exceeds {max}x{max}, clamping (malformed pipe data?)",
+            self.columns, self.lines
+        );
+        if self.lines > max {
+            self.cursor_y = self.cursor_y.saturating_sub(usize::from(self.lines - max));
+            self.lines = max;
+        }
+        self.columns = self.columns.min(max);
+        self.cursor_x = self.cursor_x.min(usize::from(self.columns) - 1);
+        Some(warning)
+    }
 }
 
 /// Pure function: parse from string (separated for testability)
@@ -38,11 +131,11 @@ pub fn parse_pipe_data_str(s: &str) -> Result<PipeData> {
         bail!("KITTY_PIPE_DATA: expected 3 colon-separated parts");
     }
 
-    // part0: scrolled_by (validate as numeric, don't use)
+    // part0: scrolled_by
     if part0.contains(',') {
         bail!("KITTY_PIPE_DATA: invalid scrolled_by '{part0}' (unexpected comma)");
     }
-    let _scrolled_by: usize = part0
+    let scrolled_by: usize = part0
         .parse()
         .context("KITTY_PIPE_DATA: invalid scrolled_by")?;
 
@@ -99,23 +192,61 @@ pub fn parse_pipe_data_str(s: &str) -> Result<PipeData> {
         cursor_y: cursor_y_1 - 1,
         lines,
         columns,
+        scrolled_by,
+        cursor_shape: None,
     })
 }
 
-/// Read `KITTY_PIPE_DATA` environment variable and delegate to `parse_pipe_data_str`
+/// Strict parse first; if that fails and the input has only the two
+/// `cursor:geometry` parts, retry with `scrolled_by` defaulted to 0.
+/// On failure the strict parser's error is returned.
+pub fn parse_pipe_data_lenient(s: &str) -> Result<PipeData> {
+    let strict_err = match parse_pipe_data_str(s) {
+        Ok(data) => return Ok(data),
+        Err(e) => e,
+    };
+    if s.trim().matches(':').count() == 1 {
+        if let Ok(data) = parse_pipe_data_str(&format!("0:{}", s.trim())) {
+            return Ok(data);
+        }
+    }
+    Err(strict_err)
+}
+
+/// Like `parse_pipe_data_lenient`, but also accepts a trailing
+/// `:{cursor_shape}` part (`block`, `beam` or `underline`), in case Kitty
+/// starts reporting it. An unknown trailing word is an error as before.
+pub fn parse_pipe_data_ext(s: &str) -> Result<PipeData> {
+    let s = s.trim();
+    if let Some((base, shape)) = s.rsplit_once(':') {
+        if let Some(shape) = CursorShape::parse(shape) {
+            let mut data = parse_pipe_data_lenient(base)?;
+            data.cursor_shape = Some(shape);
+            return Ok(data);
+        }
+    }
+    parse_pipe_data_lenient(s)
+}
+
+/// Read `KITTY_PIPE_DATA` environment variable and delegate to `parse_pipe_data_ext`
 pub fn parse_pipe_data() -> Result<PipeData> {
     let val =
         std::env::var("KITTY_PIPE_DATA").context("KITTY_PIPE_DATA environment variable not set")?;
-    parse_pipe_data_str(&val)
+    parse_pipe_data_ext(&val)
 }
 
 /// Pure function: validate and parse a kitty window ID string (separated for testability)
+/// Decimal by default; a `0x`/`0X` prefix selects hex.
 pub fn parse_window_id(s: &str) -> Result<WindowId> {
-    let id: u32 = s.parse().with_context(|| {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    let id: u32 = parsed.with_context(|| {
         format!("invalid kitty window ID '{s}' — expected a number (check your kitty.conf)")
     })?;
     if id == 0 {
-        bail!("invalid kitty window ID '0' — window IDs start at 1");
+        bail!("invalid kitty window ID '{s}' — window IDs start at 1");
     }
     Ok(WindowId(id))
 }
@@ -151,6 +282,19 @@ pub fn get_palette(window_id: WindowId) -> [u8; 48] {
     }
 }
 
+/// Query Kitty for the window's default foreground and background.
+/// `None` if the command fails or does not report both.
+pub fn get_default_colors(window_id: WindowId) -> Option<([u8; 3], [u8; 3])> {
+    let out = std::process::Command::new("kitty")
+        .args(["@", "get-colors", "--match", &format!("id:{window_id}")])
+        .output()
+        .ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    palette::parse_kitty_default_colors(&String::from_utf8_lossy(&out.stdout))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -158,6 +302,7 @@ mod tests {
     #[test]
     fn parse_pipe_data_valid() {
         let data = parse_pipe_data_str("42:6,24:50,120").unwrap();
+        assert_eq!(data.scrolled_by, 42);
         assert_eq!(data.cursor_x, 5);
         assert_eq!(data.cursor_y, 23);
         assert_eq!(data.lines, 50);
@@ -192,12 +337,69 @@ mod tests {
         assert!(parse_pipe_data_str("abc:1,1:24,80").is_err());
     }
 
+    #[test]
+    fn parse_pipe_data_lenient_two_parts() {
+        assert!(parse_pipe_data_str("6,24:50,120").is_err());
+        let data = parse_pipe_data_lenient("6,24:50,120").unwrap();
+        assert_eq!(data.scrolled_by, 0);
+        assert_eq!(data.cursor_x, 5);
+        assert_eq!(data.cursor_y, 23);
+        assert_eq!(data.lines, 50);
+        assert_eq!(data.columns, 120);
+    }
+
+    #[test]
+    fn parse_pipe_data_lenient_accepts_three_parts() {
+        let data = parse_pipe_data_lenient("3:1,1:24,80").unwrap();
+        assert_eq!((data.cursor_x, data.cursor_y), (0, 0));
+    }
+
+    #[test]
+    fn parse_pipe_data_lenient_keeps_strict_error() {
+        let err = parse_pipe_data_lenient("1,2").unwrap_err();
+        assert!(err.to_string().contains("3 colon-separated parts"));
+        assert!(parse_pipe_data_lenient("0,1:24,80").is_err()); // cursor_x = 0
+    }
+
+    #[test]
+    fn parse_pipe_data_ext_cursor_shape() {
+        let data = parse_pipe_data_ext("3:1,2:24,80:beam").unwrap();
+        assert_eq!(data.cursor_shape, Some(CursorShape::Beam));
+        assert_eq!((data.scrolled_by, data.cursor_y), (3, 1));
+        // Also with the two-part form
+        let data = parse_pipe_data_ext("1,2:24,80:underline").unwrap();
+        assert_eq!(data.cursor_shape, Some(CursorShape::Underline));
+        assert_eq!(data.scrolled_by, 0);
+
+        let data = parse_pipe_data_ext("3:1,2:24,80").unwrap();
+        assert_eq!(data.cursor_shape, None);
+        assert!(parse_pipe_data_ext("3:1,2:24,80:hollow").is_err());
+    }
+
     #[test]
     fn parse_window_id_valid() {
         assert_eq!(parse_window_id("42").unwrap(), WindowId(42));
         assert_eq!(parse_window_id("1").unwrap(), WindowId(1));
     }
 
+    #[test]
+    fn clamp_geometry_shrinks_huge_screens() {
+        let mut data = parse_pipe_data_str("0:65535,65535:65535,65535").unwrap();
+        let warning = data.clamp_geometry(1000).unwrap();
+        assert!(warning.contains("65535x65535"), "{warning}");
+        assert_eq!((data.lines, data.columns), (1000, 1000));
+        // Cursor on the bottom row stays on the bottom row
+        assert_eq!((data.cursor_x, data.cursor_y), (999, 999));
+    }
+
+    #[test]
+    fn clamp_geometry_leaves_normal_screens() {
+        let mut data = parse_pipe_data_str("3:10,20:50,200").unwrap();
+        assert_eq!(data.clamp_geometry(1000), None);
+        assert_eq!((data.lines, data.columns), (50, 200));
+        assert_eq!((data.cursor_x, data.cursor_y), (9, 19));
+    }
+
     #[test]
     fn parse_window_id_normalizes() {
         assert_eq!(parse_window_id("042").unwrap(), WindowId(42));
@@ -234,6 +436,28 @@ mod tests {
         assert!(parse_window_id("abc").is_err());
     }
 
+    #[test]
+    fn parse_window_id_hex() {
+        assert_eq!(parse_window_id("0x2a").unwrap(), WindowId(42));
+        assert_eq!(parse_window_id("0X2A").unwrap(), WindowId(42));
+        assert_eq!(parse_window_id("42").unwrap(), WindowId(42));
+    }
+
+    #[test]
+    fn parse_window_id_hex_rejects_zero() {
+        let msg = parse_window_id("0x0").unwrap_err().to_string();
+        assert!(
+            msg.contains("start at 1"),
+            "should be rejected as zero: {msg}"
+        );
+    }
+
+    #[test]
+    fn parse_window_id_hex_rejects_invalid() {
+        assert!(parse_window_id("0x").is_err());
+        assert!(parse_window_id("0xzz").is_err());
+    }
+
     #[test]
     fn parse_pipe_data_rejects_zero_lines() {
         let err = parse_pipe_data_str("0:1,1:0,80");
@@ -398,4 +622,21 @@ mod tests {
         assert_eq!(palette[1], 0x22);
         assert_eq!(palette[2], 0x33);
     }
+
+    #[test]
+    fn with_capture_columns_overrides_width() {
+        let live = parse_pipe_data_str("0:11,24:50,80").unwrap();
+        let captured = live.with_capture_columns(120);
+        assert_eq!(captured.columns, 120);
+        assert_eq!(captured.lines, 50);
+        assert_eq!(captured.cursor_x, 10);
+        assert_eq!(captured.cursor_y, 23);
+    }
+
+    #[test]
+    fn with_capture_columns_clamps_cursor() {
+        let live = parse_pipe_data_str("0:71,1:24,80").unwrap();
+        let captured = live.with_capture_columns(40);
+        assert_eq!(captured.cursor_x, 39);
+    }
 }
@@ -1,8 +1,23 @@
+mod append;
+mod bench;
+mod colorscheme;
+mod encoding;
+mod face_rules;
+mod gc;
+mod interrupt;
+mod kak;
 mod kitty;
+mod osc;
 mod output;
 mod palette;
+mod profile;
+mod serve;
+mod socket;
+mod stats;
 mod terminal;
 mod tmux;
+mod vim;
+mod watchdog;
 
 use anyhow::{bail, Context, Result};
 use std::env;
@@ -22,6 +37,15 @@ impl TargetId {
             TargetId::Tmux(_) => "tmux",
         }
     }
+
+    /// Line limit used when `KAKOUNE_SCROLLBACK_MAX_LINES` is unset.
+    /// tmux history is bounded by `history-limit`, so its default is smaller.
+    pub fn default_max_scrollback_lines(&self) -> usize {
+        match self {
+            TargetId::Kitty(_) => terminal::DEFAULT_MAX_SCROLLBACK_LINES,
+            TargetId::Tmux(_) => terminal::TMUX_DEFAULT_MAX_SCROLLBACK_LINES,
+        }
+    }
 }
 
 impl fmt::Display for TargetId {
@@ -33,12 +57,112 @@ impl fmt::Display for TargetId {
     }
 }
 
+/// Flags accepted by the Kitty and tmux run modes.
+#[derive(Default)]
+struct RunOptions {
+    /// tmux only: query the pane's geometry/cursor instead of trusting SCROLLBACK_PIPE_DATA
+    query_geometry: bool,
+    /// Keep colors only in the command block (OSC 133) containing the cursor
+    focus_command: bool,
+    /// Built-in palette name (`--theme`); overrides the backend's palette
+    theme: Option<String>,
+    /// kitty.conf-style `colorN` overrides layered over the theme (`--palette-file`)
+    palette_file: Option<std::path::PathBuf>,
+    /// `FROM => TO` face rewrites, loaded into `init.face_rules` by the run
+    face_rules_file: Option<std::path::PathBuf>,
+    /// Single-slot overrides from `--color N=#RRGGBB`, applied last
+    colors: Vec<(usize, [u8; 3])>,
+    /// tmux only: query the outer terminal's colors with OSC 4/10/11
+    palette_from_terminal: bool,
+    /// Write the resolved palette as a Kakoune colorscheme here instead of opening kak
+    export_colorscheme: Option<std::path::PathBuf>,
+    /// Probe kak's `Default` face and remap colors that would clash with it
+    match_theme: bool,
+    /// Stretch trailing background colors to the full terminal width
+    fill_lines: bool,
+    /// Abort the whole run (read, process, write) after this long
+    limit: Option<std::time::Duration>,
+    process: terminal::ProcessOptions,
+    /// Width the scrollback was recorded at, if it differs from the live screen
+    capture_columns: Option<u16>,
+    /// Print capture statistics instead of opening kak
+    stats: bool,
+    /// Print a report on the raw input bytes instead of opening kak
+    validate: bool,
+    /// Print vt100's cells for the visible screen instead of opening kak
+    dump_cells: bool,
+    /// Append the plain text to this file instead of opening kak
+    append_to: Option<std::path::PathBuf>,
+    /// Send the artifact paths to a daemon on this Unix socket instead of exec'ing kak
+    socket: Option<std::path::PathBuf>,
+    /// Leave the temp dir behind if a signal arrives before kak is exec'd
+    no_cleanup_on_signal: bool,
+    /// Line separator for `--append-to` (text.txt for kak is always LF)
+    line_ending: output::LineEnding,
+    /// Write Kakoune files and open kak, or write files for Neovim
+    format: output::OutputFormat,
+    /// Also write the resolved cursor as `L.C` to this file
+    cursor_out: Option<std::path::PathBuf>,
+    /// Collapse runs of identical lines into one annotated with `(×N)`
+    squeeze: bool,
+    /// Collapse runs of blank lines into one
+    squeeze_blanks: bool,
+    /// Also open each OSC 133 command block as its own buffer
+    split_commands: bool,
+    /// Give leading timestamps the `comment` face
+    dim_timestamps: bool,
+    /// Color uncolored `+`/`-`/`@@` lines like a unified diff
+    colorize_diff: bool,
+    /// Start at the first line matching this pattern instead of the terminal cursor
+    jump_to: Option<regex::Regex>,
+    /// Hard-wrap buffer lines longer than this many bytes
+    wrap_at: Option<usize>,
+    /// Highlight the characters at tab-stop columns as alignment guides
+    tab_guides: bool,
+    /// Prefix each buffer line with its line number as real text
+    number_lines: bool,
+    /// Prepend `$ <--command>` as a styled first line
+    command_header: bool,
+    /// Skip the marker line atop a capture that lost older lines
+    no_truncation_marker: bool,
+    /// Cover the gaps between spans with explicit `default,default` ranges
+    explicit_gaps: bool,
+    /// Append one line per distinct face, drawn in that face
+    legend: bool,
+    /// Merge truecolor gradients into one span per this many columns
+    gradient_downsample: Option<usize>,
+    /// Write a Chrome trace of the run's phases to this file
+    profile: Option<std::path::PathBuf>,
+    /// Transcode stdin from this encoding to UTF-8 before parsing
+    input_encoding: Option<&'static encoding_rs::Encoding>,
+    /// Keep every `\r`-overwritten state of a line as its own line
+    expand_cr: bool,
+    /// Drop blank lines at the top of the capture (e.g. above a clear-screen)
+    trim_leading_blanks: bool,
+    init: output::InitOptions,
+}
+
 enum CliAction {
     ShowVersion,
     ShowHelp,
-    RunKitty { window_id_arg: String },
-    RunTmux { pane_id: String },
+    RunKitty {
+        window_id_arg: String,
+        opts: RunOptions,
+    },
+    RunTmux {
+        pane_id: String,
+        opts: RunOptions,
+    },
     GenerateTmuxConf,
+    CheckTmuxConf,
+    Serve,
+    Gc {
+        max_age_hours: u64,
+    },
+    /// Hidden: time the pipeline on synthetic input
+    Benchmark {
+        lines: usize,
+    },
 }
 
 fn parse_args(args: &[String]) -> Result<CliAction, String> {
@@ -47,16 +171,201 @@ fn parse_args(args: &[String]) -> Result<CliAction, String> {
         Some("-h" | "--help") => Ok(CliAction::ShowHelp),
         Some("-V" | "--version") => Ok(CliAction::ShowVersion),
         Some("--generate-tmux-conf") => Ok(CliAction::GenerateTmuxConf),
-        Some("--tmux-pane") => match args.get(2).map(String::as_str) {
-            Some(pane_id) if !pane_id.is_empty() => Ok(CliAction::RunTmux {
-                pane_id: pane_id.to_string(),
+        Some("--check-tmux-conf") => Ok(CliAction::CheckTmuxConf),
+        Some("--serve") => Ok(CliAction::Serve),
+        Some("--gc") => match args.get(2).map(String::as_str) {
+            None => Ok(CliAction::Gc {
+                max_age_hours: gc::DEFAULT_MAX_AGE_HOURS,
             }),
-            _ => Err("--tmux-pane requires a pane ID argument".into()),
+            Some(hours) => match hours.parse() {
+                Ok(max_age_hours) => Ok(CliAction::Gc { max_age_hours }),
+                Err(_) => Err(format!("invalid --gc age '{hours}' (expected hours)")),
+            },
         },
-        Some(arg) if arg.starts_with('-') => Err(format!("unexpected argument '{arg}'")),
-        Some(arg) => Ok(CliAction::RunKitty {
-            window_id_arg: arg.to_string(),
-        }),
+        Some("--benchmark") => match args.get(2).map(|v| v.parse::<usize>()) {
+            Some(Ok(lines)) if lines > 0 => Ok(CliAction::Benchmark { lines }),
+            _ => Err("--benchmark requires a positive line count".into()),
+        },
+        Some(_) => parse_run_args(&args[1..]),
+    }
+}
+
+/// Parse the target (`<WINDOW_ID>` or `--tmux-pane <PANE_ID>`) plus run flags,
+/// which may appear in any order.
+fn parse_run_args(args: &[String]) -> Result<CliAction, String> {
+    let mut opts = RunOptions::default();
+    let mut window_id_arg: Option<String> = None;
+    let mut pane_id: Option<String> = None;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--tmux-pane" => match iter.next() {
+                Some(id) if !id.is_empty() => pane_id = Some(id.clone()),
+                _ => return Err("--tmux-pane requires a pane ID argument".into()),
+            },
+            "--query-geometry" => opts.query_geometry = true,
+            "--focus-command" => opts.focus_command = true,
+            "--editable" => opts.init.editable = true,
+            "--mark" => opts.init.mark = true,
+            "--restore-scroll" => opts.init.restore_scroll = true,
+            "--select-scroll-view" => opts.init.select_scroll_view = true,
+            "--open-mode" => match iter.next().and_then(|v| kitty::OpenMode::parse(v)) {
+                Some(mode) => opts.init.kitty_open_mode = mode,
+                None => return Err("--open-mode requires 'overlay' or 'os-window'".into()),
+            },
+            "--return-focus" => opts.init.return_focus = true,
+            "--color-command" => opts.init.ranges_mode = output::RangesWrapMode::Command,
+            "--highlight-urls" => opts.init.highlight_urls = true,
+            "--match-theme" => opts.match_theme = true,
+            "--fill-lines" => opts.fill_lines = true,
+            "--squeeze" => opts.squeeze = true,
+            "--register" => match iter.next().map(|v| {
+                let mut chars = v.chars();
+                (chars.next(), chars.next())
+            }) {
+                Some((Some(reg), None)) if output::is_register_name(reg) => {
+                    opts.init.register = Some(reg);
+                }
+                _ => return Err("--register requires a register name (a-z)".into()),
+            },
+            "--command" => match iter.next() {
+                Some(command) if !command.is_empty() => {
+                    opts.init.source_command = Some(command.clone());
+                }
+                _ => return Err("--command requires a command string".into()),
+            },
+            "--command-header" => opts.command_header = true,
+            "--no-truncation-marker" => opts.no_truncation_marker = true,
+            "--expand-cr" => opts.expand_cr = true,
+            "--trim-leading-blanks" => opts.trim_leading_blanks = true,
+            "--explicit-gaps" => opts.explicit_gaps = true,
+            "--legend" => opts.legend = true,
+            "--squeeze-blanks" => opts.squeeze_blanks = true,
+            "--split-commands" => opts.split_commands = true,
+            "--dim-timestamps" => opts.dim_timestamps = true,
+            "--colorize-diff" => opts.colorize_diff = true,
+            "--quantize" => opts.process.quantize = true,
+            "--bold-is-bright" => opts.process.bold_is_bright = true,
+            "--skip-blank-fg" => opts.process.skip_blank_fg = true,
+            "--screen-only" => opts.process.screen_only = true,
+            "--no-trailing-trim" => opts.process.no_trailing_trim = true,
+            "--tab-guides" => opts.tab_guides = true,
+            "--wrap-at" => match iter.next().map(|v| v.parse::<usize>()) {
+                Some(Ok(n)) if n > 0 => opts.wrap_at = Some(n),
+                _ => return Err("--wrap-at requires a positive column".into()),
+            },
+            "--max-span-bytes" => match iter.next().map(|v| v.parse::<usize>()) {
+                Some(Ok(n)) if n > 0 => opts.init.max_span_bytes = Some(n),
+                _ => return Err("--max-span-bytes requires a positive byte count".into()),
+            },
+            "--gradient-downsample" => match iter.next().map(|v| v.parse::<usize>()) {
+                Some(Ok(n)) if n > 1 => opts.gradient_downsample = Some(n),
+                _ => {
+                    return Err("--gradient-downsample requires a number of columns above 1".into())
+                }
+            },
+            "--number-lines" => opts.number_lines = true,
+            "--stats" => opts.stats = true,
+            "--validate" => opts.validate = true,
+            "--dump-cells" => opts.dump_cells = true,
+            "--cursor-out" => match iter.next() {
+                Some(path) if !path.is_empty() => opts.cursor_out = Some(path.into()),
+                _ => return Err("--cursor-out requires a file path".into()),
+            },
+            "--format" => match iter.next().and_then(|v| output::OutputFormat::parse(v)) {
+                Some(format) => opts.format = format,
+                None => return Err("--format requires 'kak' or 'vim'".into()),
+            },
+            "--line-ending" => match iter.next().and_then(|v| output::LineEnding::parse(v)) {
+                Some(ending) => opts.line_ending = ending,
+                None => return Err("--line-ending requires 'lf' or 'crlf'".into()),
+            },
+            "--profile" => match iter.next() {
+                Some(path) if !path.is_empty() => opts.profile = Some(path.into()),
+                _ => return Err("--profile requires a file path".into()),
+            },
+            "--no-cleanup-on-signal" => opts.no_cleanup_on_signal = true,
+            "--socket" => match iter.next() {
+                Some(path) if !path.is_empty() => opts.socket = Some(path.into()),
+                _ => return Err("--socket requires a socket path".into()),
+            },
+            "--append-to" => match iter.next() {
+                Some(path) if !path.is_empty() => opts.append_to = Some(path.into()),
+                _ => return Err("--append-to requires a file path".into()),
+            },
+            "--capture-columns" => match iter.next().map(|v| v.parse::<u16>()) {
+                Some(Ok(n)) if n > 0 => opts.capture_columns = Some(n),
+                _ => return Err("--capture-columns requires a positive column count".into()),
+            },
+            "--limit-seconds" => match iter.next() {
+                Some(value) => opts.limit = Some(watchdog::parse_limit_seconds(value)?),
+                None => return Err("--limit-seconds requires a number of seconds".into()),
+            },
+            "--input-encoding" => match iter.next() {
+                Some(name) => opts.input_encoding = Some(encoding::parse_input_encoding(name)?),
+                None => return Err("--input-encoding requires an encoding name".into()),
+            },
+            "--theme" => match iter.next() {
+                Some(name) if !name.is_empty() => opts.theme = Some(name.clone()),
+                _ => return Err("--theme requires a theme name".into()),
+            },
+            "--jump-to" => match iter.next() {
+                Some(pattern) if !pattern.is_empty() => {
+                    opts.jump_to = Some(
+                        regex::Regex::new(pattern)
+                            .map_err(|e| format!("invalid --jump-to regex '{pattern}': {e}"))?,
+                    );
+                }
+                _ => return Err("--jump-to requires a regex".into()),
+            },
+            "--underline-layer" => opts.init.underline_layer = true,
+            "--face-rules" => match iter.next() {
+                Some(path) if !path.is_empty() => opts.face_rules_file = Some(path.into()),
+                _ => return Err("--face-rules requires a file path".into()),
+            },
+            "--palette-file" => match iter.next() {
+                Some(path) if !path.is_empty() => opts.palette_file = Some(path.into()),
+                _ => return Err("--palette-file requires a file path".into()),
+            },
+            "--palette-from-terminal" => opts.palette_from_terminal = true,
+            "--export-colorscheme" => match iter.next() {
+                Some(path) if !path.is_empty() => opts.export_colorscheme = Some(path.into()),
+                _ => return Err("--export-colorscheme requires a file path".into()),
+            },
+            "--color" => match iter.next() {
+                Some(value) => opts.colors.push(palette::parse_color_arg(value)?),
+                None => return Err("--color requires N=#RRGGBB".into()),
+            },
+            flag if flag.starts_with('-') => return Err(format!("unexpected argument '{flag}'")),
+            id => {
+                if window_id_arg.is_some() {
+                    return Err(format!("unexpected argument '{id}'"));
+                }
+                window_id_arg = Some(id.to_string());
+            }
+        }
+    }
+
+    if opts.command_header && opts.init.source_command.is_none() {
+        return Err("--command-header requires --command".into());
+    }
+
+    match (window_id_arg, pane_id) {
+        (Some(_), Some(_)) => Err("<WINDOW_ID> and --tmux-pane are mutually exclusive".into()),
+        (Some(window_id_arg), None) => {
+            if opts.query_geometry {
+                return Err("--query-geometry requires --tmux-pane".into());
+            }
+            Ok(CliAction::RunKitty {
+                window_id_arg,
+                opts,
+            })
+        }
+        (None, Some(pane_id)) => Ok(CliAction::RunTmux { pane_id, opts }),
+        (None, None) => {
+            Err("missing required argument: <WINDOW_ID> or --tmux-pane <PANE_ID>".into())
+        }
     }
 }
 
@@ -74,20 +383,144 @@ USAGE:
     kakoune-scrollback <WINDOW_ID>           Kitty mode
     kakoune-scrollback --tmux-pane <PANE_ID> tmux mode
     kakoune-scrollback --generate-tmux-conf  Print tmux.conf snippet
+    kakoune-scrollback --check-tmux-conf     Validate the snippet with your tmux
+    kakoune-scrollback --gc [HOURS]          Remove leaked temp dirs
+    kakoune-scrollback --serve               Process length-prefixed frames on stdin
 
 ARGS:
     <WINDOW_ID>    Target Kitty window ID (Kitty mode)
 
 OPTIONS:
     --tmux-pane <PANE_ID>  Target tmux pane ID (tmux mode, requires tmux 3.3+)
+    --query-geometry       tmux: read the pane's cursor/size from tmux instead of
+                           SCROLLBACK_PIPE_DATA (for capturing inactive panes)
+    --focus-command        Keep colors only for the command output (OSC 133)
+                           containing the cursor
+    --editable             Leave the scrollback buffer writable
+    --restore-scroll       Kitty: scroll the window back to where it was when
+                           the viewer closes
+    --select-scroll-view   Kitty: if the window was scrolled back, open at the
+                           lines it was showing instead of at the cursor
+    --open-mode <MODE>     Kitty: how the viewer was launched, 'overlay'
+                           (default) or 'os-window'; recorded in the
+                           scrollback_kitty_open_mode option
+    --return-focus         tmux: select the originating pane again when the
+                           viewer closes
+    --register <R>         Also copy the whole text into register R (a-z)
+    --mark                 Save the cursor position to the ^ mark register
+                           (jump back with z)
+    --theme <NAME>         Use a built-in palette for ANSI colors 0-15:
+                           default, solarized-dark, gruvbox, dracula
+    --underline-layer      Draw underlines with a separate highlighter in the
+                           ScrollbackUnderline face, not in the cell faces
+    --face-rules <FILE>    Rewrite span faces with the 'FROM => TO' lines of
+                           FILE (e.g. rgb:0000CC => rgb:5555FF)
+    --palette-file <FILE>  Override colors with the colorN lines of a
+                           kitty.conf-style FILE
+    --color <N=#RRGGBB>    Override ANSI color N (0-15); repeatable
+    --palette-from-terminal
+                           tmux: ask the outer terminal for its palette with
+                           OSC 4/10/11 queries instead of using the default
+    --export-colorscheme <FILE>
+                           Write the resolved palette as a Kakoune colorscheme
+                           to FILE instead of opening kak
+    --input-encoding <ENC> Transcode input to UTF-8 first (latin1, shift_jis,
+                           euc-jp, euc-kr, gbk, big5, windows-1252)
+    --fill-lines           Extend a line's trailing background color to the
+                           full terminal width
+    --limit-seconds <N>    Abort if reading, processing and writing take longer
+                           than N seconds
+    --capture-columns <N>  Width the scrollback was recorded at, if the window
+                           was resized since (cursor column is clamped to N)
+    --stats                Print input/line/face/span counts instead of
+                           opening kak (for performance bug reports)
+    --validate             Print a UTF-8/escape/line report on the raw input
+                           instead of opening kak
+    --dump-cells           Print each visible cell as vt100 parsed it (text,
+                           colors, attributes, wide flags) instead of
+                           opening kak
+    --cursor-out <FILE>    Also write the cursor's Kakoune position (L.C) to FILE
+    --format <FORMAT>      'kak' (default) opens the capture in kak; 'vim'
+                           writes text.txt and a Neovim highlights.json to a
+                           temp dir and prints their paths as JSON
+    --socket <PATH>        Send the init.kak and text paths as one framed JSON
+                           message to a daemon listening on the Unix socket
+                           PATH instead of starting kak
+    --no-cleanup-on-signal Keep the temp dir if Ctrl-C or SIGTERM arrives
+                           before kak has started (for debugging)
+    --append-to <FILE>     Append the plain text, under a timestamped header,
+                           to FILE instead of opening kak
+    --line-ending <EOL>    Line separator for --append-to: lf (default) or crlf
+    --dim-timestamps       Show leading timestamps (ISO 8601 or HH:MM:SS) in
+                           the comment face
+    --jump-to <REGEX>      Open at the first line matching REGEX (e.g.
+                           'error:|FAILED') instead of the terminal cursor
+    --colorize-diff        Color uncolored diff lines: + green, - red, @@ cyan
+    --split-commands       Also open each command's output (OSC 133) as its
+                           own buffer; switch with :scrollback-command
+    --squeeze              Collapse runs of identical lines into one line
+                           marked (×N)
+    --squeeze-blanks       Collapse runs of blank lines into one (like cat -s)
+    --wrap-at <N>          Hard-wrap buffer lines longer than N bytes
+    --tab-guides           Highlight the character at every tab stop (each 8
+                           columns) as a vertical alignment guide
+    --max-span-bytes <N>   Split color ranges longer than N bytes into several
+                           ranges with the same face
+    --number-lines         Prefix each line with its number (`12: `) as text,
+                           so copied selections include it
+    --command <STR>        Record the command that produced the output in the
+                           scrollback_source_command option
+    --command-header       Also show `$ <STR>` as a first line in the
+                           ScrollbackHeader face (needs --command)
+    --no-truncation-marker Don't mark a capture that lost older lines with a
+                           first line in the ScrollbackTruncated face
+    --profile <FILE>       Write a Chrome trace (capture, parse, process, write
+                           timings) to FILE for performance bug reports
+    --highlight-urls       Underline URLs and email addresses
+    --color-command        Define the colors as a scrollback-apply-colors
+                           command (re-runnable) instead of setting them once
+    --no-trailing-trim     Keep trailing spaces so every line is the full
+                           terminal width (byte offsets match columns)
+    --screen-only          Open only the visible screen, without scrollback
+    --trim-leading-blanks  Drop blank lines at the top of the capture, such as
+                           those left by a clear-screen
+    --expand-cr            Keep each state of a line redrawn with `\\r` (progress
+                           bars) as its own line
+    --quantize             Snap colors to the xterm 256-color palette (bounds
+                           the number of faces for truecolor-heavy output)
+    --legend               Append a line per distinct face, showing the face
+                           drawn in itself
+    --explicit-gaps        Color the uncolored text between two spans with an
+                           explicit default,default range
+    --bold-is-bright       Show bold text in colors 0-7 with the bright variant
+                           (8-15), like terminals that render bold as bright
+    --skip-blank-fg        Leave spaces out of color ranges that only set a
+                           foreground, for fewer colored bytes
+    --gradient-downsample <N>
+                           Merge truecolor gradients into one span per N
+                           columns
+    --match-theme          Probe your kak colorscheme and drop colors that would
+                           be invisible against its Default face
     --generate-tmux-conf   Print recommended tmux.conf configuration
+    --check-tmux-conf      Source the snippet in a throwaway tmux server and
+                           report any errors
+    --gc [HOURS]           Remove ksb-* temp dirs older than HOURS (default: 24)
+    --serve                Answer framed capture requests on stdin with JSON
+                           results on stdout until EOF (for embedding)
     -h, --help             Print this help message
     -V, --version          Print version information
 
 ENVIRONMENT:
     KITTY_PIPE_DATA                Set automatically by Kitty
     SCROLLBACK_PIPE_DATA           Set by tmux keybinding (same format)
-    KAKOUNE_SCROLLBACK_MAX_LINES   Max lines to process (default: 200000)
+    KAKOUNE_SCROLLBACK_MAX_LINES   Max lines to process (default: 200000 for
+                                   Kitty, 50000 for tmux)
+    KAKOUNE_SCROLLBACK_AUTO_TRIM   Open only the last N lines of larger captures
+                                   (default: 100000, 0 disables)
+    KAKOUNE_SCROLLBACK_TMUX_START  Line the tmux keybinding captures from
+                                   (capture-pane -S; default: -)
+    KAKOUNE_SCROLLBACK_DEBUG       If set, also write debug-map.txt (line → vt100
+                                   source row) to the temp dir
 
 See README for setup instructions.
 ",
@@ -101,16 +534,53 @@ fn main() {
         Ok(CliAction::ShowVersion) => print_version(),
         Ok(CliAction::ShowHelp) => print_help(),
         Ok(CliAction::GenerateTmuxConf) => tmux::generate_conf(),
-        Ok(CliAction::RunKitty { window_id_arg }) => {
-            if let Err(e) = run_kitty(&window_id_arg) {
+        Ok(CliAction::CheckTmuxConf) => {
+            if let Err(e) = tmux::check_conf() {
+                eprintln!("kakoune-scrollback: {e:#}");
+                std::process::exit(1);
+            }
+        }
+        Ok(CliAction::Serve) => {
+            let result = serve::serve(
+                std::io::stdin().lock(),
+                std::io::stdout().lock(),
+                serve::Limits {
+                    max_frame_bytes: resolve_max_bytes(),
+                    max_scrollback_lines: resolve_max_scrollback_lines(None),
+                    max_geometry: max_geometry_from(env::var("KAKOUNE_SCROLLBACK_MAX_GEOMETRY")),
+                },
+            );
+            if let Err(e) = result {
+                eprintln!("kakoune-scrollback: {e:#}");
+                std::process::exit(1);
+            }
+        }
+        Ok(CliAction::Gc { max_age_hours }) => {
+            if let Err(e) = gc::run(max_age_hours) {
+                eprintln!("kakoune-scrollback: {e:#}");
+                std::process::exit(1);
+            }
+        }
+        Ok(CliAction::Benchmark { lines }) => match bench::run(lines) {
+            Ok(report) => println!("{report}"),
+            Err(e) => {
+                eprintln!("kakoune-scrollback: {e:#}");
+                std::process::exit(1);
+            }
+        },
+        Ok(CliAction::RunKitty {
+            window_id_arg,
+            opts,
+        }) => {
+            if let Err(e) = run_kitty(&window_id_arg, opts) {
                 eprintln!("kakoune-scrollback: {e:#}");
                 eprintln!("\nPress Enter to close.");
                 wait_for_keypress();
                 std::process::exit(1);
             }
         }
-        Ok(CliAction::RunTmux { pane_id }) => {
-            if let Err(e) = run_tmux(&pane_id) {
+        Ok(CliAction::RunTmux { pane_id, opts }) => {
+            if let Err(e) = run_tmux(&pane_id, opts) {
                 eprintln!("kakoune-scrollback: {e:#}");
                 eprintln!("\nPress Enter to close.");
                 wait_for_keypress();
@@ -132,11 +602,21 @@ fn wait_for_keypress() {
     }
 }
 
-fn check_reentry(env_val: Option<&str>) -> Result<()> {
-    if env_val.is_some() {
-        bail!("Already inside kakoune-scrollback");
+/// Refuse to capture `target` from inside a scrollback view of it.
+/// `KAKOUNE_SCROLLBACK` holds the target the enclosing view was opened for,
+/// so a shell opened from it may still capture a different window or pane.
+/// Any other value (such as the `1` set for compose windows) blocks.
+fn check_reentry(env_val: Option<&str>, target: &TargetId) -> Result<()> {
+    match env_val {
+        None => Ok(()),
+        Some(outer) if is_target_label(outer) && outer != target.to_string() => Ok(()),
+        Some(_) => bail!("Already inside kakoune-scrollback for {target}"),
     }
-    Ok(())
+}
+
+/// Whether `value` looks like a `TargetId` label (`kitty:42`, `tmux:%5`).
+fn is_target_label(value: &str) -> bool {
+    matches!(value.split_once(':'), Some(("kitty" | "tmux", id)) if !id.is_empty())
 }
 
 fn process_screen(
@@ -144,48 +624,283 @@ fn process_screen(
     palette: &[u8; 48],
     stdin_data: &[u8],
     max_scrollback_lines: usize,
+    opts: &terminal::ProcessOptions,
+    tracer: Option<&profile::Tracer>,
 ) -> terminal::ProcessedScreen {
-    terminal::process_bytes(pipe_data, stdin_data, palette, max_scrollback_lines)
+    terminal::process_bytes_traced(
+        pipe_data,
+        stdin_data,
+        palette,
+        max_scrollback_lines,
+        opts,
+        tracer,
+    )
 }
 
 fn materialize(
     screen: &terminal::ProcessedScreen,
+    commands: &[terminal::CommandBlock],
     target: &TargetId,
+    opts: &RunOptions,
+    watchdog: Option<&watchdog::Watchdog>,
 ) -> Result<(tempfile::TempDir, std::path::PathBuf, std::path::PathBuf)> {
+    let (tmp_dir, (text_path, init_path)) = fill_tempdir(&env::temp_dir(), |dir| {
+        if let Some(watchdog) = watchdog {
+            watchdog.track_dir(dir);
+        }
+        let text_path = dir.join("text.txt");
+        let ranges_path = dir.join("ranges.kak");
+        let init_path = dir.join("init.kak");
+
+        output::write_text(&text_path, screen, output::LineEnding::Lf)?;
+        output::write_ranges(
+            &ranges_path,
+            screen,
+            opts.init.ranges_mode,
+            opts.init.max_span_bytes,
+            &opts.init.face_rules,
+            opts.init.underline_layer,
+        )?;
+        output::write_init_kak(&init_path, screen, target, dir, &ranges_path, &opts.init)?;
+        if opts.split_commands {
+            output::write_command_buffers(
+                dir, &text_path, &init_path, commands, target, &opts.init,
+            )?;
+        }
+        if env::var_os("KAKOUNE_SCROLLBACK_DEBUG").is_some() {
+            output::write_debug_map(&dir.join("debug-map.txt"), screen)?;
+        }
+        Ok((text_path, init_path))
+    })?;
+
+    if let Some(path) = &opts.cursor_out {
+        let pos = format!("{}.{}\n", screen.cursor.line, screen.cursor.col);
+        output::atomic_write(path, pos.as_bytes())
+            .with_context(|| format!("failed to write cursor to {}", path.display()))?;
+    }
+
+    Ok((tmp_dir, text_path, init_path))
+}
+
+/// Create a `ksb-*` directory in `parent` and populate it with `fill`.
+/// If `fill` fails, the `TempDir` is dropped before the error propagates,
+/// so partially written files never outlive the call.
+fn fill_tempdir<T>(
+    parent: &std::path::Path,
+    fill: impl FnOnce(&std::path::Path) -> Result<T>,
+) -> Result<(tempfile::TempDir, T)> {
     let tmp_dir = tempfile::Builder::new()
         .prefix("ksb-")
-        .tempdir()
+        .tempdir_in(parent)
         .context("failed to create temporary directory")?;
-    let text_path = tmp_dir.path().join("text.txt");
-    let ranges_path = tmp_dir.path().join("ranges.kak");
-    let init_path = tmp_dir.path().join("init.kak");
-
-    output::write_text(&text_path, screen)?;
-    output::write_ranges(&ranges_path, screen)?;
-    output::write_init_kak(&init_path, screen, target, tmp_dir.path(), &ranges_path)?;
+    let value = fill(tmp_dir.path())?;
+    Ok((tmp_dir, value))
+}
 
-    Ok((tmp_dir, text_path, init_path))
+/// Per-run helpers that watch the pipeline without changing its output.
+#[derive(Clone, Copy)]
+struct RunGuards<'a> {
+    watchdog: Option<&'a watchdog::Watchdog>,
+    tracer: Option<&'a profile::Tracer>,
 }
 
 fn run_core(
     pipe_data: &kitty::PipeData,
     target: &TargetId,
     palette: &[u8; 48],
-    stdin_data: &[u8],
+    input: &Input,
     max_scrollback_lines: usize,
-) -> Result<(tempfile::TempDir, std::path::PathBuf, std::path::PathBuf)> {
-    let screen = process_screen(pipe_data, palette, stdin_data, max_scrollback_lines);
-    materialize(&screen, target)
+    opts: &RunOptions,
+    guards: RunGuards<'_>,
+) -> Result<Option<(tempfile::TempDir, std::path::PathBuf, std::path::PathBuf)>> {
+    if let Some(report) = &input.report {
+        print!("{report}");
+        return Ok(None);
+    }
+    let stdin_data = &input.data[..];
+    let captured;
+    let pipe_data = match opts.capture_columns {
+        Some(columns) => {
+            captured = clamp_pipe_geometry(pipe_data.with_capture_columns(columns));
+            &captured
+        }
+        None => pipe_data,
+    };
+    if opts.dump_cells {
+        print!("{}", terminal::dump_cells(pipe_data, stdin_data));
+        return Ok(None);
+    }
+    let process = terminal::ProcessOptions {
+        auto_trim: auto_trim_from(env::var("KAKOUNE_SCROLLBACK_AUTO_TRIM")),
+        attr_map: attr_map_from(env::var("KAKOUNE_SCROLLBACK_ATTRS")),
+        // tmux in an unknown terminal: COLORFGBG is the only hint left
+        default_colors: match target {
+            TargetId::Tmux(_)
+                if opts.theme.is_none()
+                    && opts.palette_file.is_none()
+                    && !opts.palette_from_terminal =>
+            {
+                env::var("COLORFGBG")
+                    .ok()
+                    .and_then(|v| palette::parse_colorfgbg(&v))
+            }
+            _ => None,
+        },
+        ..opts.process
+    };
+    let mut screen = process_screen(
+        pipe_data,
+        palette,
+        stdin_data,
+        max_scrollback_lines,
+        &process,
+        guards.tracer,
+    );
+    if opts.trim_leading_blanks {
+        terminal::trim_leading_blank_lines(&mut screen);
+    }
+    if let Some(pattern) = &opts.jump_to {
+        terminal::jump_to_match(&mut screen, pattern);
+    }
+    if let Some(n) = opts.gradient_downsample {
+        terminal::downsample_gradients(&mut screen, n);
+    }
+    if opts.focus_command {
+        terminal::focus_cursor_block(&mut screen);
+    }
+    if opts.fill_lines {
+        terminal::fill_line_backgrounds(&mut screen, pipe_data.columns);
+    }
+    if opts.dim_timestamps {
+        terminal::dim_timestamps(&mut screen);
+    }
+    if opts.colorize_diff {
+        terminal::colorize_diff(&mut screen);
+    }
+    if opts.squeeze_blanks {
+        terminal::squeeze_blank_lines(&mut screen);
+    }
+    if opts.squeeze {
+        terminal::squeeze_repeats(&mut screen);
+    }
+    if let Some(max_bytes) = opts.wrap_at {
+        terminal::hard_wrap(&mut screen, max_bytes);
+    }
+    if opts.tab_guides {
+        terminal::add_tab_guides(&mut screen, "ScrollbackTabGuide");
+    }
+    // The capture's own lines, without the marker, header or legend
+    let capture_len = screen.lines.len();
+    if !opts.no_truncation_marker {
+        terminal::prepend_truncation_marker(&mut screen, "ScrollbackTruncated");
+    }
+    if let (true, Some(command)) = (opts.command_header, &opts.init.source_command) {
+        terminal::prepend_header(&mut screen, &format!("$ {command}"), "ScrollbackHeader");
+    }
+    let capture_start = screen.lines.len() - capture_len + 1;
+    // After the prepended lines, so the numbers match buffer lines
+    let mut number_column = 0;
+    if opts.number_lines {
+        number_column = terminal::number_lines(&mut screen);
+    }
+    if opts.match_theme {
+        match kak::probe_kak_defaults() {
+            Some((fg, bg)) => terminal::match_default_colors(&mut screen, fg, bg),
+            None => eprintln!("warning: could not read kak's Default face, --match-theme ignored"),
+        }
+    }
+    if opts.legend {
+        terminal::append_legend(&mut screen, number_column);
+    }
+    if opts.explicit_gaps {
+        terminal::fill_span_gaps(&mut screen);
+    }
+    if opts.stats {
+        print!("{}", stats::Stats::compute(&screen, input.read_bytes));
+        return Ok(None);
+    }
+    if let Some(path) = &opts.append_to {
+        append::append_capture(
+            path,
+            &screen,
+            &target.to_string(),
+            std::time::SystemTime::now(),
+            opts.line_ending,
+        )?;
+        return Ok(None);
+    }
+    if opts.format == output::OutputFormat::Vim {
+        let (tmp_dir, (text_path, highlights_path)) = fill_tempdir(&env::temp_dir(), |dir| {
+            let text_path = dir.join("text.txt");
+            let highlights_path = dir.join("highlights.json");
+            output::write_text(&text_path, &screen, output::LineEnding::Lf)?;
+            vim::write_sidecar(&highlights_path, &screen)?;
+            Ok((text_path, highlights_path))
+        })?;
+        // The editor plugin owns the files from here
+        let tmp_path = tmp_dir.keep();
+        println!(
+            "{}",
+            vim::paths_message(&screen, &tmp_path, &text_path, &highlights_path)
+        );
+        return Ok(None);
+    }
+    let commands = if opts.split_commands {
+        terminal::split_commands(&screen, capture_start..capture_start + capture_len)
+    } else {
+        Vec::new()
+    };
+    profile::span(guards.tracer, "write", || {
+        materialize(&screen, &commands, target, opts, guards.watchdog)
+    })
+    .map(Some)
 }
 
 fn read_input_bounded<R: std::io::Read>(reader: R, max_bytes: u64) -> Result<Vec<u8>> {
-    use std::io::Read;
+    if env::var_os("KAKOUNE_SCROLLBACK_PROGRESS").is_none() {
+        return read_input_chunked(reader, max_bytes, |_| {});
+    }
+    let mut reported = false;
+    let result = read_input_chunked(reader, max_bytes, |total| {
+        eprint!("\rkakoune-scrollback: read {} MB", total / (1024 * 1024));
+        reported = true;
+    });
+    if reported {
+        eprintln!();
+    }
+    result
+}
+
+/// Bytes between progress reports while reading input.
+const PROGRESS_INTERVAL_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Read all of `reader`, failing as soon as more than `max_bytes` arrive.
+/// `on_progress` gets the running total every `PROGRESS_INTERVAL_BYTES`.
+fn read_input_chunked<R: std::io::Read>(
+    mut reader: R,
+    max_bytes: u64,
+    mut on_progress: impl FnMut(u64),
+) -> Result<Vec<u8>> {
     let mut data = Vec::new();
-    reader.take(max_bytes + 1).read_to_end(&mut data)?;
-    anyhow::ensure!(
-        data.len() as u64 <= max_bytes,
-        "scrollback input exceeds {max_bytes} bytes, aborting"
-    );
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut next_report = PROGRESS_INTERVAL_BYTES;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        anyhow::ensure!(
+            data.len() as u64 + n as u64 <= max_bytes,
+            "scrollback input exceeds {max_bytes} bytes, aborting"
+        );
+        data.extend_from_slice(&buf[..n]);
+        if data.len() as u64 >= next_report {
+            on_progress(data.len() as u64);
+            next_report = data.len() as u64 + PROGRESS_INTERVAL_BYTES;
+        }
+    }
     Ok(data)
 }
 
@@ -193,94 +908,405 @@ fn parse_max_lines(value: &str) -> Option<usize> {
     value.trim().parse().ok()
 }
 
-fn resolve_max_scrollback_lines() -> usize {
-    match env::var("KAKOUNE_SCROLLBACK_MAX_LINES") {
-        Err(env::VarError::NotPresent) => terminal::DEFAULT_MAX_SCROLLBACK_LINES,
+/// Line limit for `target`: `KAKOUNE_SCROLLBACK_MAX_LINES` if set and valid,
+/// otherwise the backend's default (the generic default when there is no target).
+fn resolve_max_scrollback_lines(target: Option<&TargetId>) -> usize {
+    let default = target.map_or(
+        terminal::DEFAULT_MAX_SCROLLBACK_LINES,
+        TargetId::default_max_scrollback_lines,
+    );
+    max_scrollback_lines_from(env::var("KAKOUNE_SCROLLBACK_MAX_LINES"), default)
+}
+
+fn max_scrollback_lines_from(var: Result<String, env::VarError>, default: usize) -> usize {
+    match var {
+        Err(env::VarError::NotPresent) => default,
         Err(env::VarError::NotUnicode(_)) => {
             eprintln!(
                 "warning: KAKOUNE_SCROLLBACK_MAX_LINES contains invalid UTF-8, \
-                 using default ({}).",
-                terminal::DEFAULT_MAX_SCROLLBACK_LINES
+                 using default ({default})."
             );
-            terminal::DEFAULT_MAX_SCROLLBACK_LINES
+            default
         }
         Ok(val) => match parse_max_lines(&val) {
             Some(n) => n,
             None => {
                 eprintln!(
-                    "warning: invalid KAKOUNE_SCROLLBACK_MAX_LINES value {:?}, \
-                     using default ({}).",
-                    val,
-                    terminal::DEFAULT_MAX_SCROLLBACK_LINES
+                    "warning: invalid KAKOUNE_SCROLLBACK_MAX_LINES value {val:?}, \
+                     using default ({default})."
+                );
+                default
+            }
+        },
+    }
+}
+
+/// Largest lines/columns accepted from pipe data, unless
+/// `KAKOUNE_SCROLLBACK_MAX_GEOMETRY` says otherwise.
+const DEFAULT_MAX_GEOMETRY: u16 = 1000;
+
+/// Resolve `KAKOUNE_SCROLLBACK_MAX_GEOMETRY`: a positive cell count.
+fn max_geometry_from(var: Result<String, env::VarError>) -> u16 {
+    match var {
+        Err(env::VarError::NotPresent) => DEFAULT_MAX_GEOMETRY,
+        Ok(val) => match val.trim().parse::<u16>() {
+            Ok(n) if n > 0 => n,
+            _ => {
+                eprintln!(
+                    "warning: invalid KAKOUNE_SCROLLBACK_MAX_GEOMETRY value {val:?}, \
+                     using default ({DEFAULT_MAX_GEOMETRY})."
                 );
-                terminal::DEFAULT_MAX_SCROLLBACK_LINES
+                DEFAULT_MAX_GEOMETRY
             }
         },
+        Err(env::VarError::NotUnicode(_)) => {
+            eprintln!(
+                "warning: KAKOUNE_SCROLLBACK_MAX_GEOMETRY contains invalid UTF-8, \
+                 using default ({DEFAULT_MAX_GEOMETRY})."
+            );
+            DEFAULT_MAX_GEOMETRY
+        }
     }
 }
 
-const MAX_STDIN_BYTES: u64 = 512 * 1024 * 1024; // 512 MB
+/// Clamp `pipe_data` to `KAKOUNE_SCROLLBACK_MAX_GEOMETRY`, warning if it was too large.
+fn clamp_pipe_geometry(mut pipe_data: kitty::PipeData) -> kitty::PipeData {
+    let max = max_geometry_from(env::var("KAKOUNE_SCROLLBACK_MAX_GEOMETRY"));
+    if let Some(warning) = pipe_data.clamp_geometry(max) {
+        eprintln!("warning: {warning}");
+    }
+    pipe_data
+}
 
-fn run_kitty(window_id_arg: &str) -> Result<()> {
-    check_reentry(env::var("KAKOUNE_SCROLLBACK").ok().as_deref())?;
+/// Line count above which only the last lines are opened, unless
+/// `KAKOUNE_SCROLLBACK_AUTO_TRIM` says otherwise.
+const DEFAULT_AUTO_TRIM_LINES: usize = 100_000;
 
-    let pipe_data = kitty::parse_pipe_data()?;
-    let window_id = kitty::parse_window_id(window_id_arg)?;
-    let palette = kitty::get_palette(window_id);
-    let stdin_data = read_input_bounded(std::io::stdin(), MAX_STDIN_BYTES)?;
+/// Resolve `KAKOUNE_SCROLLBACK_AUTO_TRIM`: a line count, or `0` to disable.
+fn auto_trim_from(var: Result<String, env::VarError>) -> Option<usize> {
+    match var {
+        Err(env::VarError::NotPresent) => Some(DEFAULT_AUTO_TRIM_LINES),
+        Ok(val) => match parse_max_lines(&val) {
+            Some(0) => None,
+            Some(n) => Some(n),
+            None => {
+                eprintln!(
+                    "warning: invalid KAKOUNE_SCROLLBACK_AUTO_TRIM value {val:?}, \
+                     using default ({DEFAULT_AUTO_TRIM_LINES})."
+                );
+                Some(DEFAULT_AUTO_TRIM_LINES)
+            }
+        },
+        Err(env::VarError::NotUnicode(_)) => {
+            eprintln!(
+                "warning: KAKOUNE_SCROLLBACK_AUTO_TRIM contains invalid UTF-8, \
+                 using default ({DEFAULT_AUTO_TRIM_LINES})."
+            );
+            Some(DEFAULT_AUTO_TRIM_LINES)
+        }
+    }
+}
+
+/// Resolve `KAKOUNE_SCROLLBACK_TMUX_START` (the keybinding's
+/// `capture-pane -S` value) to the pane row the capture begins on.
+fn tmux_start_row_from(var: Result<String, env::VarError>) -> u16 {
+    match var {
+        Err(env::VarError::NotPresent) => 0,
+        Ok(val) => tmux::capture_start_row(&val).unwrap_or_else(|| {
+            eprintln!("warning: invalid KAKOUNE_SCROLLBACK_TMUX_START value {val:?}, ignored.");
+            0
+        }),
+        Err(env::VarError::NotUnicode(_)) => {
+            eprintln!("warning: KAKOUNE_SCROLLBACK_TMUX_START contains invalid UTF-8, ignored.");
+            0
+        }
+    }
+}
+
+/// Resolve `KAKOUNE_SCROLLBACK_ATTRS`: `attr=flag` overrides of the
+/// attribute → face flag table (e.g. `italic=` to drop italics).
+fn attr_map_from(var: Result<String, env::VarError>) -> terminal::AttrMap {
+    match var {
+        Err(env::VarError::NotPresent) => terminal::AttrMap::default(),
+        Ok(val) => terminal::AttrMap::parse(&val).unwrap_or_else(|e| {
+            eprintln!("warning: invalid KAKOUNE_SCROLLBACK_ATTRS value {val:?} ({e}), ignored.");
+            terminal::AttrMap::default()
+        }),
+        Err(env::VarError::NotUnicode(_)) => {
+            eprintln!("warning: KAKOUNE_SCROLLBACK_ATTRS contains invalid UTF-8, ignored.");
+            terminal::AttrMap::default()
+        }
+    }
+}
+
+const DEFAULT_MAX_STDIN_BYTES: u64 = 512 * 1024 * 1024; // 512 MB
+/// Ceiling for `KAKOUNE_SCROLLBACK_MAX_BYTES`; larger values are clamped.
+const MAX_STDIN_BYTES_LIMIT: u64 = 16 * 1024 * 1024 * 1024; // 16 GB
+
+fn parse_max_bytes(value: &str) -> Option<u64> {
+    value.trim().parse().ok().filter(|&n| n > 0)
+}
+
+/// Input size cap: `KAKOUNE_SCROLLBACK_MAX_BYTES` if set and valid,
+/// otherwise 512 MB.
+fn resolve_max_bytes() -> u64 {
+    max_bytes_from(env::var("KAKOUNE_SCROLLBACK_MAX_BYTES"))
+}
+
+fn max_bytes_from(var: Result<String, env::VarError>) -> u64 {
+    match var {
+        Err(env::VarError::NotPresent) => DEFAULT_MAX_STDIN_BYTES,
+        Err(env::VarError::NotUnicode(_)) => {
+            eprintln!(
+                "warning: KAKOUNE_SCROLLBACK_MAX_BYTES contains invalid UTF-8, \
+                 using default ({DEFAULT_MAX_STDIN_BYTES})."
+            );
+            DEFAULT_MAX_STDIN_BYTES
+        }
+        Ok(val) => match parse_max_bytes(&val) {
+            Some(n) if n > MAX_STDIN_BYTES_LIMIT => {
+                eprintln!(
+                    "warning: KAKOUNE_SCROLLBACK_MAX_BYTES value {val:?} is too large, \
+                     using {MAX_STDIN_BYTES_LIMIT}."
+                );
+                MAX_STDIN_BYTES_LIMIT
+            }
+            Some(n) => n,
+            None => {
+                eprintln!(
+                    "warning: invalid KAKOUNE_SCROLLBACK_MAX_BYTES value {val:?}, \
+                     using default ({DEFAULT_MAX_STDIN_BYTES})."
+                );
+                DEFAULT_MAX_STDIN_BYTES
+            }
+        },
+    }
+}
+
+/// Use the `--theme` palette if one was given and known, otherwise `backend`'s.
+fn resolve_palette(theme: Option<&str>, backend: impl FnOnce() -> [u8; 48]) -> [u8; 48] {
+    if let Some(name) = theme {
+        if let Some(palette) = palette::named(name) {
+            return palette;
+        }
+        eprintln!(
+            "warning: unknown theme '{name}' (available: {}), using the terminal palette",
+            palette::THEME_NAMES.join(", ")
+        );
+    }
+    backend()
+}
+
+/// Layer the final palette: the `--theme` or backend palette, then
+/// `--palette-file`, then `--color` entries. Later layers win per slot.
+fn build_palette(opts: &RunOptions, backend: impl FnOnce() -> [u8; 48]) -> Result<[u8; 48]> {
+    let mut builder = palette::PaletteBuilder::new(resolve_palette(opts.theme.as_deref(), backend));
+    if let Some(path) = &opts.palette_file {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read palette file {}", path.display()))?;
+        let layer = palette::parse_palette_file(&text)
+            .map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))?;
+        builder = builder.overlay(&layer);
+    }
+    Ok(builder.colors(&opts.colors).build())
+}
+
+/// The captured input, plus what is reported about it as it was read.
+struct Input {
+    /// The bytes the vt100 parser sees
+    data: Vec<u8>,
+    /// Size of the input as read, for `--stats`
+    read_bytes: usize,
+    /// `--validate` report on the bytes before any rewriting
+    report: Option<stats::RawReport>,
+}
+
+/// The input as the vt100 parser should see it: transcoded from
+/// `--input-encoding`, then with `--expand-cr` applied.
+fn normalize_input(mut data: Vec<u8>, opts: &RunOptions) -> Input {
+    let report = opts.validate.then(|| stats::RawReport::analyze(&data));
+    let read_bytes = data.len();
+    if let Some(enc) = opts.input_encoding {
+        data = encoding::transcode(data, enc);
+    }
+    if opts.expand_cr {
+        data = terminal::expand_carriage_returns(data);
+    }
+    Input {
+        data,
+        read_bytes,
+        report,
+    }
+}
 
-    let max_scrollback_lines = resolve_max_scrollback_lines();
+/// Read the `--face-rules` file, if any.
+fn load_face_rules(opts: &RunOptions) -> Result<face_rules::FaceRules> {
+    let Some(path) = &opts.face_rules_file else {
+        return Ok(face_rules::FaceRules::default());
+    };
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read face rules {}", path.display()))?;
+    face_rules::FaceRules::parse(&text).map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))
+}
+
+fn run_kitty(window_id_arg: &str, mut opts: RunOptions) -> Result<()> {
+    let watchdog = opts.limit.map(watchdog::Watchdog::start);
+    let tracer = opts.profile.as_ref().map(|_| profile::Tracer::new());
 
+    let pipe_data = clamp_pipe_geometry(kitty::parse_pipe_data()?);
+    let window_id = kitty::parse_window_id(window_id_arg)?;
     let target = TargetId::Kitty(window_id);
-    let (tmp_dir, text_path, init_path) = run_core(
+    check_reentry(env::var("KAKOUNE_SCROLLBACK").ok().as_deref(), &target)?;
+    let palette = build_palette(&opts, || kitty::get_palette(window_id))?;
+    opts.init.face_rules = load_face_rules(&opts)?;
+    if let Some(path) = &opts.export_colorscheme {
+        let (fg, bg) = kitty::get_default_colors(window_id)
+            .unwrap_or_else(|| colorscheme::palette_defaults(&palette));
+        return colorscheme::write(path, &palette, fg, bg);
+    }
+    let stdin_data = profile::span(tracer.as_ref(), "capture", || {
+        read_input_bounded(std::io::stdin(), resolve_max_bytes())
+    })?;
+    let input = normalize_input(stdin_data, &opts);
+
+    let max_scrollback_lines = resolve_max_scrollback_lines(Some(&target));
+    let materialized = run_core(
         &pipe_data,
         &target,
         &palette,
-        &stdin_data,
+        &input,
         max_scrollback_lines,
+        &opts,
+        RunGuards {
+            watchdog: watchdog.as_ref(),
+            tracer: tracer.as_ref(),
+        },
     )?;
+    if let (Some(tracer), Some(path)) = (&tracer, &opts.profile) {
+        tracer.write(path)?;
+    }
+    let Some((tmp_dir, text_path, init_path)) = materialized else {
+        return Ok(());
+    };
 
-    exec_kak(tmp_dir, &text_path, &init_path)
+    if let Some(watchdog) = watchdog {
+        watchdog.disarm();
+    }
+    match &opts.socket {
+        Some(socket) => socket::send_artifacts(socket, &target, tmp_dir, &text_path, &init_path),
+        None => exec_kak(&target, tmp_dir, &text_path, &init_path, &opts),
+    }
 }
 
-fn run_tmux(pane_id: &str) -> Result<()> {
-    check_reentry(env::var("KAKOUNE_SCROLLBACK").ok().as_deref())?;
+fn run_tmux(pane_id: &str, mut opts: RunOptions) -> Result<()> {
+    let target = TargetId::Tmux(pane_id.to_string());
+    check_reentry(env::var("KAKOUNE_SCROLLBACK").ok().as_deref(), &target)?;
+    let watchdog = opts.limit.map(watchdog::Watchdog::start);
+    let tracer = opts.profile.as_ref().map(|_| profile::Tracer::new());
     tmux::check_version()?;
 
-    let pipe_data_str = env::var("SCROLLBACK_PIPE_DATA")
-        .context("SCROLLBACK_PIPE_DATA not set (should be set by tmux keybinding)")?;
-    let pipe_data = kitty::parse_pipe_data_str(&pipe_data_str)?;
-
-    let palette = palette::DEFAULT_PALETTE;
+    let pipe_data = if opts.query_geometry {
+        tmux::query_pane_geometry(pane_id)?
+    } else {
+        let pipe_data_str = env::var("SCROLLBACK_PIPE_DATA")
+            .context("SCROLLBACK_PIPE_DATA not set (should be set by tmux keybinding)")?;
+        kitty::parse_pipe_data_str(&pipe_data_str)?
+    };
+    let mut pipe_data = clamp_pipe_geometry(pipe_data);
+
+    let queried = if opts.palette_from_terminal {
+        let colors = osc::query_terminal_colors();
+        if colors.is_none() {
+            eprintln!(
+                "warning: the terminal did not answer the palette query, using default palette"
+            );
+        }
+        colors
+    } else {
+        None
+    };
+    let palette = build_palette(&opts, || {
+        let base = palette::PaletteBuilder::new(palette::DEFAULT_PALETTE);
+        match &queried {
+            Some(colors) => base.overlay(&colors.palette).build(),
+            None => base.build(),
+        }
+    })?;
+    opts.init.face_rules = load_face_rules(&opts)?;
+    if let Some(path) = &opts.export_colorscheme {
+        let (fg, bg) = match queried.as_ref().map(|c| (c.fg, c.bg)) {
+            Some((Some(fg), Some(bg))) => (fg, bg),
+            _ => colorscheme::palette_defaults(&palette),
+        };
+        return colorscheme::write(path, &palette, fg, bg);
+    }
 
-    let mut stdin_data = read_input_bounded(std::io::stdin(), MAX_STDIN_BYTES).context(
+    let stdin_data = profile::span(tracer.as_ref(), "capture", || {
+        read_input_bounded(std::io::stdin(), resolve_max_bytes())
+    })
+    .context(
         "Set KAKOUNE_SCROLLBACK_MAX_LINES to limit processing, \
                   or reduce scrollback history in tmux (set-option -g history-limit).",
     )?;
 
-    tmux::normalize_capture(&mut stdin_data);
-
-    let max_scrollback_lines = resolve_max_scrollback_lines();
+    let mut input = normalize_input(stdin_data, &opts);
+    tmux::normalize_capture(&mut input.data);
+    tmux::strip_final_newline(&mut input.data);
+
+    // A capture starting mid-screen lacks the rows above its start
+    let start_row = tmux_start_row_from(env::var("KAKOUNE_SCROLLBACK_TMUX_START"))
+        .min(pipe_data.lines.saturating_sub(1));
+    opts.process.source_row_offset = start_row;
+    pipe_data.cursor_y = pipe_data.cursor_y.saturating_sub(usize::from(start_row));
+
+    let rows = tmux::captured_rows(&input.data);
+    if let Some(row) = tmux::cursor_row_mismatch(rows, pipe_data.lines, pipe_data.cursor_y) {
+        eprintln!(
+            "warning: cursor row {} is past the {rows} captured lines, \
+             placing the cursor on the last one.",
+            pipe_data.cursor_y + 1
+        );
+        pipe_data.cursor_y = row;
+    }
 
-    let target = TargetId::Tmux(pane_id.to_string());
-    let (tmp_dir, text_path, init_path) = run_core(
+    let max_scrollback_lines = resolve_max_scrollback_lines(Some(&target));
+    let materialized = run_core(
         &pipe_data,
         &target,
         &palette,
-        &stdin_data,
+        &input,
         max_scrollback_lines,
+        &opts,
+        RunGuards {
+            watchdog: watchdog.as_ref(),
+            tracer: tracer.as_ref(),
+        },
     )?;
+    if let (Some(tracer), Some(path)) = (&tracer, &opts.profile) {
+        tracer.write(path)?;
+    }
+    let Some((tmp_dir, text_path, init_path)) = materialized else {
+        return Ok(());
+    };
 
-    exec_kak(tmp_dir, &text_path, &init_path)
+    if let Some(watchdog) = watchdog {
+        watchdog.disarm();
+    }
+    match &opts.socket {
+        Some(socket) => socket::send_artifacts(socket, &target, tmp_dir, &text_path, &init_path),
+        None => exec_kak(&target, tmp_dir, &text_path, &init_path, &opts),
+    }
 }
 
+/// The kak invocation; `KAKOUNE_SCROLLBACK` records `target` for `check_reentry`.
 fn build_kak_command(
+    target: &TargetId,
     text_path: &std::path::Path,
     init_path: &std::path::Path,
 ) -> std::process::Command {
     let init_path_escaped = output::escape_kak_single_quote(&init_path.display().to_string());
     let mut cmd = std::process::Command::new("kak");
-    cmd.env("KAKOUNE_SCROLLBACK", "1")
+    cmd.env("KAKOUNE_SCROLLBACK", target.to_string())
         .arg("-e")
         .arg(format!("source '{init_path_escaped}'"))
         .arg(text_path);
@@ -289,14 +1315,20 @@ fn build_kak_command(
 
 /// Replace the current process with kak, sourcing the generated init.kak.
 fn exec_kak(
+    target: &TargetId,
     tmp_dir: tempfile::TempDir,
     text_path: &std::path::Path,
     init_path: &std::path::Path,
+    opts: &RunOptions,
 ) -> Result<()> {
     use std::os::unix::process::CommandExt;
 
     let tmp_path = tmp_dir.keep();
-    let err = build_kak_command(text_path, init_path).exec();
+    // Until exec, nothing else removes the kept dir if we are interrupted
+    if !opts.no_cleanup_on_signal {
+        interrupt::remove_on_signal(&tmp_path);
+    }
+    let err = build_kak_command(target, text_path, init_path).exec();
 
     let _ = std::fs::remove_dir_all(&tmp_path);
     Err(err).context("failed to exec kak")
@@ -317,6 +1349,8 @@ mod tests {
             cursor_y: 0,
             lines: 24,
             columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
         }
     }
 
@@ -334,11 +1368,19 @@ mod tests {
             terminal::DEFAULT_MAX_SCROLLBACK_LINES,
         );
         let mut text_buf = Vec::new();
-        output::write_text_to(&mut text_buf, &screen).unwrap();
+        output::write_text_to(&mut text_buf, &screen, output::LineEnding::Lf).unwrap();
         let text = String::from_utf8(text_buf).unwrap();
 
         let mut ranges_buf = Vec::new();
-        output::write_ranges_to(&mut ranges_buf, &screen).unwrap();
+        output::write_ranges_to(
+            &mut ranges_buf,
+            &screen,
+            output::RangesWrapMode::Direct,
+            None,
+            &face_rules::FaceRules::default(),
+            false,
+        )
+        .unwrap();
         let ranges = String::from_utf8(ranges_buf).unwrap();
 
         let init = output::render_init_kak(
@@ -346,6 +1388,7 @@ mod tests {
             target,
             std::path::Path::new("/test/ksb-fake"),
             std::path::Path::new("/test/ksb-fake/ranges.kak"),
+            &output::InitOptions::default(),
         )
         .unwrap();
         (text, ranges, init)
@@ -355,7 +1398,7 @@ mod tests {
 
     #[test]
     fn check_reentry_blocks() {
-        let err = check_reentry(Some("1"));
+        let err = check_reentry(Some("1"), &kitty_target("1"));
         assert!(err.is_err());
         assert!(err
             .unwrap_err()
@@ -365,7 +1408,25 @@ mod tests {
 
     #[test]
     fn check_reentry_allows() {
-        assert!(check_reentry(None).is_ok());
+        assert!(check_reentry(None, &kitty_target("1")).is_ok());
+    }
+
+    #[test]
+    fn check_reentry_blocks_same_target() {
+        assert!(check_reentry(Some("kitty:42"), &kitty_target("42")).is_err());
+        let pane = TargetId::Tmux("%5".to_string());
+        assert!(check_reentry(Some("tmux:%5"), &pane).is_err());
+    }
+
+    #[test]
+    fn check_reentry_allows_different_target() {
+        assert!(check_reentry(Some("kitty:42"), &kitty_target("43")).is_ok());
+        let pane = TargetId::Tmux("%6".to_string());
+        assert!(check_reentry(Some("tmux:%5"), &pane).is_ok());
+        assert!(check_reentry(Some("kitty:42"), &pane).is_ok());
+        // Not a target label: no way to tell, so stay blocked
+        assert!(check_reentry(Some("kitty:"), &pane).is_err());
+        assert!(check_reentry(Some(""), &pane).is_err());
     }
 
     // --- build_kak_command ---
@@ -373,6 +1434,7 @@ mod tests {
     #[test]
     fn build_kak_command_program() {
         let cmd = build_kak_command(
+            &kitty_target("1"),
             std::path::Path::new("/tmp/text.txt"),
             std::path::Path::new("/tmp/init.kak"),
         );
@@ -382,6 +1444,7 @@ mod tests {
     #[test]
     fn build_kak_command_args() {
         let cmd = build_kak_command(
+            &kitty_target("1"),
             std::path::Path::new("/tmp/text.txt"),
             std::path::Path::new("/tmp/init.kak"),
         );
@@ -395,20 +1458,23 @@ mod tests {
     #[test]
     fn build_kak_command_env() {
         let cmd = build_kak_command(
+            &kitty_target("1"),
             std::path::Path::new("/tmp/text.txt"),
             std::path::Path::new("/tmp/init.kak"),
         );
         let envs: Vec<(&std::ffi::OsStr, Option<&std::ffi::OsStr>)> = cmd.get_envs().collect();
         assert!(
             envs.iter()
-                .any(|(k, v)| k == &"KAKOUNE_SCROLLBACK" && v == &Some(std::ffi::OsStr::new("1"))),
-            "KAKOUNE_SCROLLBACK=1 should be set, got: {envs:?}"
+                .any(|(k, v)| k == &"KAKOUNE_SCROLLBACK"
+                    && v == &Some(std::ffi::OsStr::new("kitty:1"))),
+            "KAKOUNE_SCROLLBACK=kitty:1 should be set, got: {envs:?}"
         );
     }
 
     #[test]
     fn build_kak_command_init_path_with_quote() {
         let cmd = build_kak_command(
+            &kitty_target("1"),
             std::path::Path::new("/tmp/text.txt"),
             std::path::Path::new("/tmp/it's/init.kak"),
         );
@@ -419,6 +1485,7 @@ mod tests {
     #[test]
     fn build_kak_command_path_with_space() {
         let cmd = build_kak_command(
+            &kitty_target("1"),
             std::path::Path::new("/tmp/my dir/text.txt"),
             std::path::Path::new("/tmp/my dir/init.kak"),
         );
@@ -455,6 +1522,124 @@ mod tests {
         assert_eq!(parse_max_lines(&overflow), None);
     }
 
+    #[test]
+    fn attr_map_from_env() {
+        assert_eq!(
+            attr_map_from(Err(env::VarError::NotPresent)),
+            terminal::AttrMap::default()
+        );
+        assert_eq!(
+            attr_map_from(Ok("italic=".into())),
+            terminal::AttrMap::parse("italic=").unwrap()
+        );
+        assert_eq!(
+            attr_map_from(Ok("italic=q".into())),
+            terminal::AttrMap::default()
+        );
+    }
+
+    #[test]
+    fn max_geometry_from_env() {
+        assert_eq!(
+            max_geometry_from(Err(env::VarError::NotPresent)),
+            DEFAULT_MAX_GEOMETRY
+        );
+        assert_eq!(max_geometry_from(Ok(" 2000 ".into())), 2000);
+        for bad in ["0", "-1", "70000", "big"] {
+            assert_eq!(max_geometry_from(Ok(bad.into())), DEFAULT_MAX_GEOMETRY);
+        }
+    }
+
+    // --- parse_max_bytes ---
+
+    #[test]
+    fn parse_max_bytes_valid() {
+        assert_eq!(parse_max_bytes("1048576"), Some(1_048_576));
+        assert_eq!(parse_max_bytes("1"), Some(1));
+        assert_eq!(parse_max_bytes(" 100 \n"), Some(100));
+    }
+
+    #[test]
+    fn parse_max_bytes_invalid() {
+        assert_eq!(parse_max_bytes(""), None);
+        assert_eq!(parse_max_bytes("0"), None);
+        assert_eq!(parse_max_bytes("-1"), None);
+        assert_eq!(parse_max_bytes("512MB"), None);
+        assert_eq!(parse_max_bytes("1_000"), None);
+    }
+
+    #[test]
+    fn parse_max_bytes_overflow() {
+        let overflow = format!("{}0", u64::MAX);
+        assert_eq!(parse_max_bytes(&overflow), None);
+    }
+
+    #[test]
+    fn max_bytes_env() {
+        assert_eq!(
+            max_bytes_from(Err(env::VarError::NotPresent)),
+            DEFAULT_MAX_STDIN_BYTES
+        );
+        assert_eq!(max_bytes_from(Ok("4096".into())), 4096);
+        assert_eq!(max_bytes_from(Ok("bad".into())), DEFAULT_MAX_STDIN_BYTES);
+        // Clamped rather than rejected, so the +1 probe read cannot overflow
+        assert_eq!(
+            max_bytes_from(Ok(u64::MAX.to_string())),
+            MAX_STDIN_BYTES_LIMIT
+        );
+    }
+
+    #[test]
+    fn auto_trim_env() {
+        assert_eq!(
+            auto_trim_from(Err(env::VarError::NotPresent)),
+            Some(DEFAULT_AUTO_TRIM_LINES)
+        );
+        assert_eq!(auto_trim_from(Ok("5000".into())), Some(5000));
+        assert_eq!(auto_trim_from(Ok("0".into())), None);
+        assert_eq!(
+            auto_trim_from(Ok("lots".into())),
+            Some(DEFAULT_AUTO_TRIM_LINES)
+        );
+    }
+
+    #[test]
+    fn tmux_start_env() {
+        assert_eq!(tmux_start_row_from(Err(env::VarError::NotPresent)), 0);
+        assert_eq!(tmux_start_row_from(Ok("-".into())), 0);
+        assert_eq!(tmux_start_row_from(Ok("-200".into())), 0);
+        assert_eq!(tmux_start_row_from(Ok("3".into())), 3);
+        assert_eq!(tmux_start_row_from(Ok("mark".into())), 0);
+    }
+
+    #[test]
+    fn max_scrollback_lines_default_per_backend() {
+        let kitty = TargetId::Kitty(kitty::parse_window_id("1").unwrap());
+        let tmux = TargetId::Tmux("%5".to_string());
+        assert!(tmux.default_max_scrollback_lines() < kitty.default_max_scrollback_lines());
+        for target in [&kitty, &tmux] {
+            let default = target.default_max_scrollback_lines();
+            assert_eq!(
+                max_scrollback_lines_from(Err(env::VarError::NotPresent), default),
+                default
+            );
+        }
+    }
+
+    #[test]
+    fn max_scrollback_lines_env_overrides_every_backend() {
+        let kitty = TargetId::Kitty(kitty::parse_window_id("1").unwrap());
+        let tmux = TargetId::Tmux("%5".to_string());
+        for target in [&kitty, &tmux] {
+            let default = target.default_max_scrollback_lines();
+            assert_eq!(max_scrollback_lines_from(Ok("1234".into()), default), 1234);
+            assert_eq!(
+                max_scrollback_lines_from(Ok("bad".into()), default),
+                default
+            );
+        }
+    }
+
     // --- read_input_bounded ---
 
     #[test]
@@ -506,18 +1691,48 @@ mod tests {
         assert!(err.is_err());
     }
 
-    // --- 2. tmpdir ---
+    #[test]
+    fn read_input_chunked_cap_at_exact_boundary() {
+        // Larger than one read buffer, so the cap is checked across chunks
+        let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let result = read_input_chunked(std::io::Cursor::new(&data), 200_000, |_| {}).unwrap();
+        assert_eq!(result, data);
+        assert!(read_input_chunked(std::io::Cursor::new(&data), 199_999, |_| {}).is_err());
+    }
 
     #[test]
-    fn pipeline_creates_expected_files() {
+    fn read_input_chunked_reports_progress() {
+        let data = vec![b'x'; 10 * 1024 * 1024];
+        let mut reports = Vec::new();
+        let result =
+            read_input_chunked(std::io::Cursor::new(&data), u64::MAX, |n| reports.push(n)).unwrap();
+        assert_eq!(result.len(), data.len());
+        assert_eq!(reports.len(), 2);
+        assert!(reports[0] >= PROGRESS_INTERVAL_BYTES);
+        assert!(reports[1] >= reports[0] + PROGRESS_INTERVAL_BYTES);
+    }
+
+    // --- 2. tmpdir ---
+
+    #[test]
+    fn pipeline_creates_expected_files() {
         let pd = default_pipe_data();
         let screen = process_screen(
             &pd,
             &palette::DEFAULT_PALETTE,
             b"hello",
             terminal::DEFAULT_MAX_SCROLLBACK_LINES,
+            &terminal::ProcessOptions::default(),
+            None,
         );
-        let (tmp_dir, text_path, init_path) = materialize(&screen, &kitty_target("1")).unwrap();
+        let (tmp_dir, text_path, init_path) = materialize(
+            &screen,
+            &[],
+            &kitty_target("1"),
+            &RunOptions::default(),
+            None,
+        )
+        .unwrap();
         let ranges_path = tmp_dir.path().join("ranges.kak");
 
         assert!(text_path.exists());
@@ -540,8 +1755,17 @@ mod tests {
             &palette::DEFAULT_PALETTE,
             b"hello",
             terminal::DEFAULT_MAX_SCROLLBACK_LINES,
+            &terminal::ProcessOptions::default(),
+            None,
         );
-        let (tmp_dir, _, _) = materialize(&screen, &kitty_target("1")).unwrap();
+        let (tmp_dir, _, _) = materialize(
+            &screen,
+            &[],
+            &kitty_target("1"),
+            &RunOptions::default(),
+            None,
+        )
+        .unwrap();
         let path = tmp_dir.path().to_path_buf();
         assert!(path.exists());
         drop(tmp_dir);
@@ -551,6 +1775,46 @@ mod tests {
         );
     }
 
+    #[test]
+    fn fill_tempdir_removes_partial_files_on_error() {
+        let parent = tempfile::tempdir().unwrap();
+        let result = fill_tempdir(parent.path(), |dir| -> Result<()> {
+            std::fs::write(dir.join("text.txt"), "partial")?;
+            bail!("simulated write failure")
+        });
+
+        assert!(result.is_err());
+        let leftovers: Vec<_> = std::fs::read_dir(parent.path()).unwrap().collect();
+        assert!(
+            leftovers.is_empty(),
+            "failed materialize must not leave a ksb-* dir behind"
+        );
+    }
+
+    #[test]
+    fn fill_tempdir_real_write_failure_cleans_up() {
+        let parent = tempfile::tempdir().unwrap();
+        let result = fill_tempdir(parent.path(), |dir| -> Result<()> {
+            std::fs::write(dir.join("text.txt"), "partial")?;
+            // Writing below a regular file fails even as root, where a
+            // read-only directory would not stop the write.
+            std::fs::write(dir.join("text.txt").join("ranges.kak"), "x")?;
+            Ok(())
+        });
+
+        assert!(result.is_err());
+        assert_eq!(std::fs::read_dir(parent.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn fill_tempdir_keeps_dir_on_success() {
+        let parent = tempfile::tempdir().unwrap();
+        let (tmp_dir, value) = fill_tempdir(parent.path(), |_| Ok(7)).unwrap();
+        assert_eq!(value, 7);
+        assert!(tmp_dir.path().starts_with(parent.path()));
+        assert!(tmp_dir.path().exists());
+    }
+
     // --- 3. end-to-end pipeline ---
 
     #[test]
@@ -560,6 +1824,8 @@ mod tests {
             cursor_y: 1,
             lines: 24,
             columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
         };
         let (text, ranges, init) = process_and_render(
             &pd,
@@ -576,6 +1842,26 @@ mod tests {
         assert!(init.contains("scrollback_kitty_window_id '42'"));
     }
 
+    /// A final `\r\n` on a screen with room to spare only opens an empty row,
+    /// which is trimmed: buffer and cursor match the input without it.
+    #[test]
+    fn pipeline_trailing_newline_keeps_cursor() {
+        let pd = PipeData {
+            cursor_x: 3,
+            cursor_y: 1,
+            lines: 24,
+            columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        for input in [&b"line one\r\nline two"[..], b"line one\r\nline two\r\n"] {
+            let (text, _ranges, init) =
+                process_and_render(&pd, &kitty_target("42"), &palette::DEFAULT_PALETTE, input);
+            assert_eq!(text, "line one\nline two\n");
+            assert!(init.contains("select 2.4,2.4"));
+        }
+    }
+
     #[test]
     fn pipeline_colored_e2e() {
         let pd = PipeData {
@@ -583,6 +1869,8 @@ mod tests {
             cursor_y: 0,
             lines: 24,
             columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
         };
         let input = b"\x1b[31mRed\x1b[0m Normal";
         let (text, ranges, init) =
@@ -607,6 +1895,8 @@ mod tests {
             cursor_y: 5,
             lines: 10,
             columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
         };
         let (_text, _ranges, init) =
             process_and_render(&pd, &kitty_target("1"), &palette::DEFAULT_PALETTE, &input);
@@ -629,22 +1919,978 @@ mod tests {
     }
 
     #[test]
-    fn pipeline_custom_palette_e2e() {
-        let mut custom_palette = palette::DEFAULT_PALETTE;
-        // Override color 1 (red) to #AABBCC
-        custom_palette[3] = 0xAA;
-        custom_palette[4] = 0xBB;
-        custom_palette[5] = 0xCC;
+    fn pipeline_custom_palette_e2e() {
+        let mut custom_palette = palette::DEFAULT_PALETTE;
+        // Override color 1 (red) to #AABBCC
+        custom_palette[3] = 0xAA;
+        custom_palette[4] = 0xBB;
+        custom_palette[5] = 0xCC;
+
+        let pd = default_pipe_data();
+        let input = b"\x1b[31mColored\x1b[0m";
+        let (_text, ranges, _init) =
+            process_and_render(&pd, &kitty_target("1"), &custom_palette, input);
+
+        // SGR 31 = color index 1 → should resolve to our custom #AABBCC
+        assert!(
+            ranges.contains("rgb:AABBCC"),
+            "Custom palette color should appear in ranges, got: {ranges}"
+        );
+    }
+
+    #[test]
+    fn pipeline_attribute_only_face_e2e() {
+        // Bold with no color: the face carries only attributes and must
+        // still reach ranges.kak rather than being dropped as "default".
+        let pd = default_pipe_data();
+        let input = b"\x1b[1mBold\x1b[0m plain";
+        let (text, ranges, _init) =
+            process_and_render(&pd, &kitty_target("1"), &palette::DEFAULT_PALETTE, input);
+
+        assert_eq!(text, "Bold plain\n");
+        assert!(
+            ranges.contains("'1.1,1.4|default,default+b'"),
+            "bold-only region should produce a default,default+b range, got: {ranges}"
+        );
+        assert_eq!(
+            ranges.matches('|').count(),
+            1,
+            "only the bold region should have a range, got: {ranges}"
+        );
+    }
+
+    #[test]
+    fn pipeline_page_boundaries_e2e() {
+        let pd = default_pipe_data();
+        let input = b"\x1b]133;A\x07$ make\r\nbuilding\r\ndone\r\n\x1b]133;A\x07$ ls\r\nfile\r\n";
+        let (_text, _ranges, init) =
+            process_and_render(&pd, &kitty_target("1"), &palette::DEFAULT_PALETTE, input);
+
+        assert!(
+            init.contains("set-option buffer scrollback_page_boundaries 1 4\n"),
+            "both command markers should be recorded, got:\n{init}"
+        );
+        assert!(init.contains("define-command -override scrollback-next-page"));
+        assert!(init.contains("define-command -override scrollback-prev-page"));
+    }
+
+    #[test]
+    fn pipeline_viewport_region_e2e() {
+        let pd = PipeData {
+            cursor_x: 0,
+            cursor_y: 9,
+            lines: 10,
+            columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        let mut input = Vec::new();
+        for i in 0..30 {
+            input.extend_from_slice(format!("line {i}\r\n").as_bytes());
+        }
+        let (_text, _ranges, init) =
+            process_and_render(&pd, &kitty_target("1"), &palette::DEFAULT_PALETTE, &input);
+
+        // total_sb = 21 → viewport is lines 22..=30 ("line 29" is 7 bytes)
+        assert!(
+            init.contains(
+                "set-option buffer scrollback_viewport %val{timestamp} '22.1,30.8|ScrollbackViewport'"
+            ),
+            "viewport region should cover the on-screen lines, got:\n{init}"
+        );
+        let viewport_pos = init.find("ranges scrollback_viewport").unwrap();
+        let colors_pos = init.find("ranges scrollback_colors").unwrap();
+        assert!(viewport_pos < colors_pos);
+    }
+
+    #[test]
+    fn pipeline_no_viewport_region_without_scrollback() {
+        let pd = default_pipe_data();
+        let (_text, _ranges, init) =
+            process_and_render(&pd, &kitty_target("1"), &palette::DEFAULT_PALETTE, b"hello");
+        assert!(!init.contains("set-option buffer scrollback_viewport"));
+    }
+
+    #[test]
+    fn validate_reports_input_as_read() {
+        let raw = b"caf\xe9 50%\r100%\r\n".to_vec();
+        let opts = RunOptions {
+            validate: true,
+            expand_cr: true,
+            input_encoding: Some(encoding::parse_input_encoding("latin1").unwrap()),
+            ..RunOptions::default()
+        };
+        let input = normalize_input(raw.clone(), &opts);
+        assert_ne!(input.data, raw);
+        let report = input.report.unwrap();
+        assert_eq!(report, stats::RawReport::analyze(&raw));
+        assert_eq!(report.input_bytes, raw.len());
+        assert_eq!(report.invalid_utf8, 1);
+
+        assert!(normalize_input(raw, &RunOptions::default())
+            .report
+            .is_none());
+    }
+
+    #[test]
+    fn stats_count_input_as_read() {
+        // Transcoding grows é to two bytes; CR expansion adds an LF
+        let raw = b"caf\xe9 50%\r100%\r\n".to_vec();
+        let opts = RunOptions {
+            stats: true,
+            expand_cr: true,
+            input_encoding: Some(encoding::parse_input_encoding("latin1").unwrap()),
+            ..RunOptions::default()
+        };
+        let input = normalize_input(raw.clone(), &opts);
+        assert_eq!(input.data.len(), raw.len() + 2);
+        assert_eq!(input.read_bytes, raw.len());
+    }
+
+    #[test]
+    fn pipeline_latin1_input_e2e() {
+        let pd = default_pipe_data();
+        let enc = encoding::parse_input_encoding("latin1").unwrap();
+        let input = encoding::transcode(b"\x1b[32mna\xefve caf\xe9\x1b[0m".to_vec(), enc);
+        let (text, ranges, _init) =
+            process_and_render(&pd, &kitty_target("1"), &palette::DEFAULT_PALETTE, &input);
+
+        assert_eq!(text, "naïve café\n");
+        // "naïve café" is 12 bytes in UTF-8
+        assert!(
+            ranges.contains("'1.1,1.12|rgb:00CC00,default'"),
+            "got: {ranges}"
+        );
+    }
+
+    #[test]
+    fn parse_args_fill_lines() {
+        let args = vec!["ksb".into(), "42".into(), "--fill-lines".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.fill_lines
+        ));
+    }
+
+    #[test]
+    fn parse_args_limit_seconds() {
+        let args = vec![
+            "ksb".into(),
+            "42".into(),
+            "--limit-seconds".into(),
+            "2.5".into(),
+        ];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. })
+                if opts.limit == Some(std::time::Duration::from_millis(2500))
+        ));
+        let args = vec![
+            "ksb".into(),
+            "42".into(),
+            "--limit-seconds".into(),
+            "0".into(),
+        ];
+        assert!(parse_args(&args).is_err());
+    }
+
+    #[test]
+    fn limit_seconds_removes_dir_tracked_by_materialize() {
+        use std::sync::mpsc;
+
+        let (tx, rx) = mpsc::channel();
+        // The real timeout's cleanup, minus the exit
+        let watchdog = watchdog::Watchdog::new(move |tracked| {
+            let dir = tracked.clone();
+            watchdog::remove_tracked(tracked);
+            tx.send(dir).unwrap();
+        });
+        let screen = process_screen(
+            &default_pipe_data(),
+            &palette::DEFAULT_PALETTE,
+            b"\x1b[31mred\x1b[0m text",
+            terminal::DEFAULT_MAX_SCROLLBACK_LINES,
+            &terminal::ProcessOptions::default(),
+            None,
+        );
+        let (tmp_dir, text_path, _) = materialize(
+            &screen,
+            &[],
+            &kitty_target("1"),
+            &RunOptions::default(),
+            Some(&watchdog),
+        )
+        .unwrap();
+        assert!(text_path.exists());
+
+        // Armed only now, so the dir is tracked before the timer can fire
+        watchdog.arm(std::time::Duration::from_millis(1));
+        let tracked = rx
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .expect("watchdog should fire");
+        assert_eq!(tracked.as_deref(), Some(tmp_dir.path()));
+        assert!(
+            !tmp_dir.path().exists(),
+            "the tracked dir should be removed"
+        );
+    }
+
+    #[test]
+    fn parse_args_no_trailing_trim() {
+        let args = vec!["ksb".into(), "42".into(), "--no-trailing-trim".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.process.no_trailing_trim
+        ));
+    }
+
+    #[test]
+    fn parse_args_screen_only() {
+        let args = vec!["ksb".into(), "7".into(), "--screen-only".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.process.screen_only
+        ));
+    }
+
+    #[test]
+    fn parse_args_quantize() {
+        let args = vec!["ksb".into(), "--quantize".into(), "42".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.process.quantize
+        ));
+    }
+
+    #[test]
+    fn parse_args_capture_columns() {
+        let args = vec![
+            "ksb".into(),
+            "42".into(),
+            "--capture-columns".into(),
+            "132".into(),
+        ];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.capture_columns == Some(132)
+        ));
+        for bad in ["0", "wide", "70000"] {
+            let args = vec![
+                "ksb".into(),
+                "42".into(),
+                "--capture-columns".into(),
+                bad.into(),
+            ];
+            assert!(parse_args(&args).is_err(), "{bad} should be rejected");
+        }
+    }
+
+    #[test]
+    fn pipeline_capture_columns_mismatch() {
+        // Scrollback recorded at 100 columns, window since shrunk to 60
+        let live = PipeData {
+            cursor_x: 5,
+            cursor_y: 1,
+            lines: 24,
+            columns: 60,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        let long_line = "x".repeat(100);
+        let input = format!("{long_line}\r\n$ ");
+
+        let at_live = process_screen(
+            &live,
+            &palette::DEFAULT_PALETTE,
+            input.as_bytes(),
+            terminal::DEFAULT_MAX_SCROLLBACK_LINES,
+            &terminal::ProcessOptions::default(),
+            None,
+        );
+        // Live width wraps the recorded line in two
+        assert_eq!(at_live.lines.len(), 3);
+
+        let captured = live.with_capture_columns(100);
+        let screen = process_screen(
+            &captured,
+            &palette::DEFAULT_PALETTE,
+            input.as_bytes(),
+            terminal::DEFAULT_MAX_SCROLLBACK_LINES,
+            &terminal::ProcessOptions::default(),
+            None,
+        );
+        assert_eq!(screen.lines.len(), 2);
+        assert_eq!(screen.lines[0].text, long_line);
+        assert_eq!(screen.lines[1].text, "$");
+        // Cursor row still counts from the bottom of the live screen
+        assert_eq!(screen.cursor.line, 2);
+        assert_eq!(screen.cursor.col, 2);
+    }
+
+    #[test]
+    fn parse_args_line_ending() {
+        let args = vec![
+            "ksb".into(),
+            "7".into(),
+            "--line-ending".into(),
+            "crlf".into(),
+        ];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.line_ending == output::LineEnding::Crlf
+        ));
+        let args = vec![
+            "ksb".into(),
+            "7".into(),
+            "--line-ending".into(),
+            "cr".into(),
+        ];
+        assert!(parse_args(&args).is_err());
+    }
+
+    #[test]
+    fn parse_args_squeeze() {
+        let args = vec!["ksb".into(), "--squeeze".into(), "7".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.squeeze
+        ));
+    }
+
+    #[test]
+    fn parse_args_dim_timestamps() {
+        let args = vec!["ksb".into(), "7".into(), "--dim-timestamps".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.dim_timestamps && !opts.squeeze
+        ));
+    }
+
+    #[test]
+    fn parse_args_colorize_diff() {
+        let args = vec!["ksb".into(), "--colorize-diff".into(), "7".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.colorize_diff
+        ));
+    }
+
+    #[test]
+    fn parse_args_jump_to() {
+        let args = vec![
+            "ksb".into(),
+            "7".into(),
+            "--jump-to".into(),
+            "error:|FAILED".into(),
+        ];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. })
+                if opts.jump_to.as_ref().is_some_and(|re| re.is_match("test FAILED"))
+        ));
+        let args = vec!["ksb".into(), "7".into(), "--jump-to".into(), "(".into()];
+        assert!(parse_args(&args).is_err());
+    }
+
+    #[test]
+    fn parse_args_split_commands() {
+        let args = vec!["ksb".into(), "7".into(), "--split-commands".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.split_commands
+        ));
+    }
+
+    #[test]
+    fn parse_args_profile() {
+        let args = vec![
+            "ksb".into(),
+            "7".into(),
+            "--profile".into(),
+            "/tmp/trace.json".into(),
+        ];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. })
+                if opts.profile.as_deref() == Some(std::path::Path::new("/tmp/trace.json"))
+        ));
+        let args = vec!["ksb".into(), "7".into(), "--profile".into()];
+        assert!(parse_args(&args).is_err());
+    }
+
+    #[test]
+    fn profile_trace_covers_each_phase() {
+        let mut input = String::new();
+        for i in 0..2500 {
+            input.push_str(&format!("\x1b[3{}mline {i}\x1b[0m\r\n", i % 8));
+        }
+        let tracer = profile::Tracer::new();
+        let data = tracer
+            .span("capture", || {
+                read_input_bounded(std::io::Cursor::new(input), DEFAULT_MAX_STDIN_BYTES)
+            })
+            .unwrap();
+        let result = run_core(
+            &default_pipe_data(),
+            &kitty_target("1"),
+            &palette::DEFAULT_PALETTE,
+            &normalize_input(data, &RunOptions::default()),
+            terminal::DEFAULT_MAX_SCROLLBACK_LINES,
+            &RunOptions::default(),
+            RunGuards {
+                watchdog: None,
+                tracer: Some(&tracer),
+            },
+        )
+        .unwrap();
+        assert!(result.is_some());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace.json");
+        tracer.write(&path).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        let names: Vec<&str> = json["traceEvents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| e["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            ["capture", "parse", "process", "process", "process", "write"]
+        );
+        // Counted before trimming: the empty cursor row after the last CRLF
+        assert_eq!(json["traceEvents"][4]["args"]["detail"], "lines 2001-2501");
+    }
+
+    #[test]
+    fn number_lines_counts_truncation_marker() {
+        let mut input = String::new();
+        for i in 0..40 {
+            input.push_str(&format!("line {i}\r\n"));
+        }
+        let opts = RunOptions {
+            number_lines: true,
+            ..RunOptions::default()
+        };
+        let (_dir, text_path, _) = run_core(
+            &default_pipe_data(),
+            &kitty_target("1"),
+            &palette::DEFAULT_PALETTE,
+            &normalize_input(input.into_bytes(), &opts),
+            2,
+            &opts,
+            RunGuards {
+                watchdog: None,
+                tracer: None,
+            },
+        )
+        .unwrap()
+        .unwrap();
+        let text = std::fs::read_to_string(text_path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(
+            lines[0].starts_with(" 1: ── scrollback truncated"),
+            "{text}"
+        );
+        assert!(lines[1].starts_with(" 2: "), "{text}");
+        let last = format!("{:>2}: ", lines.len());
+        assert!(lines.last().unwrap().starts_with(&last), "{text}");
+    }
+
+    #[test]
+    fn legend_lines_skip_the_number_column() {
+        let opts = RunOptions {
+            number_lines: true,
+            legend: true,
+            ..RunOptions::default()
+        };
+        let (_dir, text_path, _) = run_core(
+            &default_pipe_data(),
+            &kitty_target("1"),
+            &palette::DEFAULT_PALETTE,
+            &normalize_input(b"\x1b[31mred\x1b[0m\r\nplain".to_vec(), &opts),
+            terminal::DEFAULT_MAX_SCROLLBACK_LINES,
+            &opts,
+            RunGuards {
+                watchdog: None,
+                tracer: None,
+            },
+        )
+        .unwrap()
+        .unwrap();
+        let text = std::fs::read_to_string(text_path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines, ["1: red", "2: plain", "", "   rgb:CC0000,default"]);
+    }
+
+    #[test]
+    fn parse_args_stats() {
+        let args = vec![
+            "ksb".into(),
+            "--stats".into(),
+            "--tmux-pane".into(),
+            "%0".into(),
+        ];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunTmux { opts, .. }) if opts.stats
+        ));
+    }
+
+    #[test]
+    fn parse_args_validate() {
+        let args = vec!["ksb".into(), "7".into(), "--validate".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.validate && !opts.stats
+        ));
+    }
+
+    #[test]
+    fn parse_args_no_cleanup_on_signal() {
+        let args = vec!["ksb".into(), "7".into(), "--no-cleanup-on-signal".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.no_cleanup_on_signal
+        ));
+    }
+
+    #[test]
+    fn parse_args_format() {
+        let parse = |value: &str| {
+            let args = vec!["ksb".into(), "7".into(), "--format".into(), value.into()];
+            match parse_args(&args) {
+                Ok(CliAction::RunKitty { opts, .. }) => Some(opts.format),
+                _ => None,
+            }
+        };
+        assert_eq!(parse("vim"), Some(output::OutputFormat::Vim));
+        assert_eq!(parse("kak"), Some(output::OutputFormat::Kak));
+        assert_eq!(parse("emacs"), None);
+    }
+
+    #[test]
+    fn parse_args_socket() {
+        let args = vec![
+            "ksb".into(),
+            "--tmux-pane".into(),
+            "%1".into(),
+            "--socket".into(),
+            "/run/ksb.sock".into(),
+        ];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunTmux { opts, .. })
+                if opts.socket.as_deref() == Some(std::path::Path::new("/run/ksb.sock"))
+        ));
+        let args = vec!["ksb".into(), "42".into(), "--socket".into()];
+        assert!(parse_args(&args).is_err());
+    }
+
+    #[test]
+    fn parse_args_append_to() {
+        let args = vec![
+            "ksb".into(),
+            "42".into(),
+            "--append-to".into(),
+            "/tmp/log".into(),
+        ];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. })
+                if opts.append_to.as_deref() == Some(std::path::Path::new("/tmp/log"))
+        ));
+        let args = vec!["ksb".into(), "42".into(), "--append-to".into()];
+        assert!(parse_args(&args).is_err());
+    }
+
+    #[test]
+    fn materialize_writes_cursor_out() {
+        let pd = PipeData {
+            cursor_x: 3,
+            cursor_y: 1,
+            lines: 24,
+            columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        let screen = process_screen(
+            &pd,
+            &palette::DEFAULT_PALETTE,
+            b"hello\r\nworld",
+            terminal::DEFAULT_MAX_SCROLLBACK_LINES,
+            &terminal::ProcessOptions::default(),
+            None,
+        );
+        let out_dir = tempfile::tempdir().unwrap();
+        let cursor_path = out_dir.path().join("cursor");
+        let opts = RunOptions {
+            cursor_out: Some(cursor_path.clone()),
+            ..Default::default()
+        };
+        let (_tmp_dir, _, _) = materialize(&screen, &[], &kitty_target("1"), &opts, None).unwrap();
+        assert_eq!(std::fs::read_to_string(&cursor_path).unwrap(), "2.4\n");
+    }
+
+    #[test]
+    fn materialize_split_commands() {
+        let screen = process_screen(
+            &default_pipe_data(),
+            &palette::DEFAULT_PALETTE,
+            b"\x1b]133;A\x07$ ls\r\n\x1b[34mdir\x1b[0m\r\n\
+              \x1b]133;A\x07$ echo hi\r\nhi",
+            terminal::DEFAULT_MAX_SCROLLBACK_LINES,
+            &terminal::ProcessOptions::default(),
+            None,
+        );
+        let opts = RunOptions {
+            split_commands: true,
+            ..Default::default()
+        };
+        let commands = terminal::split_commands(&screen, 1..screen.lines.len() + 1);
+        let (tmp_dir, text_path, init_path) =
+            materialize(&screen, &commands, &kitty_target("1"), &opts, None).unwrap();
+        let dir = tmp_dir.path();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("cmd-1.txt")).unwrap(),
+            "$ ls\ndir\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("cmd-2.txt")).unwrap(),
+            "$ echo hi\nhi\n"
+        );
+        assert!(!dir.join("cmd-3.txt").exists());
+        assert!(std::fs::read_to_string(dir.join("cmd-1.ranges.kak"))
+            .unwrap()
+            .contains("'2.1,2.3|"));
+
+        let init = std::fs::read_to_string(&init_path).unwrap();
+        assert!(init.contains("rename-buffer '*scrollback 1: $ ls*'"));
+        assert!(init.contains("rename-buffer '*scrollback 2: $ echo hi*'"));
+        assert!(init.contains(&format!("buffer '{}'", text_path.display())));
+        assert!(init.contains("define-command -override scrollback-command"));
+    }
+
+    #[test]
+    fn split_commands_leave_out_header_and_legend() {
+        let mut opts = RunOptions {
+            split_commands: true,
+            legend: true,
+            command_header: true,
+            ..RunOptions::default()
+        };
+        opts.init.source_command = Some("make".into());
+        let input = b"\x1b]133;A\x07$ ls\r\n\x1b[34mdir\x1b[0m\r\n\
+                      \x1b]133;A\x07$ echo hi\r\nhi";
+        let (tmp_dir, text_path, _) = run_core(
+            &default_pipe_data(),
+            &kitty_target("1"),
+            &palette::DEFAULT_PALETTE,
+            &normalize_input(input.to_vec(), &opts),
+            terminal::DEFAULT_MAX_SCROLLBACK_LINES,
+            &opts,
+            RunGuards {
+                watchdog: None,
+                tracer: None,
+            },
+        )
+        .unwrap()
+        .unwrap();
+        let dir = tmp_dir.path();
+        // The main buffer keeps them
+        let text = std::fs::read_to_string(text_path).unwrap();
+        assert!(text.starts_with("$ make\n$ ls\n"), "{text}");
+        assert!(
+            text.ends_with("hi\n\nScrollbackHeader\nrgb:0000CC,default\n"),
+            "{text}"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("cmd-1.txt")).unwrap(),
+            "$ ls\ndir\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("cmd-2.txt")).unwrap(),
+            "$ echo hi\nhi\n"
+        );
+        assert!(!dir.join("cmd-3.txt").exists());
+    }
+
+    #[test]
+    fn parse_args_skip_blank_fg() {
+        let args = vec!["ksb".into(), "7".into(), "--skip-blank-fg".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.process.skip_blank_fg
+        ));
+    }
+
+    #[test]
+    fn parse_args_underline_layer() {
+        let args = vec!["ksb".into(), "42".into(), "--underline-layer".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.init.underline_layer
+        ));
+    }
+
+    #[test]
+    fn parse_args_face_rules() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("faces.rules");
+        std::fs::write(&path, "rgb:0000CC => rgb:5555FF\n").unwrap();
+        let args = vec![
+            "ksb".into(),
+            "42".into(),
+            "--face-rules".into(),
+            path.display().to_string(),
+        ];
+        let Ok(CliAction::RunKitty { opts, .. }) = parse_args(&args) else {
+            panic!("expected a kitty run");
+        };
+        assert_eq!(opts.face_rules_file.as_deref(), Some(path.as_path()));
+        let rules = load_face_rules(&opts).unwrap();
+        assert_eq!(rules.apply("rgb:0000CC,default"), "rgb:5555FF,default");
+
+        std::fs::write(&path, "rgb:0000CC\n").unwrap();
+        let err = load_face_rules(&opts).unwrap_err().to_string();
+        assert!(err.contains("line 1"), "{err}");
+        let opts = RunOptions {
+            face_rules_file: Some("/nonexistent/faces.rules".into()),
+            ..Default::default()
+        };
+        assert!(load_face_rules(&opts).is_err());
+        let args = vec!["ksb".into(), "42".into(), "--face-rules".into()];
+        assert!(parse_args(&args).is_err());
+    }
+
+    #[test]
+    fn parse_args_dump_cells() {
+        let args = vec![
+            "ksb".into(),
+            "--dump-cells".into(),
+            "--tmux-pane".into(),
+            "%3".into(),
+        ];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunTmux { opts, .. }) if opts.dump_cells && !opts.validate
+        ));
+    }
+
+    #[test]
+    fn parse_args_no_truncation_marker() {
+        let args = vec!["ksb".into(), "42".into(), "--no-truncation-marker".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.no_truncation_marker
+        ));
+    }
+
+    #[test]
+    fn parse_args_tab_guides() {
+        let args = vec!["ksb".into(), "42".into(), "--tab-guides".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.tab_guides
+        ));
+    }
+
+    #[test]
+    fn parse_args_wrap_at() {
+        let args = vec!["ksb".into(), "42".into(), "--wrap-at".into(), "80".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.wrap_at == Some(80)
+        ));
+        let args = vec!["ksb".into(), "42".into(), "--wrap-at".into(), "0".into()];
+        assert!(parse_args(&args).is_err());
+    }
+
+    #[test]
+    fn parse_args_max_span_bytes() {
+        let args = vec![
+            "ksb".into(),
+            "42".into(),
+            "--max-span-bytes".into(),
+            "256".into(),
+        ];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.init.max_span_bytes == Some(256)
+        ));
+        let args = vec![
+            "ksb".into(),
+            "42".into(),
+            "--max-span-bytes".into(),
+            "0".into(),
+        ];
+        assert!(parse_args(&args).is_err());
+    }
+
+    #[test]
+    fn parse_args_gradient_downsample() {
+        let args = vec![
+            "ksb".into(),
+            "42".into(),
+            "--gradient-downsample".into(),
+            "4".into(),
+        ];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.gradient_downsample == Some(4)
+        ));
+        let args = vec![
+            "ksb".into(),
+            "42".into(),
+            "--gradient-downsample".into(),
+            "1".into(),
+        ];
+        assert!(parse_args(&args).is_err());
+    }
+
+    #[test]
+    fn parse_args_squeeze_blanks() {
+        let args = vec!["ksb".into(), "7".into(), "--squeeze-blanks".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.squeeze_blanks && !opts.squeeze
+        ));
+    }
+
+    #[test]
+    fn parse_args_register() {
+        let args = vec!["ksb".into(), "7".into(), "--register".into(), "s".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.init.register == Some('s')
+        ));
+        for bad in ["", "ab", "A", "\""] {
+            let args = vec!["ksb".into(), "7".into(), "--register".into(), bad.into()];
+            assert!(parse_args(&args).is_err(), "{bad:?} should fail");
+        }
+    }
+
+    #[test]
+    fn parse_args_number_lines() {
+        let args = vec!["ksb".into(), "--number-lines".into(), "42".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.number_lines
+        ));
+    }
+
+    #[test]
+    fn parse_args_command() {
+        let args = vec![
+            "ksb".into(),
+            "7".into(),
+            "--command".into(),
+            "make 'all'".into(),
+            "--command-header".into(),
+        ];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. })
+                if opts.command_header
+                    && opts.init.source_command.as_deref() == Some("make 'all'")
+        ));
+        let args = vec!["ksb".into(), "7".into(), "--command-header".into()];
+        assert!(parse_args(&args).is_err());
+        let args = vec!["ksb".into(), "7".into(), "--command".into(), "".into()];
+        assert!(parse_args(&args).is_err());
+    }
+
+    #[test]
+    fn parse_args_bold_is_bright() {
+        let args = vec!["ksb".into(), "7".into(), "--bold-is-bright".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.process.bold_is_bright
+        ));
+    }
+
+    #[test]
+    fn parse_args_legend() {
+        let args = vec!["ksb".into(), "7".into(), "--legend".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.legend
+        ));
+    }
+
+    #[test]
+    fn parse_args_explicit_gaps() {
+        let args = vec!["ksb".into(), "7".into(), "--explicit-gaps".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.explicit_gaps
+        ));
+    }
+
+    #[test]
+    fn parse_args_trim_leading_blanks() {
+        let args = vec!["ksb".into(), "7".into(), "--trim-leading-blanks".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.trim_leading_blanks
+        ));
+    }
+
+    #[test]
+    fn parse_args_expand_cr() {
+        let args = vec!["ksb".into(), "7".into(), "--expand-cr".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.expand_cr
+        ));
+    }
 
-        let pd = default_pipe_data();
-        let input = b"\x1b[31mColored\x1b[0m";
-        let (_text, ranges, _init) =
-            process_and_render(&pd, &kitty_target("1"), &custom_palette, input);
+    #[test]
+    fn parse_args_input_encoding() {
+        let args = vec![
+            "ksb".into(),
+            "42".into(),
+            "--input-encoding".into(),
+            "shift_jis".into(),
+        ];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.input_encoding == Some(encoding_rs::SHIFT_JIS)
+        ));
+        let args = vec![
+            "ksb".into(),
+            "42".into(),
+            "--input-encoding".into(),
+            "ebcdic".into(),
+        ];
+        assert!(parse_args(&args).is_err());
+    }
 
-        // SGR 31 = color index 1 → should resolve to our custom #AABBCC
-        assert!(
-            ranges.contains("rgb:AABBCC"),
-            "Custom palette color should appear in ranges, got: {ranges}"
+    #[test]
+    fn pipeline_debug_map_e2e() {
+        let pd = PipeData {
+            cursor_x: 0,
+            cursor_y: 2,
+            lines: 3,
+            columns: 10,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        let screen = process_screen(
+            &pd,
+            &palette::DEFAULT_PALETTE,
+            b"first\r\n0123456789wrapped\r\nthird\r\nfourth",
+            terminal::DEFAULT_MAX_SCROLLBACK_LINES,
+            &terminal::ProcessOptions::default(),
+            None,
+        );
+        let mut buf = Vec::new();
+        output::write_debug_map_to(&mut buf, &screen).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "1 -> 2 row 0\n2 -> 2 row 1\n3 -> 2 row 2 (cont)\n4 -> 1 row 2\n5 -> 0 row 2\n"
         );
     }
 
@@ -655,10 +2901,31 @@ mod tests {
             process_and_render(&pd, &kitty_target("1"), &palette::DEFAULT_PALETTE, b"");
 
         // Empty input → text is empty, ranges empty, but init still has structure
-        assert!(text.is_empty() || text == "\n");
+        assert!(text.is_empty());
         assert!(ranges.is_empty());
         assert!(init.contains("scrollback_kitty_window_id"));
-        assert!(init.contains("select"));
+        assert!(init.contains("kakoune-scrollback-setup-keymaps"));
+        assert!(
+            !init.contains("select 1.1,1.1"),
+            "empty screen should skip the cursor selection"
+        );
+    }
+
+    #[test]
+    fn pipeline_newline_only_input() {
+        for input in [&b"\n"[..], b"\r\n", b"\r\n\r\n\r\n", b"   \r\n\x1b[0m\r\n"] {
+            let pd = PipeData {
+                cursor_x: 0,
+                cursor_y: 1,
+                ..default_pipe_data()
+            };
+            let (text, ranges, init) =
+                process_and_render(&pd, &kitty_target("1"), &palette::DEFAULT_PALETTE, input);
+            assert_eq!(text, "", "{input:?} should give an empty file");
+            assert!(ranges.is_empty());
+            assert!(!init.contains("try %{\n    select "), "{input:?}: {init}");
+            assert!(init.contains("kakoune-scrollback-setup-keymaps"));
+        }
     }
 
     // --- 4. init.kak verification ---
@@ -671,8 +2938,17 @@ mod tests {
             &palette::DEFAULT_PALETTE,
             b"hello",
             terminal::DEFAULT_MAX_SCROLLBACK_LINES,
+            &terminal::ProcessOptions::default(),
+            None,
         );
-        let (tmp_dir, _, init_path) = materialize(&screen, &kitty_target("1")).unwrap();
+        let (tmp_dir, _, init_path) = materialize(
+            &screen,
+            &[],
+            &kitty_target("1"),
+            &RunOptions::default(),
+            None,
+        )
+        .unwrap();
         let init = std::fs::read_to_string(&init_path).unwrap();
         let tmp_dir_str = tmp_dir.path().to_str().unwrap();
 
@@ -710,6 +2986,8 @@ mod tests {
             cursor_y: 0,
             lines: 24,
             columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
         };
         let (_text, _ranges, init) =
             process_and_render(&pd, &kitty_target("1"), &palette::DEFAULT_PALETTE, input);
@@ -813,14 +3091,25 @@ mod tests {
             cursor_y: 1,
             lines: 24,
             columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
         };
         let screen = process_screen(
             &pd,
             &palette::DEFAULT_PALETTE,
             b"hello\r\nworld",
             terminal::DEFAULT_MAX_SCROLLBACK_LINES,
+            &terminal::ProcessOptions::default(),
+            None,
         );
-        let (tmp_dir, text_path, init_path) = materialize(&screen, &kitty_target("1")).unwrap();
+        let (tmp_dir, text_path, init_path) = materialize(
+            &screen,
+            &[],
+            &kitty_target("1"),
+            &RunOptions::default(),
+            None,
+        )
+        .unwrap();
         validate_in_kak(&text_path, &init_path, tmp_dir.path());
     }
 
@@ -836,8 +3125,17 @@ mod tests {
             &palette::DEFAULT_PALETTE,
             input,
             terminal::DEFAULT_MAX_SCROLLBACK_LINES,
+            &terminal::ProcessOptions::default(),
+            None,
         );
-        let (tmp_dir, text_path, init_path) = materialize(&screen, &kitty_target("1")).unwrap();
+        let (tmp_dir, text_path, init_path) = materialize(
+            &screen,
+            &[],
+            &kitty_target("1"),
+            &RunOptions::default(),
+            None,
+        )
+        .unwrap();
         validate_in_kak(&text_path, &init_path, tmp_dir.path());
     }
 
@@ -853,8 +3151,17 @@ mod tests {
             &palette::DEFAULT_PALETTE,
             input,
             terminal::DEFAULT_MAX_SCROLLBACK_LINES,
+            &terminal::ProcessOptions::default(),
+            None,
         );
-        let (tmp_dir, text_path, init_path) = materialize(&screen, &kitty_target("1")).unwrap();
+        let (tmp_dir, text_path, init_path) = materialize(
+            &screen,
+            &[],
+            &kitty_target("1"),
+            &RunOptions::default(),
+            None,
+        )
+        .unwrap();
         validate_in_kak(&text_path, &init_path, tmp_dir.path());
     }
 
@@ -872,14 +3179,25 @@ mod tests {
             cursor_y: 5,
             lines: 24,
             columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
         };
         let screen = process_screen(
             &pd,
             &palette::DEFAULT_PALETTE,
             &input,
             terminal::DEFAULT_MAX_SCROLLBACK_LINES,
+            &terminal::ProcessOptions::default(),
+            None,
         );
-        let (tmp_dir, text_path, init_path) = materialize(&screen, &kitty_target("1")).unwrap();
+        let (tmp_dir, text_path, init_path) = materialize(
+            &screen,
+            &[],
+            &kitty_target("1"),
+            &RunOptions::default(),
+            None,
+        )
+        .unwrap();
         validate_in_kak(&text_path, &init_path, tmp_dir.path());
     }
 
@@ -904,8 +3222,17 @@ mod tests {
             &palette::DEFAULT_PALETTE,
             &input,
             terminal::DEFAULT_MAX_SCROLLBACK_LINES,
+            &terminal::ProcessOptions::default(),
+            None,
         );
-        let (tmp_dir, text_path, init_path) = materialize(&screen, &kitty_target("1")).unwrap();
+        let (tmp_dir, text_path, init_path) = materialize(
+            &screen,
+            &[],
+            &kitty_target("1"),
+            &RunOptions::default(),
+            None,
+        )
+        .unwrap();
         validate_in_kak(&text_path, &init_path, tmp_dir.path());
     }
 
@@ -920,11 +3247,55 @@ mod tests {
             &palette::DEFAULT_PALETTE,
             b"",
             terminal::DEFAULT_MAX_SCROLLBACK_LINES,
+            &terminal::ProcessOptions::default(),
+            None,
         );
-        let (tmp_dir, text_path, init_path) = materialize(&screen, &kitty_target("1")).unwrap();
+        let (tmp_dir, text_path, init_path) = materialize(
+            &screen,
+            &[],
+            &kitty_target("1"),
+            &RunOptions::default(),
+            None,
+        )
+        .unwrap();
         validate_in_kak(&text_path, &init_path, tmp_dir.path());
     }
 
+    #[test]
+    fn kak_validates_blank_only_input() {
+        if !kak_available() {
+            return;
+        }
+        // Only blanks, newlines and escapes → zero lines after trimming
+        let pd = PipeData {
+            cursor_x: 4,
+            cursor_y: 5,
+            lines: 24,
+            columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        let screen = process_screen(
+            &pd,
+            &palette::DEFAULT_PALETTE,
+            b"\x1b[2J\x1b[H   \r\n\r\n\x1b[0m\r\n",
+            terminal::DEFAULT_MAX_SCROLLBACK_LINES,
+            &terminal::ProcessOptions::default(),
+            None,
+        );
+        assert!(screen.lines.is_empty());
+        let (tmp_dir, text_path, init_path) = materialize(
+            &screen,
+            &[],
+            &kitty_target("1"),
+            &RunOptions::default(),
+            None,
+        )
+        .unwrap();
+        let desc = validate_cursor_in_kak(&text_path, &init_path, tmp_dir.path());
+        assert_eq!(desc, "1.1,1.1");
+    }
+
     #[test]
     fn kak_scrollback_colors_populated() {
         if !kak_available() {
@@ -937,8 +3308,17 @@ mod tests {
             &palette::DEFAULT_PALETTE,
             input,
             terminal::DEFAULT_MAX_SCROLLBACK_LINES,
+            &terminal::ProcessOptions::default(),
+            None,
         );
-        let (tmp_dir, text_path, init_path) = materialize(&screen, &kitty_target("1")).unwrap();
+        let (tmp_dir, text_path, init_path) = materialize(
+            &screen,
+            &[],
+            &kitty_target("1"),
+            &RunOptions::default(),
+            None,
+        )
+        .unwrap();
         let plugin = plugin_path();
         let result_dir = tempfile::tempdir().unwrap();
         let result_path = result_dir.path().join("colors_result");
@@ -972,14 +3352,25 @@ mod tests {
             cursor_y: 1,
             lines: 24,
             columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
         };
         let screen = process_screen(
             &pd,
             &palette::DEFAULT_PALETTE,
             b"hello\r\nworld",
             terminal::DEFAULT_MAX_SCROLLBACK_LINES,
+            &terminal::ProcessOptions::default(),
+            None,
         );
-        let (tmp_dir, text_path, init_path) = materialize(&screen, &kitty_target("1")).unwrap();
+        let (tmp_dir, text_path, init_path) = materialize(
+            &screen,
+            &[],
+            &kitty_target("1"),
+            &RunOptions::default(),
+            None,
+        )
+        .unwrap();
         let desc = validate_cursor_in_kak(&text_path, &init_path, tmp_dir.path());
         assert_eq!(
             desc.trim(),
@@ -999,14 +3390,25 @@ mod tests {
             cursor_y: 0,
             lines: 24,
             columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
         };
         let screen = process_screen(
             &pd,
             &palette::DEFAULT_PALETTE,
             input,
             terminal::DEFAULT_MAX_SCROLLBACK_LINES,
+            &terminal::ProcessOptions::default(),
+            None,
         );
-        let (tmp_dir, text_path, init_path) = materialize(&screen, &kitty_target("1")).unwrap();
+        let (tmp_dir, text_path, init_path) = materialize(
+            &screen,
+            &[],
+            &kitty_target("1"),
+            &RunOptions::default(),
+            None,
+        )
+        .unwrap();
         let desc = validate_cursor_in_kak(&text_path, &init_path, tmp_dir.path());
         assert_eq!(
             desc.trim(),
@@ -1046,7 +3448,7 @@ mod tests {
         let args = vec!["ksb".into(), "42".into()];
         assert!(matches!(
             parse_args(&args),
-            Ok(CliAction::RunKitty { window_id_arg }) if window_id_arg == "42"
+            Ok(CliAction::RunKitty { window_id_arg, .. }) if window_id_arg == "42"
         ));
     }
 
@@ -1075,7 +3477,7 @@ mod tests {
         let args = vec!["ksb".into(), "--tmux-pane".into(), "%5".into()];
         assert!(matches!(
             parse_args(&args),
-            Ok(CliAction::RunTmux { pane_id }) if pane_id == "%5"
+            Ok(CliAction::RunTmux { pane_id, .. }) if pane_id == "%5"
         ));
     }
 
@@ -1091,12 +3493,350 @@ mod tests {
         assert!(parse_args(&args).is_err());
     }
 
+    #[test]
+    fn parse_args_tmux_query_geometry() {
+        let args = vec![
+            "ksb".into(),
+            "--tmux-pane".into(),
+            "%5".into(),
+            "--query-geometry".into(),
+        ];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunTmux { pane_id, opts }) if pane_id == "%5" && opts.query_geometry
+        ));
+    }
+
+    #[test]
+    fn parse_args_flag_before_target() {
+        let args = vec![
+            "ksb".into(),
+            "--query-geometry".into(),
+            "--tmux-pane".into(),
+            "%5".into(),
+        ];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunTmux { opts, .. }) if opts.query_geometry
+        ));
+    }
+
+    #[test]
+    fn parse_args_query_geometry_requires_tmux() {
+        let args = vec!["ksb".into(), "42".into(), "--query-geometry".into()];
+        assert!(parse_args(&args).is_err());
+    }
+
+    #[test]
+    fn parse_args_rejects_two_targets() {
+        let args = vec!["ksb".into(), "42".into(), "43".into()];
+        assert!(parse_args(&args).is_err());
+        let args = vec!["ksb".into(), "42".into(), "--tmux-pane".into(), "%5".into()];
+        assert!(parse_args(&args).is_err());
+    }
+
+    #[test]
+    fn parse_args_focus_command() {
+        let args = vec!["ksb".into(), "42".into(), "--focus-command".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.focus_command
+        ));
+    }
+
+    #[test]
+    fn parse_args_editable() {
+        let args = vec!["ksb".into(), "42".into(), "--editable".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.init.editable
+        ));
+        let args = vec!["ksb".into(), "42".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if !opts.init.editable
+        ));
+    }
+
+    #[test]
+    fn parse_args_theme() {
+        let args = vec![
+            "ksb".into(),
+            "--tmux-pane".into(),
+            "%1".into(),
+            "--theme".into(),
+            "gruvbox".into(),
+        ];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunTmux { opts, .. }) if opts.theme.as_deref() == Some("gruvbox")
+        ));
+        let args = vec!["ksb".into(), "42".into(), "--theme".into()];
+        assert!(parse_args(&args).is_err());
+    }
+
+    #[test]
+    fn parse_args_match_theme() {
+        let args = vec!["ksb".into(), "--match-theme".into(), "42".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.match_theme
+        ));
+    }
+
+    #[test]
+    fn resolve_palette_theme_overrides_backend() {
+        let palette = resolve_palette(Some("solarized-dark"), || unreachable!());
+        assert_eq!(palette, palette::named("solarized-dark").unwrap());
+    }
+
+    #[test]
+    fn resolve_palette_unknown_theme_falls_back() {
+        let mut backend = palette::DEFAULT_PALETTE;
+        backend[0] = 0x12;
+        assert_eq!(resolve_palette(Some("nope"), || backend), backend);
+        assert_eq!(resolve_palette(None, || backend), backend);
+    }
+
+    #[test]
+    fn build_palette_precedence() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("colors.conf");
+        std::fs::write(&file, "color1 #010101\ncolor2 #020202\n").unwrap();
+        let opts = RunOptions {
+            theme: Some("dracula".into()),
+            palette_file: Some(file),
+            colors: vec![(2, [0xFF, 0, 0])],
+            ..Default::default()
+        };
+        let p = build_palette(&opts, || unreachable!()).unwrap();
+        assert_eq!(p[0..3], palette::named("dracula").unwrap()[0..3]);
+        assert_eq!(p[3..6], [1, 1, 1]);
+        assert_eq!(p[6..9], [0xFF, 0, 0]);
+
+        // Without a theme the backend palette is the base layer
+        let mut backend = palette::DEFAULT_PALETTE;
+        backend[0] = 0x12;
+        let opts = RunOptions {
+            colors: vec![(1, [7, 7, 7])],
+            ..Default::default()
+        };
+        let p = build_palette(&opts, || backend).unwrap();
+        assert_eq!(p[0], 0x12);
+        assert_eq!(p[3..6], [7, 7, 7]);
+    }
+
+    #[test]
+    fn parse_args_palette_from_terminal() {
+        let args = vec![
+            "ksb".into(),
+            "--tmux-pane".into(),
+            "%1".into(),
+            "--palette-from-terminal".into(),
+        ];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunTmux { opts, .. }) if opts.palette_from_terminal
+        ));
+    }
+
+    #[test]
+    fn parse_args_export_colorscheme() {
+        let args = vec![
+            "ksb".into(),
+            "42".into(),
+            "--export-colorscheme".into(),
+            "/tmp/term.kak".into(),
+        ];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. })
+                if opts.export_colorscheme.as_deref() == Some(std::path::Path::new("/tmp/term.kak"))
+        ));
+        let args = vec!["ksb".into(), "42".into(), "--export-colorscheme".into()];
+        assert!(parse_args(&args).is_err());
+    }
+
+    #[test]
+    fn build_palette_missing_file_errors() {
+        let opts = RunOptions {
+            palette_file: Some("/nonexistent/colors.conf".into()),
+            ..Default::default()
+        };
+        assert!(build_palette(&opts, || palette::DEFAULT_PALETTE).is_err());
+    }
+
+    #[test]
+    fn parse_args_palette_overrides() {
+        let args: Vec<String> = [
+            "ksb",
+            "--color",
+            "1=#ff0000",
+            "42",
+            "--palette-file",
+            "x.conf",
+            "--color",
+            "3=#00f",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. })
+                if opts.colors == vec![(1, [0xFF, 0, 0]), (3, [0, 0, 0xFF])]
+                    && opts.palette_file.as_deref() == Some(std::path::Path::new("x.conf"))
+        ));
+        let args: Vec<String> = ["ksb", "42", "--color", "16=#000000"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert!(parse_args(&args).is_err());
+    }
+
+    #[test]
+    fn parse_args_mark() {
+        let args = vec![
+            "ksb".into(),
+            "--tmux-pane".into(),
+            "%3".into(),
+            "--mark".into(),
+        ];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunTmux { opts, .. }) if opts.init.mark && !opts.init.editable
+        ));
+    }
+
+    #[test]
+    fn parse_args_open_mode() {
+        let args = vec![
+            "ksb".into(),
+            "7".into(),
+            "--open-mode".into(),
+            "os-window".into(),
+        ];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. })
+                if opts.init.kitty_open_mode == kitty::OpenMode::OsWindow
+        ));
+        let args = vec!["ksb".into(), "7".into(), "--open-mode".into(), "tab".into()];
+        assert!(parse_args(&args).is_err());
+    }
+
+    #[test]
+    fn parse_args_select_scroll_view() {
+        let args = vec!["ksb".into(), "7".into(), "--select-scroll-view".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.init.select_scroll_view
+        ));
+    }
+
+    #[test]
+    fn parse_args_restore_scroll() {
+        let args = vec!["ksb".into(), "7".into(), "--restore-scroll".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. }) if opts.init.restore_scroll
+        ));
+    }
+
+    #[test]
+    fn parse_args_highlight_urls() {
+        let args = vec![
+            "ksb".into(),
+            "--tmux-pane".into(),
+            "%1".into(),
+            "--highlight-urls".into(),
+        ];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunTmux { opts, .. }) if opts.init.highlight_urls
+        ));
+    }
+
+    #[test]
+    fn parse_args_return_focus() {
+        let args = vec![
+            "ksb".into(),
+            "--return-focus".into(),
+            "--tmux-pane".into(),
+            "%5".into(),
+        ];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunTmux { opts, .. }) if opts.init.return_focus
+        ));
+    }
+
+    #[test]
+    fn parse_args_color_command() {
+        let args = vec!["ksb".into(), "7".into(), "--color-command".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::RunKitty { opts, .. })
+                if opts.init.ranges_mode == output::RangesWrapMode::Command
+        ));
+    }
+
+    #[test]
+    fn parse_args_serve() {
+        let args = vec!["ksb".into(), "--serve".into()];
+        assert!(matches!(parse_args(&args), Ok(CliAction::Serve)));
+    }
+
+    #[test]
+    fn parse_args_benchmark() {
+        let args = vec!["ksb".into(), "--benchmark".into(), "5000".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::Benchmark { lines: 5000 })
+        ));
+        for bad in [vec![], vec!["0".to_string()], vec!["many".to_string()]] {
+            let mut args = vec!["ksb".to_string(), "--benchmark".to_string()];
+            args.extend(bad);
+            assert!(parse_args(&args).is_err(), "{args:?}");
+        }
+    }
+
     #[test]
     fn parse_args_generate_tmux_conf() {
         let args = vec!["ksb".into(), "--generate-tmux-conf".into()];
         assert!(matches!(parse_args(&args), Ok(CliAction::GenerateTmuxConf)));
     }
 
+    #[test]
+    fn parse_args_check_tmux_conf() {
+        let args = vec!["ksb".into(), "--check-tmux-conf".into()];
+        assert!(matches!(parse_args(&args), Ok(CliAction::CheckTmuxConf)));
+    }
+
+    #[test]
+    fn parse_args_gc_default() {
+        let args = vec!["ksb".into(), "--gc".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::Gc { max_age_hours }) if max_age_hours == gc::DEFAULT_MAX_AGE_HOURS
+        ));
+    }
+
+    #[test]
+    fn parse_args_gc_hours() {
+        let args = vec!["ksb".into(), "--gc".into(), "6".into()];
+        assert!(matches!(
+            parse_args(&args),
+            Ok(CliAction::Gc { max_age_hours: 6 })
+        ));
+    }
+
+    #[test]
+    fn parse_args_gc_invalid_hours() {
+        let args = vec!["ksb".into(), "--gc".into(), "soon".into()];
+        assert!(parse_args(&args).is_err());
+    }
+
     // --- TargetId ---
 
     #[test]
@@ -1128,6 +3868,8 @@ mod tests {
             cursor_y: 1,
             lines: 24,
             columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
         };
         let target = TargetId::Tmux("%5".to_string());
         let (text, _ranges, init) = process_and_render(
@@ -1167,6 +3909,8 @@ mod tests {
             cursor_y: 1,
             lines: 24,
             columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
         };
         let target = TargetId::Tmux("%5".to_string());
         let screen = process_screen(
@@ -1174,8 +3918,11 @@ mod tests {
             &palette::DEFAULT_PALETTE,
             b"hello\r\nworld",
             terminal::DEFAULT_MAX_SCROLLBACK_LINES,
+            &terminal::ProcessOptions::default(),
+            None,
         );
-        let (tmp_dir, text_path, init_path) = materialize(&screen, &target).unwrap();
+        let (tmp_dir, text_path, init_path) =
+            materialize(&screen, &[], &target, &RunOptions::default(), None).unwrap();
         validate_in_kak(&text_path, &init_path, tmp_dir.path());
     }
 
@@ -1190,6 +3937,8 @@ mod tests {
             cursor_y: 0,
             lines: 24,
             columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
         };
         let (text, _ranges, _init) = process_and_render(
             &pd,
@@ -1215,6 +3964,41 @@ mod tests {
         assert!(ranges.contains("rgb:"));
     }
 
+    /// `capture-pane -p` of a full 5-row pane with 4 lines of history and
+    /// the cursor on row 3 ("line8"). The final newline must not scroll the
+    /// replay one row further, with or without it in the capture.
+    #[test]
+    fn tmux_capture_final_newline_keeps_cursor() {
+        let pd = PipeData {
+            cursor_x: 2,
+            cursor_y: 3,
+            lines: 5,
+            columns: 40,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        let capture = b"line1\nline2\nline3\nline4\nline5\nline6\nline7\nline8\nprompt";
+        for final_newline in [false, true] {
+            let mut input = capture.to_vec();
+            if final_newline {
+                input.push(b'\n');
+            }
+            tmux::normalize_capture(&mut input);
+            tmux::strip_final_newline(&mut input);
+            let (text, _ranges, init) = process_and_render(
+                &pd,
+                &TargetId::Tmux("%0".to_string()),
+                &palette::DEFAULT_PALETTE,
+                &input,
+            );
+            assert_eq!(text.lines().count(), 9, "final newline: {final_newline}");
+            assert!(
+                init.contains("select 8.3,8.3"),
+                "final newline: {final_newline}"
+            );
+        }
+    }
+
     /// Regression: without normalization, bare LF causes text to shift right
     /// because the vt100 parser does not reset the column on bare LF.
     #[test]
@@ -1226,6 +4010,8 @@ mod tests {
             cursor_y: 0,
             lines: 24,
             columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
         };
         let (text, _ranges, _init) = process_and_render(
             &pd,
@@ -0,0 +1,162 @@
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use crate::palette::PaletteLayer;
+
+/// How long to wait for the terminal to answer the color queries.
+const QUERY_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Colors reported by the terminal in answer to OSC 4 / 10 / 11 queries.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct TerminalColors {
+    pub palette: PaletteLayer,
+    pub fg: Option<[u8; 3]>,
+    pub bg: Option<[u8; 3]>,
+}
+
+/// Parse an X11 color spec `rgb:R/G/B` with 1-4 hex digits per channel,
+/// scaled to 8 bits (`cccc` → 0xCC).
+fn parse_x11_rgb(spec: &str) -> Option<[u8; 3]> {
+    let mut channels = spec.strip_prefix("rgb:")?.split('/');
+    let mut rgb = [0u8; 3];
+    for c in &mut rgb {
+        let hex = channels.next()?;
+        if hex.is_empty() || hex.len() > 4 {
+            return None;
+        }
+        let value = u32::from_str_radix(hex, 16).ok()?;
+        let max = (1u32 << (4 * hex.len())) - 1;
+        *c = ((value * 255 + max / 2) / max) as u8;
+    }
+    channels.next().is_none().then_some(rgb)
+}
+
+/// Parse the OSC color responses in `data`: `ESC ] 4 ; N ; rgb:… ST`,
+/// `ESC ] 10 ; rgb:… ST` (foreground) and `ESC ] 11 ; rgb:… ST`
+/// (background), terminated by BEL or `ESC \`. Anything else is skipped.
+pub(crate) fn parse_osc_color_responses(data: &[u8]) -> TerminalColors {
+    let mut colors = TerminalColors::default();
+    let text = String::from_utf8_lossy(data);
+    for chunk in text.split("\x1b]").skip(1) {
+        let end = chunk.find(['\x07', '\x1b']).unwrap_or(chunk.len());
+        let body = &chunk[..end];
+        let mut parts = body.split(';');
+        match parts.next() {
+            Some("4") => {
+                let idx = parts.next().and_then(|n| n.parse::<usize>().ok());
+                let rgb = parts.next().and_then(parse_x11_rgb);
+                if let (Some(idx), Some(rgb)) = (idx, rgb) {
+                    if idx < 16 {
+                        colors.palette[idx] = Some(rgb);
+                    }
+                }
+            }
+            Some("10") => colors.fg = parts.next().and_then(parse_x11_rgb),
+            Some("11") => colors.bg = parts.next().and_then(parse_x11_rgb),
+            _ => {}
+        }
+    }
+    colors
+}
+
+/// The query: OSC 4 for colors 0-15, OSC 10/11, then Primary Device
+/// Attributes, which every terminal answers, so its reply marks the end.
+fn query_sequence() -> Vec<u8> {
+    let mut q = Vec::new();
+    for idx in 0..16 {
+        q.extend_from_slice(format!("\x1b]4;{idx};?\x07").as_bytes());
+    }
+    q.extend_from_slice(b"\x1b]10;?\x07\x1b]11;?\x07\x1b[c");
+    q
+}
+
+/// Whether `data` ends with a Device Attributes reply (`ESC [ ? … c`).
+fn has_da_reply(data: &[u8]) -> bool {
+    data.windows(3)
+        .rposition(|w| w == b"\x1b[?")
+        .is_some_and(|start| data[start..].contains(&b'c'))
+}
+
+fn stty(args: &[&str]) -> Option<String> {
+    let tty = std::fs::File::open("/dev/tty").ok()?;
+    let out = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::from(tty))
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    out.status
+        .success()
+        .then(|| String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
+
+/// Ask the controlling terminal for its colors via OSC queries on
+/// `/dev/tty`. The tty is put in raw mode (via `stty`) for the duration
+/// and restored afterwards. `None` if there is no tty or no answer.
+pub(crate) fn query_terminal_colors() -> Option<TerminalColors> {
+    let saved = stty(&["-g"])?;
+    // Non-canonical, no echo; reads return after at most 0.1s
+    stty(&["raw", "-echo", "min", "0", "time", "1"])?;
+
+    let result = (|| {
+        let mut tty = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/tty")
+            .ok()?;
+        tty.write_all(&query_sequence()).ok()?;
+        tty.flush().ok()?;
+
+        let deadline = Instant::now() + QUERY_TIMEOUT;
+        let mut data = Vec::new();
+        let mut buf = [0u8; 1024];
+        while Instant::now() < deadline && !has_da_reply(&data) {
+            match tty.read(&mut buf) {
+                Ok(n) => data.extend_from_slice(&buf[..n]),
+                Err(_) => break,
+            }
+        }
+        Some(parse_osc_color_responses(&data))
+    })();
+
+    stty(&[&saved]);
+    result.filter(|c| c.palette.iter().any(Option::is_some))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_x11_rgb_scales_channels() {
+        assert_eq!(parse_x11_rgb("rgb:cccc/0000/0000"), Some([0xCC, 0, 0]));
+        assert_eq!(parse_x11_rgb("rgb:ff/80/00"), Some([0xFF, 0x80, 0]));
+        assert_eq!(parse_x11_rgb("rgb:f/8/0"), Some([0xFF, 0x88, 0]));
+        assert_eq!(parse_x11_rgb("rgb:ffff/ffff"), None);
+        assert_eq!(parse_x11_rgb("#ffffff"), None);
+    }
+
+    #[test]
+    fn parse_responses_bel_and_st() {
+        let data = b"\x1b]4;1;rgb:cccc/0000/0000\x07\
+                     \x1b]4;12;rgb:5c5c/8e8e/ffff\x1b\\\
+                     \x1b]10;rgb:dddd/dddd/dddd\x07\
+                     \x1b]11;rgb:1a1a/1b1b/2626\x07\
+                     \x1b[?62;22c";
+        let colors = parse_osc_color_responses(data);
+        assert_eq!(colors.palette[1], Some([0xCC, 0, 0]));
+        assert_eq!(colors.palette[12], Some([0x5C, 0x8E, 0xFF]));
+        assert_eq!(colors.palette.iter().flatten().count(), 2);
+        assert_eq!(colors.fg, Some([0xDD, 0xDD, 0xDD]));
+        assert_eq!(colors.bg, Some([0x1A, 0x1B, 0x26]));
+        assert!(has_da_reply(data));
+    }
+
+    #[test]
+    fn parse_responses_ignores_garbage() {
+        let colors = parse_osc_color_responses(b"\x1b]4;99;rgb:ff/ff/ff\x07\x1b]4;x\x07junk");
+        assert_eq!(colors, TerminalColors::default());
+        assert!(!has_da_reply(b"\x1b]4;1;rgb:ff/00/00\x07"));
+    }
+}
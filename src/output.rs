@@ -1,79 +1,309 @@
+use std::collections::HashMap;
 use std::fmt::Write as FmtWrite;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use anyhow::Result;
 
-use crate::terminal::ProcessedScreen;
+use crate::face_rules::FaceRules;
+use crate::kitty::OpenMode;
+use crate::terminal::{self, CommandBlock, ProcessedScreen, Span};
 use crate::TargetId;
 
+/// Line separator for exported text.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    /// Only for exports read outside kak; ranges.kak offsets assume the
+    /// LF buffer Kakoune sees
+    Crlf,
+}
+
+impl LineEnding {
+    /// Parse a `--line-ending` value (`lf` or `crlf`).
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "lf" => Some(LineEnding::Lf),
+            "crlf" => Some(LineEnding::Crlf),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(self) -> &'static [u8] {
+        match self {
+            LineEnding::Lf => b"\n",
+            LineEnding::Crlf => b"\r\n",
+        }
+    }
+}
+
+/// What a run produces for the editor (`--format`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// text.txt, ranges.kak and init.kak, opened in kak
+    #[default]
+    Kak,
+    /// text.txt plus a Neovim highlights sidecar, paths printed to stdout
+    Vim,
+}
+
+impl OutputFormat {
+    /// Parse a `--format` value (`kak` or `vim`).
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "kak" => Some(OutputFormat::Kak),
+            "vim" => Some(OutputFormat::Vim),
+            _ => None,
+        }
+    }
+}
+
 /// Render plain text to a writer
-pub fn write_text_to<W: std::io::Write>(w: &mut W, screen: &ProcessedScreen) -> Result<()> {
+pub fn write_text_to<W: std::io::Write>(
+    w: &mut W,
+    screen: &ProcessedScreen,
+    ending: LineEnding,
+) -> Result<()> {
     for (i, line) in screen.lines.iter().enumerate() {
         if i > 0 {
-            w.write_all(b"\n")?;
+            w.write_all(ending.as_bytes())?;
         }
         w.write_all(line.text.as_bytes())?;
     }
     // Ensure output ends with a newline
     if !screen.lines.is_empty() {
-        w.write_all(b"\n")?;
+        w.write_all(ending.as_bytes())?;
     }
     Ok(())
 }
 
 /// Generate plain text file
-pub fn write_text(path: &Path, screen: &ProcessedScreen) -> Result<()> {
+pub fn write_text(path: &Path, screen: &ProcessedScreen, ending: LineEnding) -> Result<()> {
     let mut f = std::fs::File::create(path)?;
-    write_text_to(&mut f, screen)
+    write_text_to(&mut f, screen, ending)
+}
+
+/// How ranges.kak applies the colors when sourced.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RangesWrapMode {
+    /// Plain `set-option` commands, applied immediately
+    #[default]
+    Direct,
+    /// The same commands inside `define-command scrollback-apply-colors`,
+    /// so the colors can be re-applied on demand
+    Command,
+}
+
+/// `set-option` commands filling one range-specs option, each kept under
+/// `MAX_CHUNK_SIZE` bytes.
+struct RangesChunks {
+    option: &'static str,
+    chunk: String,
+    has_entries: bool,
 }
 
-/// Render range-specs commands to a writer
-pub fn write_ranges_to<W: std::io::Write>(w: &mut W, screen: &ProcessedScreen) -> Result<()> {
+impl RangesChunks {
     const MAX_CHUNK_SIZE: usize = 900_000; // ~900KB per command
 
-    let mut chunk = String::with_capacity(MAX_CHUNK_SIZE);
-    chunk.push_str("set-option buffer scrollback_colors %val{timestamp}");
-    let mut chunk_has_entries = false;
+    fn new(option: &'static str) -> Self {
+        let mut chunk = String::with_capacity(Self::MAX_CHUNK_SIZE);
+        write!(chunk, "set-option buffer {option} %val{{timestamp}}").unwrap();
+        RangesChunks {
+            option,
+            chunk,
+            has_entries: false,
+        }
+    }
+
+    fn push<W: std::io::Write>(&mut self, w: &mut W, entry: &str) -> Result<()> {
+        // Flush chunk if adding this entry would exceed limit
+        if self.chunk.len() + 1 + entry.len() > Self::MAX_CHUNK_SIZE && self.has_entries {
+            writeln!(w, "{}", self.chunk)?;
+            self.chunk.clear();
+            write!(self.chunk, "set-option -add buffer {}", self.option).unwrap();
+        }
+        self.chunk.push(' ');
+        self.chunk.push_str(entry);
+        self.has_entries = true;
+        Ok(())
+    }
+
+    fn finish<W: std::io::Write>(self, w: &mut W) -> Result<()> {
+        if self.has_entries {
+            writeln!(w, "{}", self.chunk)?;
+        }
+        Ok(())
+    }
+}
+
+/// Face for `--underline-layer` ranges, set in the plugin's rc.
+const UNDERLINE_FACE: &str = "ScrollbackUnderline";
+
+/// `face` without its `u` flag, or `None` if it has none.
+fn strip_underline(face: &str) -> Option<String> {
+    let (colors, rest) = face.split_once('+')?;
+    let (flags, base) = match rest.split_once('@') {
+        Some((flags, base)) => (flags, Some(base)),
+        None => (rest, None),
+    };
+    if !flags.contains('u') {
+        return None;
+    }
+    let mut s = colors.to_string();
+    let flags: String = flags.chars().filter(|&c| c != 'u').collect();
+    if !flags.is_empty() {
+        s.push('+');
+        s.push_str(&flags);
+    }
+    if let Some(base) = base {
+        s.push('@');
+        s.push_str(base);
+    }
+    Some(s)
+}
+
+/// Render range-specs commands to a writer. With `underline_layer`, the `u`
+/// flag is taken out of the faces and its ranges go to
+/// `scrollback_underlines` instead.
+pub fn write_ranges_to<W: std::io::Write>(
+    w: &mut W,
+    screen: &ProcessedScreen,
+    mode: RangesWrapMode,
+    max_span_bytes: Option<usize>,
+    face_rules: &FaceRules,
+    underline_layer: bool,
+) -> Result<()> {
+    if mode == RangesWrapMode::Command {
+        writeln!(
+            w,
+            "define-command -override -hidden scrollback-apply-colors %{{"
+        )?;
+    }
+
+    let mut colors = RangesChunks::new("scrollback_colors");
+    let mut underlines = RangesChunks::new("scrollback_underlines");
 
+    // Each distinct face string, remapped, canonicalized and escaped once,
+    // with whether it goes to the underline layer. An empty face is
+    // nothing but an underline.
+    let mut faces: HashMap<&str, (String, bool)> = HashMap::new();
+    let mut sorted: Vec<&Span> = Vec::new();
     for (line_idx, line) in screen.lines.iter().enumerate() {
         let line_num = line_idx + 1; // 1-based
-        for span in &line.spans {
-            // Escape | and \ in face strings
-            let escaped_face = escape_face(&span.face);
-            // Range format: "line.start_col,line.end_col|face"
-            // end_byte is exclusive, but Kakoune range-specs uses inclusive end
-            let end_byte_inclusive = span.end_byte - 1;
-            let entry = format!(
-                "'{line_num}.{start},{line_num}.{end}|{face}'",
-                start = span.start_byte,
-                end = end_byte_inclusive,
-                face = escaped_face,
-            );
 
-            // Flush chunk if adding this entry would exceed limit
-            if chunk.len() + 1 + entry.len() > MAX_CHUNK_SIZE && chunk_has_entries {
-                writeln!(w, "{chunk}")?;
-                chunk.clear();
-                chunk.push_str("set-option -add buffer scrollback_colors");
+        // Lines are visited in order; keep entries within a line in buffer
+        // order too, so the whole range-specs list is sorted by (line, start)
+        sorted.clear();
+        sorted.extend(&line.spans);
+        if !sorted.is_sorted_by_key(|span| span.start_byte) {
+            sorted.sort_by_key(|span| span.start_byte);
+        }
+        for span in &sorted {
+            // Escape | and \ in face strings
+            let (escaped_face, underlined) = faces.entry(&span.face).or_insert_with(|| {
+                let face = terminal::canonicalize_face(&face_rules.apply(&span.face));
+                match strip_underline(&face).filter(|_| underline_layer) {
+                    Some(rest) if rest == "default,default" => (String::new(), true),
+                    Some(rest) => (escape_face(&rest), true),
+                    None => (escape_face(&face), false),
+                }
+            });
+            for (start, end) in span_pieces(&line.text, span, max_span_bytes) {
+                // Range format: "line.start_col,line.end_col|face"
+                // end is exclusive, but Kakoune range-specs uses inclusive end
+                let range = format!("{line_num}.{start},{line_num}.{end}", end = end - 1);
+                if !escaped_face.is_empty() {
+                    colors.push(w, &format!("'{range}|{escaped_face}'"))?;
+                }
+                if *underlined {
+                    underlines.push(w, &format!("'{range}|{UNDERLINE_FACE}'"))?;
+                }
             }
-
-            chunk.push(' ');
-            chunk.push_str(&entry);
-            chunk_has_entries = true;
         }
     }
 
-    if chunk_has_entries {
-        writeln!(w, "{chunk}")?;
+    colors.finish(w)?;
+    underlines.finish(w)?;
+    if mode == RangesWrapMode::Command {
+        writeln!(w, "}}")?;
     }
 
     Ok(())
 }
 
+/// 1-based exclusive byte ranges to emit for `span`: the span itself, or
+/// consecutive pieces of at most `max_span_bytes` bytes, split at char
+/// boundaries of `text`.
+fn span_pieces(text: &str, span: &Span, max_span_bytes: Option<usize>) -> Vec<(usize, usize)> {
+    let whole = vec![(span.start_byte, span.end_byte)];
+    let Some(max) = max_span_bytes else {
+        return whole;
+    };
+    let start = span.start_byte - 1;
+    match text.get(start..span.end_byte - 1) {
+        Some(covered) if covered.len() > max => terminal::wrap_points(covered, max)
+            .into_iter()
+            .map(|(s, e)| (start + s + 1, start + e + 1))
+            .collect(),
+        _ => whole,
+    }
+}
+
 /// Generate range-specs command file
-pub fn write_ranges(path: &Path, screen: &ProcessedScreen) -> Result<()> {
+pub fn write_ranges(
+    path: &Path,
+    screen: &ProcessedScreen,
+    mode: RangesWrapMode,
+    max_span_bytes: Option<usize>,
+    face_rules: &FaceRules,
+    underline_layer: bool,
+) -> Result<()> {
+    let mut f = std::fs::File::create(path)?;
+    write_ranges_to(
+        &mut f,
+        screen,
+        mode,
+        max_span_bytes,
+        face_rules,
+        underline_layer,
+    )
+}
+
+/// Render the line → vt100 source map (diagnostics only, never sourced by kak)
+pub fn write_debug_map_to<W: std::io::Write>(w: &mut W, screen: &ProcessedScreen) -> Result<()> {
+    for (i, src) in screen.line_sources.iter().enumerate() {
+        let cont = if src.continuation { " (cont)" } else { "" };
+        writeln!(w, "{} -> {} row {}{cont}", i + 1, src.offset, src.row)?;
+    }
+    Ok(())
+}
+
+/// Generate the debug map sidecar file
+pub fn write_debug_map(path: &Path, screen: &ProcessedScreen) -> Result<()> {
     let mut f = std::fs::File::create(path)?;
-    write_ranges_to(&mut f, screen)
+    write_debug_map_to(&mut f, screen)
+}
+
+/// Write a file the user keeps (`--cursor-out`, `--profile`, ...) through a
+/// temp file in the same directory renamed into place, so readers see the
+/// old content or the new, never a partial write.
+pub(crate) fn atomic_write(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    // 0o666 before the umask, as `std::fs::write` would create it
+    let mut tmp = tempfile::Builder::new()
+        .prefix(".ksb-")
+        .suffix(".tmp")
+        .permissions(std::fs::Permissions::from_mode(0o666))
+        .tempfile_in(dir)?;
+    tmp.write_all(bytes)?;
+    tmp.persist(path).map_err(|e| e.error)?;
+    Ok(())
 }
 
 /// Escape for Kakoune single-quoted strings (' → '')
@@ -93,12 +323,56 @@ fn escape_face(face: &str) -> String {
         .replace('\'', "''")
 }
 
+/// Optional init.kak behaviors selected on the command line.
+#[derive(Default)]
+pub struct InitOptions {
+    /// Leave the scrollback buffer writable (omit `readonly true`)
+    pub editable: bool,
+    /// Also save the cursor to the `^` mark register, so `z` jumps back to it
+    pub mark: bool,
+    /// Kitty only: scroll the window back to `scrolled_by` when kak exits
+    pub restore_scroll: bool,
+    /// Kitty only: open a scrolled-back window at its scroll view, not the cursor
+    pub select_scroll_view: bool,
+    /// Kitty only: how the viewer window was launched (`--open-mode`)
+    pub kitty_open_mode: OpenMode,
+    /// How ranges.kak was written; `Command` makes init.kak call the command
+    pub ranges_mode: RangesWrapMode,
+    /// Split color ranges longer than this many bytes (`--max-span-bytes`)
+    pub max_span_bytes: Option<usize>,
+    /// Face rewrites applied as the ranges are written (`--face-rules`)
+    pub face_rules: FaceRules,
+    /// Draw underlines with their own `scrollback_underlines` highlighter
+    pub underline_layer: bool,
+    /// Underline URLs and email addresses with a regex highlighter
+    pub highlight_urls: bool,
+    /// tmux only: select the originating pane again when kak exits
+    pub return_focus: bool,
+    /// Also load the full text into this register (`--register`)
+    pub register: Option<char>,
+    /// The command that produced the output (`--command`), recorded in
+    /// `scrollback_source_command`
+    pub source_command: Option<String>,
+}
+
+/// Whether `--register` accepts `name`: the named registers `a`-`z`.
+pub fn is_register_name(name: char) -> bool {
+    name.is_ascii_lowercase()
+}
+
+/// URLs and email addresses for `--highlight-urls`. Brace-free, so it can be
+/// quoted with `%{}`.
+const URL_REGEX: &str = r#"\b(?:https?|ftp|file)://[^\s<>"'`]+|\b[\w.+-]+@[\w-]+(?:\.[\w-]+)+\b"#;
+/// Face for `--highlight-urls`; `default` keeps the cell colors underneath.
+const URL_FACE: &str = "default+u";
+
 /// Render Kakoune initialization script to a String
 pub fn render_init_kak(
     screen: &ProcessedScreen,
     target: &TargetId,
     tmp_dir: &Path,
     ranges_path: &Path,
+    opts: &InitOptions,
 ) -> Result<String> {
     let mut script = String::new();
     let tmp_dir_s = tmp_dir.display().to_string();
@@ -106,37 +380,78 @@ pub fn render_init_kak(
     let tmp_dir_sh = escape_shell_single_quote(&tmp_dir_s);
     let ranges_path_kak = escape_kak_single_quote(&ranges_path.display().to_string());
 
-    // Backend type for dispatch
+    // Lets the plugin detect that it is older than this binary
     writeln!(
         script,
-        "set-option global scrollback_backend '{}'",
-        target.backend_name()
+        "declare-option -hidden str scrollback_expected_plugin_version"
+    )?;
+    writeln!(
+        script,
+        "set-option global scrollback_expected_plugin_version '{}'",
+        env!("CARGO_PKG_VERSION")
     )?;
 
-    // Backend-specific target ID
-    match target {
-        TargetId::Kitty(wid) => {
-            writeln!(
-                script,
-                "set-option global scrollback_kitty_window_id '{wid}'"
-            )?;
-        }
-        TargetId::Tmux(pane_id) => {
-            let escaped = escape_kak_single_quote(pane_id);
-            writeln!(
-                script,
-                "set-option global scrollback_tmux_pane_id '{escaped}'"
-            )?;
-        }
+    // Buffer options, so captures sharing a session keep their own target
+    write_target_options(&mut script, target)?;
+    if let TargetId::Kitty(_) = target {
+        writeln!(
+            script,
+            "declare-option -hidden str scrollback_kitty_open_mode"
+        )?;
+        writeln!(
+            script,
+            "set-option buffer scrollback_kitty_open_mode '{}'",
+            opts.kitty_open_mode.as_str()
+        )?;
+    }
+    if let Some(shape) = screen.cursor_shape {
+        writeln!(script, "declare-option -hidden str scrollback_cursor_shape")?;
+        writeln!(
+            script,
+            "set-option buffer scrollback_cursor_shape '{}'",
+            shape.as_str()
+        )?;
     }
     writeln!(script)?;
 
     // Buffer settings
-    writeln!(script, "set-option buffer readonly true")?;
+    if !opts.editable {
+        writeln!(script, "set-option buffer readonly true")?;
+    }
     writeln!(
         script,
         "set-option buffer scrollback_tmp_dir '{tmp_dir_kak}'"
     )?;
+    if let Some(command) = &opts.source_command {
+        writeln!(
+            script,
+            "declare-option -hidden str scrollback_source_command"
+        )?;
+        writeln!(
+            script,
+            "set-option buffer scrollback_source_command '{}'",
+            escape_kak_single_quote(command)
+        )?;
+    }
+    writeln!(script)?;
+
+    // Shade the lines that were on screen, unless there is no history above them.
+    // Added before the color highlighter so explicit cell colors win.
+    writeln!(
+        script,
+        "declare-option -hidden range-specs scrollback_viewport"
+    )?;
+    if screen.viewport_top_line > 1 {
+        let bottom = screen.viewport_bottom_line;
+        let bottom_len = screen.lines.get(bottom - 1).map_or(0, |l| l.text.len());
+        writeln!(
+            script,
+            "set-option buffer scrollback_viewport %val{{timestamp}} '{}.1,{bottom}.{}|ScrollbackViewport'",
+            screen.viewport_top_line,
+            bottom_len + 1,
+        )?;
+    }
+    writeln!(script, "add-highlighter buffer/ ranges scrollback_viewport")?;
     writeln!(script)?;
 
     // Range-specs declaration + apply
@@ -145,47 +460,418 @@ pub fn render_init_kak(
         "declare-option -hidden range-specs scrollback_colors"
     )?;
     writeln!(script, "add-highlighter buffer/ ranges scrollback_colors")?;
+    if opts.underline_layer {
+        // Above the colors, so the underline lands on the cell colors
+        writeln!(
+            script,
+            "declare-option -hidden range-specs scrollback_underlines"
+        )?;
+        writeln!(
+            script,
+            "add-highlighter buffer/ ranges scrollback_underlines"
+        )?;
+    }
     writeln!(script, "source '{ranges_path_kak}'")?;
+    if opts.ranges_mode == RangesWrapMode::Command {
+        writeln!(script, "scrollback-apply-colors")?;
+    }
     writeln!(script, "update-option buffer scrollback_colors")?;
+    if opts.underline_layer {
+        writeln!(script, "update-option buffer scrollback_underlines")?;
+    }
+    if opts.editable {
+        let underlines = if opts.underline_layer {
+            " update-option buffer scrollback_underlines;"
+        } else {
+            ""
+        };
+        // Edits bump the buffer timestamp; move the stored ranges along with
+        // them whenever the user pauses, so the options never go stale
+        for idle in ["NormalIdle", "InsertIdle"] {
+            writeln!(
+                script,
+                "hook -group scrollback-ranges buffer {idle} .* %{{ \
+                 update-option buffer scrollback_colors;{underlines} \
+                 update-option buffer scrollback_viewport }}"
+            )?;
+        }
+    }
     writeln!(script)?;
 
-    // Viewport position restore: pin the original terminal's top visible line,
-    // then restore cursor position
-    writeln!(script, "try %{{")?;
-    writeln!(
-        script,
-        "    select {vt}.1,{vt}.1",
-        vt = screen.viewport_top_line,
-    )?;
-    writeln!(script, "    execute-keys vt")?;
-    writeln!(script, "}}")?;
+    if opts.highlight_urls {
+        writeln!(
+            script,
+            "add-highlighter buffer/ regex %{{{URL_REGEX}}} 0:{URL_FACE}"
+        )?;
+        writeln!(script)?;
+    }
+
+    // Page boundaries (OSC 133 prompts / form feeds) + navigation
     writeln!(
         script,
-        "select {line}.{col},{line}.{col}",
-        line = screen.cursor.line,
-        col = screen.cursor.col,
+        "declare-option -hidden int-list scrollback_page_boundaries"
     )?;
+    write!(script, "set-option buffer scrollback_page_boundaries")?;
+    for line in &screen.page_boundaries {
+        write!(script, " {line}")?;
+    }
+    writeln!(script)?;
+    write_page_commands(&mut script)?;
     writeln!(script)?;
 
+    if let Some(reg) = opts.register {
+        write_register_commands(&mut script, screen, reg)?;
+        writeln!(script)?;
+    }
+
+    // Viewport position restore: pin the original terminal's top visible line,
+    // then restore cursor position. An empty screen has nothing to select;
+    // kak's default selection on the empty buffer is already correct.
+    if !screen.lines.is_empty() {
+        let (vt, line, col) = match scroll_view_top(screen, target, opts) {
+            Some(top) => (top, top, 1),
+            None => (
+                screen.viewport_top_line,
+                screen.cursor.line,
+                clamped_cursor_col(screen),
+            ),
+        };
+        writeln!(script, "try %{{")?;
+        writeln!(script, "    select {vt}.1,{vt}.1")?;
+        writeln!(script, "    execute-keys vt")?;
+        writeln!(script, "}}")?;
+        writeln!(script, "select {line}.{col},{line}.{col}")?;
+        if opts.mark {
+            // -save-regs '' so the Z write to ^ outlives execute-keys
+            writeln!(script, "execute-keys -save-regs '' Z")?;
+        }
+        writeln!(script)?;
+    }
+
+    if screen.truncated_lines > 0 {
+        writeln!(
+            script,
+            "echo -markup '{{Information}}kakoune-scrollback: {} older lines not loaded \
+             (KAKOUNE_SCROLLBACK_AUTO_TRIM)'",
+            screen.truncated_lines
+        )?;
+        writeln!(script)?;
+    }
+
     // Enable keymaps
     writeln!(script, "kakoune-scrollback-setup-keymaps")?;
     writeln!(script)?;
 
-    // Cleanup hook (guard: don't fire when compose client closes)
-    writeln!(script, "hook -always global ClientClose .* %{{")?;
+    // Each capture removes its own dir when its buffer goes away
+    writeln!(
+        script,
+        "hook -always buffer BufClose .* %{{ nop %sh{{ rm -rf -- '{tmp_dir_sh}' }} }}"
+    )?;
+
+    // Viewer hook, replacing the previous capture's (guard: don't fire
+    // when the compose client closes)
+    writeln!(script, "remove-hooks global kakoune-scrollback-viewer")?;
+    writeln!(
+        script,
+        "hook -always -group kakoune-scrollback-viewer global ClientClose .* %{{"
+    )?;
     writeln!(script, "    evaluate-commands %sh{{")?;
     writeln!(
         script,
         "        if [ -d '{tmp_dir_sh}' ] && [ \"$(printf '%s' \"$kak_client_list\" | wc -w)\" -le 1 ]; then"
     )?;
     writeln!(script, "            rm -rf -- '{tmp_dir_sh}'")?;
+    match target {
+        TargetId::Kitty(wid) if opts.restore_scroll && screen.scrolled_by > 0 => {
+            // Return to the bottom, then scroll up to where the user was
+            writeln!(
+                script,
+                "            kitty @ scroll-window --match id:{wid} end >/dev/null 2>&1"
+            )?;
+            writeln!(
+                script,
+                "            kitty @ scroll-window --match id:{wid} {}l- >/dev/null 2>&1",
+                screen.scrolled_by
+            )?;
+        }
+        TargetId::Tmux(pane_id) if opts.return_focus => {
+            writeln!(
+                script,
+                "            tmux select-pane -t '{}' >/dev/null 2>&1",
+                escape_shell_single_quote(pane_id)
+            )?;
+        }
+        _ => {}
+    }
+    writeln!(script, "        fi")?;
+    writeln!(script, "    }}")?;
+    writeln!(script, "}}")?;
+
+    Ok(script)
+}
+
+/// Load the buffer text into register `reg`. The text is built up in a
+/// hidden str option with `set-option -add`, chunked like ranges.kak, then
+/// copied to the register in one go.
+fn write_register_commands(script: &mut String, screen: &ProcessedScreen, reg: char) -> Result<()> {
+    const MAX_CHUNK_SIZE: usize = 900_000;
+
+    writeln!(
+        script,
+        "declare-option -hidden str scrollback_register_text"
+    )?;
+    writeln!(script, "set-option buffer scrollback_register_text ''")?;
+    let mut chunk = String::new();
+    for line in &screen.lines {
+        if !chunk.is_empty() && chunk.len() + line.text.len() + 1 > MAX_CHUNK_SIZE {
+            writeln!(
+                script,
+                "set-option -add buffer scrollback_register_text '{}'",
+                escape_kak_single_quote(&chunk)
+            )?;
+            chunk.clear();
+        }
+        chunk.push_str(&line.text);
+        chunk.push('\n');
+    }
+    if !chunk.is_empty() {
+        writeln!(
+            script,
+            "set-option -add buffer scrollback_register_text '{}'",
+            escape_kak_single_quote(&chunk)
+        )?;
+    }
+    writeln!(
+        script,
+        "set-register {reg} %opt{{scrollback_register_text}}"
+    )?;
+    writeln!(script, "unset-option buffer scrollback_register_text")?;
+    Ok(())
+}
+
+/// Set the backend and its target ID as buffer options, which the send,
+/// execute and compose commands dispatch on.
+fn write_target_options(script: &mut String, target: &TargetId) -> Result<()> {
+    writeln!(
+        script,
+        "set-option buffer scrollback_backend '{}'",
+        target.backend_name()
+    )?;
+    match target {
+        TargetId::Kitty(wid) => writeln!(
+            script,
+            "set-option buffer scrollback_kitty_window_id '{wid}'"
+        )?,
+        TargetId::Tmux(pane_id) => writeln!(
+            script,
+            "set-option buffer scrollback_tmux_pane_id '{}'",
+            escape_kak_single_quote(pane_id)
+        )?,
+    }
+    Ok(())
+}
+
+/// Define `scrollback-next-page` / `scrollback-prev-page`, which jump to the
+/// nearest entry of `scrollback_page_boundaries` below / above the cursor.
+fn write_page_commands(script: &mut String) -> Result<()> {
+    writeln!(script, "define-command -override scrollback-next-page %{{")?;
+    writeln!(script, "    evaluate-commands %sh{{")?;
+    writeln!(
+        script,
+        "        for l in $kak_opt_scrollback_page_boundaries; do"
+    )?;
+    writeln!(
+        script,
+        "            if [ \"$l\" -gt \"$kak_cursor_line\" ]; then"
+    )?;
+    writeln!(
+        script,
+        "                printf 'select %s.1,%s.1\\nexecute-keys vt\\n' \"$l\" \"$l\""
+    )?;
+    writeln!(script, "                exit")?;
+    writeln!(script, "            fi")?;
+    writeln!(script, "        done")?;
+    writeln!(script, "        echo \"fail 'no next page'\"")?;
+    writeln!(script, "    }}")?;
+    writeln!(script, "}}")?;
+    writeln!(script, "define-command -override scrollback-prev-page %{{")?;
+    writeln!(script, "    evaluate-commands %sh{{")?;
+    writeln!(script, "        target=")?;
+    writeln!(
+        script,
+        "        for l in $kak_opt_scrollback_page_boundaries; do"
+    )?;
+    writeln!(
+        script,
+        "            [ \"$l\" -lt \"$kak_cursor_line\" ] && target=$l"
+    )?;
+    writeln!(script, "        done")?;
+    writeln!(script, "        if [ -n \"$target\" ]; then")?;
+    writeln!(
+        script,
+        "            printf 'select %s.1,%s.1\\nexecute-keys vt\\n' \"$target\" \"$target\""
+    )?;
+    writeln!(script, "        else")?;
+    writeln!(script, "            echo \"fail 'no previous page'\"")?;
     writeln!(script, "        fi")?;
     writeln!(script, "    }}")?;
     writeln!(script, "}}")?;
+    Ok(())
+}
 
+/// A `--split-commands` buffer: its name and the files it is loaded from.
+pub struct CommandBuffer {
+    pub name: String,
+    pub text_path: PathBuf,
+    pub ranges_path: PathBuf,
+}
+
+/// Render the init.kak tail that opens each command buffer, then returns to
+/// `main_text` and defines `scrollback-command <buffer>` to switch between them.
+pub fn render_command_buffers(
+    main_text: &Path,
+    buffers: &[CommandBuffer],
+    target: &TargetId,
+    opts: &InitOptions,
+) -> Result<String> {
+    let mut script = String::new();
+    writeln!(script, "# Command buffers")?;
+    writeln!(
+        script,
+        "declare-option -hidden str-list scrollback_command_buffers"
+    )?;
+    let names: Vec<String> = buffers
+        .iter()
+        .map(|buf| format!("'{}'", escape_kak_single_quote(&buf.name)))
+        .collect();
+    let set_names = format!(
+        "set-option buffer scrollback_command_buffers {}",
+        names.join(" ")
+    );
+    for (buf, name) in buffers.iter().zip(&names) {
+        writeln!(
+            script,
+            "edit -existing '{}'",
+            escape_kak_single_quote(&buf.text_path.display().to_string())
+        )?;
+        writeln!(script, "rename-buffer {name}")?;
+        write_target_options(&mut script, target)?;
+        if !opts.editable {
+            writeln!(script, "set-option buffer readonly true")?;
+        }
+        writeln!(script, "add-highlighter buffer/ ranges scrollback_colors")?;
+        if opts.underline_layer {
+            writeln!(
+                script,
+                "add-highlighter buffer/ ranges scrollback_underlines"
+            )?;
+        }
+        writeln!(
+            script,
+            "source '{}'",
+            escape_kak_single_quote(&buf.ranges_path.display().to_string())
+        )?;
+        writeln!(script, "kakoune-scrollback-setup-keymaps")?;
+        writeln!(script, "{set_names}")?;
+    }
+    writeln!(
+        script,
+        "buffer '{}'",
+        escape_kak_single_quote(&main_text.display().to_string())
+    )?;
+    writeln!(script, "{set_names}")?;
+    writeln!(
+        script,
+        "define-command -override scrollback-command -params 1 -shell-script-candidates %{{"
+    )?;
+    writeln!(
+        script,
+        "    eval \"set -- $kak_quoted_opt_scrollback_command_buffers\""
+    )?;
+    writeln!(script, "    printf '%s\\n' \"$@\"")?;
+    writeln!(script, "}} %{{ buffer %arg{{1}} }}")?;
     Ok(script)
 }
 
+/// Write each block as `cmd-N.txt` / `cmd-N.ranges.kak` in `dir` and append
+/// the commands that open them as `*scrollback N: <title>*` buffers to init.kak.
+pub fn write_command_buffers(
+    dir: &Path,
+    main_text: &Path,
+    init_path: &Path,
+    blocks: &[CommandBlock],
+    target: &TargetId,
+    opts: &InitOptions,
+) -> Result<()> {
+    let mut buffers = Vec::with_capacity(blocks.len());
+    for (i, block) in blocks.iter().enumerate() {
+        let n = i + 1;
+        let buf = CommandBuffer {
+            name: format!("*scrollback {n}: {}*", block.title),
+            text_path: dir.join(format!("cmd-{n}.txt")),
+            ranges_path: dir.join(format!("cmd-{n}.ranges.kak")),
+        };
+        write_text(&buf.text_path, &block.screen, LineEnding::Lf)?;
+        write_ranges(
+            &buf.ranges_path,
+            &block.screen,
+            RangesWrapMode::Direct,
+            opts.max_span_bytes,
+            &opts.face_rules,
+            opts.underline_layer,
+        )?;
+        buffers.push(buf);
+    }
+    let mut f = std::fs::OpenOptions::new().append(true).open(init_path)?;
+    std::io::Write::write_all(
+        &mut f,
+        render_command_buffers(main_text, &buffers, target, opts)?.as_bytes(),
+    )?;
+    Ok(())
+}
+
+/// With `--select-scroll-view`, the first line a scrolled-back Kitty window
+/// was showing. The live cursor may be far below it, or off screen.
+fn scroll_view_top(
+    screen: &ProcessedScreen,
+    target: &TargetId,
+    opts: &InitOptions,
+) -> Option<usize> {
+    match target {
+        TargetId::Kitty(_) if opts.select_scroll_view && screen.scrolled_by > 0 => Some(
+            screen
+                .viewport_top_line
+                .saturating_sub(screen.scrolled_by)
+                .max(1),
+        ),
+        _ => None,
+    }
+}
+
+/// The end of the cursor line (its text plus the newline) if the cursor
+/// column lies past it. Processing already moves a cursor out of trimmed
+/// trailing blanks, so this means the column was past the whole row.
+fn cursor_col_overrun(screen: &ProcessedScreen) -> Option<usize> {
+    let line = screen.lines.get(screen.cursor.line.checked_sub(1)?)?;
+    let line_end = line.text.len() + 1;
+    (screen.cursor.col > line_end).then_some(line_end)
+}
+
+/// The cursor column, clamped to the end of the cursor line so `select`
+/// never points past it. Warns when it had to clamp.
+fn clamped_cursor_col(screen: &ProcessedScreen) -> usize {
+    match cursor_col_overrun(screen) {
+        Some(line_end) => {
+            eprintln!(
+                "warning: cursor column {} is past the end of line {} (clamped to {line_end})",
+                screen.cursor.col, screen.cursor.line
+            );
+            line_end
+        }
+        None => screen.cursor.col,
+    }
+}
+
 /// Generate Kakoune initialization script file
 pub fn write_init_kak(
     path: &Path,
@@ -193,8 +879,12 @@ pub fn write_init_kak(
     target: &TargetId,
     tmp_dir: &Path,
     ranges_path: &Path,
+    opts: &InitOptions,
 ) -> Result<()> {
-    std::fs::write(path, render_init_kak(screen, target, tmp_dir, ranges_path)?)?;
+    std::fs::write(
+        path,
+        render_init_kak(screen, target, tmp_dir, ranges_path, opts)?,
+    )?;
     Ok(())
 }
 
@@ -214,6 +904,13 @@ mod tests {
             lines,
             cursor,
             viewport_top_line: 1,
+            viewport_bottom_line: 1,
+            page_boundaries: vec![],
+            line_sources: vec![],
+            scrolled_by: 0,
+            truncated_lines: 0,
+            history_full: false,
+            cursor_shape: None,
         }
     }
 
@@ -222,10 +919,18 @@ mod tests {
         cursor: CursorPosition,
         viewport_top_line: usize,
     ) -> ProcessedScreen {
+        let viewport_bottom_line = lines.len().max(viewport_top_line);
         ProcessedScreen {
             lines,
             cursor,
             viewport_top_line,
+            viewport_bottom_line,
+            page_boundaries: vec![],
+            line_sources: vec![],
+            scrolled_by: 0,
+            truncated_lines: 0,
+            history_full: false,
+            cursor_shape: None,
         }
     }
 
@@ -245,31 +950,196 @@ mod tests {
             CursorPosition { line: 1, col: 1 },
         );
         let mut buf = Vec::new();
-        write_text_to(&mut buf, &screen).unwrap();
+        write_text_to(&mut buf, &screen, LineEnding::Lf).unwrap();
         let content = String::from_utf8(buf).unwrap();
         assert_eq!(content, "Hello\nWorld\n");
     }
 
     #[test]
-    fn write_ranges_basic() {
+    fn write_text_crlf() {
         let screen = make_screen(
-            vec![ProcessedLine {
-                text: "Hello World".to_string(),
-                spans: vec![Span {
-                    start_byte: 1,
-                    end_byte: 6,
-                    face: "rgb:FF0000,default+b".to_string(),
-                }],
-            }],
+            vec![
+                ProcessedLine {
+                    text: "Hello".to_string(),
+                    spans: vec![],
+                },
+                ProcessedLine {
+                    text: "World".to_string(),
+                    spans: vec![],
+                },
+            ],
             CursorPosition { line: 1, col: 1 },
         );
         let mut buf = Vec::new();
-        write_ranges_to(&mut buf, &screen).unwrap();
-        let content = String::from_utf8(buf).unwrap();
+        write_text_to(&mut buf, &screen, LineEnding::Crlf).unwrap();
+        assert_eq!(buf, b"Hello\r\nWorld\r\n");
+    }
+
+    #[test]
+    fn line_ending_parse() {
+        assert_eq!(LineEnding::parse("lf"), Some(LineEnding::Lf));
+        assert_eq!(LineEnding::parse("CRLF"), Some(LineEnding::Crlf));
+        assert_eq!(LineEnding::parse("cr"), None);
+    }
+
+    #[test]
+    fn write_ranges_basic() {
+        let screen = make_screen(
+            vec![ProcessedLine {
+                text: "Hello World".to_string(),
+                spans: vec![Span {
+                    start_byte: 1,
+                    end_byte: 6,
+                    face: "rgb:FF0000,default+b".into(),
+                }],
+            }],
+            CursorPosition { line: 1, col: 1 },
+        );
+        let mut buf = Vec::new();
+        write_ranges_to(
+            &mut buf,
+            &screen,
+            RangesWrapMode::Direct,
+            None,
+            &FaceRules::default(),
+            false,
+        )
+        .unwrap();
+        let content = String::from_utf8(buf).unwrap();
         assert!(content.contains("set-option buffer scrollback_colors"));
         assert!(content.contains("1.1,1.5|rgb:FF0000,default+b"));
     }
 
+    #[test]
+    fn write_ranges_applies_face_rules() {
+        let span = |start_byte, end_byte, face: &str| Span {
+            start_byte,
+            end_byte,
+            face: face.into(),
+        };
+        let screen = make_screen(
+            vec![ProcessedLine {
+                text: "blue red blue".to_string(),
+                spans: vec![
+                    span(1, 5, "rgb:0000CC,default"),
+                    span(6, 9, "rgb:CC0000,default"),
+                    span(10, 14, "default,rgb:0000CC+b"),
+                ],
+            }],
+            CursorPosition { line: 1, col: 1 },
+        );
+        let rules = FaceRules::parse("rgb:0000CC => rgb:5555FF").unwrap();
+        let mut buf = Vec::new();
+        write_ranges_to(
+            &mut buf,
+            &screen,
+            RangesWrapMode::Direct,
+            None,
+            &rules,
+            false,
+        )
+        .unwrap();
+        let content = String::from_utf8(buf).unwrap();
+        assert!(content.contains("'1.1,1.4|rgb:5555FF,default'"));
+        assert!(content.contains("'1.6,1.8|rgb:CC0000,default'"));
+        assert!(content.contains("'1.10,1.13|default,rgb:5555FF+b'"));
+        assert!(!content.contains("0000CC"));
+    }
+
+    #[test]
+    fn write_ranges_underline_layer() {
+        let span = |start_byte, end_byte, face: &str| Span {
+            start_byte,
+            end_byte,
+            face: face.into(),
+        };
+        let screen = make_screen(
+            vec![ProcessedLine {
+                text: "link bold plain".to_string(),
+                spans: vec![
+                    span(1, 5, "rgb:0000CC,default+u"),
+                    span(6, 10, "default,default+bu"),
+                    span(11, 16, "rgb:CC0000,default+b"),
+                ],
+            }],
+            CursorPosition { line: 1, col: 1 },
+        );
+        let render = |underline_layer| {
+            let mut buf = Vec::new();
+            write_ranges_to(
+                &mut buf,
+                &screen,
+                RangesWrapMode::Direct,
+                None,
+                &FaceRules::default(),
+                underline_layer,
+            )
+            .unwrap();
+            String::from_utf8(buf).unwrap()
+        };
+
+        assert_eq!(
+            render(true),
+            "set-option buffer scrollback_colors %val{timestamp} \
+             '1.1,1.4|rgb:0000CC,default' '1.6,1.9|default,default+b' \
+             '1.11,1.15|rgb:CC0000,default+b'\n\
+             set-option buffer scrollback_underlines %val{timestamp} \
+             '1.1,1.4|ScrollbackUnderline' '1.6,1.9|ScrollbackUnderline'\n"
+        );
+        let baked = render(false);
+        assert!(baked.contains("'1.1,1.4|rgb:0000CC,default+u'"));
+        assert!(!baked.contains("scrollback_underlines"));
+    }
+
+    #[test]
+    fn strip_underline_flag() {
+        assert_eq!(strip_underline("red,default+u"), Some("red,default".into()));
+        assert_eq!(
+            strip_underline("red,default+bui"),
+            Some("red,default+bi".into())
+        );
+        assert_eq!(
+            strip_underline("red,default+u@Error"),
+            Some("red,default@Error".into())
+        );
+        assert_eq!(strip_underline("red,default+b"), None);
+        assert_eq!(strip_underline("Error"), None);
+    }
+
+    #[test]
+    fn write_ranges_canonical_flag_order() {
+        let span = |start_byte, end_byte, face: &str| Span {
+            start_byte,
+            end_byte,
+            face: face.into(),
+        };
+        let screen = make_screen(
+            vec![ProcessedLine {
+                text: "Hello World".to_string(),
+                spans: vec![
+                    span(1, 6, "rgb:FF0000,default+bi"),
+                    span(7, 12, "rgb:FF0000,default+ib"),
+                ],
+            }],
+            CursorPosition { line: 1, col: 1 },
+        );
+        let mut buf = Vec::new();
+        write_ranges_to(
+            &mut buf,
+            &screen,
+            RangesWrapMode::Direct,
+            None,
+            &FaceRules::default(),
+            false,
+        )
+        .unwrap();
+        let content = String::from_utf8(buf).unwrap();
+        assert!(
+            content.contains("'1.1,1.5|rgb:FF0000,default+bi' '1.7,1.11|rgb:FF0000,default+bi'")
+        );
+        assert!(!content.contains("+ib"));
+    }
+
     #[test]
     fn write_ranges_empty() {
         let screen = make_screen(
@@ -280,7 +1150,15 @@ mod tests {
             CursorPosition { line: 1, col: 1 },
         );
         let mut buf = Vec::new();
-        write_ranges_to(&mut buf, &screen).unwrap();
+        write_ranges_to(
+            &mut buf,
+            &screen,
+            RangesWrapMode::Direct,
+            None,
+            &FaceRules::default(),
+            false,
+        )
+        .unwrap();
         let content = String::from_utf8(buf).unwrap();
         assert!(content.is_empty());
     }
@@ -306,6 +1184,7 @@ mod tests {
             &kitty_target("42"),
             Path::new("/tmp/ksb-fake"),
             Path::new("/tmp/ksb-fake/ranges.kak"),
+            &InitOptions::default(),
         )
         .unwrap();
 
@@ -321,6 +1200,12 @@ mod tests {
         assert!(content.contains("kakoune-scrollback-setup-keymaps"));
         assert!(content.contains("ClientClose"));
         assert!(content.contains("rm -rf --"));
+        // Scoped to this capture, so a long-lived session does not pile
+        // them up or mix up targets
+        assert!(content.contains("hook -always buffer BufClose .* %{ nop %sh{ rm -rf -- '"));
+        assert!(content.contains("remove-hooks global kakoune-scrollback-viewer\n"));
+        assert!(content.contains("set-option buffer scrollback_backend 'kitty'\n"));
+        assert!(!content.contains("set-option global scrollback_backend"));
     }
 
     #[test]
@@ -339,6 +1224,7 @@ mod tests {
             &kitty_target("1"),
             Path::new("/tmp/ksb-fake"),
             Path::new("/tmp/ksb-fake/ranges.kak"),
+            &InitOptions::default(),
         )
         .unwrap();
 
@@ -388,14 +1274,22 @@ mod tests {
                     .map(|_| Span {
                         start_byte: 1,
                         end_byte: 2,
-                        face: face.clone(),
+                        face: face.as_str().into(),
                     })
                     .collect(),
             }],
             CursorPosition { line: 1, col: 1 },
         );
         let mut buf = Vec::new();
-        write_ranges_to(&mut buf, &screen).unwrap();
+        write_ranges_to(
+            &mut buf,
+            &screen,
+            RangesWrapMode::Direct,
+            None,
+            &FaceRules::default(),
+            false,
+        )
+        .unwrap();
         let content = String::from_utf8(buf).unwrap();
 
         let output_lines: Vec<&str> = content.lines().collect();
@@ -410,6 +1304,70 @@ mod tests {
         assert!(output_lines[1].contains("-add"));
     }
 
+    #[test]
+    fn write_ranges_command_wraps_chunks() {
+        const MAX_CHUNK_SIZE: usize = 900_000;
+        let face = "rgb:FF0000,default".to_string();
+        let sample = format!("'1.1,1.1|{face}'");
+        let num_spans = MAX_CHUNK_SIZE / (sample.len() + 1) + 2;
+
+        let screen = make_screen(
+            vec![ProcessedLine {
+                text: "x".to_string(),
+                spans: (0..num_spans)
+                    .map(|_| Span {
+                        start_byte: 1,
+                        end_byte: 2,
+                        face: face.as_str().into(),
+                    })
+                    .collect(),
+            }],
+            CursorPosition { line: 1, col: 1 },
+        );
+        let mut buf = Vec::new();
+        write_ranges_to(
+            &mut buf,
+            &screen,
+            RangesWrapMode::Command,
+            None,
+            &FaceRules::default(),
+            false,
+        )
+        .unwrap();
+        let content = String::from_utf8(buf).unwrap();
+
+        let output_lines: Vec<&str> = content.lines().collect();
+        assert_eq!(output_lines.len(), 4);
+        assert_eq!(
+            output_lines[0],
+            "define-command -override -hidden scrollback-apply-colors %{"
+        );
+        assert!(output_lines[1].starts_with("set-option buffer scrollback_colors %val{timestamp} "));
+        assert!(output_lines[2].starts_with("set-option -add buffer scrollback_colors "));
+        assert_eq!(output_lines[3], "}");
+    }
+
+    #[test]
+    fn write_init_kak_calls_color_command() {
+        let screen = make_screen(vec![], CursorPosition { line: 1, col: 1 });
+        let render = |ranges_mode| {
+            render_init_kak(
+                &screen,
+                &kitty_target("1"),
+                Path::new("/tmp/ksb-fake"),
+                Path::new("/tmp/ksb-fake/ranges.kak"),
+                &InitOptions {
+                    ranges_mode,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+        assert!(render(RangesWrapMode::Command)
+            .contains("source '/tmp/ksb-fake/ranges.kak'\nscrollback-apply-colors\n"));
+        assert!(!render(RangesWrapMode::Direct).contains("scrollback-apply-colors"));
+    }
+
     #[test]
     fn span_end_byte_exclusive_to_inclusive() {
         use crate::kitty::PipeData;
@@ -421,6 +1379,8 @@ mod tests {
             cursor_y: 0,
             lines: 24,
             columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
         };
         let screen = terminal::process_bytes(
             &pd,
@@ -436,7 +1396,15 @@ mod tests {
 
         // write_ranges converts to inclusive end (end_byte - 1)
         let mut buf = Vec::new();
-        write_ranges_to(&mut buf, &screen).unwrap();
+        write_ranges_to(
+            &mut buf,
+            &screen,
+            RangesWrapMode::Direct,
+            None,
+            &FaceRules::default(),
+            false,
+        )
+        .unwrap();
         let content = String::from_utf8(buf).unwrap();
 
         // Output should use inclusive end: 6 - 1 = 5
@@ -449,7 +1417,7 @@ mod tests {
     fn write_text_empty_screen() {
         let screen = make_screen(vec![], CursorPosition { line: 1, col: 1 });
         let mut buf = Vec::new();
-        write_text_to(&mut buf, &screen).unwrap();
+        write_text_to(&mut buf, &screen, LineEnding::Lf).unwrap();
         let content = String::from_utf8(buf).unwrap();
         assert_eq!(content, "");
     }
@@ -463,24 +1431,32 @@ mod tests {
                     Span {
                         start_byte: 1,
                         end_byte: 4,
-                        face: "rgb:FF0000,default".to_string(),
+                        face: "rgb:FF0000,default".into(),
                     },
                     Span {
                         start_byte: 4,
                         end_byte: 9,
-                        face: "rgb:00FF00,default".to_string(),
+                        face: "rgb:00FF00,default".into(),
                     },
                     Span {
                         start_byte: 9,
                         end_byte: 13,
-                        face: "rgb:0000FF,default".to_string(),
+                        face: "rgb:0000FF,default".into(),
                     },
                 ],
             }],
             CursorPosition { line: 1, col: 1 },
         );
         let mut buf = Vec::new();
-        write_ranges_to(&mut buf, &screen).unwrap();
+        write_ranges_to(
+            &mut buf,
+            &screen,
+            RangesWrapMode::Direct,
+            None,
+            &FaceRules::default(),
+            false,
+        )
+        .unwrap();
         let content = String::from_utf8(buf).unwrap();
 
         // All spans should be in one set-option command
@@ -499,7 +1475,7 @@ mod tests {
                     spans: vec![Span {
                         start_byte: 1,
                         end_byte: 4,
-                        face: "rgb:FF0000,default".to_string(),
+                        face: "rgb:FF0000,default".into(),
                     }],
                 },
                 ProcessedLine {
@@ -507,7 +1483,7 @@ mod tests {
                     spans: vec![Span {
                         start_byte: 1,
                         end_byte: 6,
-                        face: "rgb:00FF00,default".to_string(),
+                        face: "rgb:00FF00,default".into(),
                     }],
                 },
                 ProcessedLine {
@@ -515,14 +1491,22 @@ mod tests {
                     spans: vec![Span {
                         start_byte: 1,
                         end_byte: 5,
-                        face: "rgb:0000FF,default".to_string(),
+                        face: "rgb:0000FF,default".into(),
                     }],
                 },
             ],
             CursorPosition { line: 1, col: 1 },
         );
         let mut buf = Vec::new();
-        write_ranges_to(&mut buf, &screen).unwrap();
+        write_ranges_to(
+            &mut buf,
+            &screen,
+            RangesWrapMode::Direct,
+            None,
+            &FaceRules::default(),
+            false,
+        )
+        .unwrap();
         let content = String::from_utf8(buf).unwrap();
 
         assert!(content.contains("1.1,1.3|rgb:FF0000,default"));
@@ -530,6 +1514,90 @@ mod tests {
         assert!(content.contains("3.1,3.4|rgb:0000FF,default"));
     }
 
+    #[test]
+    fn write_ranges_max_span_bytes_splits_long_spans() {
+        let screen = make_screen(
+            vec![ProcessedLine {
+                text: "ab0123456é789z".to_string(),
+                spans: vec![Span {
+                    start_byte: 3,
+                    end_byte: 15,
+                    face: "red".into(),
+                }],
+            }],
+            CursorPosition { line: 1, col: 1 },
+        );
+        let render = |max_span_bytes| {
+            let mut buf = Vec::new();
+            write_ranges_to(
+                &mut buf,
+                &screen,
+                RangesWrapMode::Direct,
+                max_span_bytes,
+                &FaceRules::default(),
+                false,
+            )
+            .unwrap();
+            String::from_utf8(buf).unwrap()
+        };
+
+        assert_eq!(
+            render(None),
+            "set-option buffer scrollback_colors %val{timestamp} '1.3,1.14|red'\n"
+        );
+        // 12 bytes in pieces of at most 4; the 2-byte é is not cut in half
+        assert_eq!(
+            render(Some(4)),
+            "set-option buffer scrollback_colors %val{timestamp} \
+             '1.3,1.6|red' '1.7,1.9|red' '1.10,1.13|red' '1.14,1.14|red'\n"
+        );
+        assert_eq!(render(Some(12)), render(None));
+    }
+
+    #[test]
+    fn write_ranges_sorted_by_line_and_start() {
+        let span = |start_byte, end_byte| Span {
+            start_byte,
+            end_byte,
+            face: "red".into(),
+        };
+        let screen = make_screen(
+            vec![
+                ProcessedLine {
+                    text: "abcdefghij".to_string(),
+                    spans: vec![span(8, 10), span(1, 3), span(4, 6)],
+                },
+                ProcessedLine {
+                    text: "abcdef".to_string(),
+                    spans: vec![span(5, 7), span(1, 2)],
+                },
+            ],
+            CursorPosition { line: 1, col: 1 },
+        );
+        let mut buf = Vec::new();
+        write_ranges_to(
+            &mut buf,
+            &screen,
+            RangesWrapMode::Direct,
+            None,
+            &FaceRules::default(),
+            false,
+        )
+        .unwrap();
+        let content = String::from_utf8(buf).unwrap();
+
+        let entries: Vec<(usize, usize)> = content
+            .split('\'')
+            .filter(|e| e.contains('|'))
+            .map(|e| {
+                let (line, rest) = e.split_once('.').unwrap();
+                let start = rest.split(',').next().unwrap();
+                (line.parse().unwrap(), start.parse().unwrap())
+            })
+            .collect();
+        assert_eq!(entries, vec![(1, 1), (1, 4), (1, 8), (2, 1), (2, 5)]);
+    }
+
     // --- Phase 3: LOW priority ---
 
     #[test]
@@ -542,7 +1610,7 @@ mod tests {
             CursorPosition { line: 1, col: 1 },
         );
         let mut buf = Vec::new();
-        write_text_to(&mut buf, &screen).unwrap();
+        write_text_to(&mut buf, &screen, LineEnding::Lf).unwrap();
         let content = String::from_utf8(buf).unwrap();
         assert_eq!(content, "\n");
     }
@@ -598,8 +1666,14 @@ mod tests {
         );
         let evil_dir = Path::new("/tmp/it's-a-dir");
         let ranges_path = evil_dir.join("ranges.kak");
-        let content =
-            render_init_kak(&screen, &kitty_target("42"), evil_dir, &ranges_path).unwrap();
+        let content = render_init_kak(
+            &screen,
+            &kitty_target("42"),
+            evil_dir,
+            &ranges_path,
+            &InitOptions::default(),
+        )
+        .unwrap();
 
         // Kakoune contexts should use '' escaping
         assert!(
@@ -627,6 +1701,24 @@ mod tests {
         );
     }
 
+    #[test]
+    fn atomic_write_replaces_without_leftovers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cursor.txt");
+        std::fs::write(&path, "old content that is longer\n").unwrap();
+        atomic_write(&path, b"2.4\n").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "2.4\n");
+
+        let entries: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(entries, ["cursor.txt"]);
+
+        let missing = dir.path().join("no-such-dir").join("out.txt");
+        assert!(atomic_write(&missing, b"x").is_err());
+    }
+
     #[test]
     fn write_init_kak_large_cursor_coords() {
         let screen = make_screen(
@@ -644,11 +1736,69 @@ mod tests {
             &kitty_target("42"),
             Path::new("/tmp/ksb-fake"),
             Path::new("/tmp/ksb-fake/ranges.kak"),
+            &InitOptions::default(),
         )
         .unwrap();
         assert!(content.contains("select 1000.50,1000.50"));
     }
 
+    #[test]
+    fn write_init_kak_clamps_cursor_col_to_line_end() {
+        let screen = make_screen(
+            vec![
+                ProcessedLine {
+                    text: "first line".to_string(),
+                    spans: vec![],
+                },
+                ProcessedLine {
+                    text: "$ ls".to_string(),
+                    spans: vec![],
+                },
+            ],
+            CursorPosition { line: 2, col: 40 },
+        );
+        let content = render_init_kak(
+            &screen,
+            &kitty_target("42"),
+            Path::new("/tmp/ksb-fake"),
+            Path::new("/tmp/ksb-fake/ranges.kak"),
+            &InitOptions::default(),
+        )
+        .unwrap();
+        assert!(content.contains("select 2.5,2.5"), "got:\n{content}");
+        assert_eq!(cursor_col_overrun(&screen), Some(5));
+    }
+
+    #[test]
+    fn cursor_after_prompt_space_is_not_an_overrun() {
+        // `$ ` with the cursor after the trimmed space
+        let pd = kitty::PipeData {
+            cursor_x: 2,
+            cursor_y: 0,
+            lines: 5,
+            columns: 20,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        let screen = terminal::process_bytes(
+            &pd,
+            b"$ ",
+            &crate::palette::DEFAULT_PALETTE,
+            terminal::DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        assert_eq!(screen.lines[0].text, "$");
+        assert_eq!(cursor_col_overrun(&screen), None);
+        let content = render_init_kak(
+            &screen,
+            &kitty_target("42"),
+            Path::new("/tmp/ksb-fake"),
+            Path::new("/tmp/ksb-fake/ranges.kak"),
+            &InitOptions::default(),
+        )
+        .unwrap();
+        assert!(content.contains("select 1.2,1.2"), "got:\n{content}");
+    }
+
     // --- tmux TargetId tests ---
 
     #[test]
@@ -666,6 +1816,7 @@ mod tests {
             &target,
             Path::new("/tmp/ksb-fake"),
             Path::new("/tmp/ksb-fake/ranges.kak"),
+            &InitOptions::default(),
         )
         .unwrap();
 
@@ -692,6 +1843,7 @@ mod tests {
             &target,
             Path::new("/tmp/ksb-fake"),
             Path::new("/tmp/ksb-fake/ranges.kak"),
+            &InitOptions::default(),
         )
         .unwrap();
 
@@ -715,6 +1867,7 @@ mod tests {
             &kitty_target("1"),
             Path::new("/tmp/ksb-fake"),
             Path::new("/tmp/ksb-fake/ranges.kak"),
+            &InitOptions::default(),
         )
         .unwrap();
 
@@ -722,4 +1875,616 @@ mod tests {
         assert!(content.contains("scrollback_kitty_window_id '1'"));
         assert!(!content.contains("scrollback_tmux_pane_id"));
     }
+
+    #[test]
+    fn write_init_kak_expected_plugin_version() {
+        let screen = make_screen(
+            vec![ProcessedLine {
+                text: "test".to_string(),
+                spans: vec![],
+            }],
+            CursorPosition { line: 1, col: 1 },
+        );
+        let content = render_init_kak(
+            &screen,
+            &kitty_target("1"),
+            Path::new("/tmp/ksb-fake"),
+            Path::new("/tmp/ksb-fake/ranges.kak"),
+            &InitOptions::default(),
+        )
+        .unwrap();
+
+        let expected = format!(
+            "set-option global scrollback_expected_plugin_version '{}'",
+            env!("CARGO_PKG_VERSION")
+        );
+        assert!(content.lines().any(|l| l == expected));
+        // Declared before use, so an older plugin does not fail on it
+        let declare = content
+            .find("declare-option -hidden str scrollback_expected_plugin_version")
+            .unwrap();
+        assert!(declare < content.find(&expected).unwrap());
+    }
+
+    #[test]
+    fn write_init_kak_register_escapes_text() {
+        let screen = make_screen(
+            vec![
+                ProcessedLine {
+                    text: "it's 100%".to_string(),
+                    spans: vec![],
+                },
+                ProcessedLine {
+                    text: "{braces} 'quoted'".to_string(),
+                    spans: vec![],
+                },
+            ],
+            CursorPosition { line: 1, col: 1 },
+        );
+        let content = render_init_kak(
+            &screen,
+            &kitty_target("1"),
+            Path::new("/tmp/ksb-fake"),
+            Path::new("/tmp/ksb-fake/ranges.kak"),
+            &InitOptions {
+                register: Some('r'),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert!(content.contains(
+            "set-option -add buffer scrollback_register_text 'it''s 100%\n{braces} ''quoted''\n'\n"
+        ));
+        let set = content
+            .find("set-register r %opt{scrollback_register_text}")
+            .unwrap();
+        assert!(
+            content
+                .find("set-option -add buffer scrollback_register_text")
+                .unwrap()
+                < set
+        );
+        assert!(content.contains("unset-option buffer scrollback_register_text"));
+
+        let without = render_init_kak(
+            &screen,
+            &kitty_target("1"),
+            Path::new("/tmp/ksb-fake"),
+            Path::new("/tmp/ksb-fake/ranges.kak"),
+            &InitOptions::default(),
+        )
+        .unwrap();
+        assert!(!without.contains("set-register"));
+    }
+
+    #[test]
+    fn write_register_commands_chunks_large_text() {
+        let line = "y".repeat(1000);
+        let screen = make_screen(
+            (0..2000)
+                .map(|_| ProcessedLine {
+                    text: line.clone(),
+                    spans: vec![],
+                })
+                .collect(),
+            CursorPosition { line: 1, col: 1 },
+        );
+        let mut script = String::new();
+        write_register_commands(&mut script, &screen, 'a').unwrap();
+        let chunks = script
+            .matches("set-option -add buffer scrollback_register_text '")
+            .count();
+        assert_eq!(chunks, 3);
+        // Chunks break between lines, so no text is lost or duplicated
+        assert_eq!(script.matches('y').count(), 2000 * 1000);
+        assert!(script.contains("yyy\n'\nset-option -add"));
+    }
+
+    #[test]
+    fn write_init_kak_source_command() {
+        let screen = make_screen(vec![], CursorPosition { line: 1, col: 1 });
+        let render = |source_command: Option<&str>| {
+            render_init_kak(
+                &screen,
+                &kitty_target("1"),
+                Path::new("/tmp/ksb-fake"),
+                Path::new("/tmp/ksb-fake/ranges.kak"),
+                &InitOptions {
+                    source_command: source_command.map(String::from),
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+        let content = render(Some("grep 'foo bar' log"));
+        assert!(content.contains("declare-option -hidden str scrollback_source_command\n"));
+        assert!(content
+            .contains("set-option buffer scrollback_source_command 'grep ''foo bar'' log'\n"));
+        assert!(!render(None).contains("scrollback_source_command"));
+    }
+
+    #[test]
+    fn write_init_kak_cursor_shape() {
+        let mut screen = make_screen(
+            vec![ProcessedLine {
+                text: "test".to_string(),
+                spans: vec![],
+            }],
+            CursorPosition { line: 1, col: 1 },
+        );
+        let render = |screen: &ProcessedScreen| {
+            render_init_kak(
+                screen,
+                &kitty_target("1"),
+                Path::new("/tmp/ksb-fake"),
+                Path::new("/tmp/ksb-fake/ranges.kak"),
+                &InitOptions::default(),
+            )
+            .unwrap()
+        };
+        assert!(!render(&screen).contains("scrollback_cursor_shape"));
+        screen.cursor_shape = Some(crate::kitty::CursorShape::Beam);
+        assert!(render(&screen).contains("set-option buffer scrollback_cursor_shape 'beam'\n"));
+    }
+
+    #[test]
+    fn write_init_kak_page_boundaries() {
+        let mut screen = make_screen(
+            vec![ProcessedLine {
+                text: "test".to_string(),
+                spans: vec![],
+            }],
+            CursorPosition { line: 1, col: 1 },
+        );
+        screen.page_boundaries = vec![1, 12];
+        let content = render_init_kak(
+            &screen,
+            &kitty_target("1"),
+            Path::new("/tmp/ksb-fake"),
+            Path::new("/tmp/ksb-fake/ranges.kak"),
+            &InitOptions::default(),
+        )
+        .unwrap();
+
+        assert!(content.contains("declare-option -hidden int-list scrollback_page_boundaries"));
+        assert!(
+            content.contains("set-option buffer scrollback_page_boundaries 1 12\n"),
+            "boundaries should be written as an int-list, got:\n{content}"
+        );
+        assert!(content.contains("define-command -override scrollback-next-page"));
+        assert!(content.contains("define-command -override scrollback-prev-page"));
+    }
+
+    #[test]
+    fn write_init_kak_empty_screen_skips_selection() {
+        let screen = make_screen(vec![], CursorPosition { line: 1, col: 1 });
+        let content = render_init_kak(
+            &screen,
+            &kitty_target("1"),
+            Path::new("/tmp/ksb-fake"),
+            Path::new("/tmp/ksb-fake/ranges.kak"),
+            &InitOptions::default(),
+        )
+        .unwrap();
+        assert!(
+            !content.contains("select 1.1,1.1"),
+            "empty screen must not select a position, got:\n{content}"
+        );
+        assert!(!content.contains("execute-keys vt\n}"));
+        // The rest of the script is still emitted
+        assert!(content.contains("kakoune-scrollback-setup-keymaps"));
+        assert!(content.contains("ClientClose"));
+    }
+
+    #[test]
+    fn write_debug_map_lists_sources() {
+        use crate::terminal::LineSource;
+
+        let mut screen = make_screen(vec![], CursorPosition { line: 1, col: 1 });
+        screen.line_sources = vec![
+            LineSource {
+                offset: 1,
+                row: 0,
+                continuation: false,
+            },
+            LineSource {
+                offset: 1,
+                row: 1,
+                continuation: true,
+            },
+            LineSource {
+                offset: 0,
+                row: 1,
+                continuation: false,
+            },
+        ];
+        let mut buf = Vec::new();
+        write_debug_map_to(&mut buf, &screen).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "1 -> 1 row 0\n2 -> 1 row 1 (cont)\n3 -> 0 row 1\n"
+        );
+    }
+
+    #[test]
+    fn write_init_kak_mark_saves_cursor_selection() {
+        let screen = make_screen(
+            vec![ProcessedLine {
+                text: "hello".to_string(),
+                spans: vec![],
+            }],
+            CursorPosition { line: 1, col: 4 },
+        );
+        let opts = InitOptions {
+            mark: true,
+            ..Default::default()
+        };
+        let content = render_init_kak(
+            &screen,
+            &kitty_target("1"),
+            Path::new("/tmp/ksb-fake"),
+            Path::new("/tmp/ksb-fake/ranges.kak"),
+            &opts,
+        )
+        .unwrap();
+        assert!(
+            content.contains("select 1.4,1.4\nexecute-keys -save-regs '' Z\n"),
+            "mark should be saved right after the cursor select, got:\n{content}"
+        );
+
+        let content = render_init_kak(
+            &screen,
+            &kitty_target("1"),
+            Path::new("/tmp/ksb-fake"),
+            Path::new("/tmp/ksb-fake/ranges.kak"),
+            &InitOptions::default(),
+        )
+        .unwrap();
+        assert!(!content.contains(" Z\n"));
+    }
+
+    #[test]
+    fn write_init_kak_highlight_urls() {
+        let screen = make_screen(vec![], CursorPosition { line: 1, col: 1 });
+        let render = |highlight_urls| {
+            render_init_kak(
+                &screen,
+                &kitty_target("1"),
+                Path::new("/tmp/ksb-fake"),
+                Path::new("/tmp/ksb-fake/ranges.kak"),
+                &InitOptions {
+                    highlight_urls,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+        let content = render(true);
+        let expected = format!("add-highlighter buffer/ regex %{{{URL_REGEX}}} 0:default+u\n");
+        assert!(content.contains(&expected), "got:\n{content}");
+        // Layered above the cell colors
+        assert!(content.find(&expected) > content.find("ranges scrollback_colors"));
+        assert!(!URL_REGEX.contains(['{', '}']));
+        assert!(!render(false).contains("regex"));
+    }
+
+    #[test]
+    fn write_init_kak_return_focus_tmux() {
+        let screen = make_screen(vec![], CursorPosition { line: 1, col: 1 });
+        let opts = InitOptions {
+            return_focus: true,
+            ..Default::default()
+        };
+        let render = |target: &TargetId| {
+            render_init_kak(
+                &screen,
+                target,
+                Path::new("/tmp/ksb-fake"),
+                Path::new("/tmp/ksb-fake/ranges.kak"),
+                &opts,
+            )
+            .unwrap()
+        };
+        let content = render(&TargetId::Tmux("%5".to_string()));
+        assert!(
+            content.contains("tmux select-pane -t '%5' >/dev/null 2>&1"),
+            "got:\n{content}"
+        );
+        let rm = content.find("rm -rf --").unwrap();
+        assert!(rm < content.find("select-pane").unwrap());
+
+        let quoted = render(&TargetId::Tmux("%5'x".to_string()));
+        assert!(quoted.contains("select-pane -t '%5'\\''x'"));
+
+        assert!(!render(&kitty_target("1")).contains("select-pane"));
+    }
+
+    #[test]
+    fn render_command_buffers_opens_and_lists() {
+        let buffers = [
+            CommandBuffer {
+                name: "*scrollback 1: $ ls*".into(),
+                text_path: "/tmp/ksb-fake/cmd-1.txt".into(),
+                ranges_path: "/tmp/ksb-fake/cmd-1.ranges.kak".into(),
+            },
+            CommandBuffer {
+                name: "*scrollback 2: $ echo 'hi'*".into(),
+                text_path: "/tmp/ksb-fake/cmd-2.txt".into(),
+                ranges_path: "/tmp/ksb-fake/cmd-2.ranges.kak".into(),
+            },
+        ];
+        let content = render_command_buffers(
+            Path::new("/tmp/ksb-fake/text.txt"),
+            &buffers,
+            &TargetId::Tmux("%5".into()),
+            &InitOptions::default(),
+        )
+        .unwrap();
+        assert!(content.contains(
+            "edit -existing '/tmp/ksb-fake/cmd-1.txt'\nrename-buffer '*scrollback 1: $ ls*'\n"
+        ));
+        assert!(content.contains("source '/tmp/ksb-fake/cmd-2.ranges.kak'"));
+        // Every buffer knows the whole list and its capture's target
+        let list = "set-option buffer scrollback_command_buffers \
+                    '*scrollback 1: $ ls*' '*scrollback 2: $ echo ''hi''*'\n";
+        assert_eq!(content.matches(list).count(), 3);
+        assert_eq!(
+            content
+                .matches("set-option buffer scrollback_tmux_pane_id '%5'")
+                .count(),
+            2
+        );
+        assert_eq!(
+            content.matches("set-option buffer readonly true").count(),
+            2
+        );
+        // Back to the full scrollback before defining the switcher
+        let back = content.find("buffer '/tmp/ksb-fake/text.txt'").unwrap();
+        assert!(back > content.rfind("rename-buffer").unwrap());
+        assert!(content.contains("define-command -override scrollback-command -params 1"));
+    }
+
+    #[test]
+    fn write_init_kak_auto_trim_notice() {
+        let mut screen = make_screen(
+            vec![ProcessedLine {
+                text: "x".into(),
+                spans: vec![],
+            }],
+            CursorPosition { line: 1, col: 1 },
+        );
+        let render = |screen: &ProcessedScreen| {
+            render_init_kak(
+                screen,
+                &kitty_target("1"),
+                Path::new("/tmp/ksb-fake"),
+                Path::new("/tmp/ksb-fake/ranges.kak"),
+                &InitOptions::default(),
+            )
+            .unwrap()
+        };
+        assert!(!render(&screen).contains("not loaded"));
+        screen.truncated_lines = 1234;
+        assert!(render(&screen).contains("1234 older lines not loaded"));
+    }
+
+    #[test]
+    fn write_init_kak_kitty_open_mode() {
+        let screen = make_screen(vec![], CursorPosition { line: 1, col: 1 });
+        let render = |target: &TargetId, kitty_open_mode| {
+            render_init_kak(
+                &screen,
+                target,
+                Path::new("/tmp/ksb-fake"),
+                Path::new("/tmp/ksb-fake/ranges.kak"),
+                &InitOptions {
+                    kitty_open_mode,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+        assert!(render(&kitty_target("1"), OpenMode::default())
+            .contains("set-option buffer scrollback_kitty_open_mode 'overlay'\n"));
+        let content = render(&kitty_target("1"), OpenMode::OsWindow);
+        assert!(content.contains("declare-option -hidden str scrollback_kitty_open_mode\n"));
+        assert!(content.contains("set-option buffer scrollback_kitty_open_mode 'os-window'\n"));
+        assert!(!render(&TargetId::Tmux("%5".into()), OpenMode::OsWindow)
+            .contains("scrollback_kitty_open_mode"));
+    }
+
+    #[test]
+    fn write_init_kak_restore_scroll() {
+        let mut screen = make_screen(vec![], CursorPosition { line: 1, col: 1 });
+        screen.scrolled_by = 37;
+        let opts = InitOptions {
+            restore_scroll: true,
+            ..Default::default()
+        };
+        let content = render_init_kak(
+            &screen,
+            &kitty_target("9"),
+            Path::new("/tmp/ksb-fake"),
+            Path::new("/tmp/ksb-fake/ranges.kak"),
+            &opts,
+        )
+        .unwrap();
+        assert!(content.contains("kitty @ scroll-window --match id:9 end"));
+        assert!(
+            content.contains("kitty @ scroll-window --match id:9 37l-"),
+            "should scroll window 9 back up 37 lines, got:\n{content}"
+        );
+        // Restore happens inside the last-client guard, after cleanup
+        let rm = content.find("rm -rf --").unwrap();
+        let scroll = content.find("scroll-window").unwrap();
+        assert!(rm < scroll);
+    }
+
+    #[test]
+    fn write_init_kak_select_scroll_view() {
+        let lines = (0..60)
+            .map(|i| ProcessedLine {
+                text: format!("line {i}"),
+                spans: vec![],
+            })
+            .collect();
+        let mut screen = make_screen_with_viewport(lines, CursorPosition { line: 55, col: 3 }, 37);
+        screen.scrolled_by = 20;
+        let render = |screen: &ProcessedScreen, select_scroll_view| {
+            let opts = InitOptions {
+                select_scroll_view,
+                ..Default::default()
+            };
+            render_init_kak(
+                screen,
+                &kitty_target("9"),
+                Path::new("/tmp/ksb-fake"),
+                Path::new("/tmp/ksb-fake/ranges.kak"),
+                &opts,
+            )
+            .unwrap()
+        };
+
+        let content = render(&screen, true);
+        assert!(content.contains("    select 17.1,17.1\n    execute-keys vt"));
+        assert!(content.contains("\nselect 17.1,17.1\n"), "got:\n{content}");
+        assert!(!content.contains("select 55.3"));
+
+        let content = render(&screen, false);
+        assert!(content.contains("    select 37.1,37.1\n    execute-keys vt"));
+        assert!(content.contains("\nselect 55.3,55.3\n"));
+
+        // Scrolled past the start of the (trimmed) buffer: clamp to line 1
+        screen.scrolled_by = 100;
+        assert!(render(&screen, true).contains("\nselect 1.1,1.1\n"));
+    }
+
+    #[test]
+    fn write_init_kak_restore_scroll_skipped() {
+        let opts = InitOptions {
+            restore_scroll: true,
+            ..Default::default()
+        };
+        // Not scrolled
+        let screen = make_screen(vec![], CursorPosition { line: 1, col: 1 });
+        let content = render_init_kak(
+            &screen,
+            &kitty_target("9"),
+            Path::new("/tmp/ksb-fake"),
+            Path::new("/tmp/ksb-fake/ranges.kak"),
+            &opts,
+        )
+        .unwrap();
+        assert!(!content.contains("scroll-window"));
+
+        // Flag not set
+        let mut screen = make_screen(vec![], CursorPosition { line: 1, col: 1 });
+        screen.scrolled_by = 5;
+        let content = render_init_kak(
+            &screen,
+            &kitty_target("9"),
+            Path::new("/tmp/ksb-fake"),
+            Path::new("/tmp/ksb-fake/ranges.kak"),
+            &InitOptions::default(),
+        )
+        .unwrap();
+        assert!(!content.contains("scroll-window"));
+    }
+
+    #[test]
+    fn write_init_kak_readonly_by_default() {
+        let screen = make_screen(vec![], CursorPosition { line: 1, col: 1 });
+        let content = render_init_kak(
+            &screen,
+            &kitty_target("1"),
+            Path::new("/tmp/ksb-fake"),
+            Path::new("/tmp/ksb-fake/ranges.kak"),
+            &InitOptions::default(),
+        )
+        .unwrap();
+        assert!(content.contains("set-option buffer readonly true"));
+    }
+
+    #[test]
+    fn write_init_kak_editable_omits_readonly() {
+        let screen = make_screen(vec![], CursorPosition { line: 1, col: 1 });
+        let opts = InitOptions {
+            editable: true,
+            ..Default::default()
+        };
+        let content = render_init_kak(
+            &screen,
+            &kitty_target("1"),
+            Path::new("/tmp/ksb-fake"),
+            Path::new("/tmp/ksb-fake/ranges.kak"),
+            &opts,
+        )
+        .unwrap();
+        assert!(
+            !content.contains("readonly true"),
+            "editable buffer must not be readonly, got:\n{content}"
+        );
+        assert!(content.contains("kakoune-scrollback-setup-keymaps"));
+    }
+
+    #[test]
+    fn write_init_kak_editable_refreshes_ranges_on_idle() {
+        let screen = make_screen(vec![], CursorPosition { line: 1, col: 1 });
+        let render = |editable| {
+            let opts = InitOptions {
+                editable,
+                ..Default::default()
+            };
+            render_init_kak(
+                &screen,
+                &kitty_target("1"),
+                Path::new("/tmp/ksb-fake"),
+                Path::new("/tmp/ksb-fake/ranges.kak"),
+                &opts,
+            )
+            .unwrap()
+        };
+        let content = render(true);
+        for idle in ["NormalIdle", "InsertIdle"] {
+            assert!(
+                content.contains(&format!(
+                    "hook -group scrollback-ranges buffer {idle} .* %{{ \
+                     update-option buffer scrollback_colors; \
+                     update-option buffer scrollback_viewport }}"
+                )),
+                "missing {idle} hook, got:\n{content}"
+            );
+        }
+        // The hooks come after the ranges are sourced
+        assert!(content.find("source '/tmp/ksb-fake/ranges.kak'") < content.find("NormalIdle"));
+
+        assert!(!render(false).contains("scrollback-ranges"));
+    }
+
+    #[test]
+    fn write_init_kak_underline_layer_highlighter() {
+        let screen = make_screen(vec![], CursorPosition { line: 1, col: 1 });
+        let opts = InitOptions {
+            underline_layer: true,
+            editable: true,
+            ..Default::default()
+        };
+        let content = render_init_kak(
+            &screen,
+            &kitty_target("1"),
+            Path::new("/tmp/ksb-fake"),
+            Path::new("/tmp/ksb-fake/ranges.kak"),
+            &opts,
+        )
+        .unwrap();
+        let colors = content.find("add-highlighter buffer/ ranges scrollback_colors");
+        let underlines = content.find("add-highlighter buffer/ ranges scrollback_underlines");
+        assert!(colors.is_some() && colors < underlines);
+        assert!(content.contains("declare-option -hidden range-specs scrollback_underlines"));
+        assert!(content.contains("update-option buffer scrollback_underlines\n"));
+        assert!(content.contains(
+            "update-option buffer scrollback_colors; update-option buffer scrollback_underlines;"
+        ));
+    }
 }
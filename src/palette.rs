@@ -20,6 +20,113 @@ pub const DEFAULT_PALETTE: [u8; 48] = [
     0xFF, 0xFF, 0xFF, // 15: Bright White
 ];
 
+/// Solarized Dark ANSI palette
+const SOLARIZED_DARK: [u8; 48] = [
+    0x07, 0x36, 0x42, // 0
+    0xDC, 0x32, 0x2F, // 1
+    0x85, 0x99, 0x00, // 2
+    0xB5, 0x89, 0x00, // 3
+    0x26, 0x8B, 0xD2, // 4
+    0xD3, 0x36, 0x82, // 5
+    0x2A, 0xA1, 0x98, // 6
+    0xEE, 0xE8, 0xD5, // 7
+    0x00, 0x2B, 0x36, // 8
+    0xCB, 0x4B, 0x16, // 9
+    0x58, 0x6E, 0x75, // 10
+    0x65, 0x7B, 0x83, // 11
+    0x83, 0x94, 0x96, // 12
+    0x6C, 0x71, 0xC4, // 13
+    0x93, 0xA1, 0xA1, // 14
+    0xFD, 0xF6, 0xE3, // 15
+];
+
+/// Gruvbox (dark) ANSI palette
+const GRUVBOX_DARK: [u8; 48] = [
+    0x28, 0x28, 0x28, // 0
+    0xCC, 0x24, 0x1D, // 1
+    0x98, 0x97, 0x1A, // 2
+    0xD7, 0x99, 0x21, // 3
+    0x45, 0x85, 0x88, // 4
+    0xB1, 0x62, 0x86, // 5
+    0x68, 0x9D, 0x6A, // 6
+    0xA8, 0x99, 0x84, // 7
+    0x92, 0x83, 0x74, // 8
+    0xFB, 0x49, 0x34, // 9
+    0xB8, 0xBB, 0x26, // 10
+    0xFA, 0xBD, 0x2F, // 11
+    0x83, 0xA5, 0x98, // 12
+    0xD3, 0x86, 0x9B, // 13
+    0x8E, 0xC0, 0x7C, // 14
+    0xEB, 0xDB, 0xB2, // 15
+];
+
+/// Dracula ANSI palette
+const DRACULA: [u8; 48] = [
+    0x21, 0x22, 0x2C, // 0
+    0xFF, 0x55, 0x55, // 1
+    0x50, 0xFA, 0x7B, // 2
+    0xF1, 0xFA, 0x8C, // 3
+    0xBD, 0x93, 0xF9, // 4
+    0xFF, 0x79, 0xC6, // 5
+    0x8B, 0xE9, 0xFD, // 6
+    0xF8, 0xF8, 0xF2, // 7
+    0x62, 0x72, 0xA4, // 8
+    0xFF, 0x6E, 0x6E, // 9
+    0x69, 0xFF, 0x94, // 10
+    0xFF, 0xFF, 0xA5, // 11
+    0xD6, 0xAC, 0xFF, // 12
+    0xFF, 0x92, 0xDF, // 13
+    0xA4, 0xFF, 0xFF, // 14
+    0xFF, 0xFF, 0xFF, // 15
+];
+
+/// Names accepted by `--theme`.
+pub const THEME_NAMES: &[&str] = &["default", "solarized-dark", "gruvbox", "dracula"];
+
+/// Look up a built-in palette by name.
+pub fn named(name: &str) -> Option<[u8; 48]> {
+    match name {
+        "default" => Some(DEFAULT_PALETTE),
+        "solarized-dark" => Some(SOLARIZED_DARK),
+        "gruvbox" => Some(GRUVBOX_DARK),
+        "dracula" => Some(DRACULA),
+        _ => None,
+    }
+}
+
+/// Parse `#RRGGBB` or `#RGB` (the `#` is optional).
+fn parse_hex_color(s: &str) -> Option<[u8; 3]> {
+    let hex = s.trim().trim_start_matches('#');
+    if !hex.is_ascii() {
+        return None;
+    }
+    let channel = |digits: &str| u8::from_str_radix(digits, 16).ok();
+    match hex.len() {
+        6 => Some([
+            channel(&hex[0..2])?,
+            channel(&hex[2..4])?,
+            channel(&hex[4..6])?,
+        ]),
+        // #RGB shorthand: each digit doubled (e.g. #F0A → #FF00AA)
+        3 => Some([
+            channel(&hex[0..1])? * 17,
+            channel(&hex[1..2])? * 17,
+            channel(&hex[2..3])? * 17,
+        ]),
+        _ => None,
+    }
+}
+
+/// Split a `colorN VALUE` line into its index (0-15) and value. Any run of
+/// spaces or tabs separates the two, and the value ends at the next one, so
+/// aligned columns and a trailing `# comment` are ignored.
+fn split_color_line(line: &str) -> Option<(usize, &str)> {
+    let rest = line.trim().strip_prefix("color")?;
+    let (idx_str, value) = rest.split_once(|c: char| c.is_ascii_whitespace())?;
+    let idx = idx_str.parse::<usize>().ok().filter(|&i| i < 16)?;
+    Some((idx, value.split_ascii_whitespace().next()?))
+}
+
 /// Parse `kitty @ get-colors` output into a 48-byte ANSI palette.
 ///
 /// Expects lines like `colorN #RRGGBB` (or `colorN #RGB`).
@@ -27,55 +134,111 @@ pub const DEFAULT_PALETTE: [u8; 48] = [
 pub fn parse_kitty_colors(output: &str) -> [u8; 48] {
     let mut palette = DEFAULT_PALETTE;
     for line in output.lines() {
-        let line = line.trim();
-        // Match lines like "color0 #000000" or "color15 #ffffff"
-        let Some(rest) = line.strip_prefix("color") else {
+        let Some((idx, value)) = split_color_line(line) else {
             continue;
         };
-        let Some((idx_str, hex_str)) = rest.split_once(|c: char| c.is_ascii_whitespace()) else {
+        let Some(rgb) = parse_hex_color(value) else {
             continue;
         };
-        let Ok(idx) = idx_str.parse::<u8>() else {
+        palette[idx * 3..idx * 3 + 3].copy_from_slice(&rgb);
+    }
+    palette
+}
+
+/// The `foreground` and `background` colors from `kitty @ get-colors`
+/// output; `None` unless both are present.
+pub fn parse_kitty_default_colors(output: &str) -> Option<([u8; 3], [u8; 3])> {
+    let mut fg = None;
+    let mut bg = None;
+    for line in output.lines() {
+        let Some((key, value)) = line.trim().split_once(|c: char| c.is_ascii_whitespace()) else {
             continue;
         };
-        if idx > 15 {
-            continue;
+        let value = value.split_ascii_whitespace().next().unwrap_or_default();
+        match key {
+            "foreground" => fg = parse_hex_color(value),
+            "background" => bg = parse_hex_color(value),
+            _ => {}
         }
-        let hex_str = hex_str.trim().trim_start_matches('#');
-        let (r, g, b) = match hex_str.len() {
-            6 => {
-                let Ok(r) = u8::from_str_radix(&hex_str[0..2], 16) else {
-                    continue;
-                };
-                let Ok(g) = u8::from_str_radix(&hex_str[2..4], 16) else {
-                    continue;
-                };
-                let Ok(b) = u8::from_str_radix(&hex_str[4..6], 16) else {
-                    continue;
-                };
-                (r, g, b)
-            }
-            3 => {
-                // #RGB shorthand: each digit doubled (e.g. #F0A → #FF00AA)
-                let Ok(r) = u8::from_str_radix(&hex_str[0..1], 16) else {
-                    continue;
-                };
-                let Ok(g) = u8::from_str_radix(&hex_str[1..2], 16) else {
-                    continue;
-                };
-                let Ok(b) = u8::from_str_radix(&hex_str[2..3], 16) else {
-                    continue;
-                };
-                (r * 17, g * 17, b * 17)
-            }
-            _ => continue,
+    }
+    Some((fg?, bg?))
+}
+
+/// Parse `COLORFGBG` (`fg;bg`, or rxvt's `fg;default;bg`) into the palette
+/// slots of the terminal's default foreground and background.
+pub fn parse_colorfgbg(value: &str) -> Option<(u8, u8)> {
+    let fields: Vec<&str> = value.trim().split(';').collect();
+    let [fg, .., bg] = fields.as_slice() else {
+        return None;
+    };
+    let slot = |s: &str| s.parse::<u8>().ok().filter(|&idx| idx < 16);
+    Some((slot(fg)?, slot(bg)?))
+}
+
+/// A partial palette: `Some` for each of colors 0-15 the layer sets.
+pub type PaletteLayer = [Option<[u8; 3]>; 16];
+
+/// Parse a `--palette-file` in kitty.conf syntax. `colorN #RRGGBB` lines
+/// set a slot; comments and every other setting are ignored, so a kitty
+/// theme file can be used as-is.
+pub fn parse_palette_file(text: &str) -> Result<PaletteLayer, String> {
+    let mut layer = [None; 16];
+    for (n, line) in text.lines().enumerate() {
+        let Some((idx, value)) = split_color_line(line) else {
+            continue;
         };
-        let base = idx as usize * 3;
-        palette[base] = r;
-        palette[base + 1] = g;
-        palette[base + 2] = b;
+        let rgb = parse_hex_color(value)
+            .ok_or_else(|| format!("line {}: invalid color '{value}'", n + 1))?;
+        layer[idx] = Some(rgb);
+    }
+    Ok(layer)
+}
+
+/// Parse a `--color N=#RRGGBB` value.
+pub fn parse_color_arg(value: &str) -> Result<(usize, [u8; 3]), String> {
+    let err = || format!("invalid --color value '{value}' (expected N=#RRGGBB with N in 0-15)");
+    let (idx, hex) = value.split_once('=').ok_or_else(err)?;
+    let idx = idx
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .filter(|&i| i < 16)
+        .ok_or_else(err)?;
+    Ok((idx, parse_hex_color(hex).ok_or_else(err)?))
+}
+
+/// Builds the final palette from layers applied in order; a later layer
+/// wins for every slot it sets.
+pub struct PaletteBuilder {
+    palette: [u8; 48],
+}
+
+impl PaletteBuilder {
+    pub fn new(base: [u8; 48]) -> Self {
+        PaletteBuilder { palette: base }
+    }
+
+    /// Replace only the slots `layer` sets.
+    pub fn overlay(mut self, layer: &PaletteLayer) -> Self {
+        for (idx, rgb) in layer.iter().enumerate() {
+            if let Some(rgb) = rgb {
+                self.palette[idx * 3..idx * 3 + 3].copy_from_slice(rgb);
+            }
+        }
+        self
+    }
+
+    /// Set single slots, e.g. from `--color`.
+    pub fn colors(mut self, colors: &[(usize, [u8; 3])]) -> Self {
+        for &(idx, rgb) in colors {
+            self.palette[idx * 3..idx * 3 + 3].copy_from_slice(&rgb);
+        }
+        self
+    }
+
+    pub fn build(self) -> [u8; 48] {
+        self.palette
     }
-    palette
 }
 
 /// Convert indexed color (16-231) from 6x6x6 cube to RGB
@@ -98,6 +261,39 @@ pub fn idx_to_rgb(idx: u8) -> (u8, u8, u8) {
     }
 }
 
+/// Nearest xterm-256 color (16-255) to `rgb` by squared RGB distance.
+/// Colors 0-15 are skipped because their values depend on the palette.
+pub fn nearest_xterm_index(rgb: [u8; 3]) -> u8 {
+    const LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+    let dist = |a: [u8; 3], b: (u8, u8, u8)| -> u32 {
+        let d = |x: u8, y: u8| (i32::from(x) - i32::from(y)).unsigned_abs().pow(2);
+        d(a[0], b.0) + d(a[1], b.1) + d(a[2], b.2)
+    };
+    let nearest_level = |c: u8| {
+        (0..6u8)
+            .min_by_key(|&i| (i32::from(LEVELS[usize::from(i)]) - i32::from(c)).unsigned_abs())
+            .unwrap()
+    };
+    let cube = 16 + 36 * nearest_level(rgb[0]) + 6 * nearest_level(rgb[1]) + nearest_level(rgb[2]);
+
+    // Grayscale ramp 232-255 = 8, 18, ..., 238
+    let avg = (u32::from(rgb[0]) + u32::from(rgb[1]) + u32::from(rgb[2])) / 3;
+    let gray_step = (avg.saturating_sub(3) / 10).min(23) as u8;
+    let gray = 232 + gray_step;
+
+    if dist(rgb, idx_to_rgb(gray)) < dist(rgb, idx_to_rgb(cube)) {
+        gray
+    } else {
+        cube
+    }
+}
+
+/// Snap `rgb` to the nearest xterm-256 color.
+pub fn quantize_rgb(rgb: [u8; 3]) -> [u8; 3] {
+    let (r, g, b) = idx_to_rgb(nearest_xterm_index(rgb));
+    [r, g, b]
+}
+
 /// Resolve `vt100::Color` to normalized RGB. Returns `None` for `Default`.
 pub fn color_to_rgb(color: vt100::Color, palette: &[u8; 48]) -> Option<[u8; 3]> {
     match color {
@@ -137,6 +333,16 @@ pub fn color_to_kak(color: vt100::Color, palette: &[u8; 48]) -> Option<String> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn parse_colorfgbg_values() {
+        assert_eq!(parse_colorfgbg("15;0"), Some((15, 0)));
+        assert_eq!(parse_colorfgbg("0;15"), Some((0, 15)));
+        assert_eq!(parse_colorfgbg("0;default;15"), Some((0, 15)));
+        for value in ["", "15", "15;", "a;0", "15;16", "default;default", "-1;0"] {
+            assert_eq!(parse_colorfgbg(value), None, "{value:?}");
+        }
+    }
+
     #[test]
     fn default_color_returns_none() {
         assert_eq!(color_to_kak(vt100::Color::Default, &DEFAULT_PALETTE), None);
@@ -324,6 +530,25 @@ color0     #000000
 
     // --- #RGB shorthand tests ---
 
+    #[test]
+    fn parse_kitty_colors_tab_and_aligned_columns() {
+        let output = "color0\t#000\ncolor1   #CC0000   # red\n  color2\t \t#4E9A06\t# green\n";
+        let palette = parse_kitty_colors(output);
+        assert_eq!(palette[0..3], [0, 0, 0]);
+        assert_eq!(palette[3..6], [0xCC, 0, 0]);
+        assert_eq!(palette[6..9], [0x4E, 0x9A, 0x06]);
+        assert_eq!(palette[9..], DEFAULT_PALETTE[9..]);
+    }
+
+    #[test]
+    fn parse_kitty_default_colors_trailing_comment() {
+        let output = "foreground\t#dddddd  # text\nbackground      #1a1b26\n";
+        assert_eq!(
+            parse_kitty_default_colors(output),
+            Some(([0xDD, 0xDD, 0xDD], [0x1A, 0x1B, 0x26]))
+        );
+    }
+
     #[test]
     fn parse_kitty_colors_short_hex() {
         let palette = parse_kitty_colors("color0 #F0A\n");
@@ -350,4 +575,107 @@ color0     #000000
         assert_eq!(palette[4], 0x22);
         assert_eq!(palette[5], 0x33);
     }
+
+    // --- named themes ---
+
+    #[test]
+    fn named_themes_slot1_color() {
+        assert_eq!(named("default").unwrap()[3..6], [0xCC, 0x00, 0x00]);
+        assert_eq!(named("solarized-dark").unwrap()[3..6], [0xDC, 0x32, 0x2F]);
+        assert_eq!(named("gruvbox").unwrap()[3..6], [0xCC, 0x24, 0x1D]);
+        assert_eq!(named("dracula").unwrap()[3..6], [0xFF, 0x55, 0x55]);
+    }
+
+    #[test]
+    fn named_covers_all_theme_names() {
+        for name in THEME_NAMES {
+            assert!(named(name).is_some(), "{name} should resolve");
+        }
+    }
+
+    #[test]
+    fn named_unknown_returns_none() {
+        assert_eq!(named("no-such-theme"), None);
+        assert_eq!(named(""), None);
+    }
+
+    // --- nearest_xterm_index ---
+
+    #[test]
+    fn nearest_xterm_index_exact_colors() {
+        assert_eq!(nearest_xterm_index([255, 0, 0]), 196);
+        assert_eq!(nearest_xterm_index([95, 135, 175]), 67);
+        assert_eq!(nearest_xterm_index([0, 0, 0]), 16);
+        assert_eq!(nearest_xterm_index([238, 238, 238]), 255);
+        assert_eq!(nearest_xterm_index([8, 8, 8]), 232);
+    }
+
+    #[test]
+    fn nearest_xterm_index_near_identical_colors_agree() {
+        assert_eq!(
+            nearest_xterm_index([250, 10, 10]),
+            nearest_xterm_index([252, 4, 8])
+        );
+        assert_eq!(
+            nearest_xterm_index([100, 140, 170]),
+            nearest_xterm_index([97, 133, 178])
+        );
+    }
+
+    #[test]
+    fn nearest_xterm_index_prefers_grayscale_for_grays() {
+        // (128,128,128): gray 244 = 128 beats cube (135,135,135)
+        assert_eq!(nearest_xterm_index([128, 128, 128]), 244);
+        assert_eq!(quantize_rgb([30, 30, 31]), [28, 28, 28]);
+    }
+
+    #[test]
+    fn parse_kitty_default_colors_fg_bg() {
+        let output = "foreground #c0caf5\nbackground #1a1b26\ncolor0 #15161e\n";
+        assert_eq!(
+            parse_kitty_default_colors(output),
+            Some(([0xC0, 0xCA, 0xF5], [0x1A, 0x1B, 0x26]))
+        );
+        assert_eq!(parse_kitty_default_colors("foreground #ffffff\n"), None);
+    }
+
+    // --- layering ---
+
+    #[test]
+    fn parse_palette_file_kitty_conf() {
+        let text =
+            "# my theme\nforeground #ffffff\ncolor1 #112233\ncolor12  #abc\ncolor16 #000000\n";
+        let layer = parse_palette_file(text).unwrap();
+        assert_eq!(layer[1], Some([0x11, 0x22, 0x33]));
+        assert_eq!(layer[12], Some([0xAA, 0xBB, 0xCC]));
+        assert_eq!(layer.iter().flatten().count(), 2);
+        assert!(parse_palette_file("color3 nope\n")
+            .unwrap_err()
+            .starts_with("line 1"));
+    }
+
+    #[test]
+    fn parse_color_arg_valid_and_invalid() {
+        assert_eq!(parse_color_arg("4=#0000ff"), Ok((4, [0, 0, 0xFF])));
+        assert_eq!(parse_color_arg("15=fff"), Ok((15, [0xFF, 0xFF, 0xFF])));
+        for value in ["16=#000000", "1", "x=#000000", "1=#12345"] {
+            assert!(parse_color_arg(value).is_err(), "{value:?} should fail");
+        }
+    }
+
+    #[test]
+    fn later_layers_win_per_slot() {
+        let mut file = [None; 16];
+        file[1] = Some([1, 1, 1]);
+        file[2] = Some([2, 2, 2]);
+        let palette = PaletteBuilder::new(GRUVBOX_DARK)
+            .overlay(&file)
+            .colors(&[(2, [9, 9, 9]), (2, [8, 8, 8])])
+            .build();
+        // Slot 0 from the base, 1 from the file, 2 from the last --color
+        assert_eq!(palette[0..3], GRUVBOX_DARK[0..3]);
+        assert_eq!(palette[3..6], [1, 1, 1]);
+        assert_eq!(palette[6..9], [8, 8, 8]);
+        assert_eq!(palette[9..], GRUVBOX_DARK[9..]);
+    }
 }
@@ -0,0 +1,113 @@
+use std::cell::RefCell;
+use std::fmt::Write as FmtWrite;
+use std::path::Path;
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+
+use crate::output;
+use crate::serve::json_escape;
+
+/// One completed span, in microseconds since the tracer started.
+struct Event {
+    name: &'static str,
+    detail: Option<String>,
+    start_us: u128,
+    dur_us: u128,
+}
+
+/// Coarse span recorder for `--profile`, written as Chrome trace JSON
+/// (load it in `chrome://tracing` or Perfetto).
+pub(crate) struct Tracer {
+    origin: Instant,
+    events: RefCell<Vec<Event>>,
+}
+
+impl Tracer {
+    pub(crate) fn new() -> Self {
+        Tracer {
+            origin: Instant::now(),
+            events: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Record a span named `name` running from `start` until now.
+    pub(crate) fn record(&self, name: &'static str, detail: Option<String>, start: Instant) {
+        let start_us = start.saturating_duration_since(self.origin).as_micros();
+        let dur_us = start.elapsed().as_micros();
+        self.events.borrow_mut().push(Event {
+            name,
+            detail,
+            start_us,
+            dur_us,
+        });
+    }
+
+    /// Run `f` and record it as a span.
+    pub(crate) fn span<T>(&self, name: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let value = f();
+        self.record(name, None, start);
+        value
+    }
+
+    pub(crate) fn to_json(&self) -> String {
+        let mut s = String::from("{\"traceEvents\":[");
+        for (i, ev) in self.events.borrow().iter().enumerate() {
+            if i > 0 {
+                s.push(',');
+            }
+            write!(
+                s,
+                "{{\"name\":\"{}\",\"ph\":\"X\",\"ts\":{},\"dur\":{},\"pid\":1,\"tid\":1",
+                ev.name, ev.start_us, ev.dur_us
+            )
+            .unwrap();
+            if let Some(detail) = &ev.detail {
+                write!(s, ",\"args\":{{\"detail\":\"{}\"}}", json_escape(detail)).unwrap();
+            }
+            s.push('}');
+        }
+        s.push_str("]}\n");
+        s
+    }
+
+    pub(crate) fn write(&self, path: &Path) -> Result<()> {
+        output::atomic_write(path, self.to_json().as_bytes())
+            .with_context(|| format!("failed to write profile to {}", path.display()))
+    }
+}
+
+/// Run `f`, recording it as a span when a tracer is present.
+pub(crate) fn span<T>(tracer: Option<&Tracer>, name: &'static str, f: impl FnOnce() -> T) -> T {
+    match tracer {
+        Some(tracer) => tracer.span(name, f),
+        None => f(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_trace_is_valid_json() {
+        let json: serde_json::Value = serde_json::from_str(&Tracer::new().to_json()).unwrap();
+        assert_eq!(json["traceEvents"].as_array().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn spans_are_complete_events_in_order() {
+        let tracer = Tracer::new();
+        let v = tracer.span("parse", || 42);
+        assert_eq!(v, 42);
+        tracer.record("process", Some("lines 1-1000".into()), Instant::now());
+
+        let json: serde_json::Value = serde_json::from_str(&tracer.to_json()).unwrap();
+        let events = json["traceEvents"].as_array().unwrap();
+        assert_eq!(events[0]["name"], "parse");
+        assert_eq!(events[0]["ph"], "X");
+        assert_eq!(events[1]["args"]["detail"], "lines 1-1000");
+        assert!(events[1]["ts"].as_u64() >= events[0]["ts"].as_u64());
+    }
+}
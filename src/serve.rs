@@ -0,0 +1,293 @@
+use std::fmt::Write as FmtWrite;
+use std::io::{Read, Write};
+
+use anyhow::{bail, Context, Result};
+
+use crate::kitty;
+use crate::terminal::{self, ProcessedScreen};
+
+/// Read one frame. Returns `Ok(None)` on EOF at a frame boundary.
+pub(crate) fn read_frame<R: Read>(r: &mut R, max_len: u64) -> Result<Option<Vec<u8>>> {
+    let mut len_buf = [0u8; 4];
+    let mut filled = 0;
+    while filled < len_buf.len() {
+        let n = r.read(&mut len_buf[filled..])?;
+        if n == 0 {
+            if filled == 0 {
+                return Ok(None);
+            }
+            bail!("truncated frame header ({filled} of 4 bytes)");
+        }
+        filled += n;
+    }
+    let len = u32::from_le_bytes(len_buf);
+    if u64::from(len) > max_len {
+        bail!("frame of {len} bytes exceeds limit of {max_len} bytes");
+    }
+    let mut payload = vec![0u8; len as usize];
+    r.read_exact(&mut payload)
+        .context("truncated frame payload")?;
+    Ok(Some(payload))
+}
+
+/// Write one frame and flush it.
+pub(crate) fn write_frame<W: Write>(w: &mut W, payload: &[u8]) -> Result<()> {
+    let len = u32::try_from(payload.len()).context("frame payload exceeds u32::MAX bytes")?;
+    w.write_all(&len.to_le_bytes())?;
+    w.write_all(payload)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// A parsed request: pipe data clamped to the geometry limit, the warning
+/// if it had to be, palette and terminal bytes.
+type Request<'a> = (kitty::PipeData, Option<String>, [u8; 48], &'a [u8]);
+
+/// Split a request payload into pipe data, palette and terminal bytes,
+/// clamping the geometry to `max_geometry` like the Kitty and tmux runs.
+fn parse_request(payload: &[u8], max_geometry: u16) -> Result<Request<'_>> {
+    let nl = payload
+        .iter()
+        .position(|&b| b == b'\n')
+        .context("request: missing newline after pipe data")?;
+    let pipe_data_str =
+        std::str::from_utf8(&payload[..nl]).context("request: pipe data is not UTF-8")?;
+    let mut pipe_data = kitty::parse_pipe_data_str(pipe_data_str)?;
+    let warning = pipe_data.clamp_geometry(max_geometry);
+    let rest = &payload[nl + 1..];
+    if rest.len() < 48 {
+        bail!("request: expected 48 palette bytes, got {}", rest.len());
+    }
+    let mut palette = [0u8; 48];
+    palette.copy_from_slice(&rest[..48]);
+    Ok((pipe_data, warning, palette, &rest[48..]))
+}
+
+/// Escape a string for inclusion inside JSON double quotes.
+pub(crate) fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                write!(out, "\\u{:04x}", c as u32).unwrap();
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Serialize a `ProcessedScreen` as a JSON object.
+pub(crate) fn screen_to_json(screen: &ProcessedScreen) -> String {
+    let mut s = String::new();
+    s.push_str("{\"lines\":[");
+    for (i, line) in screen.lines.iter().enumerate() {
+        if i > 0 {
+            s.push(',');
+        }
+        write!(s, "{{\"text\":\"{}\",\"spans\":[", json_escape(&line.text)).unwrap();
+        for (j, span) in line.spans.iter().enumerate() {
+            if j > 0 {
+                s.push(',');
+            }
+            write!(
+                s,
+                "{{\"start_byte\":{},\"end_byte\":{},\"face\":\"{}\"}}",
+                span.start_byte,
+                span.end_byte,
+                json_escape(&span.face)
+            )
+            .unwrap();
+        }
+        s.push_str("]}");
+    }
+    write!(
+        s,
+        "],\"cursor\":{{\"line\":{},\"col\":{}}},\"viewport_top_line\":{},\
+         \"viewport_bottom_line\":{},\"page_boundaries\":[",
+        screen.cursor.line,
+        screen.cursor.col,
+        screen.viewport_top_line,
+        screen.viewport_bottom_line
+    )
+    .unwrap();
+    for (i, line) in screen.page_boundaries.iter().enumerate() {
+        if i > 0 {
+            s.push(',');
+        }
+        write!(s, "{line}").unwrap();
+    }
+    s.push_str("]}");
+    s
+}
+
+fn handle_request(payload: &[u8], limits: Limits, faces: &mut terminal::FaceCache) -> String {
+    match parse_request(payload, limits.max_geometry) {
+        Ok((pipe_data, warning, palette, data)) => {
+            let screen = terminal::process_bytes_cached(
+                &pipe_data,
+                data,
+                &palette,
+                limits.max_scrollback_lines,
+                &terminal::ProcessOptions::default(),
+                faces,
+            );
+            let mut json = screen_to_json(&screen);
+            if let Some(warning) = warning {
+                json.insert_str(1, &format!("\"warning\":\"{}\",", json_escape(&warning)));
+            }
+            json
+        }
+        Err(e) => format!("{{\"error\":\"{}\"}}", json_escape(&format!("{e:#}"))),
+    }
+}
+
+/// Per-request limits for `--serve`.
+#[derive(Clone, Copy)]
+pub(crate) struct Limits {
+    pub max_frame_bytes: u64,
+    pub max_scrollback_lines: usize,
+    /// Largest screen side accepted from pipe data (`KAKOUNE_SCROLLBACK_MAX_GEOMETRY`)
+    pub max_geometry: u16,
+}
+
+/// `--serve` mode: answer length-prefixed frames until EOF.
+///
+/// Every frame (both directions) is a `u32` little-endian length followed by
+/// that many payload bytes. A request payload is the pipe data in
+/// `KITTY_PIPE_DATA` format plus `\n`, then 48 palette bytes, then the raw
+/// terminal bytes. The response is the `ProcessedScreen` as JSON, or
+/// `{"error": "..."}` if the request was malformed; a clamped geometry adds
+/// a `"warning"` field. Framing errors end the session.
+pub(crate) fn serve<R: Read, W: Write>(mut r: R, mut w: W, limits: Limits) -> Result<()> {
+    let mut faces = terminal::FaceCache::new();
+    while let Some(payload) = read_frame(&mut r, limits.max_frame_bytes)? {
+        let response = handle_request(&payload, limits, &mut faces);
+        write_frame(&mut w, response.as_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::palette::DEFAULT_PALETTE;
+
+    fn request(pipe_data: &str, data: &[u8]) -> Vec<u8> {
+        let mut payload = format!("{pipe_data}\n").into_bytes();
+        payload.extend_from_slice(&DEFAULT_PALETTE);
+        payload.extend_from_slice(data);
+        payload
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, b"hello").unwrap();
+        write_frame(&mut buf, b"").unwrap();
+        assert_eq!(&buf[..4], &5u32.to_le_bytes());
+
+        let mut r = std::io::Cursor::new(buf);
+        assert_eq!(read_frame(&mut r, 100).unwrap().unwrap(), b"hello");
+        assert_eq!(read_frame(&mut r, 100).unwrap().unwrap(), b"");
+        assert!(read_frame(&mut r, 100).unwrap().is_none());
+    }
+
+    #[test]
+    fn read_frame_rejects_oversized() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, &[0u8; 10]).unwrap();
+        assert!(read_frame(&mut std::io::Cursor::new(buf), 9).is_err());
+    }
+
+    #[test]
+    fn read_frame_truncated() {
+        assert!(read_frame(&mut std::io::Cursor::new(vec![1u8, 0]), 100).is_err());
+        let mut buf = 10u32.to_le_bytes().to_vec();
+        buf.extend_from_slice(b"short");
+        assert!(read_frame(&mut std::io::Cursor::new(buf), 100).is_err());
+    }
+
+    #[test]
+    fn json_escape_special_chars() {
+        assert_eq!(json_escape("a\"b\\c"), "a\\\"b\\\\c");
+        assert_eq!(json_escape("x\ny\t"), "x\\ny\\t");
+        assert_eq!(json_escape("\x07"), "\\u0007");
+        assert_eq!(json_escape("日本"), "日本");
+    }
+
+    const LIMITS: Limits = Limits {
+        max_frame_bytes: 1 << 20,
+        max_scrollback_lines: 1000,
+        max_geometry: 1000,
+    };
+
+    #[test]
+    fn bad_request_gets_error_response() {
+        let response = handle_request(b"not pipe data", LIMITS, &mut terminal::FaceCache::new());
+        assert!(response.starts_with("{\"error\":"), "got: {response}");
+    }
+
+    #[test]
+    fn huge_geometry_is_clamped_with_warning() {
+        let payload = request("0:1,1:65535,65535", b"hi");
+        let (pipe_data, warning, _, _) = parse_request(&payload, 1000).unwrap();
+        assert_eq!((pipe_data.lines, pipe_data.columns), (1000, 1000));
+        assert!(warning.is_some());
+
+        let limits = Limits {
+            max_geometry: 40,
+            ..LIMITS
+        };
+        let response = handle_request(
+            &request("0:1,1:24,500", b"hi"),
+            limits,
+            &mut terminal::FaceCache::new(),
+        );
+        let json: serde_json::Value = serde_json::from_str(&response).unwrap();
+        assert!(
+            json["warning"].as_str().unwrap().contains("500x24"),
+            "{response}"
+        );
+        assert_eq!(json["lines"][0]["text"], "hi");
+    }
+
+    #[test]
+    fn serve_two_frames_over_duplex_pipe() {
+        use std::os::unix::net::UnixStream;
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let server_thread = std::thread::spawn(move || {
+            let reader = server.try_clone().unwrap();
+            serve(reader, server, LIMITS).unwrap();
+        });
+
+        let mut frames = Vec::new();
+        write_frame(&mut frames, &request("0:1,1:24,80", b"\x1b[31mRed\x1b[0m")).unwrap();
+        write_frame(&mut frames, &request("0:3,2:24,80", b"one\r\ntwo")).unwrap();
+        client.write_all(&frames).unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+
+        let first = read_frame(&mut client, 1 << 20).unwrap().unwrap();
+        let second = read_frame(&mut client, 1 << 20).unwrap().unwrap();
+        assert!(read_frame(&mut client, 1 << 20).unwrap().is_none());
+        server_thread.join().unwrap();
+
+        let first = String::from_utf8(first).unwrap();
+        assert_eq!(
+            first,
+            "{\"lines\":[{\"text\":\"Red\",\"spans\":[{\"start_byte\":1,\"end_byte\":4,\
+             \"face\":\"rgb:CC0000,default\"}]}],\"cursor\":{\"line\":1,\"col\":1},\
+             \"viewport_top_line\":1,\"viewport_bottom_line\":1,\"page_boundaries\":[]}"
+        );
+        let second = String::from_utf8(second).unwrap();
+        assert!(second.contains("{\"text\":\"one\",\"spans\":[]}"));
+        assert!(second.contains("{\"text\":\"two\",\"spans\":[]}"));
+        assert!(second.contains("\"cursor\":{\"line\":2,\"col\":3}"));
+    }
+}
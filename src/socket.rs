@@ -0,0 +1,118 @@
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::output::escape_kak_single_quote;
+use crate::serve::{json_escape, write_frame};
+use crate::TargetId;
+
+/// The `--socket` message: a JSON object with the artifact paths and the
+/// Kakoune commands that open them in an existing session.
+pub(crate) fn artifacts_message(
+    target: &TargetId,
+    tmp_dir: &Path,
+    text_path: &Path,
+    init_path: &Path,
+) -> String {
+    let text = text_path.display().to_string();
+    let init = init_path.display().to_string();
+    let commands = format!(
+        "edit -existing '{}'; source '{}'",
+        escape_kak_single_quote(&text),
+        escape_kak_single_quote(&init)
+    );
+    format!(
+        "{{\"target\":\"{}\",\"tmp_dir\":\"{}\",\"text\":\"{}\",\"init\":\"{}\",\"kak_commands\":\"{}\"}}",
+        json_escape(&target.to_string()),
+        json_escape(&tmp_dir.display().to_string()),
+        json_escape(&text),
+        json_escape(&init),
+        json_escape(&commands)
+    )
+}
+
+/// Hand the artifacts to the daemon listening on `socket` as one frame
+/// (see `serve::write_frame`). The temp dir is left in place; init.kak
+/// removes it when the capture's buffer is deleted.
+pub(crate) fn send_artifacts(
+    socket: &Path,
+    target: &TargetId,
+    tmp_dir: tempfile::TempDir,
+    text_path: &Path,
+    init_path: &Path,
+) -> Result<()> {
+    let mut stream = UnixStream::connect(socket)
+        .with_context(|| format!("failed to connect to {}", socket.display()))?;
+    let tmp_path = tmp_dir.keep();
+    let message = artifacts_message(target, &tmp_path, text_path, init_path);
+    if let Err(e) = write_frame(&mut stream, message.as_bytes()) {
+        let _ = std::fs::remove_dir_all(&tmp_path);
+        return Err(e).with_context(|| format!("failed to write to {}", socket.display()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serve::read_frame;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn send_artifacts_writes_one_frame() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("ksb.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let reader = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let frame = read_frame(&mut stream, 1 << 20).unwrap().unwrap();
+            // The sender closes the connection after its single frame
+            assert!(read_frame(&mut stream, 1 << 20).unwrap().is_none());
+            frame
+        });
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let tmp_path = tmp_dir.path().to_path_buf();
+        let text = tmp_path.join("it's.txt");
+        let init = tmp_path.join("init.kak");
+        let target = TargetId::Tmux("%5".to_string());
+        send_artifacts(&socket, &target, tmp_dir, &text, &init).unwrap();
+
+        let frame = reader.join().unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&frame).unwrap();
+        assert_eq!(json["target"], "tmux:%5");
+        assert_eq!(json["tmp_dir"], tmp_path.display().to_string());
+        assert_eq!(json["text"], text.display().to_string());
+        assert_eq!(json["init"], init.display().to_string());
+        assert_eq!(
+            json["kak_commands"],
+            format!(
+                "edit -existing '{}/it''s.txt'; source '{}/init.kak'",
+                tmp_path.display(),
+                tmp_path.display()
+            )
+        );
+        // Kept for the daemon's kak to use
+        assert!(tmp_path.is_dir());
+        std::fs::remove_dir_all(&tmp_path).unwrap();
+    }
+
+    #[test]
+    fn send_artifacts_without_listener_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let tmp_path = tmp_dir.path().to_path_buf();
+        let err = send_artifacts(
+            &dir.path().join("missing.sock"),
+            &TargetId::Tmux("%5".to_string()),
+            tmp_dir,
+            &tmp_path.join("text.txt"),
+            &tmp_path.join("init.kak"),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("failed to connect"), "{err}");
+        // Dropped without being kept, so it is cleaned up
+        assert!(!tmp_path.exists());
+    }
+}
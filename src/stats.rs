@@ -0,0 +1,243 @@
+use std::fmt;
+
+use unicode_width::UnicodeWidthChar;
+
+use crate::terminal::{self, ProcessedScreen};
+
+/// Shape of a capture, printed by `--stats` for performance bug reports.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Stats {
+    pub input_bytes: usize,
+    pub lines: usize,
+    pub max_line_bytes: usize,
+    pub distinct_faces: usize,
+    pub spans: usize,
+    pub scrollback_depth: usize,
+}
+
+impl Stats {
+    pub(crate) fn compute(screen: &ProcessedScreen, input_bytes: usize) -> Self {
+        let spans = screen.lines.iter().map(|l| l.spans.len()).sum();
+        Stats {
+            input_bytes,
+            lines: screen.lines.len(),
+            max_line_bytes: screen.lines.iter().map(|l| l.text.len()).max().unwrap_or(0),
+            distinct_faces: terminal::distinct_faces(screen).len(),
+            spans,
+            scrollback_depth: screen.viewport_top_line.saturating_sub(1),
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "input_bytes:      {}", self.input_bytes)?;
+        writeln!(f, "lines:            {}", self.lines)?;
+        writeln!(f, "max_line_bytes:   {}", self.max_line_bytes)?;
+        writeln!(f, "distinct_faces:   {}", self.distinct_faces)?;
+        writeln!(f, "spans:            {}", self.spans)?;
+        writeln!(f, "scrollback_depth: {}", self.scrollback_depth)
+    }
+}
+
+/// Shape of the raw input bytes, printed by `--validate` before any
+/// terminal processing.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct RawReport {
+    pub input_bytes: usize,
+    pub invalid_utf8: usize,
+    pub first_invalid: Option<usize>,
+    pub escape_sequences: usize,
+    pub lines: usize,
+    pub max_line_width: usize,
+}
+
+impl RawReport {
+    pub(crate) fn analyze(data: &[u8]) -> Self {
+        let mut invalid_utf8 = 0;
+        let mut first_invalid = None;
+        let mut rest = data;
+        while let Err(e) = std::str::from_utf8(rest) {
+            let offset = data.len() - rest.len() + e.valid_up_to();
+            first_invalid.get_or_insert(offset);
+            invalid_utf8 += 1;
+            match e.error_len() {
+                Some(len) => rest = &rest[e.valid_up_to() + len..],
+                // Truncated sequence at the very end
+                None => break,
+            }
+        }
+
+        let text = String::from_utf8_lossy(data);
+        let mut escape_sequences = 0;
+        let mut max_line_width = 0;
+        let mut lines = 0;
+        for line in text.split_inclusive('\n') {
+            lines += 1;
+            let (width, escapes) = visible_width(line);
+            escape_sequences += escapes;
+            max_line_width = max_line_width.max(width);
+        }
+
+        RawReport {
+            input_bytes: data.len(),
+            invalid_utf8,
+            first_invalid,
+            escape_sequences,
+            lines,
+            max_line_width,
+        }
+    }
+}
+
+/// Display width of `line` with escape sequences and control characters
+/// skipped, and the number of escape sequences seen. CSI runs to its final
+/// byte, OSC/DCS/APC/PM/SOS strings to BEL or ST, anything else is
+/// ESC plus one character.
+fn visible_width(line: &str) -> (usize, usize) {
+    let mut width = 0;
+    let mut escapes = 0;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            width += c.width().unwrap_or(0);
+            continue;
+        }
+        escapes += 1;
+        match chars.next() {
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('\x40'..='\x7e').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            Some(']' | 'P' | '_' | '^' | 'X') => {
+                let mut prev = '\0';
+                for c in chars.by_ref() {
+                    if c == '\x07' || (prev == '\x1b' && c == '\\') {
+                        break;
+                    }
+                    prev = c;
+                }
+            }
+            _ => {}
+        }
+    }
+    (width, escapes)
+}
+
+impl fmt::Display for RawReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "input_bytes:      {}", self.input_bytes)?;
+        match self.first_invalid {
+            None => writeln!(f, "utf8:             valid")?,
+            Some(offset) => writeln!(
+                f,
+                "utf8:             {} invalid sequence(s), first at byte {offset}",
+                self.invalid_utf8
+            )?,
+        }
+        writeln!(f, "escape_sequences: {}", self.escape_sequences)?;
+        writeln!(f, "lines:            {}", self.lines)?;
+        writeln!(f, "max_line_width:   {}", self.max_line_width)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kitty::PipeData;
+    use crate::palette;
+    use crate::terminal::DEFAULT_MAX_SCROLLBACK_LINES;
+
+    fn screen_for(input: &[u8], lines: u16) -> ProcessedScreen {
+        let pd = PipeData {
+            cursor_x: 0,
+            cursor_y: 0,
+            lines,
+            columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        terminal::process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        )
+    }
+
+    #[test]
+    fn stats_for_single_colored_span() {
+        let input = b"\x1b[31mRed\x1b[0m plain\r\nsecond line";
+        let stats = Stats::compute(&screen_for(input, 24), input.len());
+        assert_eq!(
+            stats,
+            Stats {
+                input_bytes: input.len(),
+                lines: 2,
+                max_line_bytes: 11,
+                distinct_faces: 1,
+                spans: 1,
+                scrollback_depth: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn stats_counts_repeated_faces_once() {
+        let input = b"\x1b[31mA\x1b[0m \x1b[31mB\x1b[0m \x1b[32mC\x1b[0m";
+        let stats = Stats::compute(&screen_for(input, 24), input.len());
+        assert_eq!(stats.spans, 3);
+        assert_eq!(stats.distinct_faces, 2);
+    }
+
+    #[test]
+    fn stats_scrollback_depth() {
+        let mut input = Vec::new();
+        for i in 0..30 {
+            input.extend_from_slice(format!("line {i}\r\n").as_bytes());
+        }
+        let stats = Stats::compute(&screen_for(&input, 10), input.len());
+        assert_eq!(stats.scrollback_depth, 21);
+        assert_eq!(stats.lines, 30);
+    }
+
+    #[test]
+    fn raw_report_with_one_invalid_byte() {
+        let input = b"\x1b[31mred\x1b[0m ok\r\nbad \xff byte \x1b]0;title\x07\r\n\xe6\x97\xa5\xe6\x9c\xac\n";
+        assert_eq!(
+            RawReport::analyze(input),
+            RawReport {
+                input_bytes: input.len(),
+                invalid_utf8: 1,
+                first_invalid: Some(21),
+                escape_sequences: 3,
+                lines: 3,
+                max_line_width: 11,
+            }
+        );
+        let text = RawReport::analyze(input).to_string();
+        assert!(text.contains("utf8:             1 invalid sequence(s), first at byte 21\n"));
+    }
+
+    #[test]
+    fn raw_report_valid_and_empty() {
+        let report = RawReport::analyze("日本語".as_bytes());
+        assert_eq!(report.first_invalid, None);
+        assert_eq!((report.lines, report.max_line_width), (1, 6));
+        assert!(report.to_string().contains("utf8:             valid\n"));
+
+        let empty = RawReport::analyze(b"");
+        assert_eq!((empty.lines, empty.escape_sequences), (0, 0));
+    }
+
+    #[test]
+    fn stats_display() {
+        let input = b"\x1b[31mRed\x1b[0m";
+        let text = Stats::compute(&screen_for(input, 24), input.len()).to_string();
+        assert!(text.contains("distinct_faces:   1\n"));
+        assert!(text.contains("input_bytes:      12\n"));
+    }
+}
@@ -1,5 +1,12 @@
-use crate::kitty::PipeData;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as FmtWrite;
+use std::ops::Range;
+use std::rc::Rc;
+use std::time::Instant;
+
+use crate::kitty::{CursorShape, PipeData};
 use crate::palette;
+use crate::profile::{self, Tracer};
 
 /// Cursor position (calculated as Kakoune byte offset)
 pub struct CursorPosition {
@@ -11,46 +18,338 @@ pub struct ProcessedScreen {
     pub lines: Vec<ProcessedLine>,
     pub cursor: CursorPosition,
     pub viewport_top_line: usize, // 1-based: first visible line of original terminal
+    pub viewport_bottom_line: usize, // 1-based: last visible line of original terminal
+    pub page_boundaries: Vec<usize>, // 1-based lines where a page (prompt / form feed) starts
+    pub line_sources: Vec<LineSource>, // one per line: where it was read from (diagnostics)
+    pub scrolled_by: usize,       // lines the source window was scrolled up (Kitty)
+    pub truncated_lines: usize,   // leading lines dropped by auto-trim
+    pub history_full: bool,       // vt100's scrollback filled up, older lines were dropped
+    pub cursor_shape: Option<CursorShape>, // terminal cursor shape, if reported
+}
+
+/// Where an output line was read from in the vt100 grid.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LineSource {
+    pub offset: usize,      // scrollback offset the row was read at
+    pub row: u16,           // 0-based visible row at that offset (pane row for tmux)
+    pub continuation: bool, // the previous row soft-wrapped into this one
 }
 
+#[derive(Clone, PartialEq)]
 pub struct ProcessedLine {
     pub text: String,
     pub spans: Vec<Span>,
 }
 
+#[derive(Clone, PartialEq)]
 pub struct Span {
     pub start_byte: usize, // 1-based byte offset
     pub end_byte: usize,   // 1-based byte offset (exclusive)
-    pub face: String,      // Kakoune face string (e.g. "rgb:FF0000,default+bi")
+    pub face: Rc<str>,     // Kakoune face string (e.g. "rgb:FF0000,default+bi")
 }
 
 pub(crate) const DEFAULT_MAX_SCROLLBACK_LINES: usize = 200_000;
+pub(crate) const TMUX_DEFAULT_MAX_SCROLLBACK_LINES: usize = 50_000;
+
+/// Processing switches that change how faces are generated.
+#[derive(Clone, Copy, Default)]
+pub struct ProcessOptions {
+    /// Snap colors to the nearest xterm-256 color to bound distinct faces
+    pub quantize: bool,
+    /// Keep only the last this-many lines (never cutting into the viewport)
+    pub auto_trim: Option<usize>,
+    /// Keep each row's trailing spaces, so byte offsets match terminal columns
+    pub no_trailing_trim: bool,
+    /// Read only the visible screen, skipping the scrollback walk
+    pub screen_only: bool,
+    /// Draw bold text in colors 0-7 with the bright variant (8-15)
+    pub bold_is_bright: bool,
+    /// Which Kakoune flag each cell attribute becomes
+    pub attr_map: AttrMap,
+    /// Palette slots for the default side of a cell with one explicit color
+    /// (from `COLORFGBG`)
+    pub default_colors: Option<(u8, u8)>,
+    /// Leave blank cells out of spans whose face only sets a foreground
+    pub skip_blank_fg: bool,
+    /// Pane row the capture's first line came from, added to recorded
+    /// source rows (a tmux capture started mid-screen with `-S N`)
+    pub source_row_offset: u16,
+}
+
+/// Kakoune face flags, in the order they are written; bit `i` of
+/// `FaceKey::attrs` is `FACE_FLAGS[i]`.
+const FACE_FLAGS: [char; 5] = ['b', 'd', 'i', 'u', 'r'];
+
+/// `FaceKey::attrs` bits that still show on a blank cell: `u` and `r`.
+const BLANK_VISIBLE_ATTRS: u8 = 1 << 3 | 1 << 4;
+
+type CellAttr = fn(&vt100::Cell) -> bool;
+
+/// Cell attributes by name, in `AttrMap` order.
+const CELL_ATTRS: [(&str, CellAttr); 5] = [
+    ("bold", vt100::Cell::bold),
+    ("dim", vt100::Cell::dim),
+    ("italic", vt100::Cell::italic),
+    ("underline", vt100::Cell::underline),
+    ("inverse", vt100::Cell::inverse),
+];
+
+/// Cell attribute → Kakoune face flag table. By default every attribute
+/// maps to its own flag (bold → `b`, italic → `i`, ...); an entry can be
+/// remapped to another flag or dropped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AttrMap {
+    /// `FaceKey::attrs` bit per `CELL_ATTRS` entry; 0 drops the attribute
+    bits: [u8; 5],
+}
+
+impl Default for AttrMap {
+    fn default() -> Self {
+        AttrMap {
+            bits: [0x01, 0x02, 0x04, 0x08, 0x10],
+        }
+    }
+}
+
+impl AttrMap {
+    /// Apply comma-separated `attr=flag` overrides to the default table,
+    /// e.g. `italic=` (drop italic) or `inverse=u,dim=`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut map = AttrMap::default();
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (name, flag) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("expected attr=flag, got '{entry}'"))?;
+            let idx = CELL_ATTRS
+                .iter()
+                .position(|(attr, _)| *attr == name.trim())
+                .ok_or_else(|| format!("unknown attribute '{}'", name.trim()))?;
+            map.bits[idx] = match flag.trim() {
+                "" => 0,
+                flag => {
+                    let pos = FACE_FLAGS
+                        .iter()
+                        .position(|f| f.to_string() == flag)
+                        .ok_or_else(|| format!("unknown face flag '{flag}'"))?;
+                    1 << pos
+                }
+            };
+        }
+        Ok(map)
+    }
+}
+
+/// Per-cell face inputs (and the trim switch) threaded through row processing.
+struct FaceCtx<'a> {
+    palette: &'a [u8; 48],
+    quantize: bool,
+    bold_is_bright: bool,
+    default_fg: Option<[u8; 3]>,
+    default_bg: Option<[u8; 3]>,
+    attr_map: AttrMap,
+    no_trailing_trim: bool,
+    skip_blank_fg: bool,
+}
+
+/// Rendered face strings by `FaceKey`, so each distinct face is formatted
+/// once and shared by every span drawn in it. Keys hold resolved RGB, so one
+/// cache can be reused across captures with different palettes (`--serve`
+/// keeps one for the whole session).
+#[derive(Default)]
+pub struct FaceCache {
+    faces: HashMap<FaceKey, Rc<str>>,
+}
+
+impl FaceCache {
+    /// Entries kept before the cache is cleared, bounding memory for
+    /// long sessions of truecolor-heavy input.
+    const MAX_ENTRIES: usize = 65_536;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn face(&mut self, key: &FaceKey) -> Rc<str> {
+        if let Some(face) = self.faces.get(key) {
+            return Rc::clone(face);
+        }
+        if self.faces.len() >= Self::MAX_ENTRIES {
+            self.faces.clear();
+        }
+        let face: Rc<str> = face_key_to_string(key).into();
+        self.faces.insert(*key, Rc::clone(&face));
+        face
+    }
+}
 
 /// Process from byte slice directly
+#[cfg(test)]
 pub fn process_bytes(
     pipe_data: &PipeData,
     data: &[u8],
     palette: &[u8; 48],
     max_scrollback_lines: usize,
 ) -> ProcessedScreen {
+    process_bytes_with(
+        pipe_data,
+        data,
+        palette,
+        max_scrollback_lines,
+        &ProcessOptions::default(),
+    )
+}
+
+/// `process_bytes` with explicit `ProcessOptions`
+#[cfg(test)]
+pub fn process_bytes_with(
+    pipe_data: &PipeData,
+    data: &[u8],
+    palette: &[u8; 48],
+    max_scrollback_lines: usize,
+    opts: &ProcessOptions,
+) -> ProcessedScreen {
+    process_bytes_traced(pipe_data, data, palette, max_scrollback_lines, opts, None)
+}
+
+/// Lines per `process` span recorded by `--profile`.
+const TRACE_CHUNK_LINES: usize = 1000;
+
+/// `process_bytes_with`, recording `parse` and per-chunk `process` spans
+pub(crate) fn process_bytes_traced(
+    pipe_data: &PipeData,
+    data: &[u8],
+    palette: &[u8; 48],
+    max_scrollback_lines: usize,
+    opts: &ProcessOptions,
+    tracer: Option<&Tracer>,
+) -> ProcessedScreen {
+    let mut faces = FaceCache::new();
+    process_bytes_inner(
+        pipe_data,
+        data,
+        palette,
+        max_scrollback_lines,
+        opts,
+        tracer,
+        &mut faces,
+    )
+}
+
+/// `process_bytes_with`, reusing face strings from `faces` across calls
+pub fn process_bytes_cached(
+    pipe_data: &PipeData,
+    data: &[u8],
+    palette: &[u8; 48],
+    max_scrollback_lines: usize,
+    opts: &ProcessOptions,
+    faces: &mut FaceCache,
+) -> ProcessedScreen {
+    process_bytes_inner(
+        pipe_data,
+        data,
+        palette,
+        max_scrollback_lines,
+        opts,
+        None,
+        faces,
+    )
+}
+
+fn process_bytes_inner(
+    pipe_data: &PipeData,
+    data: &[u8],
+    palette: &[u8; 48],
+    max_scrollback_lines: usize,
+    opts: &ProcessOptions,
+    tracer: Option<&Tracer>,
+    faces: &mut FaceCache,
+) -> ProcessedScreen {
+    let slot_rgb = |idx| palette::color_to_rgb(vt100::Color::Idx(idx), palette);
+    let ctx = FaceCtx {
+        palette,
+        quantize: opts.quantize,
+        bold_is_bright: opts.bold_is_bright,
+        default_fg: opts.default_colors.and_then(|(fg, _)| slot_rgb(fg)),
+        default_bg: opts.default_colors.and_then(|(_, bg)| slot_rgb(bg)),
+        attr_map: opts.attr_map,
+        no_trailing_trim: opts.no_trailing_trim,
+        skip_blank_fg: opts.skip_blank_fg,
+    };
     // Clamp to minimum 1: vt100::Parser panics with 0 rows or 0 columns.
     // parse_pipe_data_str() already rejects 0, but this guards against
     // direct PipeData construction (e.g. in tests).
     let rows = pipe_data.lines.max(1);
     let cols = pipe_data.columns.max(1);
 
-    let mut parser = vt100::Parser::new(rows, cols, max_scrollback_lines);
-    parser.process(data);
+    let data = if cols < 2 {
+        narrow_wide_chars(data)
+    } else {
+        std::borrow::Cow::Borrowed(data)
+    };
+    let data = &data[..];
+
+    // Slack above the limit: a history longer than the limit means lines
+    // were evicted, while one exactly at the limit is still complete
+    let history_cap = max_scrollback_lines.saturating_add(HISTORY_SLACK);
+    let mut parser = vt100::Parser::new(rows, cols, history_cap);
+
+    // Feed the input in segments split at page markers so the cursor line
+    // right after each marker can be recorded as a page boundary: both as
+    // vt100 sees it and counting the lines pushed into the history so far,
+    // which still holds once older lines are evicted.
+    let mut page_boundaries = Vec::new();
+    let mut pushed = Some(0);
+    profile::span(tracer, "parse", || {
+        let mut pos = 0;
+        for end in find_page_markers(data) {
+            pushed = process_counting_history(&mut parser, &data[pos..end], history_cap, pushed);
+            pos = end;
+            let screen = parser.screen_mut();
+            let row = usize::from(screen.cursor_position().0);
+            page_boundaries.push((cursor_absolute_line(screen), pushed.map(|p| p + row + 1)));
+        }
+        pushed = process_counting_history(&mut parser, &data[pos..], history_cap, pushed);
+    });
 
     let screen = parser.screen_mut();
 
-    // Find total scrollback lines
+    // Find total scrollback lines; with screen_only the history is skipped
+    // and lines are numbered from the top of the visible screen
     screen.set_scrollback(usize::MAX);
-    let total_sb = screen.scrollback();
+    let evicted = screen.scrollback() > max_scrollback_lines;
+    let held = screen.scrollback().min(max_scrollback_lines);
+    let skipped = if opts.screen_only { held } else { 0 };
+    let total_sb = held - skipped;
+    // Evicted lines shift every boundary up; ones that fell into the
+    // evicted part are gone
+    let mut page_boundaries: Vec<usize> = match pushed {
+        Some(pushed) => page_boundaries
+            .iter()
+            .filter_map(|&(_, line)| line?.checked_sub(pushed - held))
+            .filter(|&line| line > 0)
+            .collect(),
+        None if !evicted => page_boundaries.iter().map(|&(line, _)| line).collect(),
+        None => {
+            if !page_boundaries.is_empty() {
+                eprintln!(
+                    "warning: the scrollback overflowed KAKOUNE_SCROLLBACK_MAX_LINES \
+                     ({max_scrollback_lines}) and could not be re-paged, page marks dropped."
+                );
+            }
+            Vec::new()
+        }
+    };
+    if skipped > 0 {
+        page_boundaries.retain(|&l| l > skipped);
+        for boundary in &mut page_boundaries {
+            *boundary -= skipped;
+        }
+    }
 
     let viewport_top_line_raw = total_sb.saturating_add(1);
 
     let mut lines = Vec::new();
+    let mut line_sources = Vec::new();
     let mut cursor = CursorPosition { line: 1, col: 1 };
 
     // The cursor in the output buffer is at line (total_sb + cursor_y + 1), 1-based
@@ -58,38 +357,59 @@ pub fn process_bytes(
         .saturating_add(pipe_data.cursor_y)
         .saturating_add(1);
 
-    // Read initial screen rows from the max scrollback offset
-    screen.set_scrollback(total_sb);
-    for row in 0..rows {
-        push_row(
+    let mut chunk_start = Instant::now();
+    let mut trace_chunk = |lines: &[ProcessedLine], force: bool| {
+        let Some(tracer) = tracer else { return };
+        let len = lines.len();
+        if len > 0 && (len.is_multiple_of(TRACE_CHUNK_LINES) || force) {
+            let first = (len - 1) / TRACE_CHUNK_LINES * TRACE_CHUNK_LINES + 1;
+            tracer.record("process", Some(format!("lines {first}-{len}")), chunk_start);
+            chunk_start = Instant::now();
+        }
+    };
+
+    let mut push_row = |screen: &vt100::Screen, row: u16, lines: &mut Vec<ProcessedLine>| {
+        let line_idx = lines.len();
+        let is_cursor_line = line_idx + 1 == cursor_output_line;
+        let cursor_x = is_cursor_line.then_some(pipe_data.cursor_x);
+        lines.push(process_row(
             screen,
             row,
-            pipe_data,
-            cursor_output_line,
-            &mut lines,
+            pipe_data.columns,
+            cursor_x,
             &mut cursor,
-            palette,
-        );
+            &ctx,
+            faces,
+        ));
+        if is_cursor_line {
+            cursor.line = line_idx + 1;
+        }
+    };
+
+    // Read initial screen rows from the max scrollback offset
+    screen.set_scrollback(total_sb);
+    for row in 0..rows {
+        push_row(screen, row, &mut lines);
+        line_sources.push(line_source(screen, total_sb, row));
+        trace_chunk(&lines, false);
     }
 
     // Read one new line at the bottom for each offset decrease
     for offset in (0..total_sb).rev() {
         screen.set_scrollback(offset);
-        push_row(
-            screen,
-            rows - 1,
-            pipe_data,
-            cursor_output_line,
-            &mut lines,
-            &mut cursor,
-            palette,
-        );
+        push_row(screen, rows - 1, &mut lines);
+        line_sources.push(line_source(screen, offset, rows - 1));
+        trace_chunk(&lines, false);
+    }
+    if !lines.len().is_multiple_of(TRACE_CHUNK_LINES) {
+        trace_chunk(&lines, true);
     }
 
     // Trim trailing empty lines
+    // (all spaces when trailing trim is off)
     while lines
         .last()
-        .is_some_and(|l| l.text.is_empty() && l.spans.is_empty())
+        .is_some_and(|l| l.text.bytes().all(|b| b == b' ') && l.spans.is_empty())
     {
         lines.pop();
     }
@@ -106,219 +426,1271 @@ pub fn process_bytes(
     } else {
         viewport_top_line_raw.min(lines.len())
     };
+    let viewport_bottom_line = total_sb
+        .saturating_add(usize::from(rows))
+        .min(lines.len())
+        .max(viewport_top_line);
 
-    ProcessedScreen {
+    // Drop boundaries that fell into the trimmed tail
+    page_boundaries.retain(|&l| l <= lines.len());
+    page_boundaries.dedup();
+    line_sources.truncate(lines.len());
+    for source in &mut line_sources {
+        source.row = source.row.saturating_add(opts.source_row_offset);
+    }
+
+    let mut screen = ProcessedScreen {
         lines,
         cursor,
         viewport_top_line,
+        viewport_bottom_line,
+        page_boundaries,
+        line_sources,
+        scrolled_by: pipe_data.scrolled_by,
+        truncated_lines: 0,
+        history_full: !opts.screen_only && evicted,
+        cursor_shape: pipe_data.cursor_shape,
+    };
+    if let Some(keep) = opts.auto_trim {
+        keep_last_lines(&mut screen, keep);
     }
+    screen
 }
 
-fn push_row(
-    screen: &vt100::Screen,
-    row: u16,
-    pipe_data: &PipeData,
-    cursor_output_line: usize,
-    lines: &mut Vec<ProcessedLine>,
-    cursor: &mut CursorPosition,
-    palette: &[u8; 48],
-) {
-    let line_idx = lines.len();
-    let is_cursor_line = line_idx + 1 == cursor_output_line;
-    let cursor_x = if is_cursor_line {
-        Some(pipe_data.cursor_x)
-    } else {
-        None
-    };
-    let pline = process_row(screen, row, pipe_data.columns, cursor_x, cursor, palette);
-    lines.push(pline);
-    if is_cursor_line {
-        cursor.line = line_idx + 1;
+/// Drop leading lines so at most `keep` remain, but never any from the
+/// viewport down, and record the count in `truncated_lines`. Loading and
+/// positioning a multi-hundred-thousand-line buffer is slow in Kakoune.
+fn keep_last_lines(screen: &mut ProcessedScreen, keep: usize) {
+    let len = screen.lines.len();
+    let keep = keep.max(len + 1 - screen.viewport_top_line.min(len + 1));
+    if len <= keep {
+        return;
     }
+    let drop = len - keep;
+    drop_leading_lines(screen, drop);
+    screen.truncated_lines = drop;
 }
 
-fn process_row(
-    screen: &vt100::Screen,
-    row: u16,
-    cols: u16,
-    cursor_x: Option<usize>,
-    cursor: &mut CursorPosition,
-    palette: &[u8; 48],
-) -> ProcessedLine {
-    let mut text = String::new();
-    let mut spans: Vec<Span> = Vec::new();
-    let mut current_key: Option<FaceKey> = None;
-    let mut span_start_byte: usize = 1; // 1-based
+/// Remove the first `drop` lines, moving the cursor, viewport and page
+/// boundaries up with the rest. A cursor on a removed line goes to 1.1.
+fn drop_leading_lines(screen: &mut ProcessedScreen, drop: usize) {
+    screen.lines.drain(..drop);
+    screen
+        .line_sources
+        .drain(..drop.min(screen.line_sources.len()));
+    if screen.cursor.line > drop {
+        screen.cursor.line -= drop;
+    } else {
+        screen.cursor = CursorPosition { line: 1, col: 1 };
+    }
+    screen.viewport_top_line = screen.viewport_top_line.saturating_sub(drop).max(1);
+    screen.viewport_bottom_line = screen.viewport_bottom_line.saturating_sub(drop).max(1);
+    screen.page_boundaries.retain(|&l| l > drop);
+    for boundary in &mut screen.page_boundaries {
+        *boundary -= drop;
+    }
+}
 
-    for col in 0..cols {
-        let Some(cell) = screen.cell(row, col) else {
-            break;
-        };
+/// Remove blank lines (only spaces, no spans) from the top of the capture,
+/// such as those left above a clear-screen, the way trailing blank lines
+/// are always trimmed.
+pub fn trim_leading_blank_lines(screen: &mut ProcessedScreen) {
+    let blank = screen
+        .lines
+        .iter()
+        .take_while(|l| l.text.bytes().all(|b| b == b' ') && l.spans.is_empty())
+        .count();
+    if blank > 0 {
+        drop_leading_lines(screen, blank);
+    }
+}
 
-        // Skip wide continuation cells
-        if cell.is_wide_continuation() {
-            continue;
+/// Move the cursor to the start of the first match of `pattern`.
+/// Returns `false` (cursor untouched) when no line matches.
+pub fn jump_to_match(screen: &mut ProcessedScreen, pattern: &regex::Regex) -> bool {
+    for (idx, line) in screen.lines.iter().enumerate() {
+        if let Some(m) = pattern.find(&line.text) {
+            screen.cursor = CursorPosition {
+                line: idx + 1,
+                col: m.start() + 1,
+            };
+            return true;
         }
+    }
+    false
+}
 
-        let contents = cell.contents();
-        let byte_offset_before = text.len(); // 0-based
+/// Strip spans outside the page (command block) containing the cursor line.
+/// A screen without page boundaries is a single block and is left untouched.
+pub fn focus_cursor_block(screen: &mut ProcessedScreen) {
+    let cursor_line = screen.cursor.line;
+    let start = screen
+        .page_boundaries
+        .iter()
+        .copied()
+        .filter(|&l| l <= cursor_line)
+        .max()
+        .unwrap_or(1);
+    let end = screen
+        .page_boundaries
+        .iter()
+        .copied()
+        .find(|&l| l > cursor_line)
+        .unwrap_or(usize::MAX);
 
-        // Track cursor column (byte offset)
-        if let Some(cx) = cursor_x {
-            if usize::from(col) == cx {
-                cursor.col = byte_offset_before + 1; // 1-based
-            }
+    for (idx, line) in screen.lines.iter_mut().enumerate() {
+        let line_num = idx + 1;
+        if line_num < start || line_num >= end {
+            line.spans.clear();
         }
+    }
+}
 
-        // Append cell content (or space if empty)
-        if contents.is_empty() {
-            text.push(' ');
-        } else {
-            text.push_str(contents);
-        }
+/// One command's output, cut out of the scrollback by `split_commands`.
+pub struct CommandBlock {
+    /// The block's first line (normally the prompt and command), trimmed
+    pub title: String,
+    pub screen: ProcessedScreen,
+}
 
-        let key = cell_face_key(cell, palette);
+/// Longest `CommandBlock::title`, in chars.
+const MAX_TITLE_CHARS: usize = 60;
 
-        if key != current_key {
-            let byte_now = byte_offset_before + 1; // 1-based
-            if let Some(ref k) = current_key {
-                if span_start_byte < byte_now {
-                    spans.push(Span {
-                        start_byte: span_start_byte,
-                        end_byte: byte_now,
-                        face: face_key_to_string(k),
-                    });
-                }
-            }
-            current_key = key;
-            span_start_byte = byte_now;
+/// Cut the 1-based line range `capture` at its page boundaries (OSC 133
+/// prompts / form feeds) into one block per command, leaving out lines
+/// added around the capture. Blank blocks are skipped; a range without
+/// boundaries is a single block.
+pub fn split_commands(screen: &ProcessedScreen, capture: Range<usize>) -> Vec<CommandBlock> {
+    let mut starts = vec![capture.start];
+    starts.extend(
+        screen
+            .page_boundaries
+            .iter()
+            .copied()
+            .filter(|l| capture.start < *l && *l < capture.end),
+    );
+    starts.dedup();
+    let mut blocks = Vec::new();
+    for (i, &start) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).copied().unwrap_or(capture.end);
+        let Some(lines) = screen.lines.get(start - 1..end - 1) else {
+            continue;
+        };
+        if lines.iter().all(|l| l.text.trim().is_empty()) {
+            continue;
         }
+        let title = lines[0].text.trim().chars().take(MAX_TITLE_CHARS).collect();
+        let line_sources = screen
+            .line_sources
+            .get(start - 1..end - 1)
+            .map_or_else(Vec::new, <[LineSource]>::to_vec);
+        blocks.push(CommandBlock {
+            title,
+            screen: ProcessedScreen {
+                lines: lines.to_vec(),
+                cursor: CursorPosition { line: 1, col: 1 },
+                viewport_top_line: 1,
+                viewport_bottom_line: lines.len(),
+                page_boundaries: vec![],
+                line_sources,
+                scrolled_by: 0,
+                truncated_lines: 0,
+                history_full: false,
+                cursor_shape: None,
+            },
+        });
     }
+    blocks
+}
 
-    // Close final span
-    let byte_end = text.len() + 1; // 1-based, exclusive
-    if let Some(ref k) = current_key {
-        if span_start_byte < byte_end {
-            spans.push(Span {
-                start_byte: span_start_byte,
-                end_byte: byte_end,
-                face: face_key_to_string(k),
-            });
+/// Turn every carriage return that starts overwriting a line into CR LF,
+/// so each state of a `\r`-redrawn progress line stays as its own line.
+/// CRs followed by LF, another CR, or the end of the input are kept as-is.
+pub fn expand_carriage_returns(data: Vec<u8>) -> Vec<u8> {
+    let is_overwrite =
+        |i: usize| data[i] == b'\r' && !matches!(data.get(i + 1), None | Some(b'\n' | b'\r'));
+    let count = (0..data.len()).filter(|&i| is_overwrite(i)).count();
+    if count == 0 {
+        return data;
+    }
+    let mut out = Vec::with_capacity(data.len() + count);
+    for (i, &b) in data.iter().enumerate() {
+        out.push(b);
+        if is_overwrite(i) {
+            out.push(b'\n');
         }
     }
+    out
+}
 
-    // Trim trailing spaces from text
-    let trimmed_len = text.trim_end().len();
-    if trimmed_len < text.len() {
-        text.truncate(trimmed_len);
-        // Adjust spans that extend beyond trimmed text
-        let max_byte = trimmed_len + 1; // 1-based exclusive
-        spans.retain(|s| s.start_byte < max_byte);
-        if let Some(last) = spans.last_mut() {
-            if last.end_byte > max_byte {
-                last.end_byte = max_byte;
-            }
-        }
+/// Replace double-width characters with `?` for a 1-column terminal.
+/// A wide char cannot fit there, and vt100 panics trying to place one
+/// (it silently drops U+FFFD, so that is no use as a placeholder).
+fn narrow_wide_chars(data: &[u8]) -> std::borrow::Cow<'_, [u8]> {
+    use unicode_width::UnicodeWidthChar;
+
+    let text = String::from_utf8_lossy(data);
+    if !text.chars().any(|c| c.width() == Some(2)) {
+        return std::borrow::Cow::Borrowed(data);
     }
+    let narrowed: String = text
+        .chars()
+        .map(|c| if c.width() == Some(2) { '?' } else { c })
+        .collect();
+    std::borrow::Cow::Owned(narrowed.into_bytes())
+}
 
-    ProcessedLine { text, spans }
+/// Pad lines whose trailing span carries a background color out to `cols`
+/// display columns and stretch that span to match, so full-line highlights
+/// render edge-to-edge instead of stopping at the trimmed text.
+pub fn fill_line_backgrounds(screen: &mut ProcessedScreen, cols: u16) {
+    use unicode_width::UnicodeWidthStr;
+
+    for line in &mut screen.lines {
+        let text_end = line.text.len() + 1;
+        let Some(last) = line.spans.last_mut() else {
+            continue;
+        };
+        if last.end_byte != text_end || !face_has_background(&last.face) {
+            continue;
+        }
+        let width = line.text.width();
+        let pad = usize::from(cols).saturating_sub(width);
+        line.text.extend(std::iter::repeat_n(' ', pad));
+        last.end_byte = line.text.len() + 1;
+    }
 }
 
-/// Cell visual attributes as a normalized, zero-allocation key.
-///
-/// fg/bg store resolved RGB (not raw `vt100::Color`) so that
-/// `Idx(1)` and `Rgb(204,0,0)` compare equal when the palette matches.
-#[derive(Clone, Copy, PartialEq, Eq)]
-struct FaceKey {
-    fg: Option<[u8; 3]>,
-    bg: Option<[u8; 3]>,
-    attrs: u8, // b=0x01, d=0x02, i=0x04, u=0x08, r=0x10
+fn face_has_background(face: &str) -> bool {
+    let colors = face.split('+').next().unwrap_or(face);
+    colors
+        .split_once(',')
+        .is_some_and(|(_, bg)| bg != "default")
 }
 
-fn cell_face_key(cell: &vt100::Cell, palette: &[u8; 48]) -> Option<FaceKey> {
-    let fg = palette::color_to_rgb(cell.fgcolor(), palette);
-    let bg = palette::color_to_rgb(cell.bgcolor(), palette);
+/// Hard-wrap lines longer than `max_bytes` bytes (breaking only at char
+/// boundaries), splitting spans at each break and re-mapping the cursor,
+/// viewport, page boundaries and line sources onto the new lines.
+pub fn hard_wrap(screen: &mut ProcessedScreen, max_bytes: usize) {
+    let max_bytes = max_bytes.max(1);
+    let old_lines = std::mem::take(&mut screen.lines);
+    let old_sources = std::mem::take(&mut screen.line_sources);
+    // first_new[i] = 0-based index of the first new line for old line i;
+    // the extra trailing entry marks the end of the last old line
+    let mut first_new = Vec::with_capacity(old_lines.len() + 1);
+    let mut cursor = None;
 
-    let mut attrs = 0u8;
-    if cell.bold() {
-        attrs |= 0x01;
-    }
-    if cell.dim() {
-        attrs |= 0x02;
-    }
-    if cell.italic() {
-        attrs |= 0x04;
+    for (idx, line) in old_lines.into_iter().enumerate() {
+        first_new.push(screen.lines.len());
+        let is_cursor_line = idx + 1 == screen.cursor.line;
+        let cursor_byte = screen.cursor.col - 1;
+
+        for (piece_idx, (start, end)) in wrap_points(&line.text, max_bytes).into_iter().enumerate()
+        {
+            let is_last = end == line.text.len();
+            if is_cursor_line && cursor.is_none() && (cursor_byte < end || is_last) {
+                cursor = Some((screen.lines.len() + 1, cursor_byte - start + 1));
+            }
+            let spans = line
+                .spans
+                .iter()
+                .filter_map(|s| {
+                    let s_start = (s.start_byte - 1).max(start);
+                    let s_end = (s.end_byte - 1).min(end);
+                    (s_start < s_end).then(|| Span {
+                        start_byte: s_start - start + 1,
+                        end_byte: s_end - start + 1,
+                        face: s.face.clone(),
+                    })
+                })
+                .collect();
+            screen.lines.push(ProcessedLine {
+                text: line.text[start..end].to_string(),
+                spans,
+            });
+            if let Some(src) = old_sources.get(idx) {
+                screen.line_sources.push(LineSource {
+                    continuation: src.continuation || piece_idx > 0,
+                    ..*src
+                });
+            }
+        }
     }
-    if cell.underline() {
-        attrs |= 0x08;
+    first_new.push(screen.lines.len());
+
+    let map_first = |line: usize| first_new.get(line - 1).map_or(line, |&i| i + 1);
+    if let Some((line, col)) = cursor {
+        screen.cursor.line = line;
+        screen.cursor.col = col;
     }
-    if cell.inverse() {
-        attrs |= 0x10;
+    screen.viewport_top_line = map_first(screen.viewport_top_line);
+    screen.viewport_bottom_line = first_new
+        .get(screen.viewport_bottom_line)
+        .copied()
+        .unwrap_or(screen.viewport_bottom_line)
+        .max(screen.viewport_top_line);
+    for boundary in &mut screen.page_boundaries {
+        *boundary = map_first(*boundary);
     }
+}
 
-    if fg.is_none() && bg.is_none() && attrs == 0 {
-        return None;
+/// Prefix every line with its right-aligned buffer line number (`  7: `),
+/// shifting spans and the cursor column past the prefix. All prefixes
+/// share the width of the largest number, so columns stay aligned.
+/// Returns the prefix length.
+pub fn number_lines(screen: &mut ProcessedScreen) -> usize {
+    let width = screen.lines.len().max(1).to_string().len();
+    let prefix_len = width + 2;
+    for (idx, line) in screen.lines.iter_mut().enumerate() {
+        line.text = format!("{:>width$}: {}", idx + 1, line.text);
+        for span in &mut line.spans {
+            span.start_byte += prefix_len;
+            span.end_byte += prefix_len;
+        }
     }
-
-    Some(FaceKey { fg, bg, attrs })
+    screen.cursor.col += prefix_len;
+    prefix_len
 }
 
-fn face_key_to_string(key: &FaceKey) -> String {
-    use std::fmt::Write;
-    let mut s = String::with_capacity(32);
-
-    match key.fg {
-        Some([r, g, b]) => {
-            write!(s, "rgb:{r:02X}{g:02X}{b:02X}").unwrap();
+/// Cover the uncolored gaps between spans on a line with explicit
+/// `default,default` spans, so no highlighter can bleed a face into them.
+/// Text before the first and after the last span is left alone.
+pub fn fill_span_gaps(screen: &mut ProcessedScreen) {
+    for line in &mut screen.lines {
+        if line.spans.len() < 2 {
+            continue;
         }
-        None => s.push_str("default"),
-    }
-    s.push(',');
-    match key.bg {
-        Some([r, g, b]) => {
-            write!(s, "rgb:{r:02X}{g:02X}{b:02X}").unwrap();
+        line.spans.sort_by_key(|span| span.start_byte);
+        let mut filled = Vec::with_capacity(line.spans.len() * 2);
+        for span in std::mem::take(&mut line.spans) {
+            if let Some(prev_end) = filled.last().map(|prev: &Span| prev.end_byte) {
+                if prev_end < span.start_byte {
+                    filled.push(Span {
+                        start_byte: prev_end,
+                        end_byte: span.start_byte,
+                        face: "default,default".into(),
+                    });
+                }
+            }
+            filled.push(span);
         }
-        None => s.push_str("default"),
+        line.spans = filled;
     }
+}
 
-    if key.attrs != 0 {
+/// `face` with its attribute flags in `FACE_FLAGS` order (other flags after
+/// them, sorted) and duplicates dropped, so `+ib` and `+bi` compare equal.
+/// A trailing `@base` and faces without flags are kept as they are.
+pub fn canonicalize_face(face: &str) -> String {
+    let Some((colors, rest)) = face.split_once('+') else {
+        return face.to_string();
+    };
+    let (flags, base) = match rest.split_once('@') {
+        Some((flags, base)) => (flags, Some(base)),
+        None => (rest, None),
+    };
+    let rank = |c: char| {
+        FACE_FLAGS
+            .iter()
+            .position(|&f| f == c)
+            .unwrap_or(FACE_FLAGS.len())
+    };
+    let mut flags: Vec<char> = flags.chars().collect();
+    flags.sort_by_key(|&c| (rank(c), c));
+    flags.dedup();
+
+    let mut s = colors.to_string();
+    if !flags.is_empty() {
         s.push('+');
-        if key.attrs & 0x01 != 0 {
-            s.push('b');
-        }
-        if key.attrs & 0x02 != 0 {
-            s.push('d');
-        }
-        if key.attrs & 0x04 != 0 {
-            s.push('i');
-        }
-        if key.attrs & 0x08 != 0 {
-            s.push('u');
-        }
-        if key.attrs & 0x10 != 0 {
-            s.push('r');
-        }
+        s.extend(flags);
+    }
+    if let Some(base) = base {
+        s.push('@');
+        s.push_str(base);
     }
-
     s
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::kitty::PipeData;
+/// The distinct span faces of `screen` in canonical form, in order of
+/// first use.
+pub fn distinct_faces(screen: &ProcessedScreen) -> Vec<String> {
+    let mut seen = HashSet::new();
+    screen
+        .lines
+        .iter()
+        .flat_map(|line| &line.spans)
+        .map(|span| canonicalize_face(&span.face))
+        .filter(|face| seen.insert(face.clone()))
+        .collect()
+}
 
-    fn default_pipe_data() -> PipeData {
-        PipeData {
-            cursor_x: 0,
-            cursor_y: 0,
-            lines: 24,
-            columns: 80,
-        }
+/// Append a legend after the capture: a blank line, then one line per
+/// distinct face showing the face string drawn in that face. Face lines
+/// are indented by `indent` spaces, to skip an unnumbered number column.
+pub fn append_legend(screen: &mut ProcessedScreen, indent: usize) {
+    let faces = distinct_faces(screen);
+    if faces.is_empty() {
+        return;
     }
+    screen.lines.push(ProcessedLine {
+        text: String::new(),
+        spans: Vec::new(),
+    });
+    for face in faces {
+        screen.lines.push(ProcessedLine {
+            spans: vec![Span {
+                start_byte: indent + 1,
+                end_byte: indent + face.len() + 1,
+                face: face.as_str().into(),
+            }],
+            text: format!("{:indent$}{face}", ""),
+        });
+    }
+}
 
-    #[test]
-    fn process_plain_text() {
+/// Columns between the terminal's default tab stops.
+const TAB_STOP: usize = 8;
+
+/// Mark the character starting at each tab-stop column (8, 16, ...) with a
+/// one-character span in `face`, as vertical guides through columnar
+/// output. Columns count display width, so a wide character straddling a
+/// stop gets no guide; characters already in a colored span keep their face.
+pub fn add_tab_guides(screen: &mut ProcessedScreen, face: &str) {
+    use unicode_width::UnicodeWidthChar;
+
+    for line in &mut screen.lines {
+        let mut guides = Vec::new();
+        let mut col = 0;
+        for (byte, c) in line.text.char_indices() {
+            let start = byte + 1;
+            if col > 0
+                && col % TAB_STOP == 0
+                && !line
+                    .spans
+                    .iter()
+                    .any(|s| s.start_byte <= start && start < s.end_byte)
+            {
+                guides.push(Span {
+                    start_byte: start,
+                    end_byte: start + c.len_utf8(),
+                    face: face.into(),
+                });
+            }
+            col += c.width().unwrap_or(0);
+        }
+        if !guides.is_empty() {
+            line.spans.extend(guides);
+            line.spans.sort_by_key(|s| s.start_byte);
+        }
+    }
+}
+
+fn dump_color(color: vt100::Color) -> String {
+    match color {
+        vt100::Color::Default => "default".to_string(),
+        vt100::Color::Idx(idx) => format!("idx:{idx}"),
+        vt100::Color::Rgb(r, g, b) => format!("rgb:{r:02X}{g:02X}{b:02X}"),
+    }
+}
+
+/// Feed `data` to vt100 and list every cell of the visible screen as
+/// `row col contents fg bg attrs flags` (0-based, tab-separated), exactly
+/// as vt100 reports it, before any of our processing.
+pub(crate) fn dump_cells(pipe_data: &PipeData, data: &[u8]) -> String {
+    let rows = pipe_data.lines.max(1);
+    let cols = pipe_data.columns.max(1);
+    let mut parser = vt100::Parser::new(rows, cols, 0);
+    parser.process(data);
+    let screen = parser.screen();
+
+    let mut out = String::from("row\tcol\tcontents\tfg\tbg\tattrs\tflags\n");
+    for row in 0..rows {
+        for col in 0..cols {
+            let Some(cell) = screen.cell(row, col) else {
+                continue;
+            };
+            let attrs: Vec<&str> = [
+                ("bold", cell.bold()),
+                ("dim", cell.dim()),
+                ("italic", cell.italic()),
+                ("underline", cell.underline()),
+                ("inverse", cell.inverse()),
+            ]
+            .into_iter()
+            .filter_map(|(name, on)| on.then_some(name))
+            .collect();
+            let flags = if cell.is_wide() {
+                "wide"
+            } else if cell.is_wide_continuation() {
+                "continuation"
+            } else {
+                "-"
+            };
+            let contents = cell.contents();
+            let contents = if contents.is_empty() {
+                "-".to_string()
+            } else {
+                format!("{contents:?}")
+            };
+            writeln!(
+                out,
+                "{row}\t{col}\t{contents}\t{}\t{}\t{}\t{flags}",
+                dump_color(cell.fgcolor()),
+                dump_color(cell.bgcolor()),
+                if attrs.is_empty() {
+                    "-".to_string()
+                } else {
+                    attrs.join(",")
+                },
+            )
+            .unwrap();
+        }
+    }
+    out
+}
+
+/// Whether older lines are missing from `screen`: auto-trim dropped some, or
+/// the capture overflowed the vt100 scrollback limit.
+pub fn is_truncated(screen: &ProcessedScreen) -> bool {
+    screen.truncated_lines > 0 || screen.history_full
+}
+
+/// Prepend a `── scrollback truncated ... ──` line in `face` when older
+/// lines are missing (see `is_truncated`), shifting the cursor down by one.
+pub fn prepend_truncation_marker(screen: &mut ProcessedScreen, face: &str) {
+    if !is_truncated(screen) {
+        return;
+    }
+    let omitted = match screen.truncated_lines {
+        0 => "older lines omitted".to_string(),
+        n => format!("{n} older lines omitted"),
+    };
+    prepend_header(
+        screen,
+        &format!("── scrollback truncated ({omitted}) ──"),
+        face,
+    );
+}
+
+/// Insert `text` as a new first line drawn in `face`, shifting the cursor,
+/// viewport and page boundaries down by one. Control characters become
+/// spaces so the header stays a single line. The header has no grid row, so
+/// its line source is a placeholder at offset 0, row 0.
+pub fn prepend_header(screen: &mut ProcessedScreen, text: &str, face: &str) {
+    let text: String = text
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect();
+    let spans = if text.is_empty() {
+        Vec::new()
+    } else {
+        vec![Span {
+            start_byte: 1,
+            end_byte: text.len() + 1,
+            face: face.into(),
+        }]
+    };
+    screen.lines.insert(0, ProcessedLine { text, spans });
+    screen.line_sources.insert(
+        0,
+        LineSource {
+            offset: 0,
+            row: 0,
+            continuation: false,
+        },
+    );
+    screen.cursor.line += 1;
+    screen.viewport_top_line += 1;
+    screen.viewport_bottom_line += 1;
+    for boundary in &mut screen.page_boundaries {
+        *boundary += 1;
+    }
+}
+
+/// Collapse runs of identical consecutive lines (same text and spans) into
+/// their first line, suffixed with ` (×N)`, and remap the cursor, viewport
+/// and page boundaries onto the collapsed lines.
+pub fn squeeze_repeats(screen: &mut ProcessedScreen) {
+    let old_lines = std::mem::take(&mut screen.lines);
+    let old_sources = std::mem::take(&mut screen.line_sources);
+    // new_index[i] = 0-based index of the collapsed line holding old line i
+    let mut new_index = Vec::with_capacity(old_lines.len());
+    let mut counts: Vec<usize> = Vec::new();
+
+    for (idx, line) in old_lines.into_iter().enumerate() {
+        if screen.lines.last() == Some(&line) {
+            *counts.last_mut().unwrap() += 1;
+        } else {
+            screen.lines.push(line);
+            counts.push(1);
+            if let Some(src) = old_sources.get(idx) {
+                screen.line_sources.push(*src);
+            }
+        }
+        new_index.push(screen.lines.len() - 1);
+    }
+    for (line, count) in screen.lines.iter_mut().zip(counts) {
+        if count > 1 {
+            let sep = if line.text.is_empty() { "" } else { " " };
+            line.text.push_str(&format!("{sep}(×{count})"));
+        }
+    }
+
+    remap_collapsed_lines(screen, &new_index);
+}
+
+/// Collapse runs of blank lines (whitespace only, no spans) into a single
+/// blank line, like `cat -s`, remapping the cursor, viewport and page
+/// boundaries onto the remaining lines.
+pub fn squeeze_blank_lines(screen: &mut ProcessedScreen) {
+    let is_blank = |line: &ProcessedLine| line.spans.is_empty() && line.text.trim().is_empty();
+    let old_lines = std::mem::take(&mut screen.lines);
+    let old_sources = std::mem::take(&mut screen.line_sources);
+    let mut new_index = Vec::with_capacity(old_lines.len());
+    let mut cursor_merged = false;
+
+    for (idx, line) in old_lines.into_iter().enumerate() {
+        let merge = is_blank(&line) && screen.lines.last().is_some_and(is_blank);
+        if merge {
+            cursor_merged |= idx + 1 == screen.cursor.line;
+        } else {
+            screen.lines.push(line);
+            if let Some(src) = old_sources.get(idx) {
+                screen.line_sources.push(*src);
+            }
+        }
+        new_index.push(screen.lines.len() - 1);
+    }
+
+    remap_collapsed_lines(screen, &new_index);
+    if cursor_merged {
+        if let Some(line) = screen.lines.get(screen.cursor.line - 1) {
+            // The column came from another line; keep it on a character
+            // of the kept one, at most its last
+            let text = &line.text;
+            let last = text.char_indices().next_back().map_or(0, |(i, _)| i);
+            let mut byte = (screen.cursor.col - 1).min(last);
+            while !text.is_char_boundary(byte) {
+                byte -= 1;
+            }
+            screen.cursor.col = byte + 1;
+        }
+    }
+}
+
+/// Point the cursor, viewport and page boundaries at the lines their old
+/// lines were merged into; `new_index[i]` is the 0-based new index of old
+/// line `i`.
+fn remap_collapsed_lines(screen: &mut ProcessedScreen, new_index: &[usize]) {
+    let map = |line: usize| new_index.get(line - 1).map_or(line, |&i| i + 1);
+    screen.cursor.line = map(screen.cursor.line);
+    screen.viewport_top_line = map(screen.viewport_top_line);
+    screen.viewport_bottom_line = map(screen.viewport_bottom_line).max(screen.viewport_top_line);
+    for boundary in &mut screen.page_boundaries {
+        *boundary = map(*boundary);
+    }
+    screen.page_boundaries.dedup();
+}
+
+/// Byte ranges of the pieces `text` is split into; always at least one.
+pub(crate) fn wrap_points(text: &str, max_bytes: usize) -> Vec<(usize, usize)> {
+    let mut points = Vec::new();
+    let mut start = 0;
+    while text.len() - start > max_bytes {
+        let mut end = start + max_bytes;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end == start {
+            // A single char wider than max_bytes gets a piece of its own
+            end = start + text[start..].chars().next().map_or(1, char::len_utf8);
+        }
+        points.push((start, end));
+        start = end;
+    }
+    points.push((start, text.len()));
+    points
+}
+
+/// Rewrite span colors that would vanish against Kakoune's `Default` face:
+/// a foreground equal to the editor background, or a background equal to the
+/// editor foreground, becomes `default`. Spans left with no color or
+/// attribute are dropped.
+pub fn match_default_colors(screen: &mut ProcessedScreen, kak_fg: [u8; 3], kak_bg: [u8; 3]) {
+    let kak_fg = rgb_to_kak(kak_fg);
+    let kak_bg = rgb_to_kak(kak_bg);
+    for line in &mut screen.lines {
+        for span in &mut line.spans {
+            let (colors, attrs) = match span.face.split_once('+') {
+                Some((colors, attrs)) => (colors, Some(attrs)),
+                None => (&*span.face, None),
+            };
+            let Some((fg, bg)) = colors.split_once(',') else {
+                continue;
+            };
+            let fg = if fg == kak_bg { "default" } else { fg };
+            let bg = if bg == kak_fg { "default" } else { bg };
+            let face = match attrs {
+                Some(attrs) => format!("{fg},{bg}+{attrs}"),
+                None => format!("{fg},{bg}"),
+            };
+            span.face = face.into();
+        }
+        line.spans.retain(|s| &*s.face != "default,default");
+    }
+}
+
+/// Face given to leading timestamps by `dim_timestamps`.
+const TIMESTAMP_FACE: &str = "comment";
+
+/// Give a leading timestamp on each line the `comment` face, replacing any
+/// colors it had. The rest of the line keeps its spans.
+pub fn dim_timestamps(screen: &mut ProcessedScreen) {
+    for line in &mut screen.lines {
+        let Some(len) = leading_timestamp_len(&line.text) else {
+            continue;
+        };
+        let end = len + 1; // 1-based exclusive
+        line.spans.retain_mut(|s| {
+            s.start_byte = s.start_byte.max(end);
+            s.start_byte < s.end_byte
+        });
+        line.spans.insert(
+            0,
+            Span {
+                start_byte: 1,
+                end_byte: end,
+                face: TIMESTAMP_FACE.into(),
+            },
+        );
+    }
+}
+
+/// Byte length of a timestamp at the start of `text`: an ISO 8601
+/// date-time (`2024-01-31T12:34:56`, optional fraction and offset, `T` or a
+/// space as separator) or a bare `HH:MM:SS[.fff]`, optionally in `[...]`.
+fn leading_timestamp_len(text: &str) -> Option<usize> {
+    let b = text.as_bytes();
+    let digits = |pos: usize, n: usize| {
+        b.get(pos..pos + n)
+            .is_some_and(|d| d.iter().all(u8::is_ascii_digit))
+    };
+    let at = |pos: usize, c: u8| b.get(pos) == Some(&c);
+    let time = |pos: usize| {
+        digits(pos, 2)
+            && at(pos + 2, b':')
+            && digits(pos + 3, 2)
+            && at(pos + 5, b':')
+            && digits(pos + 6, 2)
+    };
+    let fraction = |pos: usize| {
+        if !(at(pos, b'.') || at(pos, b',')) {
+            return pos;
+        }
+        let n = b[pos + 1..]
+            .iter()
+            .take_while(|c| c.is_ascii_digit())
+            .count();
+        if n == 0 {
+            pos
+        } else {
+            pos + 1 + n
+        }
+    };
+
+    let open = usize::from(at(0, b'['));
+    let mut pos = open;
+    if digits(pos, 4)
+        && at(pos + 4, b'-')
+        && digits(pos + 5, 2)
+        && at(pos + 7, b'-')
+        && digits(pos + 8, 2)
+        && (at(pos + 10, b'T') || at(pos + 10, b' '))
+        && time(pos + 11)
+    {
+        pos = fraction(pos + 19);
+        if at(pos, b'Z') {
+            pos += 1;
+        } else if (at(pos, b'+') || at(pos, b'-')) && digits(pos + 1, 2) {
+            pos += 3;
+            if at(pos, b':') && digits(pos + 1, 2) {
+                pos += 3;
+            } else if digits(pos, 2) {
+                pos += 2;
+            }
+        }
+    } else if time(pos) {
+        pos = fraction(pos + 8);
+    } else {
+        return None;
+    }
+
+    if open == 1 {
+        if !at(pos, b']') {
+            return None;
+        }
+        pos += 1;
+    }
+    // Reject a longer number such as `12:34:567`
+    if b.get(pos).is_some_and(u8::is_ascii_alphanumeric) {
+        return None;
+    }
+    Some(pos)
+}
+
+/// Face for a unified-diff line, chosen by its first characters.
+fn diff_line_face(text: &str) -> Option<&'static str> {
+    if text.starts_with("@@") {
+        Some("cyan")
+    } else if text.starts_with('+') {
+        Some("green")
+    } else if text.starts_with('-') {
+        Some("red")
+    } else {
+        None
+    }
+}
+
+/// Color uncolored `+`, `-` and `@@` lines like a unified diff. Lines that
+/// already have spans keep their captured colors.
+pub fn colorize_diff(screen: &mut ProcessedScreen) {
+    for line in &mut screen.lines {
+        if !line.spans.is_empty() {
+            continue;
+        }
+        if let Some(face) = diff_line_face(&line.text) {
+            line.spans.push(Span {
+                start_byte: 1,
+                end_byte: line.text.len() + 1,
+                face: face.into(),
+            });
+        }
+    }
+}
+
+/// Minimum run of adjacent, monotonically changing faces treated as a gradient.
+const GRADIENT_MIN_RUN: usize = 8;
+
+/// A generated `fg,bg[+attrs]` face; `None` colors are `default`.
+struct RgbFace<'a> {
+    fg: Option<[u8; 3]>,
+    bg: Option<[u8; 3]>,
+    attrs: &'a str,
+}
+
+/// Parse a generated face; `None` for other faces (e.g. `comment`).
+fn parse_rgb_face(face: &str) -> Option<RgbFace<'_>> {
+    let (colors, attrs) = face.split_once('+').unwrap_or((face, ""));
+    let (fg, bg) = colors.split_once(',')?;
+    let color = |c: &str| -> Option<Option<[u8; 3]>> {
+        if c == "default" {
+            return Some(None);
+        }
+        let hex = c
+            .strip_prefix("rgb:")
+            .filter(|h| h.len() == 6 && h.is_ascii())?;
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+        Some(Some([channel(0)?, channel(2)?, channel(4)?]))
+    };
+    Some(RgbFace {
+        fg: color(fg)?,
+        bg: color(bg)?,
+        attrs,
+    })
+}
+
+/// Whether `next` continues a gradient from `prev`: same attributes and
+/// color slots, a different face, and every channel moving in the
+/// direction recorded in `dirs` so far (0 = not yet moved).
+fn continues_gradient(prev: &Span, next: &Span, dirs: &mut [i8; 6]) -> bool {
+    if prev.end_byte != next.start_byte || prev.face == next.face {
+        return false;
+    }
+    let (Some(a), Some(b)) = (parse_rgb_face(&prev.face), parse_rgb_face(&next.face)) else {
+        return false;
+    };
+    if a.attrs != b.attrs || a.fg.is_some() != b.fg.is_some() || a.bg.is_some() != b.bg.is_some() {
+        return false;
+    }
+    let flat = |f: RgbFace| {
+        let (fg, bg) = (f.fg.unwrap_or_default(), f.bg.unwrap_or_default());
+        [fg[0], fg[1], fg[2], bg[0], bg[1], bg[2]]
+    };
+    let (a, b) = (flat(a), flat(b));
+    let mut new_dirs = *dirs;
+    for i in 0..6 {
+        let step = (i16::from(b[i]) - i16::from(a[i])).signum() as i8;
+        if step == 0 {
+            continue;
+        }
+        if new_dirs[i] == -step {
+            return false;
+        }
+        new_dirs[i] = step;
+    }
+    *dirs = new_dirs;
+    true
+}
+
+/// Merge truecolor gradients (runs of at least `GRADIENT_MIN_RUN` adjacent
+/// spans whose colors change monotonically) into one span per `n` spans,
+/// each taking the face of its middle span.
+pub fn downsample_gradients(screen: &mut ProcessedScreen, n: usize) {
+    let n = n.max(1);
+    for line in &mut screen.lines {
+        let spans = std::mem::take(&mut line.spans);
+        let mut out = Vec::with_capacity(spans.len());
+        let mut start = 0;
+        while start < spans.len() {
+            let mut end = start + 1;
+            let mut dirs = [0i8; 6];
+            while end < spans.len() && continues_gradient(&spans[end - 1], &spans[end], &mut dirs) {
+                end += 1;
+            }
+            let run = &spans[start..end];
+            if run.len() >= GRADIENT_MIN_RUN {
+                for chunk in run.chunks(n) {
+                    out.push(Span {
+                        start_byte: chunk[0].start_byte,
+                        end_byte: chunk[chunk.len() - 1].end_byte,
+                        face: chunk[chunk.len() / 2].face.clone(),
+                    });
+                }
+            } else {
+                out.extend_from_slice(run);
+            }
+            start = end;
+        }
+        line.spans = out;
+    }
+}
+
+fn rgb_to_kak([r, g, b]: [u8; 3]) -> String {
+    format!("rgb:{r:02X}{g:02X}{b:02X}")
+}
+
+const OSC_133_PROMPT_START: &[u8] = b"\x1b]133;A";
+
+/// History lines kept beyond the scrollback limit while parsing, so an
+/// overflow can be told apart from a history exactly at the limit and the
+/// lines pushed in between checks can be counted.
+const HISTORY_SLACK: usize = 64;
+
+/// Feed `data` to `parser`, adding the lines it pushed into the history to
+/// `pushed`. vt100 only counts pushes on a scrolled-back view, up to the
+/// history length, so the data is fed in pieces too short to reach that.
+/// `None` once the count is lost (the alternate screen, or scroll sequences
+/// that push more lines than a piece has bytes).
+fn process_counting_history(
+    parser: &mut vt100::Parser,
+    data: &[u8],
+    history_cap: usize,
+    mut pushed: Option<usize>,
+) -> Option<usize> {
+    for piece in data.chunks(history_cap.saturating_sub(2).max(1)) {
+        let Some(total) = pushed else {
+            parser.process(piece);
+            continue;
+        };
+        let screen = parser.screen_mut();
+        let alternate = screen.alternate_screen();
+        screen.set_scrollback(usize::MAX);
+        let len_before = screen.scrollback();
+        screen.set_scrollback(1);
+        parser.process(piece);
+        let screen = parser.screen_mut();
+        let offset = screen.scrollback();
+        screen.set_scrollback(usize::MAX);
+        let len_after = screen.scrollback();
+        screen.set_scrollback(0);
+        let count = if alternate || screen.alternate_screen() {
+            None
+        } else if len_before == 0 {
+            (len_after < history_cap).then_some(len_after)
+        } else {
+            (offset < history_cap).then(|| offset - 1)
+        };
+        pushed = count.map(|n| total + n);
+    }
+    pushed
+}
+
+/// Byte offsets just past each page marker: an OSC 133 prompt-start
+/// sequence (`ESC ] 133 ; A ... BEL|ST`) or a form feed.
+fn find_page_markers(data: &[u8]) -> Vec<usize> {
+    let mut markers = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] == b'\x0c' {
+            markers.push(i + 1);
+            i += 1;
+        } else if data[i..].starts_with(OSC_133_PROMPT_START) {
+            let rest = &data[i + OSC_133_PROMPT_START.len()..];
+            // Split after the terminator so the parser sees the whole sequence
+            let Some(end) = rest.iter().enumerate().find_map(|(j, &b)| match b {
+                0x07 => Some(j + 1),
+                0x1b if rest.get(j + 1) == Some(&b'\\') => Some(j + 2),
+                _ => None,
+            }) else {
+                break;
+            };
+            i += OSC_133_PROMPT_START.len() + end;
+            markers.push(i);
+        } else {
+            i += 1;
+        }
+    }
+    markers
+}
+
+/// 1-based line of the cursor counted from the top of the scrollback.
+///
+/// Once `max_scrollback_lines` is exceeded the oldest lines are dropped,
+/// so boundaries recorded before that point drift by the dropped amount.
+fn cursor_absolute_line(screen: &mut vt100::Screen) -> usize {
+    screen.set_scrollback(usize::MAX);
+    let sb = screen.scrollback();
+    screen.set_scrollback(0);
+    sb + usize::from(screen.cursor_position().0) + 1
+}
+
+fn line_source(screen: &vt100::Screen, offset: usize, row: u16) -> LineSource {
+    LineSource {
+        offset,
+        row,
+        continuation: row > 0 && screen.row_wrapped(row - 1),
+    }
+}
+
+fn process_row(
+    screen: &vt100::Screen,
+    row: u16,
+    cols: u16,
+    cursor_x: Option<usize>,
+    cursor: &mut CursorPosition,
+    ctx: &FaceCtx,
+    faces: &mut FaceCache,
+) -> ProcessedLine {
+    let mut text = String::new();
+    let mut spans: Vec<Span> = Vec::new();
+    let mut current_key: Option<FaceKey> = None;
+    let mut span_start_byte: usize = 1; // 1-based
+    let mut last_cell_start: usize = 0; // 0-based byte offset of the previous cell
+    let mut cursor_seen = false;
+
+    for col in 0..cols {
+        let Some(cell) = screen.cell(row, col) else {
+            break;
+        };
+
+        // Skip wide continuation cells; a cursor on one belongs to the
+        // wide character's first byte
+        if cell.is_wide_continuation() {
+            if cursor_x == Some(usize::from(col)) {
+                cursor.col = last_cell_start + 1;
+                cursor_seen = true;
+            }
+            continue;
+        }
+
+        let contents = cell.contents();
+        let byte_offset_before = text.len(); // 0-based
+        last_cell_start = byte_offset_before;
+
+        // Track cursor column (byte offset)
+        if let Some(cx) = cursor_x {
+            if usize::from(col) == cx {
+                cursor.col = byte_offset_before + 1; // 1-based
+                cursor_seen = true;
+            }
+        }
+
+        // Append cell content (or space if empty / only control characters)
+        push_cell_contents(&mut text, contents);
+
+        let key = match cell_face_key(cell, ctx) {
+            // A foreground color draws nothing on a blank cell
+            Some(k)
+                if ctx.skip_blank_fg
+                    && k.bg.is_none()
+                    && k.attrs & BLANK_VISIBLE_ATTRS == 0
+                    && contents.trim().is_empty() =>
+            {
+                None
+            }
+            key => key,
+        };
+
+        if key != current_key {
+            let byte_now = byte_offset_before + 1; // 1-based
+            if let Some(ref k) = current_key {
+                if span_start_byte < byte_now {
+                    spans.push(Span {
+                        start_byte: span_start_byte,
+                        end_byte: byte_now,
+                        face: faces.face(k),
+                    });
+                }
+            }
+            current_key = key;
+            span_start_byte = byte_now;
+        }
+    }
+
+    // Close final span
+    let byte_end = text.len() + 1; // 1-based, exclusive
+    if let Some(ref k) = current_key {
+        if span_start_byte < byte_end {
+            spans.push(Span {
+                start_byte: span_start_byte,
+                end_byte: byte_end,
+                face: faces.face(k),
+            });
+        }
+    }
+
+    // Trim trailing spaces from text
+    let trimmed_len = text.trim_end().len();
+    if trimmed_len < text.len() && !ctx.no_trailing_trim {
+        text.truncate(trimmed_len);
+        // Adjust spans that extend beyond trimmed text
+        let max_byte = trimmed_len + 1; // 1-based exclusive
+        spans.retain(|s| s.start_byte < max_byte);
+        if let Some(last) = spans.last_mut() {
+            if last.end_byte > max_byte {
+                last.end_byte = max_byte;
+            }
+        }
+    }
+
+    // A cursor column the cells never reached, or one in the trimmed
+    // trailing blanks, is clamped onto the line
+    if let Some(cx) = cursor_x {
+        let line_end = text.len() + 1;
+        if !cursor_seen || text.is_empty() {
+            cursor.col = (cx + 1).min(line_end);
+        } else {
+            cursor.col = cursor.col.min(line_end);
+        }
+    }
+
+    ProcessedLine { text, spans }
+}
+
+/// C0 controls and DEL never belong in buffer text. vt100 normally consumes
+/// them, but stray ones from `capture-pane -e` output are dropped here.
+fn is_ignorable_control(c: char) -> bool {
+    c.is_ascii_control()
+}
+
+/// Append a cell's contents minus ignorable controls. A cell that ends up
+/// empty becomes a space, so every cell contributes at least one byte and
+/// span / cursor offsets stay aligned with the cells.
+fn push_cell_contents(text: &mut String, contents: &str) {
+    let before = text.len();
+    if contents.chars().any(is_ignorable_control) {
+        text.extend(contents.chars().filter(|&c| !is_ignorable_control(c)));
+    } else {
+        text.push_str(contents);
+    }
+    if text.len() == before {
+        text.push(' ');
+    }
+}
+
+/// Cell visual attributes as a normalized, zero-allocation key.
+///
+/// fg/bg store resolved RGB (not raw `vt100::Color`) so that
+/// `Idx(1)` and `Rgb(204,0,0)` compare equal when the palette matches.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct FaceKey {
+    fg: Option<[u8; 3]>,
+    bg: Option<[u8; 3]>,
+    attrs: u8, // bit i = FACE_FLAGS[i]
+}
+
+fn cell_face_key(cell: &vt100::Cell, ctx: &FaceCtx) -> Option<FaceKey> {
+    let fgcolor = match cell.fgcolor() {
+        vt100::Color::Idx(idx) if ctx.bold_is_bright && cell.bold() && idx < 8 => {
+            vt100::Color::Idx(idx + 8)
+        }
+        color => color,
+    };
+    let mut fg = palette::color_to_rgb(fgcolor, ctx.palette);
+    let mut bg = palette::color_to_rgb(cell.bgcolor(), ctx.palette);
+    // The COLORFGBG colors only complete a face that sets the other color,
+    // so plain text stays span-free
+    if fg.is_some() || bg.is_some() {
+        fg = fg.or(ctx.default_fg);
+        bg = bg.or(ctx.default_bg);
+    }
+    if ctx.quantize {
+        fg = fg.map(palette::quantize_rgb);
+        bg = bg.map(palette::quantize_rgb);
+    }
+
+    let mut attrs = 0u8;
+    for ((_, has), bit) in CELL_ATTRS.iter().zip(ctx.attr_map.bits) {
+        if bit != 0 && has(cell) {
+            attrs |= bit;
+        }
+    }
+
+    if fg.is_none() && bg.is_none() && attrs == 0 {
+        return None;
+    }
+
+    Some(FaceKey { fg, bg, attrs })
+}
+
+fn face_key_to_string(key: &FaceKey) -> String {
+    use std::fmt::Write;
+    let mut s = String::with_capacity(32);
+
+    match key.fg {
+        Some([r, g, b]) => {
+            write!(s, "rgb:{r:02X}{g:02X}{b:02X}").unwrap();
+        }
+        None => s.push_str("default"),
+    }
+    s.push(',');
+    match key.bg {
+        Some([r, g, b]) => {
+            write!(s, "rgb:{r:02X}{g:02X}{b:02X}").unwrap();
+        }
+        None => s.push_str("default"),
+    }
+
+    if key.attrs != 0 {
+        s.push('+');
+        for (i, flag) in FACE_FLAGS.iter().enumerate() {
+            if key.attrs & (1 << i) != 0 {
+                s.push(*flag);
+            }
+        }
+    }
+
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kitty::PipeData;
+
+    fn default_pipe_data() -> PipeData {
+        PipeData {
+            cursor_x: 0,
+            cursor_y: 0,
+            lines: 24,
+            columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
+        }
+    }
+
+    #[test]
+    fn process_plain_text() {
         let input = b"Hello World";
         let pd = default_pipe_data();
         let screen = process_bytes(
@@ -327,13 +1699,910 @@ mod tests {
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
         );
-        assert_eq!(screen.lines[0].text, "Hello World");
-        assert_eq!(screen.lines[0].spans.len(), 0);
+        assert_eq!(screen.lines[0].text, "Hello World");
+        assert_eq!(screen.lines[0].spans.len(), 0);
+    }
+
+    fn one_column_screen(input: &[u8]) -> ProcessedScreen {
+        let pd = PipeData {
+            columns: 1,
+            lines: 4,
+            ..default_pipe_data()
+        };
+        process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        )
+    }
+
+    #[test]
+    fn one_column_ascii_wraps_per_char() {
+        let screen = one_column_screen(b"\x1b[31mab\x1b[0mc");
+        let texts: Vec<&str> = screen.lines.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(texts, ["a", "b", "c"]);
+        assert_eq!(screen.lines[0].spans.len(), 1);
+        assert_eq!(screen.lines[0].spans[0].end_byte, 2);
+        assert!(screen.lines[2].spans.is_empty());
+    }
+
+    #[test]
+    fn one_column_wide_char_is_replaced() {
+        let screen = one_column_screen("\x1b[32m日\x1b[0mb\r\n本".as_bytes());
+        let texts: Vec<&str> = screen.lines.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(texts, ["?", "b", "?"]);
+        let span = &screen.lines[0].spans[0];
+        assert_eq!((span.start_byte, span.end_byte), (1, 2));
+        assert_eq!(screen.cursor.col, 1);
+    }
+
+    #[test]
+    fn process_colored_text() {
+        let input = b"\x1b[31mHello\x1b[0m World";
+        let pd = default_pipe_data();
+        let screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        assert_eq!(screen.lines[0].text, "Hello World");
+        assert!(!screen.lines[0].spans.is_empty());
+        assert!(screen.lines[0].spans[0].face.contains("rgb:"));
+    }
+
+    #[test]
+    fn default_spans_skipped() {
+        let input = b"plain text";
+        let pd = default_pipe_data();
+        let screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        assert_eq!(screen.lines[0].spans.len(), 0);
+    }
+
+    #[test]
+    fn span_merging() {
+        // Entire "Hello World" in red — should be 1 span
+        let input = b"\x1b[31mHello World\x1b[0m";
+        let pd = default_pipe_data();
+        let screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        assert_eq!(screen.lines[0].text, "Hello World");
+        assert_eq!(screen.lines[0].spans.len(), 1);
+    }
+
+    #[test]
+    fn wide_characters() {
+        let input = "日本語test".as_bytes();
+        let pd = default_pipe_data();
+        let screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        assert_eq!(screen.lines[0].text, "日本語test");
+    }
+
+    #[test]
+    fn wide_char_byte_offsets() {
+        // "日" = 3 bytes, "本" = 3 bytes, "語" = 3 bytes
+        let input = b"\x1b[31m\xe6\x97\xa5\x1b[0m\xe6\x9c\xac"; // red "日" + normal "本"
+        let pd = default_pipe_data();
+        let screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        assert_eq!(screen.lines[0].text, "日本");
+        assert_eq!(screen.lines[0].spans.len(), 1);
+        // Red span covers "日" = bytes 1..4 (1-based, exclusive end)
+        assert_eq!(screen.lines[0].spans[0].start_byte, 1);
+        assert_eq!(screen.lines[0].spans[0].end_byte, 4); // 3 bytes + 1
+    }
+
+    #[test]
+    fn attributes_combined() {
+        let input = b"\x1b[1;3mBoldItalic\x1b[0m";
+        let pd = default_pipe_data();
+        let screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        assert!(screen.lines[0].spans[0].face.contains("+bi"));
+    }
+
+    #[test]
+    fn cursor_position_simple() {
+        let input = b"line1\r\nline2\r\nline3";
+        let pd = PipeData {
+            cursor_x: 3,
+            cursor_y: 2,
+            lines: 24,
+            columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        let screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        // Cursor should be on line 3 (1-based), col 4 (1-based, after "lin")
+        assert_eq!(screen.cursor.line, 3);
+        assert_eq!(screen.cursor.col, 4);
+    }
+
+    #[test]
+    fn empty_input() {
+        let pd = default_pipe_data();
+        let screen = process_bytes(
+            &pd,
+            b"",
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        // Should have no lines (all trimmed as empty)
+        assert!(screen.lines.is_empty() || screen.lines.iter().all(|l| l.text.is_empty()));
+    }
+
+    #[test]
+    fn scrollback_lines() {
+        // Generate enough output to create scrollback
+        let mut input = Vec::new();
+        for i in 0..30 {
+            input.extend_from_slice(format!("line {i}\r\n").as_bytes());
+        }
+        let pd = PipeData {
+            cursor_x: 0,
+            cursor_y: 0,
+            lines: 10,
+            columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        let screen = process_bytes(
+            &pd,
+            &input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        // Should have all 30 lines (some in scrollback, some on screen)
+        assert!(screen.lines.len() >= 30);
+        assert_eq!(screen.lines[0].text, "line 0");
+        assert_eq!(screen.lines[29].text, "line 29");
+    }
+
+    // --- Phase 1: HIGH priority ---
+
+    #[test]
+    fn cursor_position_with_scrollback() {
+        let mut input = Vec::new();
+        for i in 0..30 {
+            input.extend_from_slice(format!("line {i}\r\n").as_bytes());
+        }
+        let pd = PipeData {
+            cursor_x: 0,
+            cursor_y: 5,
+            lines: 10,
+            columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        let screen = process_bytes(
+            &pd,
+            &input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        // 30 lines + trailing \r\n = 31 rows, total_sb = 21
+        // cursor_output_line = 21 + 5 + 1 = 27
+        assert_eq!(screen.cursor.line, 27);
+    }
+
+    #[test]
+    fn cursor_clamped_on_trimmed_empty_lines() {
+        let input = b"Hello";
+        let pd = PipeData {
+            cursor_x: 0,
+            cursor_y: 23,
+            lines: 24,
+            columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        let screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        // Only line 0 has text "Hello", lines 1-23 are empty and get trimmed
+        // Cursor was at line 24 (1-based), gets clamped to last line
+        assert_eq!(screen.lines.len(), 1);
+        assert_eq!(screen.cursor.line, 1);
+        assert_eq!(screen.cursor.col, 1);
+    }
+
+    #[test]
+    fn multiple_colors_same_line() {
+        let input = b"\x1b[31mRed\x1b[32mGreen\x1b[0m";
+        let pd = default_pipe_data();
+        let screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        assert_eq!(screen.lines[0].text, "RedGreen");
+        assert_eq!(screen.lines[0].spans.len(), 2);
+        // Red span: bytes 1..4 (exclusive), covers "Red"
+        assert_eq!(screen.lines[0].spans[0].start_byte, 1);
+        assert_eq!(screen.lines[0].spans[0].end_byte, 4);
+        // Green span: bytes 4..9 (exclusive), covers "Green"
+        assert_eq!(screen.lines[0].spans[1].start_byte, 4);
+        assert_eq!(screen.lines[0].spans[1].end_byte, 9);
+    }
+
+    #[test]
+    fn background_color_only() {
+        let input = b"\x1b[42mHighlighted\x1b[0m";
+        let pd = default_pipe_data();
+        let screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        assert_eq!(screen.lines[0].text, "Highlighted");
+        assert_eq!(screen.lines[0].spans.len(), 1);
+        // SGR 42 = green background, default foreground
+        assert_eq!(&*screen.lines[0].spans[0].face, "default,rgb:00CC00");
+    }
+
+    #[test]
+    fn span_adjustment_on_trailing_trim() {
+        // Red "Hi" followed by red trailing spaces that get trimmed
+        let input = b"\x1b[31mHi   \x1b[0m";
+        let pd = PipeData {
+            cursor_x: 0,
+            cursor_y: 0,
+            lines: 24,
+            columns: 20,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        let screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        assert_eq!(screen.lines[0].text, "Hi");
+        assert_eq!(screen.lines[0].spans.len(), 1);
+        // Span should be trimmed to text length: bytes 1..3 (exclusive)
+        assert_eq!(screen.lines[0].spans[0].start_byte, 1);
+        assert_eq!(screen.lines[0].spans[0].end_byte, 3);
+    }
+
+    // --- Phase 2: MEDIUM priority ---
+
+    #[test]
+    fn attribute_dim() {
+        let input = b"\x1b[2mDim\x1b[0m";
+        let pd = default_pipe_data();
+        let screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        assert_eq!(screen.lines[0].text, "Dim");
+        assert!(screen.lines[0].spans[0].face.contains("+d"));
+    }
+
+    #[test]
+    fn attribute_underline() {
+        let input = b"\x1b[4mUnderlined\x1b[0m";
+        let pd = default_pipe_data();
+        let screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        assert_eq!(screen.lines[0].text, "Underlined");
+        assert!(screen.lines[0].spans[0].face.contains("+u"));
+    }
+
+    #[test]
+    fn attribute_inverse() {
+        let input = b"\x1b[7mReversed\x1b[0m";
+        let pd = default_pipe_data();
+        let screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        assert_eq!(screen.lines[0].text, "Reversed");
+        assert!(screen.lines[0].spans[0].face.contains("+r"));
+    }
+
+    // --- SGR partial attribute resets (22/23/24/27) ---
+
+    fn span_triples(input: &[u8]) -> Vec<(usize, usize, String)> {
+        let screen = process_bytes(
+            &default_pipe_data(),
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        screen.lines[0]
+            .spans
+            .iter()
+            .map(|s| (s.start_byte, s.end_byte, s.face.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn sgr_22_ends_bold() {
+        assert_eq!(
+            span_triples(b"\x1b[1mBold\x1b[22mNormal"),
+            [(1, 5, "default,default+b".to_string())]
+        );
+    }
+
+    #[test]
+    fn sgr_22_ends_dim() {
+        assert_eq!(
+            span_triples(b"\x1b[2mDim\x1b[22mNormal"),
+            [(1, 4, "default,default+d".to_string())]
+        );
+    }
+
+    #[test]
+    fn sgr_23_ends_italic() {
+        assert_eq!(
+            span_triples(b"\x1b[3mItalic\x1b[23mNormal"),
+            [(1, 7, "default,default+i".to_string())]
+        );
+    }
+
+    #[test]
+    fn sgr_24_ends_underline() {
+        assert_eq!(
+            span_triples(b"\x1b[4mUnder\x1b[24mNormal"),
+            [(1, 6, "default,default+u".to_string())]
+        );
+    }
+
+    #[test]
+    fn sgr_27_ends_inverse() {
+        assert_eq!(
+            span_triples(b"\x1b[7mInv\x1b[27mNormal"),
+            [(1, 4, "default,default+r".to_string())]
+        );
+    }
+
+    #[test]
+    fn partial_reset_keeps_other_attributes_and_color() {
+        // Bold+underline red, then underline off: bold red remains
+        assert_eq!(
+            span_triples(b"\x1b[1;4;31mAB\x1b[24mCD\x1b[0mE"),
+            [
+                (1, 3, "rgb:CC0000,default+bu".to_string()),
+                (3, 5, "rgb:CC0000,default+b".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn push_cell_contents_drops_controls() {
+        let mut text = String::from("a");
+        push_cell_contents(&mut text, "\x07");
+        push_cell_contents(&mut text, "b\x1b\x7f");
+        push_cell_contents(&mut text, "");
+        push_cell_contents(&mut text, "é");
+        // BEL-only cell → space, controls stripped, empty → space
+        assert_eq!(text, "a b é");
+    }
+
+    #[test]
+    fn combining_cell_span_covers_whole_cell() {
+        // e + COMBINING ACUTE ACCENT (3 bytes) and a + COMBINING RING + COMBINING
+        // DOT BELOW (5 bytes), each a single colored cell
+        let input = "x\x1b[31me\u{0301}\x1b[0my\x1b[32ma\u{030A}\u{0323}\x1b[0m";
+        let screen = process_bytes(
+            &default_pipe_data(),
+            input.as_bytes(),
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        let line = &screen.lines[0];
+        assert_eq!(line.text, "xe\u{0301}ya\u{030A}\u{0323}");
+        assert_eq!(line.spans.len(), 2);
+        let cell_bytes = ["e\u{0301}".len(), "a\u{030A}\u{0323}".len()];
+        for (span, len) in line.spans.iter().zip(cell_bytes) {
+            assert_eq!(span.end_byte, span.start_byte + len);
+        }
+        assert_eq!(line.spans[0].start_byte, 2);
+        assert_eq!(line.spans[1].start_byte, 6);
+        assert_eq!(
+            &line.text[line.spans[1].start_byte - 1..line.spans[1].end_byte - 1],
+            "a\u{030A}\u{0323}"
+        );
+
+        // A combining mark written after the reset still joins the red cell
+        let screen = process_bytes(
+            &default_pipe_data(),
+            "\x1b[31me\x1b[0m\u{0301}z".as_bytes(),
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        let span = &screen.lines[0].spans[0];
+        assert_eq!((span.start_byte, span.end_byte), (1, 4));
+    }
+
+    #[test]
+    fn zwj_sequence_is_one_span() {
+        // MAN ZWJ WOMAN ZWJ GIRL: 18 bytes drawn in one color
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        let input = format!("x\x1b[31m{family}\x1b[0m\x1b[32my\x1b[0m  ");
+        let screen = process_bytes(
+            &default_pipe_data(),
+            input.as_bytes(),
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        let line = &screen.lines[0];
+        assert_eq!(line.text, format!("x{family}y"));
+        assert_eq!(line.spans.len(), 2);
+        let (emoji, after) = (&line.spans[0], &line.spans[1]);
+        assert_eq!(&*emoji.face, "rgb:CC0000,default");
+        assert_eq!((emoji.start_byte, emoji.end_byte), (2, 2 + family.len()));
+        assert_eq!(&line.text[emoji.start_byte - 1..emoji.end_byte - 1], family);
+        assert_eq!(&*after.face, "rgb:00CC00,default");
+        assert_eq!((after.start_byte, after.end_byte), (20, 21));
+    }
+
+    #[test]
+    fn bel_in_stream_leaves_no_trace_and_offsets_align() {
+        let input = b"\x1b[31mab\x07cd\x1b[0m\x07 \x1b[32mef\x1b[0m";
+        let pd = PipeData {
+            cursor_x: 6,
+            cursor_y: 0,
+            lines: 24,
+            columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        let screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        let line = &screen.lines[0];
+        assert_eq!(line.text, "abcd ef");
+        assert!(!line.text.contains('\x07'));
+        assert_eq!((line.spans[0].start_byte, line.spans[0].end_byte), (1, 5));
+        assert_eq!((line.spans[1].start_byte, line.spans[1].end_byte), (6, 8));
+        assert_eq!(screen.cursor.col, 7);
+    }
+
+    #[test]
+    fn reset_then_new_color() {
+        let input = b"\x1b[31mR\x1b[0m N \x1b[34mB\x1b[0m";
+        let pd = default_pipe_data();
+        let screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        assert_eq!(screen.lines[0].text, "R N B");
+        assert_eq!(screen.lines[0].spans.len(), 2);
+        // Red span for "R"
+        assert_eq!(screen.lines[0].spans[0].start_byte, 1);
+        assert_eq!(screen.lines[0].spans[0].end_byte, 2);
+        // Blue span for "B"
+        assert_eq!(screen.lines[0].spans[1].start_byte, 5);
+        assert_eq!(screen.lines[0].spans[1].end_byte, 6);
+    }
+
+    #[test]
+    fn line_with_only_formatting_no_visible_text() {
+        let input = b"\x1b[31m   \x1b[0m";
+        let pd = default_pipe_data();
+        let screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        // All text was colored spaces, trimmed to empty; all empty lines removed
+        assert!(screen.lines.is_empty());
+    }
+
+    #[test]
+    fn cursor_on_wide_character() {
+        let input = "日test".as_bytes();
+        let pd = PipeData {
+            cursor_x: 2,
+            cursor_y: 0,
+            lines: 24,
+            columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        let screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        assert_eq!(screen.lines[0].text, "日test");
+        // "日" = 3 bytes (occupies cols 0-1), cursor_x:2 = 't'
+        // byte offset = 3 (0-based) + 1 = 4 (1-based)
+        assert_eq!(screen.cursor.col, 4);
+    }
+
+    #[test]
+    fn scrollback_cursor_position_variants() {
+        let mut input = Vec::new();
+        for i in 0..30 {
+            input.extend_from_slice(format!("line {i}\r\n").as_bytes());
+        }
+
+        // Cursor at first visible line (cursor_y:0)
+        let pd = PipeData {
+            cursor_x: 0,
+            cursor_y: 0,
+            lines: 10,
+            columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        let screen = process_bytes(
+            &pd,
+            &input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        // 30 lines + trailing \r\n = 31 rows, total_sb = 21
+        // cursor_output_line = 21 + 0 + 1 = 22
+        assert_eq!(screen.cursor.line, 22);
+
+        // Cursor at last visible line (cursor_y:9)
+        let pd = PipeData {
+            cursor_x: 0,
+            cursor_y: 9,
+            lines: 10,
+            columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        let screen = process_bytes(
+            &pd,
+            &input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        // cursor_output_line = 21 + 9 + 1 = 31, but trailing empty line trimmed
+        // lines.len() = 30, so cursor clamped to 30 with col = 1
+        assert_eq!(screen.cursor.line, 30);
+        assert_eq!(screen.cursor.col, 1);
+    }
+
+    #[test]
+    fn trailing_spaces_trimmed() {
+        let input = b"Hello";
+        let pd = default_pipe_data(); // 80 columns
+        let screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        assert_eq!(screen.lines[0].text, "Hello");
+        assert_eq!(screen.lines[0].text.len(), 5);
+    }
+
+    #[test]
+    fn small_max_scrollback_truncates_old_lines() {
+        let mut input = Vec::new();
+        for i in 0..30 {
+            input.extend_from_slice(format!("line {i}\r\n").as_bytes());
+        }
+        let pd = PipeData {
+            cursor_x: 0,
+            cursor_y: 0,
+            lines: 10,
+            columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        // max_scrollback_lines=5 → scrollback buffer limited to 5 lines
+        let screen = process_bytes(&pd, &input, &palette::DEFAULT_PALETTE, 5);
+        // Oldest lines should be truncated
+        assert_ne!(screen.lines[0].text, "line 0");
+    }
+
+    #[test]
+    fn cursor_clamped_when_scrollback_truncated() {
+        let mut input = Vec::new();
+        for i in 0..30 {
+            input.extend_from_slice(format!("line {i}\r\n").as_bytes());
+        }
+        let pd = PipeData {
+            cursor_x: 0,
+            cursor_y: 5,
+            lines: 10,
+            columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        let screen = process_bytes(&pd, &input, &palette::DEFAULT_PALETTE, 5);
+        // Cursor line should not exceed lines.len()
+        assert!(screen.cursor.line <= screen.lines.len());
+        assert!(screen.cursor.line >= 1);
+    }
+
+    #[test]
+    fn process_bytes_does_not_panic_with_zero_lines() {
+        let pd = PipeData {
+            cursor_x: 0,
+            cursor_y: 0,
+            lines: 0,
+            columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        // Should not panic — process_bytes clamps lines to 1
+        let screen = process_bytes(
+            &pd,
+            b"Hello",
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        assert!(screen.cursor.line >= 1);
+    }
+
+    #[test]
+    fn process_bytes_does_not_panic_with_zero_columns() {
+        let pd = PipeData {
+            cursor_x: 0,
+            cursor_y: 0,
+            lines: 24,
+            columns: 0,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        // Should not panic — process_bytes clamps columns to 1
+        let screen = process_bytes(
+            &pd,
+            b"Hello",
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        assert!(screen.cursor.line >= 1);
+    }
+
+    #[test]
+    fn process_bytes_saturating_add_no_panic() {
+        let pd = PipeData {
+            cursor_x: 0,
+            cursor_y: usize::MAX,
+            lines: 24,
+            columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        let screen = process_bytes(
+            &pd,
+            b"Hello",
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        // Saturated cursor_output_line → is_cursor_line never matches → cursor stays at initial (1,1)
+        assert_eq!(screen.cursor.line, 1);
+        assert_eq!(screen.cursor.col, 1);
+    }
+
+    // --- viewport_top_line ---
+
+    #[test]
+    fn viewport_top_line_no_scrollback() {
+        let input = b"Hello";
+        let pd = default_pipe_data();
+        let screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        // No scrollback → total_sb = 0 → viewport_top_line = 1
+        assert_eq!(screen.viewport_top_line, 1);
+    }
+
+    #[test]
+    fn viewport_top_line_with_scrollback() {
+        let mut input = Vec::new();
+        for i in 0..30 {
+            input.extend_from_slice(format!("line {i}\r\n").as_bytes());
+        }
+        let pd = PipeData {
+            cursor_x: 0,
+            cursor_y: 0,
+            lines: 10,
+            columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        let screen = process_bytes(
+            &pd,
+            &input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        // 30 lines + trailing \r\n = 31 rows in 10-line terminal → total_sb = 21
+        // viewport_top_line = 21 + 1 = 22
+        assert_eq!(screen.viewport_top_line, 22);
+        // viewport ends at 21 + 10 = 31, clamped to the 30 non-empty lines
+        assert_eq!(screen.viewport_bottom_line, 30);
+    }
+
+    #[test]
+    fn viewport_top_line_clamped_after_trim() {
+        // Only 1 line of real content, cursor far below → empty lines trimmed
+        let input = b"Hello";
+        let pd = PipeData {
+            cursor_x: 0,
+            cursor_y: 0,
+            lines: 24,
+            columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        let screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        // No scrollback, lines trimmed to 1 → viewport_top_line = min(1, 1) = 1
+        assert_eq!(screen.viewport_top_line, 1);
+        assert_eq!(screen.viewport_bottom_line, 1);
+        assert_eq!(screen.lines.len(), 1);
+    }
+
+    #[test]
+    fn viewport_top_line_empty_input() {
+        let pd = default_pipe_data();
+        let screen = process_bytes(
+            &pd,
+            b"",
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        // Empty → viewport_top_line falls back to 1
+        assert_eq!(screen.viewport_top_line, 1);
+        assert_eq!(screen.viewport_bottom_line, 1);
+    }
+
+    #[test]
+    fn idx_and_rgb_same_color_merge() {
+        // ESC[31m (Idx 1 = palette red #CC0000) then ESC[38;2;204;0;0m (direct RGB)
+        // With default palette, Idx(1) resolves to the same RGB, so adjacent
+        // cells should merge into a single span.
+        let input = b"\x1b[31mA\x1b[38;2;204;0;0mB\x1b[0m";
+        let pd = default_pipe_data();
+        let screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        assert_eq!(screen.lines[0].text, "AB");
+        assert_eq!(
+            screen.lines[0].spans.len(),
+            1,
+            "Idx(1) and Rgb(204,0,0) should merge into one span"
+        );
+    }
+
+    // --- page boundaries ---
+
+    #[test]
+    fn find_page_markers_osc133_and_form_feed() {
+        let data = b"a\x1b]133;A\x07b\x0cc\x1b]133;A\x1b\\d";
+        // Split points: after BEL (9), after FF (11), after ST (21)
+        assert_eq!(find_page_markers(data), vec![9, 11, 21]);
+    }
+
+    #[test]
+    fn find_page_markers_ignores_other_osc() {
+        let data = b"\x1b]133;C\x07\x1b]0;title\x07text";
+        assert!(find_page_markers(data).is_empty());
+    }
+
+    #[test]
+    fn find_page_markers_unterminated() {
+        assert!(find_page_markers(b"x\x1b]133;A").is_empty());
+    }
+
+    #[test]
+    fn page_boundaries_from_command_markers() {
+        let input = b"\x1b]133;A\x07$ ls\r\nfile\r\n\x1b]133;A\x07$ pwd\r\n/home\r\n";
+        let pd = default_pipe_data();
+        let screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        assert_eq!(screen.lines[0].text, "$ ls");
+        assert_eq!(screen.lines[2].text, "$ pwd");
+        assert_eq!(screen.page_boundaries, vec![1, 3]);
     }
 
     #[test]
-    fn process_colored_text() {
-        let input = b"\x1b[31mHello\x1b[0m World";
+    fn page_boundaries_with_scrollback() {
+        let mut input = Vec::new();
+        for i in 0..30 {
+            if i % 10 == 0 {
+                input.extend_from_slice(b"\x1b]133;A\x07");
+            }
+            input.extend_from_slice(format!("line {i}\r\n").as_bytes());
+        }
+        let pd = PipeData {
+            cursor_x: 0,
+            cursor_y: 0,
+            lines: 10,
+            columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        let screen = process_bytes(
+            &pd,
+            &input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        assert_eq!(screen.page_boundaries, vec![1, 11, 21]);
+        assert_eq!(screen.lines[20].text, "line 20");
+
+        // 21 lines scrolled off but only 5 are kept: the boundaries move up
+        // with the evicted lines, and those inside them are gone
+        let screen = process_bytes(&pd, &input, &palette::DEFAULT_PALETTE, 5);
+        assert!(screen.history_full);
+        assert_eq!(screen.lines[0].text, "line 16");
+        assert_eq!(screen.page_boundaries, vec![5]);
+        assert_eq!(screen.lines[4].text, "line 20");
+
+        // Evicted well past the slack the parser keeps
+        let screen = process_bytes(&pd, &input.repeat(10), &palette::DEFAULT_PALETTE, 5);
+        assert_eq!(screen.lines[0].text, "line 16");
+        assert_eq!(screen.page_boundaries, vec![5]);
+    }
+
+    #[test]
+    fn page_boundaries_form_feed() {
+        let input = b"page one\r\x0cpage two";
         let pd = default_pipe_data();
         let screen = process_bytes(
             &pd,
@@ -341,14 +2610,13 @@ mod tests {
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
         );
-        assert_eq!(screen.lines[0].text, "Hello World");
-        assert!(screen.lines[0].spans.len() >= 1);
-        assert!(screen.lines[0].spans[0].face.contains("rgb:"));
+        assert_eq!(screen.lines[1].text, "page two");
+        assert_eq!(screen.page_boundaries, vec![2]);
     }
 
     #[test]
-    fn default_spans_skipped() {
-        let input = b"plain text";
+    fn page_boundaries_in_trimmed_tail_dropped() {
+        let input = b"text\r\n\x0c\x0c";
         let pd = default_pipe_data();
         let screen = process_bytes(
             &pd,
@@ -356,570 +2624,1554 @@ mod tests {
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
         );
-        assert_eq!(screen.lines[0].spans.len(), 0);
+        assert_eq!(screen.lines.len(), 1);
+        assert!(screen.page_boundaries.is_empty());
+    }
+
+    #[test]
+    fn focus_cursor_block_keeps_only_cursor_block() {
+        let input = b"\x1b]133;A\x07\x1b[31m$ one\x1b[0m\r\n\x1b[32mout one\x1b[0m\r\n\
+\x1b]133;A\x07\x1b[31m$ two\x1b[0m\r\n\x1b[32mout two\x1b[0m";
+        let pd = PipeData {
+            cursor_x: 0,
+            cursor_y: 3,
+            lines: 24,
+            columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        let mut screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        assert_eq!(screen.page_boundaries, vec![1, 3]);
+        assert_eq!(screen.cursor.line, 4);
+
+        focus_cursor_block(&mut screen);
+
+        assert!(screen.lines[0].spans.is_empty());
+        assert!(screen.lines[1].spans.is_empty());
+        assert_eq!(screen.lines[2].spans.len(), 1);
+        assert_eq!(screen.lines[3].spans.len(), 1);
+    }
+
+    #[test]
+    fn focus_cursor_block_first_block() {
+        let input = b"\x1b]133;A\x07\x1b[31mone\x1b[0m\r\n\x1b]133;A\x07\x1b[31mtwo\x1b[0m";
+        let pd = default_pipe_data(); // cursor on line 1
+        let mut screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        focus_cursor_block(&mut screen);
+        assert_eq!(screen.lines[0].spans.len(), 1);
+        assert!(screen.lines[1].spans.is_empty());
+    }
+
+    #[test]
+    fn focus_cursor_block_without_boundaries_is_noop() {
+        let input = b"\x1b[31mone\x1b[0m\r\n\x1b[31mtwo\x1b[0m";
+        let pd = default_pipe_data();
+        let mut screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        focus_cursor_block(&mut screen);
+        assert_eq!(screen.lines[0].spans.len(), 1);
+        assert_eq!(screen.lines[1].spans.len(), 1);
+    }
+
+    // --- wide characters at the trim boundary ---
+
+    #[test]
+    fn wide_char_at_trim_boundary_keeps_full_span() {
+        let input = "\x1b[31m日   \x1b[0m".as_bytes();
+        let pd = PipeData {
+            cursor_x: 0,
+            cursor_y: 0,
+            lines: 24,
+            columns: 200,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        let screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        assert_eq!(screen.lines[0].text, "日");
+        assert_eq!(screen.lines[0].spans.len(), 1);
+        // "日" = 3 bytes → span 1..4 (exclusive)
+        assert_eq!(screen.lines[0].spans[0].start_byte, 1);
+        assert_eq!(screen.lines[0].spans[0].end_byte, 4);
+    }
+
+    #[test]
+    fn cursor_on_wide_char_continuation_resolves_to_first_byte() {
+        // "ab日" then trailing spaces; 日 occupies cols 2-3, cursor on col 3
+        let input = "ab日   ".as_bytes();
+        let pd = PipeData {
+            cursor_x: 3,
+            cursor_y: 0,
+            lines: 24,
+            columns: 200,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        let screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        assert_eq!(screen.lines[0].text, "ab日");
+        assert_eq!(screen.cursor.col, 3);
+    }
+
+    #[test]
+    fn cursor_on_wide_char_first_cell_at_trim_boundary() {
+        let input = "日   ".as_bytes();
+        for cursor_x in [0, 1] {
+            let pd = PipeData {
+                cursor_x,
+                cursor_y: 0,
+                lines: 24,
+                columns: 200,
+                scrolled_by: 0,
+                cursor_shape: None,
+            };
+            let screen = process_bytes(
+                &pd,
+                input,
+                &palette::DEFAULT_PALETTE,
+                DEFAULT_MAX_SCROLLBACK_LINES,
+            );
+            assert_eq!(screen.lines[0].text, "日");
+            assert_eq!(screen.cursor.col, 1, "cursor_x={cursor_x}");
+        }
+    }
+
+    // --- match_default_colors ---
+
+    #[test]
+    fn match_default_colors_rewrites_clashing_colors() {
+        let pd = default_pipe_data();
+        // fg #282828 on default bg, default fg on bg #EBDBB2, bold #282828
+        let input = b"\x1b[38;2;40;40;40mA\x1b[0m\x1b[48;2;235;219;178mB\x1b[0m\
+                      \x1b[1;38;2;40;40;40mC\x1b[0m\x1b[31mD\x1b[0m";
+        let mut screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        match_default_colors(&mut screen, [0xEB, 0xDB, 0xB2], [0x28, 0x28, 0x28]);
+        let faces: Vec<&str> = screen.lines[0].spans.iter().map(|s| &*s.face).collect();
+        assert_eq!(faces, ["default,default+b", "rgb:CC0000,default"]);
+    }
+
+    // --- fill_line_backgrounds ---
+
+    #[test]
+    fn fill_line_backgrounds_extends_to_terminal_width() {
+        let pd = default_pipe_data();
+        let mut screen = process_bytes(
+            &pd,
+            b"\x1b[44m short \x1b[0m\r\nplain",
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        assert_eq!(screen.lines[0].spans[0].end_byte, 7);
+
+        fill_line_backgrounds(&mut screen, 80);
+
+        let line = &screen.lines[0];
+        assert_eq!(line.text.len(), 80);
+        assert_eq!(line.spans.len(), 1);
+        assert_eq!(line.spans[0].start_byte, 1);
+        // Exclusive end 81 → the range reaches column 80
+        assert_eq!(line.spans[0].end_byte, 81);
+        assert_eq!(&*line.spans[0].face, "default,rgb:0000CC");
+        // Lines without a trailing background are untouched
+        assert_eq!(screen.lines[1].text, "plain");
+    }
+
+    #[test]
+    fn fill_line_backgrounds_skips_foreground_and_interior_spans() {
+        let pd = default_pipe_data();
+        let mut screen = process_bytes(
+            &pd,
+            b"\x1b[31mred\x1b[0m\r\n\x1b[44mbg\x1b[0m tail",
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        fill_line_backgrounds(&mut screen, 80);
+        assert_eq!(screen.lines[0].text, "red");
+        assert_eq!(screen.lines[1].text, "bg tail");
+        assert_eq!(screen.lines[1].spans[0].end_byte, 3);
+    }
+
+    #[test]
+    fn fill_line_backgrounds_counts_wide_chars_as_two_columns() {
+        let pd = default_pipe_data();
+        let mut screen = process_bytes(
+            &pd,
+            "\x1b[44m日本\x1b[0m".as_bytes(),
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        fill_line_backgrounds(&mut screen, 10);
+        // 2 wide chars = 4 columns, padded with 6 spaces
+        assert_eq!(screen.lines[0].text, "日本      ");
+        assert_eq!(screen.lines[0].spans[0].end_byte, 13);
+    }
+
+    // --- line_sources ---
+
+    #[test]
+    fn line_sources_track_offsets_and_wraps() {
+        // 4-row, 10-col terminal; the first line wraps once
+        let pd = PipeData {
+            cursor_x: 0,
+            cursor_y: 3,
+            lines: 4,
+            columns: 10,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        let input = b"0123456789abc\r\nl2\r\nl3\r\nl4\r\nl5";
+        let screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        // Lines: "0123456789", "abc", "l2", "l3", "l4", "l5" → total_sb = 2
+        assert_eq!(screen.lines.len(), 6);
+        assert_eq!(screen.line_sources.len(), 6);
+        let sources: Vec<(usize, u16, bool)> = screen
+            .line_sources
+            .iter()
+            .map(|s| (s.offset, s.row, s.continuation))
+            .collect();
+        assert_eq!(
+            sources,
+            [
+                (2, 0, false),
+                (2, 1, true),
+                (2, 2, false),
+                (2, 3, false),
+                (1, 3, false),
+                (0, 3, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn line_sources_shifted_by_source_row_offset() {
+        // `capture-pane -S 2` of a 5-row pane: pane rows 2-4, replayed from
+        // the top of the screen
+        let pd = PipeData {
+            cursor_x: 0,
+            cursor_y: 2,
+            lines: 5,
+            columns: 10,
+            ..default_pipe_data()
+        };
+        let opts = ProcessOptions {
+            source_row_offset: 2,
+            ..ProcessOptions::default()
+        };
+        let screen = process_bytes_with(
+            &pd,
+            b"r2\r\nr3\r\nr4",
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+            &opts,
+        );
+        let rows: Vec<(usize, u16)> = screen
+            .line_sources
+            .iter()
+            .map(|s| (s.offset, s.row))
+            .collect();
+        assert_eq!(rows, [(0, 2), (0, 3), (0, 4)]);
+        assert_eq!(screen.lines[screen.cursor.line - 1].text, "r4");
+    }
+
+    // --- quantize ---
+
+    // --- cursor on a blank line ---
+
+    #[test]
+    fn cursor_on_blank_line_is_clamped() {
+        let pd = PipeData {
+            cursor_x: 5,
+            cursor_y: 1,
+            ..default_pipe_data()
+        };
+        let input = b"first\r\n\r\nthird";
+        let screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        assert_eq!(screen.lines[1].text, "");
+        assert_eq!((screen.cursor.line, screen.cursor.col), (2, 1));
+
+        // Untrimmed, the blank line is wide enough for the real column
+        let screen = process_bytes_with(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+            &ProcessOptions {
+                no_trailing_trim: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!((screen.cursor.line, screen.cursor.col), (2, 6));
+    }
+
+    // --- AttrMap ---
+
+    #[test]
+    fn attr_map_parse_overrides() {
+        assert_eq!(AttrMap::parse(""), Ok(AttrMap::default()));
+        let map = AttrMap::parse("italic=, inverse=u").unwrap();
+        assert_eq!(map.bits, [0x01, 0x02, 0, 0x08, 0x08]);
+        assert!(AttrMap::parse("italic").is_err());
+        assert!(AttrMap::parse("blink=b").is_err());
+        assert!(AttrMap::parse("bold=x").is_err());
+        assert!(AttrMap::parse("bold=bi").is_err());
+    }
+
+    #[test]
+    fn attr_map_drops_italic() {
+        let input = b"\x1b[3mit\x1b[0m \x1b[1;3;31mbi\x1b[0m";
+        let pd = default_pipe_data();
+        let default = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        let faces: Vec<&str> = default.lines[0].spans.iter().map(|s| &*s.face).collect();
+        assert_eq!(faces, vec!["default,default+i", "rgb:CC0000,default+bi"]);
+
+        let screen = process_bytes_with(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+            &ProcessOptions {
+                attr_map: AttrMap::parse("italic=").unwrap(),
+                ..Default::default()
+            },
+        );
+        // Italic-only text loses its span entirely
+        let faces: Vec<&str> = screen.lines[0].spans.iter().map(|s| &*s.face).collect();
+        assert_eq!(faces, vec!["rgb:CC0000,default+b"]);
+        assert_eq!(screen.lines[0].spans[0].start_byte, 4);
+    }
+
+    // --- FaceCache ---
+
+    #[test]
+    fn face_cache_returns_identical_strings() {
+        let mut cache = FaceCache::new();
+        let key = FaceKey {
+            fg: Some([0xFF, 0, 0]),
+            bg: None,
+            attrs: 0x01,
+        };
+        let first = cache.face(&key);
+        assert_eq!(&*first, face_key_to_string(&key));
+        assert!(Rc::ptr_eq(&cache.face(&key), &first));
+        assert_eq!(cache.faces.len(), 1);
+    }
+
+    #[test]
+    fn face_cache_reused_across_frames() {
+        // 2000 colorful lines using 8 distinct faces, processed twice
+        let mut input = Vec::new();
+        for i in 0..2000 {
+            input.extend_from_slice(format!("\x1b[3{}mline {i}\x1b[0m\r\n", i % 8).as_bytes());
+        }
+        let pd = default_pipe_data();
+        let mut cache = FaceCache::new();
+        let frames: Vec<ProcessedScreen> = (0..2)
+            .map(|_| {
+                process_bytes_cached(
+                    &pd,
+                    &input,
+                    &palette::DEFAULT_PALETTE,
+                    DEFAULT_MAX_SCROLLBACK_LINES,
+                    &ProcessOptions::default(),
+                    &mut cache,
+                )
+            })
+            .collect();
+        // Formatted once per distinct face; every span shares that string
+        assert_eq!(cache.faces.len(), 8);
+        let face = |frame: usize, line: usize| &frames[frame].lines[line].spans[0].face;
+        assert!(Rc::ptr_eq(face(0, 0), face(0, 8)));
+        assert!(Rc::ptr_eq(face(0, 0), face(1, 1992)));
+        let uncached = process_bytes(
+            &pd,
+            &input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        assert!(frames[0].lines == uncached.lines);
+        assert!(frames[1].lines == uncached.lines);
+    }
+
+    // --- screen_only ---
+
+    #[test]
+    fn screen_only_matches_last_rows_of_full_output() {
+        let mut input = Vec::new();
+        for i in 0..30 {
+            if i == 5 || i == 25 {
+                input.extend_from_slice(b"\x1b]133;A\x07");
+            }
+            input.extend_from_slice(format!("\x1b[3{}mline{i}\x1b[0m", i % 8).as_bytes());
+            if i < 29 {
+                input.extend_from_slice(b"\r\n");
+            }
+        }
+        let pd = PipeData {
+            cursor_x: 2,
+            cursor_y: 9,
+            lines: 10,
+            ..default_pipe_data()
+        };
+        let full = process_bytes(
+            &pd,
+            &input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        let visible = process_bytes_with(
+            &pd,
+            &input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+            &ProcessOptions {
+                screen_only: true,
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(full.lines.len(), 30);
+        assert_eq!(visible.lines.len(), 10);
+        assert!(visible.lines == full.lines[20..]);
+        assert_eq!(
+            (visible.cursor.line, visible.cursor.col),
+            (full.cursor.line - 20, full.cursor.col)
+        );
+        assert_eq!(
+            (visible.viewport_top_line, visible.viewport_bottom_line),
+            (1, 10)
+        );
+        let expected: Vec<usize> = full
+            .page_boundaries
+            .iter()
+            .filter(|&&l| l > 20)
+            .map(|l| l - 20)
+            .collect();
+        assert_eq!(visible.page_boundaries, expected);
+        assert_eq!(expected, [6]);
     }
 
     #[test]
-    fn span_merging() {
-        // Entire "Hello World" in red — should be 1 span
-        let input = b"\x1b[31mHello World\x1b[0m";
+    fn quantize_merges_near_identical_truecolor() {
+        let input = b"\x1b[38;2;250;10;10mA\x1b[38;2;252;4;8mB\x1b[0m";
         let pd = default_pipe_data();
-        let screen = process_bytes(
+        let exact = process_bytes(
             &pd,
             input,
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
         );
-        assert_eq!(screen.lines[0].text, "Hello World");
-        assert_eq!(screen.lines[0].spans.len(), 1);
-    }
+        assert_eq!(exact.lines[0].spans.len(), 2);
 
-    #[test]
-    fn wide_characters() {
-        let input = "日本語test".as_bytes();
-        let pd = default_pipe_data();
-        let screen = process_bytes(
+        let quantized = process_bytes_with(
             &pd,
             input,
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
+            &ProcessOptions {
+                quantize: true,
+                ..Default::default()
+            },
         );
-        assert_eq!(screen.lines[0].text, "日本語test");
+        assert_eq!(quantized.lines[0].spans.len(), 1);
+        assert_eq!(&*quantized.lines[0].spans[0].face, "rgb:FF0000,default");
+        assert_eq!(quantized.lines[0].spans[0].end_byte, 3);
     }
 
     #[test]
-    fn wide_char_byte_offsets() {
-        // "日" = 3 bytes, "本" = 3 bytes, "語" = 3 bytes
-        let input = b"\x1b[31m\xe6\x97\xa5\x1b[0m\xe6\x9c\xac"; // red "日" + normal "本"
-        let pd = default_pipe_data();
-        let screen = process_bytes(
-            &pd,
+    fn quantize_applies_to_background() {
+        let input = b"\x1b[48;2;30;30;31mX\x1b[0m";
+        let screen = process_bytes_with(
+            &default_pipe_data(),
             input,
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
+            &ProcessOptions {
+                quantize: true,
+                ..Default::default()
+            },
         );
-        assert_eq!(screen.lines[0].text, "日本");
-        assert_eq!(screen.lines[0].spans.len(), 1);
-        // Red span covers "日" = bytes 1..4 (1-based, exclusive end)
-        assert_eq!(screen.lines[0].spans[0].start_byte, 1);
-        assert_eq!(screen.lines[0].spans[0].end_byte, 4); // 3 bytes + 1
+        // Nearest xterm color to (30,30,31) is grayscale 234 = (28,28,28)
+        assert_eq!(&*screen.lines[0].spans[0].face, "default,rgb:1C1C1C");
     }
 
     #[test]
-    fn attributes_combined() {
-        let input = b"\x1b[1;3mBoldItalic\x1b[0m";
+    fn bold_is_bright_uses_bright_palette_color() {
+        let input = b"\x1b[1;31mA\x1b[0m\x1b[31mB\x1b[1;91mC\x1b[0m";
         let pd = default_pipe_data();
-        let screen = process_bytes(
+        let plain = process_bytes(
             &pd,
             input,
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
         );
-        assert!(screen.lines[0].spans[0].face.contains("+bi"));
-    }
+        assert_eq!(&*plain.lines[0].spans[0].face, "rgb:CC0000,default+b");
 
-    #[test]
-    fn cursor_position_simple() {
-        let input = b"line1\r\nline2\r\nline3";
-        let pd = PipeData {
-            cursor_x: 3,
-            cursor_y: 2,
-            lines: 24,
-            columns: 80,
-        };
-        let screen = process_bytes(
+        let bright = process_bytes_with(
             &pd,
             input,
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
+            &ProcessOptions {
+                bold_is_bright: true,
+                ..Default::default()
+            },
+        );
+        let faces: Vec<&str> = bright.lines[0].spans.iter().map(|s| &*s.face).collect();
+        // Bold red becomes bright red; plain red and bold bright red are unchanged
+        assert_eq!(
+            faces,
+            vec![
+                "rgb:FF0000,default+b",
+                "rgb:CC0000,default",
+                "rgb:FF0000,default+b"
+            ]
         );
-        // Cursor should be on line 3 (1-based), col 4 (1-based, after "lin")
-        assert_eq!(screen.cursor.line, 3);
-        assert_eq!(screen.cursor.col, 4);
     }
 
     #[test]
-    fn empty_input() {
+    fn skip_blank_fg_splits_foreground_spans() {
         let pd = default_pipe_data();
-        let screen = process_bytes(
-            &pd,
-            b"",
+        let process = |input: &[u8], skip_blank_fg| {
+            let screen = process_bytes_with(
+                &pd,
+                input,
+                &palette::DEFAULT_PALETTE,
+                DEFAULT_MAX_SCROLLBACK_LINES,
+                &ProcessOptions {
+                    skip_blank_fg,
+                    ..Default::default()
+                },
+            );
+            screen.lines[0]
+                .spans
+                .iter()
+                .map(|s| (s.start_byte, s.end_byte, s.face.to_string()))
+                .collect::<Vec<_>>()
+        };
+        let red = "rgb:CC0000,default".to_string();
+
+        let input = b"\x1b[31mA   B\x1b[0m";
+        assert_eq!(process(input, false), [(1, 6, red.clone())]);
+        assert_eq!(process(input, true), [(1, 2, red.clone()), (5, 6, red)]);
+
+        // A background, underline or inverse still shows on the spaces
+        for input in [
+            &b"\x1b[31;42mA   B\x1b[0m"[..],
+            b"\x1b[31;4mA   B\x1b[0m",
+            b"\x1b[31;7mA   B\x1b[0m",
+        ] {
+            assert_eq!(process(input, true).len(), 1);
+        }
+    }
+
+    // --- downsample_gradients ---
+
+    fn gradient_line(columns: usize, red: impl Fn(usize) -> usize) -> ProcessedScreen {
+        let mut input = Vec::new();
+        for col in 0..columns {
+            input.extend_from_slice(format!("\x1b[48;2;{};0;64m ", red(col)).as_bytes());
+        }
+        input.extend_from_slice(b"\x1b[0mx");
+        process_bytes(
+            &default_pipe_data(),
+            &input,
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
+        )
+    }
+
+    #[test]
+    fn downsample_gradients_reduces_per_column_spans() {
+        let mut screen = gradient_line(40, |col| col * 6);
+        assert_eq!(screen.lines[0].spans.len(), 40);
+
+        downsample_gradients(&mut screen, 4);
+        let spans = &screen.lines[0].spans;
+        assert_eq!(spans.len(), 10);
+        assert_eq!((spans[0].start_byte, spans[0].end_byte), (1, 5));
+        // Midpoint of columns 0-3 is column 2 (red = 12)
+        assert_eq!(&*spans[0].face, "default,rgb:0C0040");
+        assert_eq!((spans[9].start_byte, spans[9].end_byte), (37, 41));
+    }
+
+    #[test]
+    fn downsample_gradients_keeps_non_monotonic_and_short_runs() {
+        // Alternating colors are not a gradient
+        let mut screen = gradient_line(20, |col| if col % 2 == 0 { 10 } else { 200 });
+        downsample_gradients(&mut screen, 4);
+        assert_eq!(screen.lines[0].spans.len(), 20);
+
+        let mut screen = gradient_line(GRADIENT_MIN_RUN - 1, |col| col * 10);
+        downsample_gradients(&mut screen, 4);
+        assert_eq!(screen.lines[0].spans.len(), GRADIENT_MIN_RUN - 1);
+    }
+
+    // --- add_tab_guides ---
+
+    #[test]
+    fn tab_guides_at_tab_stop_columns() {
+        let screen_for = |input: &str| {
+            let mut screen = process_bytes(
+                &default_pipe_data(),
+                input.as_bytes(),
+                &palette::DEFAULT_PALETTE,
+                DEFAULT_MAX_SCROLLBACK_LINES,
+            );
+            add_tab_guides(&mut screen, "ScrollbackTabGuide");
+            screen
+        };
+        let ranges = |line: &ProcessedLine| -> Vec<(usize, usize, String)> {
+            line.spans
+                .iter()
+                .map(|s| (s.start_byte, s.end_byte, s.face.to_string()))
+                .collect()
+        };
+        let guide = |start, end| (start, end, "ScrollbackTabGuide".to_string());
+
+        // Tabs expand to spaces; the stops are columns 8 and 16
+        let screen = screen_for("name\tsize\tdate\r\nx\t\x1b[31m12\x1b[0m\tmon");
+        assert_eq!(screen.lines[0].text, "name    size    date");
+        assert_eq!(ranges(&screen.lines[0]), [guide(9, 10), guide(17, 18)]);
+        // The colored "12" at column 8 keeps its own face
+        assert_eq!(
+            ranges(&screen.lines[1]),
+            [(9, 11, "rgb:CC0000,default".to_string()), guide(17, 18)]
         );
-        // Should have no lines (all trimmed as empty)
-        assert!(screen.lines.is_empty() || screen.lines.iter().all(|l| l.text.is_empty()));
+
+        // 日本語 are two columns and three bytes each: "ab日本語" fills
+        // columns 0-7 in 11 bytes, so the tab's first space (column 8) is
+        // byte 12 and "z" (column 16) byte 20. A wide char over a stop gets
+        // no guide
+        let screen = screen_for("ab日本語\tz\r\na日本語日x");
+        assert_eq!(ranges(&screen.lines[0]), [guide(12, 13), guide(20, 21)]);
+        assert_eq!(ranges(&screen.lines[1]), []);
     }
 
+    // --- number_lines ---
+
     #[test]
-    fn scrollback_lines() {
-        // Generate enough output to create scrollback
+    fn number_lines_shifts_spans_and_cursor() {
         let mut input = Vec::new();
-        for i in 0..30 {
-            input.extend_from_slice(format!("line {i}\r\n").as_bytes());
+        for n in 0..11 {
+            input.extend_from_slice(format!("line{n}\r\n").as_bytes());
         }
+        input.extend_from_slice(b"ab\x1b[31mred\x1b[0m");
         let pd = PipeData {
-            cursor_x: 0,
-            cursor_y: 0,
-            lines: 10,
-            columns: 80,
+            cursor_x: 2,
+            cursor_y: 11,
+            ..default_pipe_data()
         };
-        let screen = process_bytes(
+        let mut screen = process_bytes(
             &pd,
             &input,
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
         );
-        // Should have all 30 lines (some in scrollback, some on screen)
-        assert!(screen.lines.len() >= 30);
-        assert_eq!(screen.lines[0].text, "line 0");
-        assert_eq!(screen.lines[29].text, "line 29");
+        assert_eq!(screen.lines[11].spans[0].start_byte, 3);
+        let cursor_col = screen.cursor.col;
+
+        number_lines(&mut screen);
+
+        assert_eq!(screen.lines[0].text, " 1: line0");
+        assert_eq!(screen.lines[11].text, "12: abred");
+        assert_eq!(screen.lines[11].spans[0].start_byte, 7);
+        assert_eq!(screen.lines[11].spans[0].end_byte, 10);
+        assert_eq!(screen.cursor.col, cursor_col + 4);
+        assert_eq!(&screen.lines[11].text[screen.cursor.col - 1..], "red");
     }
 
-    // --- Phase 1: HIGH priority ---
+    // --- default_colors ---
 
     #[test]
-    fn cursor_position_with_scrollback() {
-        let mut input = Vec::new();
-        for i in 0..30 {
-            input.extend_from_slice(format!("line {i}\r\n").as_bytes());
-        }
-        let pd = PipeData {
-            cursor_x: 0,
-            cursor_y: 5,
-            lines: 10,
-            columns: 80,
-        };
-        let screen = process_bytes(
+    fn default_colors_resolve_through_palette() {
+        let pd = default_pipe_data();
+        let screen = process_bytes_with(
             &pd,
-            &input,
+            b"ab\x1b[31mc\x1b[0m\x1b[1md\x1b[0m\x1b[44me",
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
+            &ProcessOptions {
+                default_colors: Some((15, 0)),
+                ..Default::default()
+            },
         );
-        // 30 lines + trailing \r\n = 31 rows, total_sb = 21
-        // cursor_output_line = 21 + 5 + 1 = 27
-        assert_eq!(screen.cursor.line, 27);
+        let faces: Vec<&str> = screen.lines[0].spans.iter().map(|s| &*s.face).collect();
+        // Plain "ab" and bold "d" keep the terminal's defaults
+        assert_eq!(
+            faces,
+            [
+                "rgb:CC0000,rgb:000000",
+                "default,default+b",
+                "rgb:FFFFFF,rgb:0000CC"
+            ]
+        );
+        assert_eq!(screen.lines[0].spans[0].start_byte, 3);
     }
 
+    // --- cursor in trimmed trailing blanks ---
+
     #[test]
-    fn cursor_clamped_on_trimmed_empty_lines() {
-        let input = b"Hello";
+    fn cursor_in_trimmed_blanks_moves_to_end_of_text() {
         let pd = PipeData {
-            cursor_x: 0,
-            cursor_y: 23,
-            lines: 24,
-            columns: 80,
+            cursor_x: 7,
+            cursor_y: 1,
+            ..default_pipe_data()
         };
-        let screen = process_bytes(
+        let input = b"first line\r\n$ ls";
+        let process = |pd: &PipeData, no_trailing_trim| {
+            process_bytes_with(
+                pd,
+                input,
+                &palette::DEFAULT_PALETTE,
+                DEFAULT_MAX_SCROLLBACK_LINES,
+                &ProcessOptions {
+                    no_trailing_trim,
+                    ..Default::default()
+                },
+            )
+        };
+
+        let screen = process(&pd, false);
+        assert_eq!(screen.lines[1].text, "$ ls");
+        assert_eq!((screen.cursor.line, screen.cursor.col), (2, 5));
+
+        // With the blanks kept, the reported column exists
+        let screen = process(&pd, true);
+        assert_eq!((screen.cursor.line, screen.cursor.col), (2, 8));
+
+        // A cursor inside the text is left alone
+        let screen = process(&PipeData { cursor_x: 1, ..pd }, false);
+        assert_eq!(screen.cursor.col, 2);
+    }
+
+    // --- dump_cells ---
+
+    #[test]
+    fn dump_cells_colors_and_flags() {
+        let pd = PipeData {
+            lines: 2,
+            columns: 6,
+            ..default_pipe_data()
+        };
+        let dump = dump_cells(
             &pd,
-            input,
+            "\x1b[1;31ma\x1b[0m\x1b[48;2;16;32;48m日\x1b[0m\x1b[3;38;5;208mb".as_bytes(),
+        );
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines[0], "row\tcol\tcontents\tfg\tbg\tattrs\tflags");
+        // 2 rows x 6 columns
+        assert_eq!(lines.len(), 1 + 12);
+        assert_eq!(lines[1], "0\t0\t\"a\"\tidx:1\tdefault\tbold\t-");
+        assert_eq!(lines[2], "0\t1\t\"日\"\tdefault\trgb:102030\t-\twide");
+        // vt100 keeps the continuation cell's own (default) colors
+        assert_eq!(lines[3], "0\t2\t-\tdefault\tdefault\t-\tcontinuation");
+        assert_eq!(lines[4], "0\t3\t\"b\"\tidx:208\tdefault\titalic\t-");
+        assert_eq!(lines[7], "1\t0\t-\tdefault\tdefault\t-\t-");
+    }
+
+    // --- prepend_truncation_marker ---
+
+    #[test]
+    fn truncation_marker_only_when_truncated() {
+        let pd = PipeData {
+            cursor_x: 2,
+            cursor_y: 4,
+            lines: 5,
+            columns: 80,
+            ..default_pipe_data()
+        };
+        let input: String = (0..20).map(|i| format!("line {i}\r\n")).collect();
+        let input = format!("{input}$ ls");
+        let screen_with = |max_scrollback_lines| {
+            let mut screen = process_bytes(
+                &pd,
+                input.as_bytes(),
+                &palette::DEFAULT_PALETTE,
+                max_scrollback_lines,
+            );
+            let before = (screen.cursor.line, screen.cursor.col, screen.lines.len());
+            prepend_truncation_marker(&mut screen, "ScrollbackTruncated");
+            (screen, before)
+        };
+
+        // Everything fits: no marker, nothing moves
+        let (screen, (line, col, len)) = screen_with(DEFAULT_MAX_SCROLLBACK_LINES);
+        assert!(!screen.history_full);
+        assert_eq!(screen.lines.len(), len);
+        assert_eq!(screen.lines[0].text, "line 0");
+        assert_eq!((screen.cursor.line, screen.cursor.col), (line, col));
+
+        // The 16 scrolled-off lines exactly fill the history: nothing is lost
+        let (screen, (_, _, len)) = screen_with(16);
+        assert!(!screen.history_full);
+        assert_eq!(screen.lines.len(), len);
+        assert_eq!(screen.lines[0].text, "line 0");
+
+        // No history at all is only truncated once a line scrolls off
+        let screen = process_bytes(&pd, b"$ ls", &palette::DEFAULT_PALETTE, 0);
+        assert!(!screen.history_full);
+
+        // 16 lines scrolled off but only 10 are kept
+        let (screen, (line, col, len)) = screen_with(10);
+        assert!(screen.history_full);
+        assert_eq!(screen.lines.len(), len + 1);
+        assert_eq!(
+            screen.lines[0].text,
+            "── scrollback truncated (older lines omitted) ──"
+        );
+        assert_eq!(&*screen.lines[0].spans[0].face, "ScrollbackTruncated");
+        assert_eq!(screen.lines[1].text, "line 6");
+        assert_eq!((screen.cursor.line, screen.cursor.col), (line + 1, col));
+        assert_eq!(screen.lines[screen.cursor.line - 1].text, "$ ls");
+
+        // Auto-trim reports how many lines it left out
+        let mut screen = screen_with(DEFAULT_MAX_SCROLLBACK_LINES).0;
+        keep_last_lines(&mut screen, 5);
+        prepend_truncation_marker(&mut screen, "ScrollbackTruncated");
+        assert_eq!(
+            screen.lines[0].text,
+            "── scrollback truncated (16 older lines omitted) ──"
+        );
+    }
+
+    // --- canonicalize_face ---
+
+    #[test]
+    fn canonicalize_face_orders_flags() {
+        assert_eq!(
+            canonicalize_face("rgb:FF0000,default+ib"),
+            "rgb:FF0000,default+bi"
+        );
+        assert_eq!(canonicalize_face("red,default+rudib"), "red,default+bdiur");
+        assert_eq!(canonicalize_face("red+sbfb"), "red+bfs");
+        assert_eq!(canonicalize_face("red+ub@Default"), "red+bu@Default");
+        assert_eq!(canonicalize_face("red,default+"), "red,default");
+        assert_eq!(canonicalize_face("ScrollbackHeader"), "ScrollbackHeader");
+    }
+
+    #[test]
+    fn distinct_faces_collapses_flag_order() {
+        let mut screen = process_bytes(
+            &default_pipe_data(),
+            b"ab cd",
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
         );
-        // Only line 0 has text "Hello", lines 1-23 are empty and get trimmed
-        // Cursor was at line 24 (1-based), gets clamped to last line
-        assert_eq!(screen.lines.len(), 1);
-        assert_eq!(screen.cursor.line, 1);
-        assert_eq!(screen.cursor.col, 1);
+        screen.lines[0].spans = ["rgb:FF0000,default+bi", "rgb:FF0000,default+ib"]
+            .iter()
+            .enumerate()
+            .map(|(i, face)| Span {
+                start_byte: 1 + 3 * i,
+                end_byte: 3 + 3 * i,
+                face: (*face).into(),
+            })
+            .collect();
+        assert_eq!(distinct_faces(&screen), ["rgb:FF0000,default+bi"]);
     }
 
+    // --- append_legend ---
+
     #[test]
-    fn multiple_colors_same_line() {
-        let input = b"\x1b[31mRed\x1b[32mGreen\x1b[0m";
-        let pd = default_pipe_data();
-        let screen = process_bytes(
-            &pd,
-            input,
+    fn append_legend_one_line_per_face() {
+        let mut screen = process_bytes(
+            &default_pipe_data(),
+            b"\x1b[31mred\x1b[0m \x1b[1;32mgreen\x1b[0m\r\n\x1b[31magain\x1b[0m",
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
         );
-        assert_eq!(screen.lines[0].text, "RedGreen");
-        assert_eq!(screen.lines[0].spans.len(), 2);
-        // Red span: bytes 1..4 (exclusive), covers "Red"
-        assert_eq!(screen.lines[0].spans[0].start_byte, 1);
-        assert_eq!(screen.lines[0].spans[0].end_byte, 4);
-        // Green span: bytes 4..9 (exclusive), covers "Green"
-        assert_eq!(screen.lines[0].spans[1].start_byte, 4);
-        assert_eq!(screen.lines[0].spans[1].end_byte, 9);
+        let cursor = (screen.cursor.line, screen.cursor.col);
+        append_legend(&mut screen, 0);
+
+        let legend = &screen.lines[2..];
+        assert_eq!(legend[0].text, "");
+        let texts: Vec<&str> = legend[1..].iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(texts, vec!["rgb:CC0000,default", "rgb:00CC00,default+b"]);
+        for line in &legend[1..] {
+            assert_eq!(line.spans.len(), 1);
+            assert_eq!(&*line.spans[0].face, line.text);
+            assert_eq!(line.spans[0].start_byte, 1);
+            assert_eq!(line.spans[0].end_byte, line.text.len() + 1);
+        }
+        assert_eq!((screen.cursor.line, screen.cursor.col), cursor);
     }
 
     #[test]
-    fn background_color_only() {
-        let input = b"\x1b[42mHighlighted\x1b[0m";
-        let pd = default_pipe_data();
-        let screen = process_bytes(
-            &pd,
-            input,
+    fn append_legend_skips_uncolored_capture() {
+        let mut screen = process_bytes(
+            &default_pipe_data(),
+            b"plain",
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
         );
-        assert_eq!(screen.lines[0].text, "Highlighted");
-        assert_eq!(screen.lines[0].spans.len(), 1);
-        // SGR 42 = green background, default foreground
-        assert_eq!(screen.lines[0].spans[0].face, "default,rgb:00CC00");
+        append_legend(&mut screen, 0);
+        assert_eq!(screen.lines.len(), 1);
     }
 
+    // --- trim_leading_blank_lines ---
+
     #[test]
-    fn span_adjustment_on_trailing_trim() {
-        // Red "Hi" followed by red trailing spaces that get trimmed
-        let input = b"\x1b[31mHi   \x1b[0m";
+    fn trim_leading_blank_lines_after_clear_screen() {
+        // Earlier output is cleared, then the prompt is drawn on row 3
+        let input = b"old\r\noutput\x1b[2J\x1b[H\r\n\r\n\r\n$ ls\r\nfile";
         let pd = PipeData {
-            cursor_x: 0,
-            cursor_y: 0,
-            lines: 24,
-            columns: 20,
+            cursor_x: 4,
+            cursor_y: 4,
+            ..default_pipe_data()
         };
-        let screen = process_bytes(
+        let mut screen = process_bytes(
             &pd,
             input,
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
         );
-        assert_eq!(screen.lines[0].text, "Hi");
-        assert_eq!(screen.lines[0].spans.len(), 1);
-        // Span should be trimmed to text length: bytes 1..3 (exclusive)
-        assert_eq!(screen.lines[0].spans[0].start_byte, 1);
-        assert_eq!(screen.lines[0].spans[0].end_byte, 3);
+        let texts: Vec<&str> = screen.lines.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(texts, vec!["", "", "", "$ ls", "file"]);
+        assert_eq!((screen.cursor.line, screen.cursor.col), (5, 5));
+
+        trim_leading_blank_lines(&mut screen);
+
+        let texts: Vec<&str> = screen.lines.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(texts, vec!["$ ls", "file"]);
+        assert_eq!((screen.cursor.line, screen.cursor.col), (2, 5));
+        assert_eq!(screen.line_sources.len(), 2);
+        assert_eq!(screen.viewport_top_line, 1);
+        assert_eq!(screen.truncated_lines, 0);
     }
 
-    // --- Phase 2: MEDIUM priority ---
+    #[test]
+    fn trim_leading_blank_lines_keeps_content_start() {
+        let mut screen = process_bytes(
+            &default_pipe_data(),
+            b"a\r\n\r\nb",
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        trim_leading_blank_lines(&mut screen);
+        assert_eq!(screen.lines.len(), 3);
+    }
+
+    // --- expand_carriage_returns ---
 
     #[test]
-    fn attribute_dim() {
-        let input = b"\x1b[2mDim\x1b[0m";
-        let pd = default_pipe_data();
+    fn expand_carriage_returns_keeps_each_progress_step() {
+        let input = b"build\r\n 10%\r 50%\r\x1b[32m100%\x1b[0m\r\ndone\r".to_vec();
+        let expanded = expand_carriage_returns(input);
+        assert_eq!(
+            expanded,
+            b"build\r\n 10%\r\n 50%\r\n\x1b[32m100%\x1b[0m\r\ndone\r".to_vec()
+        );
+
         let screen = process_bytes(
-            &pd,
-            input,
+            &default_pipe_data(),
+            &expanded,
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
         );
-        assert_eq!(screen.lines[0].text, "Dim");
-        assert!(screen.lines[0].spans[0].face.contains("+d"));
+        let texts: Vec<&str> = screen.lines.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(texts, vec!["build", " 10%", " 50%", "100%", "done"]);
+        assert_eq!(screen.lines[3].spans.len(), 1);
     }
 
     #[test]
-    fn attribute_underline() {
-        let input = b"\x1b[4mUnderlined\x1b[0m";
-        let pd = default_pipe_data();
-        let screen = process_bytes(
+    fn expand_carriage_returns_leaves_crlf_alone() {
+        let input = b"a\r\nb\r\r\n".to_vec();
+        assert_eq!(expand_carriage_returns(input.clone()), input);
+    }
+
+    #[test]
+    fn fill_span_gaps_covers_default_text_between_spans() {
+        let mut screen = process_bytes(
+            &default_pipe_data(),
+            b"  \x1b[31mred\x1b[0m gap \x1b[32mgreen\x1b[0m tail",
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        assert_eq!(screen.lines[0].spans.len(), 2);
+
+        fill_span_gaps(&mut screen);
+
+        let spans: Vec<(usize, usize, &str)> = screen.lines[0]
+            .spans
+            .iter()
+            .map(|s| (s.start_byte, s.end_byte, &*s.face))
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                (3, 6, "rgb:CC0000,default"),
+                (6, 11, "default,default"),
+                (11, 16, "rgb:00CC00,default"),
+            ]
+        );
+    }
+
+    #[test]
+    fn prepend_header_shifts_screen() {
+        let pd = PipeData {
+            cursor_x: 1,
+            cursor_y: 1,
+            ..default_pipe_data()
+        };
+        let mut screen = process_bytes(
+            &pd,
+            b"one\r\ntwo",
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        let (line, col) = (screen.cursor.line, screen.cursor.col);
+
+        prepend_header(&mut screen, "$ echo 'a'\nb", "ScrollbackHeader");
+
+        assert_eq!(screen.lines[0].text, "$ echo 'a' b");
+        assert_eq!(screen.lines[0].spans[0].end_byte, 13);
+        assert_eq!(&*screen.lines[0].spans[0].face, "ScrollbackHeader");
+        assert_eq!(screen.lines[1].text, "one");
+        assert_eq!(screen.line_sources.len(), screen.lines.len());
+        assert_eq!((screen.cursor.line, screen.cursor.col), (line + 1, col));
+        assert_eq!(screen.viewport_top_line, 2);
+    }
+
+    // --- hard_wrap ---
+
+    #[test]
+    fn hard_wrap_splits_long_line_spans_and_cursor() {
+        // 200 chars: 'a' x 60, red 'b' x 60, 'c' x 80; cursor on byte 150
+        let mut input = "a".repeat(60).into_bytes();
+        input.extend_from_slice(b"\x1b[31m");
+        input.extend_from_slice("b".repeat(60).as_bytes());
+        input.extend_from_slice(b"\x1b[0m");
+        input.extend_from_slice("c".repeat(80).as_bytes());
+        input.extend_from_slice(b"\r\nnext");
+        let pd = PipeData {
+            cursor_x: 149,
+            cursor_y: 0,
+            lines: 24,
+            columns: 250,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        let mut screen = process_bytes(
             &pd,
-            input,
+            &input,
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
         );
-        assert_eq!(screen.lines[0].text, "Underlined");
-        assert!(screen.lines[0].spans[0].face.contains("+u"));
+        screen.page_boundaries = vec![1, 2];
+
+        hard_wrap(&mut screen, 80);
+
+        let texts: Vec<usize> = screen.lines.iter().map(|l| l.text.len()).collect();
+        assert_eq!(texts, [80, 80, 40, 4]);
+        // Red span 61..121 splits at the byte-80 break
+        assert_eq!(screen.lines[0].spans[0].start_byte, 61);
+        assert_eq!(screen.lines[0].spans[0].end_byte, 81);
+        assert_eq!(screen.lines[1].spans[0].start_byte, 1);
+        assert_eq!(screen.lines[1].spans[0].end_byte, 41);
+        assert!(screen.lines[2].spans.is_empty());
+        // Byte 150 (1-based) → line 2, col 70
+        assert_eq!((screen.cursor.line, screen.cursor.col), (2, 70));
+        assert_eq!(screen.page_boundaries, [1, 4]);
+        assert_eq!(screen.line_sources.len(), 4);
+        assert!(screen.line_sources[1].continuation);
+        assert!(!screen.line_sources[3].continuation);
     }
 
     #[test]
-    fn attribute_inverse() {
-        let input = b"\x1b[7mReversed\x1b[0m";
+    fn hard_wrap_respects_char_boundaries() {
         let pd = default_pipe_data();
-        let screen = process_bytes(
+        let mut screen = process_bytes(
             &pd,
-            input,
+            "ab日本".as_bytes(),
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
         );
-        assert_eq!(screen.lines[0].text, "Reversed");
-        assert!(screen.lines[0].spans[0].face.contains("+r"));
-    }
+        hard_wrap(&mut screen, 4);
+        let texts: Vec<&str> = screen.lines.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(texts, ["ab", "日", "本"]);
 
-    #[test]
-    fn reset_then_new_color() {
-        let input = b"\x1b[31mR\x1b[0m N \x1b[34mB\x1b[0m";
-        let pd = default_pipe_data();
-        let screen = process_bytes(
+        let mut screen = process_bytes(
             &pd,
-            input,
+            "日".as_bytes(),
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
         );
-        assert_eq!(screen.lines[0].text, "R N B");
-        assert_eq!(screen.lines[0].spans.len(), 2);
-        // Red span for "R"
-        assert_eq!(screen.lines[0].spans[0].start_byte, 1);
-        assert_eq!(screen.lines[0].spans[0].end_byte, 2);
-        // Blue span for "B"
-        assert_eq!(screen.lines[0].spans[1].start_byte, 5);
-        assert_eq!(screen.lines[0].spans[1].end_byte, 6);
+        hard_wrap(&mut screen, 1);
+        assert_eq!(screen.lines[0].text, "日");
     }
 
     #[test]
-    fn line_with_only_formatting_no_visible_text() {
-        let input = b"\x1b[31m   \x1b[0m";
+    fn hard_wrap_short_lines_untouched() {
         let pd = default_pipe_data();
-        let screen = process_bytes(
+        let mut screen = process_bytes(
             &pd,
-            input,
+            b"\x1b[31mhi\x1b[0m\r\n\r\nthere",
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
         );
-        // All text was colored spaces, trimmed to empty; all empty lines removed
-        assert!(screen.lines.is_empty());
+        hard_wrap(&mut screen, 80);
+        let texts: Vec<&str> = screen.lines.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(texts, ["hi", "", "there"]);
+        assert_eq!(screen.lines[0].spans[0].end_byte, 3);
+        assert_eq!(
+            (screen.viewport_top_line, screen.viewport_bottom_line),
+            (1, 3)
+        );
     }
 
+    // --- squeeze_repeats ---
+
     #[test]
-    fn cursor_on_wide_character() {
-        let input = "日test".as_bytes();
+    fn squeeze_collapses_repeated_line_and_remaps_cursor() {
+        let mut input = b"start\r\n".to_vec();
+        for _ in 0..5 {
+            input.extend_from_slice(b"\x1b[33mretrying\x1b[0m\r\n");
+        }
+        input.extend_from_slice(b"done\r\n$ ");
+        // Cursor on the prompt (line 8), then moved onto the 4th repeat
         let pd = PipeData {
             cursor_x: 2,
-            cursor_y: 0,
-            lines: 24,
-            columns: 80,
+            cursor_y: 7,
+            ..default_pipe_data()
         };
-        let screen = process_bytes(
+        let mut screen = process_bytes(
             &pd,
-            input,
+            &input,
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
         );
-        assert_eq!(screen.lines[0].text, "日test");
-        // "日" = 3 bytes (occupies cols 0-1), cursor_x:2 = 't'
-        // byte offset = 3 (0-based) + 1 = 4 (1-based)
-        assert_eq!(screen.cursor.col, 4);
+        screen.cursor.line = 5;
+        screen.page_boundaries = vec![2, 7];
+
+        squeeze_repeats(&mut screen);
+
+        let texts: Vec<&str> = screen.lines.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(texts, ["start", "retrying (×5)", "done", "$"]);
+        // The color still covers only the original text
+        assert_eq!(screen.lines[1].spans[0].end_byte, 9);
+        assert_eq!((screen.cursor.line, screen.cursor.col), (2, 2));
+        assert_eq!(screen.page_boundaries, [2, 3]);
+        assert_eq!(screen.line_sources.len(), 4);
+        assert_eq!(
+            (screen.viewport_top_line, screen.viewport_bottom_line),
+            (1, 4)
+        );
     }
 
     #[test]
-    fn scrollback_cursor_position_variants() {
-        let mut input = Vec::new();
-        for i in 0..30 {
-            input.extend_from_slice(format!("line {i}\r\n").as_bytes());
-        }
-
-        // Cursor at first visible line (cursor_y:0)
-        let pd = PipeData {
-            cursor_x: 0,
-            cursor_y: 0,
-            lines: 10,
-            columns: 80,
-        };
-        let screen = process_bytes(
+    fn squeeze_keeps_lines_with_different_colors() {
+        let pd = default_pipe_data();
+        let mut screen = process_bytes(
             &pd,
-            &input,
+            b"same\r\n\x1b[31msame\x1b[0m\r\nsame",
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
         );
-        // 30 lines + trailing \r\n = 31 rows, total_sb = 21
-        // cursor_output_line = 21 + 0 + 1 = 22
-        assert_eq!(screen.cursor.line, 22);
+        squeeze_repeats(&mut screen);
+        let texts: Vec<&str> = screen.lines.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(texts, ["same", "same", "same"]);
+    }
 
-        // Cursor at last visible line (cursor_y:9)
+    // --- squeeze_blank_lines ---
+
+    fn blank_runs_screen(cursor_y: usize) -> ProcessedScreen {
         let pd = PipeData {
-            cursor_x: 0,
-            cursor_y: 9,
-            lines: 10,
-            columns: 80,
+            cursor_x: 2,
+            cursor_y,
+            ..default_pipe_data()
         };
-        let screen = process_bytes(
+        process_bytes(
             &pd,
-            &input,
+            b"a\r\n\r\n\r\n\r\nb\r\n\r\n\x1b[31mc\x1b[0m\r\n\r\n\r\n$ ",
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
-        );
-        // cursor_output_line = 21 + 9 + 1 = 31, but trailing empty line trimmed
-        // lines.len() = 30, so cursor clamped to 30 with col = 1
-        assert_eq!(screen.cursor.line, 30);
-        assert_eq!(screen.cursor.col, 1);
+        )
     }
 
     #[test]
-    fn trailing_spaces_trimmed() {
-        let input = b"Hello";
-        let pd = default_pipe_data(); // 80 columns
-        let screen = process_bytes(
+    fn squeeze_blank_lines_collapses_interior_runs() {
+        // Cursor on the prompt, after both collapsed runs
+        let mut screen = blank_runs_screen(9);
+        screen.page_boundaries = vec![5, 10];
+        squeeze_blank_lines(&mut screen);
+
+        let texts: Vec<&str> = screen.lines.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(texts, ["a", "", "b", "", "c", "", "$"]);
+        assert_eq!(screen.lines[4].spans.len(), 1);
+        assert_eq!((screen.cursor.line, screen.cursor.col), (7, 2));
+        assert_eq!(screen.page_boundaries, [3, 7]);
+        assert_eq!(screen.line_sources.len(), 7);
+        assert_eq!(screen.viewport_bottom_line, 7);
+    }
+
+    #[test]
+    fn squeeze_blank_lines_cursor_inside_run() {
+        // Cursor on the third blank line of the first run
+        let mut screen = blank_runs_screen(3);
+        squeeze_blank_lines(&mut screen);
+        assert_eq!((screen.cursor.line, screen.cursor.col), (2, 1));
+    }
+
+    #[test]
+    fn squeeze_blank_lines_cursor_on_multibyte_blank() {
+        // The kept blank line is two ideographic spaces (3 bytes each), as
+        // left by --no-trailing-trim
+        let pd = default_pipe_data();
+        let mut screen = process_bytes(
             &pd,
-            input,
+            b"a\r\n\r\n\r\n$",
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
         );
-        assert_eq!(screen.lines[0].text, "Hello");
-        assert_eq!(screen.lines[0].text.len(), 5);
+        screen.lines[1].text = "\u{3000}\u{3000}".to_string();
+        screen.cursor.line = 3;
+        screen.cursor.col = 5;
+        squeeze_blank_lines(&mut screen);
+        assert_eq!((screen.cursor.line, screen.cursor.col), (2, 4));
     }
 
+    // --- dim_timestamps ---
+
     #[test]
-    fn small_max_scrollback_truncates_old_lines() {
-        let mut input = Vec::new();
-        for i in 0..30 {
-            input.extend_from_slice(format!("line {i}\r\n").as_bytes());
+    fn leading_timestamp_formats() {
+        let cases = [
+            ("2024-01-31T12:34:56Z INFO up", Some(20)),
+            ("2024-01-31 12:34:56.789+09:00 x", Some(29)),
+            ("2024-01-31T12:34:56,5-0500", Some(26)),
+            ("[12:34:56] ready", Some(10)),
+            ("12:34:56.123 ready", Some(12)),
+            ("12:34:56", Some(8)),
+            ("12:34:567", None),
+            ("[12:34:56 ready", None),
+            ("2024-01-31 ready", None),
+            ("ready 12:34:56", None),
+            ("", None),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(leading_timestamp_len(text), expected, "{text:?}");
         }
-        let pd = PipeData {
-            cursor_x: 0,
-            cursor_y: 0,
-            lines: 10,
-            columns: 80,
-        };
-        // max_scrollback_lines=5 → scrollback buffer limited to 5 lines
-        let screen = process_bytes(&pd, &input, &palette::DEFAULT_PALETTE, 5);
-        // Oldest lines should be truncated
-        assert_ne!(screen.lines[0].text, "line 0");
     }
 
     #[test]
-    fn cursor_clamped_when_scrollback_truncated() {
-        let mut input = Vec::new();
-        for i in 0..30 {
-            input.extend_from_slice(format!("line {i}\r\n").as_bytes());
-        }
+    fn jump_to_match_first_error_line() {
         let pd = PipeData {
             cursor_x: 0,
             cursor_y: 5,
-            lines: 10,
+            lines: 24,
             columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
         };
-        let screen = process_bytes(&pd, &input, &palette::DEFAULT_PALETTE, 5);
-        // Cursor line should not exceed lines.len()
-        assert!(screen.cursor.line <= screen.lines.len());
-        assert!(screen.cursor.line >= 1);
+        let input = b"   Compiling foo\r\n\
+                      warning: unused\r\n\
+                      \x1b[31merror\x1b[0m: mismatched types\r\n\
+                      error: aborting\r\n\
+                      $ ";
+        let mut screen = process_bytes(
+            &pd,
+            input,
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        let pattern = regex::Regex::new("error:|Error|FAILED").unwrap();
+        assert!(jump_to_match(&mut screen, &pattern));
+        assert_eq!((screen.cursor.line, screen.cursor.col), (3, 1));
+
+        let pattern = regex::Regex::new("mismatched").unwrap();
+        assert!(jump_to_match(&mut screen, &pattern));
+        assert_eq!((screen.cursor.line, screen.cursor.col), (3, 8));
     }
 
     #[test]
-    fn process_bytes_does_not_panic_with_zero_lines() {
+    fn jump_to_match_no_match_keeps_cursor() {
         let pd = PipeData {
-            cursor_x: 0,
-            cursor_y: 0,
-            lines: 0,
+            cursor_x: 2,
+            cursor_y: 1,
+            lines: 24,
             columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
         };
-        // Should not panic — process_bytes clamps lines to 1
-        let screen = process_bytes(
+        let mut screen = process_bytes(
             &pd,
-            b"Hello",
+            b"all good\r\n$ ",
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
         );
-        assert!(screen.cursor.line >= 1);
+        let before = (screen.cursor.line, screen.cursor.col);
+        let pattern = regex::Regex::new("error:").unwrap();
+        assert!(!jump_to_match(&mut screen, &pattern));
+        assert_eq!((screen.cursor.line, screen.cursor.col), before);
     }
 
     #[test]
-    fn process_bytes_does_not_panic_with_zero_columns() {
+    fn colorize_diff_plain_unified_diff() {
         let pd = PipeData {
             cursor_x: 0,
             cursor_y: 0,
             lines: 24,
-            columns: 0,
+            columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
         };
-        // Should not panic — process_bytes clamps columns to 1
-        let screen = process_bytes(
+        let input = b"--- a/f.rs\r\n\
+                      +++ b/f.rs\r\n\
+                      @@ -1,2 +1,2 @@\r\n\
+                       context\r\n\
+                      -old\r\n\
+                      +new\r\n\
+                      \x1b[35m+colored\x1b[0m";
+        let mut screen = process_bytes(
             &pd,
-            b"Hello",
+            input,
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
         );
-        assert!(screen.cursor.line >= 1);
+        colorize_diff(&mut screen);
+
+        let spans: Vec<Vec<(usize, usize, &str)>> = screen
+            .lines
+            .iter()
+            .map(|l| {
+                l.spans
+                    .iter()
+                    .map(|s| (s.start_byte, s.end_byte, &*s.face))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(spans[0], [(1, 11, "red")]);
+        assert_eq!(spans[1], [(1, 11, "green")]);
+        assert_eq!(spans[2], [(1, 16, "cyan")]);
+        assert!(spans[3].is_empty());
+        assert_eq!(spans[4], [(1, 5, "red")]);
+        assert_eq!(spans[5], [(1, 5, "green")]);
+        // Already colored: the captured SGR wins
+        assert_eq!(spans[6].len(), 1);
+        assert_ne!(spans[6][0].2, "green");
     }
 
     #[test]
-    fn process_bytes_saturating_add_no_panic() {
-        let pd = PipeData {
-            cursor_x: 0,
-            cursor_y: usize::MAX,
-            lines: 24,
-            columns: 80,
-        };
-        let screen = process_bytes(
+    fn dim_timestamps_covers_only_the_prefix() {
+        let pd = default_pipe_data();
+        let input = b"\x1b[32m2024-01-31T12:34:56Z \x1b[31mERROR\x1b[0m disk\r\n\
+                      plain line\r\n\
+                      [09:00:01] \x1b[1mbold\x1b[0m";
+        let mut screen = process_bytes(
             &pd,
-            b"Hello",
+            input,
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
         );
-        // Saturated cursor_output_line → is_cursor_line never matches → cursor stays at initial (1,1)
-        assert_eq!(screen.cursor.line, 1);
-        assert_eq!(screen.cursor.col, 1);
+        dim_timestamps(&mut screen);
+
+        let line = &screen.lines[0];
+        assert_eq!(line.text, "2024-01-31T12:34:56Z ERROR disk");
+        let spans: Vec<(usize, usize, &str)> = line
+            .spans
+            .iter()
+            .map(|s| (s.start_byte, s.end_byte, &*s.face))
+            .collect();
+        // Green covered the timestamp and the space; only the space keeps it
+        assert_eq!(spans[0], (1, 21, "comment"));
+        assert_eq!((spans[1].0, spans[1].1), (21, 22));
+        assert_eq!((spans[2].0, spans[2].1), (22, 27));
+        assert_eq!(spans.len(), 3);
+
+        assert!(screen.lines[1].spans.is_empty());
+
+        let spans = &screen.lines[2].spans;
+        assert_eq!((spans[0].start_byte, spans[0].end_byte), (1, 11));
+        assert_eq!((spans[1].start_byte, spans[1].end_byte), (12, 16));
+        assert_eq!(&*spans[1].face, "default,default+b");
     }
 
-    // --- viewport_top_line ---
+    // --- split_commands ---
 
     #[test]
-    fn viewport_top_line_no_scrollback() {
-        let input = b"Hello";
+    fn split_commands_at_prompts() {
         let pd = default_pipe_data();
+        let input = b"welcome\r\n\
+                      \x1b]133;A\x07$ ls\r\n\x1b[34mdir\x1b[0m\r\n\
+                      \x1b]133;A\x07$ echo hi\r\nhi\r\n\
+                      \x1b]133;A\x07$ ";
         let screen = process_bytes(
             &pd,
             input,
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
         );
-        // No scrollback → total_sb = 0 → viewport_top_line = 1
-        assert_eq!(screen.viewport_top_line, 1);
+        let blocks = split_commands(&screen, 1..screen.lines.len() + 1);
+        let titles: Vec<&str> = blocks.iter().map(|b| b.title.as_str()).collect();
+        assert_eq!(titles, ["welcome", "$ ls", "$ echo hi", "$"]);
+
+        let ls = &blocks[1].screen;
+        let texts: Vec<&str> = ls.lines.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(texts, ["$ ls", "dir"]);
+        assert_eq!(ls.lines[1].spans.len(), 1);
+        assert_eq!(ls.line_sources.len(), 2);
+        assert_eq!((ls.cursor.line, ls.cursor.col), (1, 1));
     }
 
     #[test]
-    fn viewport_top_line_with_scrollback() {
-        let mut input = Vec::new();
-        for i in 0..30 {
-            input.extend_from_slice(format!("line {i}\r\n").as_bytes());
-        }
-        let pd = PipeData {
-            cursor_x: 0,
-            cursor_y: 0,
-            lines: 10,
-            columns: 80,
-        };
+    fn split_commands_without_boundaries_is_one_block() {
+        let pd = default_pipe_data();
         let screen = process_bytes(
             &pd,
-            &input,
+            b"a\r\nb",
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
         );
-        // 30 lines + trailing \r\n = 31 rows in 10-line terminal → total_sb = 21
-        // viewport_top_line = 21 + 1 = 22
-        assert_eq!(screen.viewport_top_line, 22);
+        let blocks = split_commands(&screen, 1..3);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].screen.lines.len(), 2);
+        let empty = process_bytes(
+            &pd,
+            b"",
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        assert!(split_commands(&empty, 1..empty.lines.len() + 1).is_empty());
     }
 
+    // --- auto-trim ---
+
     #[test]
-    fn viewport_top_line_clamped_after_trim() {
-        // Only 1 line of real content, cursor far below → empty lines trimmed
-        let input = b"Hello";
+    fn auto_trim_keeps_last_lines_and_records_truncation() {
+        let mut input = String::new();
+        for i in 1..=300 {
+            input.push_str(&format!("line {i}\r\n"));
+            if i == 100 || i == 280 {
+                input.push_str("\x1b]133;A\x07");
+            }
+        }
+        input.push_str("$ ");
         let pd = PipeData {
-            cursor_x: 0,
-            cursor_y: 0,
-            lines: 24,
-            columns: 80,
+            cursor_x: 2,
+            cursor_y: 23,
+            ..default_pipe_data()
         };
-        let screen = process_bytes(
+        let opts = ProcessOptions {
+            auto_trim: Some(50),
+            ..Default::default()
+        };
+        let full = process_bytes(
             &pd,
-            input,
+            input.as_bytes(),
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
         );
-        // No scrollback, lines trimmed to 1 → viewport_top_line = min(1, 1) = 1
-        assert_eq!(screen.viewport_top_line, 1);
-        assert_eq!(screen.lines.len(), 1);
+        let screen = process_bytes_with(
+            &pd,
+            input.as_bytes(),
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+            &opts,
+        );
+        assert_eq!(full.lines.len(), 301);
+        assert_eq!(full.truncated_lines, 0);
+
+        assert_eq!(screen.lines.len(), 50);
+        assert_eq!(screen.truncated_lines, 251);
+        assert_eq!(screen.lines[0].text, "line 252");
+        assert_eq!(screen.line_sources.len(), 50);
+        assert_eq!(screen.cursor.line, full.cursor.line - 251);
+        assert_eq!(screen.cursor.col, 2);
+        assert_eq!(screen.viewport_top_line, full.viewport_top_line - 251);
+        assert_eq!(screen.page_boundaries, [281 - 251]);
     }
 
     #[test]
-    fn viewport_top_line_empty_input() {
-        let pd = default_pipe_data();
-        let screen = process_bytes(
-            &pd,
-            b"",
+    fn auto_trim_never_cuts_into_viewport() {
+        let mut input = String::new();
+        for i in 1..=100 {
+            input.push_str(&format!("line {i}\r\n"));
+        }
+        let opts = ProcessOptions {
+            auto_trim: Some(5),
+            ..Default::default()
+        };
+        let screen = process_bytes_with(
+            &default_pipe_data(),
+            input.as_bytes(),
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
+            &opts,
         );
-        // Empty → viewport_top_line falls back to 1
+        // The 24-row viewport holds lines 78..=100 plus the empty cursor row
         assert_eq!(screen.viewport_top_line, 1);
+        assert_eq!(screen.lines[0].text, "line 78");
+        assert_eq!(screen.truncated_lines, 77);
     }
 
+    // --- no_trailing_trim ---
+
     #[test]
-    fn idx_and_rgb_same_color_merge() {
-        // ESC[31m (Idx 1 = palette red #CC0000) then ESC[38;2;204;0;0m (direct RGB)
-        // With default palette, Idx(1) resolves to the same RGB, so adjacent
-        // cells should merge into a single span.
-        let input = b"\x1b[31mA\x1b[38;2;204;0;0mB\x1b[0m";
-        let pd = default_pipe_data();
-        let screen = process_bytes(
+    fn no_trailing_trim_keeps_full_width() {
+        let opts = ProcessOptions {
+            no_trailing_trim: true,
+            ..Default::default()
+        };
+        let pd = PipeData {
+            cursor_x: 5,
+            cursor_y: 2,
+            ..default_pipe_data()
+        };
+        let screen = process_bytes_with(
             &pd,
-            input,
+            b"\x1b[41mred\x1b[0m\r\n\x1b[44mbar\x1b[K\x1b[0m\r\n$ ",
             &palette::DEFAULT_PALETTE,
             DEFAULT_MAX_SCROLLBACK_LINES,
+            &opts,
         );
-        assert_eq!(screen.lines[0].text, "AB");
-        assert_eq!(
-            screen.lines[0].spans.len(),
-            1,
-            "Idx(1) and Rgb(204,0,0) should merge into one span"
-        );
+        // Trailing blank rows are still dropped
+        assert_eq!(screen.lines.len(), 3);
+        assert_eq!(screen.lines[0].text, format!("red{}", " ".repeat(77)));
+        assert_eq!(screen.lines[0].spans[0].end_byte, 4);
+        // An erased-to-end background keeps its full width
+        assert_eq!(screen.lines[1].text.len(), 80);
+        assert_eq!(screen.lines[1].spans[0].end_byte, 81);
+        assert_eq!(screen.lines[2].text.len(), 80);
+        assert_eq!((screen.cursor.line, screen.cursor.col), (3, 6));
     }
 }
@@ -1,5 +1,7 @@
 use anyhow::{bail, Context, Result};
 
+use crate::kitty::PipeData;
+
 pub(crate) const CONF_SNIPPET: &str = include_str!("../rc/tmux.conf");
 
 /// Print recommended tmux.conf configuration to stdout.
@@ -7,6 +9,53 @@ pub(crate) fn generate_conf() {
     print!("{}", CONF_SNIPPET);
 }
 
+/// Source the generated snippet in a throwaway tmux server to confirm the
+/// installed tmux accepts it. A no-op (with a note) if tmux is missing.
+pub(crate) fn check_conf() -> Result<()> {
+    match check_conf_text(CONF_SNIPPET)? {
+        true => println!("tmux.conf snippet OK"),
+        false => eprintln!("kakoune-scrollback: tmux not found, skipping --check-tmux-conf"),
+    }
+    Ok(())
+}
+
+/// Returns `Ok(false)` if tmux is not installed, an error naming tmux's
+/// complaint if sourcing `conf` fails.
+fn check_conf_text(conf: &str) -> Result<bool> {
+    let dir = tempfile::Builder::new()
+        .prefix("ksb-tmux-")
+        .tempdir()
+        .context("failed to create temporary directory")?;
+    let conf_path = dir.path().join("tmux.conf");
+    std::fs::write(&conf_path, conf).context("failed to write temporary tmux.conf")?;
+    let path = conf_path.to_string_lossy().into_owned();
+    // A private socket and no user config, so the user's server is untouched
+    let socket = dir.path().join("socket").to_string_lossy().into_owned();
+
+    let output = match std::process::Command::new("tmux")
+        .args(["-S", &socket, "-f", "/dev/null", "new-session", "-d"])
+        .args([";", "source-file", &path])
+        .stdin(std::process::Stdio::null())
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e).context("failed to run tmux"),
+    };
+    let _ = std::process::Command::new("tmux")
+        .args(["-S", &socket, "kill-server"])
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status();
+
+    if !output.status.success() {
+        let mut msg = String::from_utf8_lossy(&output.stderr).into_owned();
+        msg.push_str(&String::from_utf8_lossy(&output.stdout));
+        bail!("tmux rejected the tmux.conf snippet: {}", msg.trim());
+    }
+    Ok(true)
+}
+
 /// Parse tmux version string and verify >= 3.3.
 /// Accepts formats like "tmux 3.3", "tmux 3.3a", "3.4", etc.
 /// Unparseable components default to 0 (e.g. "not-a-version" → 0.0 → Err).
@@ -39,6 +88,103 @@ pub(crate) fn check_version() -> Result<()> {
     parse_version(&version_str)
 }
 
+/// `display-message` format for `query_pane_geometry` (cursor is 0-based).
+const GEOMETRY_FORMAT: &str = "#{cursor_x},#{cursor_y}:#{pane_height},#{pane_width}";
+
+/// Parse `display-message` output in `GEOMETRY_FORMAT`: `cx,cy:height,width`.
+fn parse_pane_geometry(s: &str) -> Result<PipeData> {
+    let s = s.trim();
+    let parse_pair = |part: &str, what: &str| -> Result<(usize, usize)> {
+        let (a, b) = part
+            .split_once(',')
+            .with_context(|| format!("tmux pane geometry: expected '{what}', got '{part}'"))?;
+        let a = a
+            .parse()
+            .with_context(|| format!("tmux pane geometry: invalid {what} '{part}'"))?;
+        let b = b
+            .parse()
+            .with_context(|| format!("tmux pane geometry: invalid {what} '{part}'"))?;
+        Ok((a, b))
+    };
+
+    let (cursor, size) = s
+        .split_once(':')
+        .with_context(|| format!("tmux pane geometry: expected 'cx,cy:h,w', got '{s}'"))?;
+    let (cursor_x, cursor_y) = parse_pair(cursor, "cursor_x,cursor_y")?;
+    let (lines, columns) = parse_pair(size, "height,width")?;
+
+    let lines = u16::try_from(lines).context("tmux pane geometry: height out of range")?;
+    let columns = u16::try_from(columns).context("tmux pane geometry: width out of range")?;
+    if lines == 0 || columns == 0 {
+        bail!("tmux pane geometry: pane size must be at least 1x1, got {lines}x{columns}");
+    }
+    if cursor_x >= usize::from(columns) || cursor_y >= usize::from(lines) {
+        bail!("tmux pane geometry: cursor ({cursor_x},{cursor_y}) outside pane {columns}x{lines}");
+    }
+
+    Ok(PipeData {
+        cursor_x,
+        cursor_y,
+        lines,
+        columns,
+        scrolled_by: 0,
+        cursor_shape: None,
+    })
+}
+
+/// Ask tmux for the target pane's own cursor and size, so capturing an
+/// inactive pane does not use the active pane's geometry.
+pub(crate) fn query_pane_geometry(pane_id: &str) -> Result<PipeData> {
+    let output = std::process::Command::new("tmux")
+        .args(["display-message", "-p", "-t", pane_id, GEOMETRY_FORMAT])
+        .output()
+        .context("failed to run 'tmux display-message'")?;
+    if !output.status.success() {
+        bail!(
+            "tmux display-message failed for pane '{pane_id}': {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    parse_pane_geometry(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// How many rows below the last captured line the reported cursor may sit
+/// (a prompt line just after a trailing newline) before it is distrusted.
+const CURSOR_ROW_TOLERANCE: usize = 1;
+
+/// Number of lines in a `capture-pane` dump; a final line without a
+/// trailing newline still counts.
+pub(crate) fn captured_rows(data: &[u8]) -> usize {
+    let newlines = data.iter().filter(|&&b| b == b'\n').count();
+    newlines + usize::from(data.last().is_some_and(|&b| b != b'\n'))
+}
+
+/// Check the reported cursor row (0-based on a `lines`-row screen) against
+/// a capture of `rows` lines. A capture shorter than the screen has no
+/// history, so its content fills the top `rows` rows; a cursor further down
+/// than that (plus a tolerance) came from a different screen state. Returns
+/// the last content row to use instead, or `None` when they agree.
+pub(crate) fn cursor_row_mismatch(rows: usize, lines: u16, cursor_y: usize) -> Option<usize> {
+    if rows == 0 || rows >= usize::from(lines) {
+        return None;
+    }
+    (cursor_y > rows - 1 + CURSOR_ROW_TOLERANCE).then_some(rows - 1)
+}
+
+/// The visible pane row a capture starting at `start` (a `capture-pane -S`
+/// value, from `KAKOUNE_SCROLLBACK_TMUX_START`) begins on. `-` and negative
+/// lines start in the history, which the replay's scrollback lines up with,
+/// so they need no shift; `N` ≥ 0 starts on pane row N. `None` if invalid.
+pub(crate) fn capture_start_row(start: &str) -> Option<u16> {
+    match start.trim() {
+        "-" => Some(0),
+        s => {
+            let line = s.parse::<i64>().ok()?;
+            Some(u16::try_from(line.max(0)).unwrap_or(u16::MAX))
+        }
+    }
+}
+
 /// Insert CR before every bare LF so the vt100 parser resets the column.
 /// `tmux capture-pane -e -p` uses LF-only line endings.
 pub(crate) fn normalize_capture(data: &mut Vec<u8>) {
@@ -69,6 +215,20 @@ pub(crate) fn normalize_capture(data: &mut Vec<u8>) {
     }
 }
 
+/// `capture-pane -p` ends every line with a newline, the last one included.
+/// That newline terminates the last row instead of starting a new one:
+/// replayed as is, it scrolls a full pane by one more row and the cursor
+/// lands a line too low. Drop it (LF or CRLF) so a capture with and without
+/// it yields the same buffer and cursor.
+pub(crate) fn strip_final_newline(data: &mut Vec<u8>) {
+    if data.last() == Some(&b'\n') {
+        data.pop();
+        if data.last() == Some(&b'\r') {
+            data.pop();
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -82,6 +242,44 @@ mod tests {
         assert!(CONF_SNIPPET.contains("capture-pane"));
     }
 
+    #[test]
+    fn check_conf_text_with_throwaway_server() {
+        let tmux_available = std::process::Command::new("tmux")
+            .arg("-V")
+            .output()
+            .is_ok_and(|o| o.status.success());
+        if !tmux_available {
+            return;
+        }
+        assert!(check_conf_text(CONF_SNIPPET).unwrap());
+        let err = check_conf_text("bind-key H no-such-command\n").unwrap_err();
+        assert!(err.to_string().contains("no-such-command"), "{err}");
+    }
+
+    #[test]
+    fn captured_rows_counts_unterminated_last_line() {
+        assert_eq!(captured_rows(b""), 0);
+        assert_eq!(captured_rows(b"a\nb\n"), 2);
+        assert_eq!(captured_rows(b"a\nb"), 2);
+        assert_eq!(captured_rows(b"\n\n"), 2);
+    }
+
+    #[test]
+    fn cursor_row_mismatch_agrees() {
+        // Full screen captured: every row has content
+        assert_eq!(cursor_row_mismatch(500, 24, 23), None);
+        // Short capture, cursor on the last line or the prompt just below it
+        assert_eq!(cursor_row_mismatch(5, 24, 4), None);
+        assert_eq!(cursor_row_mismatch(5, 24, 5), None);
+        assert_eq!(cursor_row_mismatch(0, 24, 10), None);
+    }
+
+    #[test]
+    fn cursor_row_mismatch_disagrees() {
+        assert_eq!(cursor_row_mismatch(5, 24, 6), Some(4));
+        assert_eq!(cursor_row_mismatch(1, 24, 23), Some(0));
+    }
+
     #[test]
     fn normalize_bare_lf_to_crlf() {
         let mut data = b"line1\nline2\nline3\n".to_vec();
@@ -131,6 +329,72 @@ mod tests {
         assert_eq!(data, b"A\r\n\r\n\r\nB\r\n");
     }
 
+    #[test]
+    fn strip_final_newline_once() {
+        for (input, expected) in [
+            (&b"A\r\nB\r\n"[..], &b"A\r\nB"[..]),
+            (b"A\nB\n", b"A\nB"),
+            (b"A\r\nB", b"A\r\nB"),
+            (b"A\r\n\r\n", b"A\r\n"),
+            (b"\r", b"\r"),
+            (b"", b""),
+        ] {
+            let mut data = input.to_vec();
+            strip_final_newline(&mut data);
+            assert_eq!(data, expected);
+        }
+    }
+
+    #[test]
+    fn capture_start_row_values() {
+        assert_eq!(capture_start_row("-"), Some(0));
+        assert_eq!(capture_start_row("-500"), Some(0));
+        assert_eq!(capture_start_row("0"), Some(0));
+        assert_eq!(capture_start_row(" 12 "), Some(12));
+        assert_eq!(capture_start_row("99999999"), Some(u16::MAX));
+        assert_eq!(capture_start_row("top"), None);
+        assert_eq!(capture_start_row(""), None);
+    }
+
+    // --- parse_pane_geometry tests ---
+
+    #[test]
+    fn parse_pane_geometry_valid() {
+        let pd = parse_pane_geometry("5,23:24,80\n").unwrap();
+        assert_eq!(pd.cursor_x, 5);
+        assert_eq!(pd.cursor_y, 23);
+        assert_eq!(pd.lines, 24);
+        assert_eq!(pd.columns, 80);
+    }
+
+    #[test]
+    fn parse_pane_geometry_origin() {
+        let pd = parse_pane_geometry("0,0:1,1").unwrap();
+        assert_eq!(pd.cursor_x, 0);
+        assert_eq!(pd.cursor_y, 0);
+    }
+
+    #[test]
+    fn parse_pane_geometry_rejects_cursor_outside_pane() {
+        assert!(parse_pane_geometry("80,0:24,80").is_err());
+        assert!(parse_pane_geometry("0,24:24,80").is_err());
+    }
+
+    #[test]
+    fn parse_pane_geometry_rejects_zero_size() {
+        assert!(parse_pane_geometry("0,0:0,80").is_err());
+        assert!(parse_pane_geometry("0,0:24,0").is_err());
+    }
+
+    #[test]
+    fn parse_pane_geometry_rejects_malformed() {
+        assert!(parse_pane_geometry("").is_err());
+        assert!(parse_pane_geometry("5,23").is_err());
+        assert!(parse_pane_geometry("5:24,80").is_err());
+        assert!(parse_pane_geometry("a,b:24,80").is_err());
+        assert!(parse_pane_geometry("0,0:70000,80").is_err());
+    }
+
     // --- parse_version tests ---
 
     #[test]
@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use std::fmt::Write as FmtWrite;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::serve::json_escape;
+use crate::terminal::ProcessedScreen;
+
+/// A Kakoune face as Neovim highlight attributes (`nvim_set_hl`).
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct VimHighlight {
+    pub fg: Option<String>,
+    pub bg: Option<String>,
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub reverse: bool,
+}
+
+/// `rgb:RRGGBB` → `#RRGGBB`, `default` → `None`. Named colors have no fixed
+/// value outside Kakoune, so they are rejected.
+fn vim_color(color: &str) -> Option<Option<String>> {
+    match color {
+        "default" | "" => Some(None),
+        _ => {
+            let hex = color.strip_prefix("rgb:")?;
+            (hex.len() == 6 && hex.bytes().all(|b| b.is_ascii_hexdigit()))
+                .then(|| Some(format!("#{}", hex.to_ascii_uppercase())))
+        }
+    }
+}
+
+/// Convert a `fg,bg+flags` face string. Named faces (`ScrollbackHeader`,
+/// ...) and named colors yield `None`; dim has no GUI equivalent and is
+/// dropped.
+pub(crate) fn parse_face(face: &str) -> Option<VimHighlight> {
+    let (colors, flags) = face.split_once('+').unwrap_or((face, ""));
+    let (fg, bg) = colors.split_once(',')?;
+    Some(VimHighlight {
+        fg: vim_color(fg)?,
+        bg: vim_color(bg)?,
+        bold: flags.contains('b'),
+        italic: flags.contains('i'),
+        underline: flags.contains('u'),
+        reverse: flags.contains('r'),
+    })
+}
+
+fn highlight_json(hl: &VimHighlight) -> String {
+    let mut s = String::from("{");
+    let mut fields = Vec::new();
+    if let Some(fg) = &hl.fg {
+        fields.push(format!("\"fg\":\"{fg}\""));
+    }
+    if let Some(bg) = &hl.bg {
+        fields.push(format!("\"bg\":\"{bg}\""));
+    }
+    for (name, on) in [
+        ("bold", hl.bold),
+        ("italic", hl.italic),
+        ("underline", hl.underline),
+        ("reverse", hl.reverse),
+    ] {
+        if on {
+            fields.push(format!("\"{name}\":true"));
+        }
+    }
+    s.push_str(&fields.join(","));
+    s.push('}');
+    s
+}
+
+/// Render the highlights sidecar for `--format vim`:
+///
+/// ```json
+/// {"groups": {"KakouneScrollback1": {"fg": "#CC0000", "bold": true}},
+///  "highlights": [{"line": 0, "col": 4, "len": 3, "hl": "KakouneScrollback1"}]}
+/// ```
+///
+/// `line` and `col` are 0-based and `col`/`len` count bytes, matching
+/// `nvim_buf_add_highlight(buf, ns, hl, line, col, col + len)`. Each distinct
+/// face becomes one group; a named face is referenced by its own name.
+pub(crate) fn render_sidecar(screen: &ProcessedScreen) -> String {
+    let mut groups: HashMap<&str, String> = HashMap::new();
+    let mut group_defs = String::new();
+    let mut defined = 0;
+    let mut highlights = String::new();
+    for (line_idx, line) in screen.lines.iter().enumerate() {
+        for span in &line.spans {
+            let face = &*span.face;
+            if !groups.contains_key(face) {
+                let name = match parse_face(face) {
+                    Some(hl) => {
+                        defined += 1;
+                        let name = format!("KakouneScrollback{defined}");
+                        if defined > 1 {
+                            group_defs.push(',');
+                        }
+                        write!(group_defs, "\"{name}\":{}", highlight_json(&hl)).unwrap();
+                        name
+                    }
+                    None => face.to_string(),
+                };
+                groups.insert(face, name);
+            }
+            if !highlights.is_empty() {
+                highlights.push(',');
+            }
+            write!(
+                highlights,
+                "{{\"line\":{line_idx},\"col\":{},\"len\":{},\"hl\":\"{}\"}}",
+                span.start_byte - 1,
+                span.end_byte - span.start_byte,
+                json_escape(&groups[face])
+            )
+            .unwrap();
+        }
+    }
+    format!("{{\"groups\":{{{group_defs}}},\"highlights\":[{highlights}]}}\n")
+}
+
+pub(crate) fn write_sidecar(path: &Path, screen: &ProcessedScreen) -> Result<()> {
+    std::fs::write(path, render_sidecar(screen))
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// The line printed for the editor plugin: where the files are, and the
+/// cursor as `nvim_win_set_cursor` takes it (1-based line, 0-based byte).
+pub(crate) fn paths_message(
+    screen: &ProcessedScreen,
+    tmp_dir: &Path,
+    text_path: &Path,
+    highlights_path: &Path,
+) -> String {
+    format!(
+        "{{\"tmp_dir\":\"{}\",\"text\":\"{}\",\"highlights\":\"{}\",\"cursor\":[{},{}]}}",
+        json_escape(&tmp_dir.display().to_string()),
+        json_escape(&text_path.display().to_string()),
+        json_escape(&highlights_path.display().to_string()),
+        screen.cursor.line,
+        screen.cursor.col.saturating_sub(1)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kitty::PipeData;
+    use crate::palette;
+    use crate::terminal::{self, DEFAULT_MAX_SCROLLBACK_LINES};
+
+    #[test]
+    fn parse_face_colors_and_flags() {
+        assert_eq!(
+            parse_face("rgb:cc0000,default+bid"),
+            Some(VimHighlight {
+                fg: Some("#CC0000".into()),
+                bold: true,
+                italic: true,
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            parse_face("default,rgb:1A1B26+ur"),
+            Some(VimHighlight {
+                bg: Some("#1A1B26".into()),
+                underline: true,
+                reverse: true,
+                ..Default::default()
+            })
+        );
+        assert_eq!(parse_face("ScrollbackHeader"), None);
+        assert_eq!(parse_face("red,default"), None);
+    }
+
+    #[test]
+    fn paths_message_is_json() {
+        let screen = ProcessedScreen {
+            lines: vec![],
+            cursor: terminal::CursorPosition { line: 3, col: 5 },
+            viewport_top_line: 1,
+            viewport_bottom_line: 1,
+            page_boundaries: vec![],
+            line_sources: vec![],
+            scrolled_by: 0,
+            truncated_lines: 0,
+            history_full: false,
+            cursor_shape: None,
+        };
+        let msg = paths_message(
+            &screen,
+            Path::new("/tmp/ksb-x"),
+            Path::new("/tmp/ksb-x/text.txt"),
+            Path::new("/tmp/ksb-x/highlights.json"),
+        );
+        let json: serde_json::Value = serde_json::from_str(&msg).unwrap();
+        assert_eq!(json["highlights"], "/tmp/ksb-x/highlights.json");
+        assert_eq!(json["cursor"], serde_json::json!([3, 4]));
+    }
+
+    #[test]
+    fn sidecar_for_colored_fixture() {
+        let pd = PipeData {
+            cursor_x: 0,
+            cursor_y: 0,
+            lines: 24,
+            columns: 80,
+            scrolled_by: 0,
+            cursor_shape: None,
+        };
+        let input = "ok \x1b[1;31mfail\x1b[0m\r\n日本 \x1b[42mgo\x1b[0m \x1b[31mno\x1b[0m";
+        let screen = terminal::process_bytes(
+            &pd,
+            input.as_bytes(),
+            &palette::DEFAULT_PALETTE,
+            DEFAULT_MAX_SCROLLBACK_LINES,
+        );
+        let json: serde_json::Value = serde_json::from_str(&render_sidecar(&screen)).unwrap();
+
+        let groups = &json["groups"];
+        assert_eq!(
+            groups["KakouneScrollback1"],
+            serde_json::json!({"fg": "#CC0000", "bold": true})
+        );
+        assert_eq!(
+            groups["KakouneScrollback2"],
+            serde_json::json!({"bg": "#00CC00"})
+        );
+        assert_eq!(
+            groups["KakouneScrollback3"],
+            serde_json::json!({"fg": "#CC0000"})
+        );
+
+        let highlights: Vec<(u64, u64, u64, &str)> = json["highlights"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|h| {
+                (
+                    h["line"].as_u64().unwrap(),
+                    h["col"].as_u64().unwrap(),
+                    h["len"].as_u64().unwrap(),
+                    h["hl"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        // "日本 " is 7 bytes, so "go" starts at byte 7 of line 1
+        assert_eq!(
+            highlights,
+            [
+                (0, 3, 4, "KakouneScrollback1"),
+                (1, 7, 2, "KakouneScrollback2"),
+                (1, 10, 2, "KakouneScrollback3"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,130 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Parse a `--limit-seconds` value: a positive number of seconds, fractions allowed.
+pub(crate) fn parse_limit_seconds(value: &str) -> Result<Duration, String> {
+    match value.trim().parse::<f64>() {
+        Ok(secs) if secs.is_finite() && secs > 0.0 => Duration::try_from_secs_f64(secs)
+            .map_err(|_| format!("invalid --limit-seconds value '{value}'")),
+        _ => Err(format!(
+            "invalid --limit-seconds value '{value}' (expected a positive number)"
+        )),
+    }
+}
+
+type TimeoutFn = Box<dyn FnOnce(Option<PathBuf>) + Send>;
+
+/// Wall-clock limit on a run. Once armed, a background thread fires
+/// `on_timeout` with the tracked temp dir (if any) unless `disarm` is
+/// called first.
+pub(crate) struct Watchdog {
+    done: mpsc::Sender<()>,
+    tracked: Arc<Mutex<Option<PathBuf>>>,
+    unarmed: Mutex<Option<(mpsc::Receiver<()>, TimeoutFn)>>,
+}
+
+/// Remove the temp dir the watchdog was tracking when it fired.
+pub(crate) fn remove_tracked(tracked: Option<PathBuf>) {
+    if let Some(dir) = tracked {
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
+
+impl Watchdog {
+    /// Abort the process after `limit`, removing the tracked temp dir first.
+    pub(crate) fn start(limit: Duration) -> Self {
+        let watchdog = Self::new(move |tracked| {
+            remove_tracked(tracked);
+            eprintln!(
+                "kakoune-scrollback: aborted after {:.1}s (--limit-seconds)",
+                limit.as_secs_f64()
+            );
+            std::process::exit(124);
+        });
+        watchdog.arm(limit);
+        watchdog
+    }
+
+    /// A watchdog that tracks a temp dir but has no timer until `arm`.
+    pub(crate) fn new(on_timeout: impl FnOnce(Option<PathBuf>) + Send + 'static) -> Self {
+        let (done, rx) = mpsc::channel::<()>();
+        Watchdog {
+            done,
+            tracked: Arc::new(Mutex::new(None)),
+            unarmed: Mutex::new(Some((rx, Box::new(on_timeout)))),
+        }
+    }
+
+    /// Start the timer; only the first call has an effect.
+    pub(crate) fn arm(&self, limit: Duration) {
+        let Some((rx, on_timeout)) = self.unarmed.lock().ok().and_then(|mut u| u.take()) else {
+            return;
+        };
+        let tracked = Arc::clone(&self.tracked);
+        std::thread::spawn(move || {
+            // A disconnect means the run finished (or the watchdog was dropped)
+            if let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(limit) {
+                let dir = tracked.lock().ok().and_then(|mut d| d.take());
+                on_timeout(dir);
+            }
+        });
+    }
+
+    /// Remember the temp dir to remove if the limit is hit.
+    pub(crate) fn track_dir(&self, dir: &Path) {
+        if let Ok(mut tracked) = self.tracked.lock() {
+            *tracked = Some(dir.to_path_buf());
+        }
+    }
+
+    /// Stop the watchdog; the timeout can no longer fire.
+    pub(crate) fn disarm(self) {
+        let _ = self.done.send(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_limit_seconds_valid() {
+        assert_eq!(parse_limit_seconds("5"), Ok(Duration::from_secs(5)));
+        assert_eq!(parse_limit_seconds(" 0.5 "), Ok(Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn parse_limit_seconds_invalid() {
+        for value in ["0", "-1", "abc", "", "inf", "NaN"] {
+            assert!(parse_limit_seconds(value).is_err(), "{value:?} should fail");
+        }
+    }
+
+    #[test]
+    fn fires_and_hands_over_tracked_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let watchdog = Watchdog::new(move |tracked| {
+            tx.send(tracked).unwrap();
+        });
+        // Tracked before the timer runs, so the timeout cannot miss it
+        watchdog.track_dir(dir.path());
+        watchdog.arm(Duration::from_millis(10));
+
+        let tracked = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(tracked.as_deref(), Some(dir.path()));
+    }
+
+    #[test]
+    fn disarm_prevents_timeout() {
+        let (tx, rx) = mpsc::channel();
+        let watchdog = Watchdog::new(move |_| {
+            tx.send(()).unwrap();
+        });
+        watchdog.arm(Duration::from_millis(50));
+        watchdog.disarm();
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+}